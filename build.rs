@@ -0,0 +1,7 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // tonic-build is only a build dependency with the grpc feature
+    #[cfg(feature = "grpc")]
+    tonic_build::compile_protos("proto/payments.proto")?;
+
+    Ok(())
+}
@@ -0,0 +1,43 @@
+#ifndef PAYMENTS_H
+#define PAYMENTS_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* The row was applied. */
+#define PAYMENTS_OK 0
+/* The transaction failed, e.g. insufficient funds, see payments_last_error. */
+#define PAYMENTS_REJECTED 1
+/* The row couldn't be read as a transaction, see payments_last_error. */
+#define PAYMENTS_MALFORMED 2
+/* A pointer was null. */
+#define PAYMENTS_INVALID -1
+
+typedef struct PaymentsProcessor PaymentsProcessor;
+
+PaymentsProcessor *payments_processor_new(void);
+
+/* processor isn't valid afterwards, null is ignored. */
+void payments_processor_free(PaymentsProcessor *processor);
+
+/* Applies one csv row with the columns type,client,tx,amount,currency, e.g. "deposit,1,1,10.5" or
+ * "dispute,1,1". Returns one of the codes above. */
+int payments_process_row(PaymentsProcessor *processor, const char *row);
+
+/* Why the last row wasn't applied, null if it was. Owned by the processor, valid until the next call
+ * with it. */
+const char *payments_last_error(const PaymentsProcessor *processor);
+
+/* The accounts as csv, the same as `payments process` writes, or null if they couldn't be written.
+ * Free it with payments_string_free. */
+char *payments_export_accounts(const PaymentsProcessor *processor);
+
+/* string isn't valid afterwards, null is ignored. */
+void payments_string_free(char *string);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
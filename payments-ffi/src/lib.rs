@@ -0,0 +1,150 @@
+//! C bindings for the payments engine, declared in `include/payments.h`.
+
+use payments::{RejectReason, TransactionProcessor};
+use std::{
+    ffi::{c_char, c_int, CStr, CString},
+    ptr,
+};
+
+/// The row was applied.
+pub const PAYMENTS_OK: c_int = 0;
+/// The transaction failed, e.g. insufficient funds, see `payments_last_error`.
+pub const PAYMENTS_REJECTED: c_int = 1;
+/// The row couldn't be read as a transaction, see `payments_last_error`.
+pub const PAYMENTS_MALFORMED: c_int = 2;
+/// A pointer was null.
+pub const PAYMENTS_INVALID: c_int = -1;
+
+/// Columns of the rows passed to `payments_process_row`, trailing ones can be left off.
+const COLUMNS: [&str; 5] = ["type", "client", "tx", "amount", "currency"];
+
+/// Opaque to C, a processor and why its last row wasn't applied.
+pub struct PaymentsProcessor {
+    processor: TransactionProcessor,
+    last_error: Option<CString>,
+}
+
+impl PaymentsProcessor {
+    fn fail(&mut self, code: c_int, message: &str) -> c_int {
+        self.last_error = Some(CString::new(message.replace('\0', "")).unwrap_or_default());
+        code
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn payments_processor_new() -> *mut PaymentsProcessor {
+    Box::into_raw(Box::new(PaymentsProcessor {
+        processor: TransactionProcessor::new(),
+        last_error: None,
+    }))
+}
+
+/// # Safety
+///
+/// `processor` must be null or from `payments_processor_new`, and isn't valid afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn payments_processor_free(processor: *mut PaymentsProcessor) {
+    if !processor.is_null() {
+        drop(Box::from_raw(processor));
+    }
+}
+
+/// Applies one csv row with the columns `type,client,tx,amount,currency`, e.g. `deposit,1,1,10.5` or
+/// `dispute,1,1`.
+///
+/// # Safety
+///
+/// `processor` must be from `payments_processor_new` and `row` a nul terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn payments_process_row(
+    processor: *mut PaymentsProcessor,
+    row: *const c_char,
+) -> c_int {
+    let Some(processor) = processor.as_mut() else {
+        return PAYMENTS_INVALID;
+    };
+
+    if row.is_null() {
+        return PAYMENTS_INVALID;
+    }
+
+    processor.last_error = None;
+
+    let row = match CStr::from_ptr(row).to_str() {
+        Ok(row) if row.trim().is_empty() || row.contains('\n') => {
+            return processor.fail(PAYMENTS_MALFORMED, "expected a single row");
+        }
+        Ok(row) => row,
+        Err(_) => return processor.fail(PAYMENTS_MALFORMED, "row isn't utf-8"),
+    };
+
+    // The header only has the columns the row has, as rows with a different number of fields are rejected.
+    // Rows with too many keep every column, so they're rejected for it.
+    let fields = row.split(',').count().min(COLUMNS.len());
+    let header = COLUMNS[..fields].join(",");
+
+    let mut rejected = None;
+    let result = processor.processor.process_transactions_with_rejects(
+        format!("{header}\n{row}").as_bytes(),
+        |rejection| {
+            rejected = Some(rejection.reason);
+            Ok(())
+        },
+    );
+
+    match (result, rejected) {
+        (Err(error), _) => processor.fail(PAYMENTS_MALFORMED, &error.to_string()),
+        (Ok(()), Some(reason @ RejectReason::Malformed(_))) => {
+            processor.fail(PAYMENTS_MALFORMED, &reason.to_string())
+        }
+        (Ok(()), Some(reason)) => processor.fail(PAYMENTS_REJECTED, &reason.to_string()),
+        (Ok(()), None) => PAYMENTS_OK,
+    }
+}
+
+/// Why the last row passed to `payments_process_row` wasn't applied, null if it was. Owned by the processor,
+/// valid until the next call with it.
+///
+/// # Safety
+///
+/// `processor` must be from `payments_processor_new`.
+#[no_mangle]
+pub unsafe extern "C" fn payments_last_error(processor: *const PaymentsProcessor) -> *const c_char {
+    processor
+        .as_ref()
+        .and_then(|processor| processor.last_error.as_ref())
+        .map_or(ptr::null(), |error| error.as_ptr())
+}
+
+/// The accounts as csv, the same as `payments process` writes, or null if they couldn't be written. Free it
+/// with `payments_string_free`.
+///
+/// # Safety
+///
+/// `processor` must be from `payments_processor_new`.
+#[no_mangle]
+pub unsafe extern "C" fn payments_export_accounts(
+    processor: *const PaymentsProcessor,
+) -> *mut c_char {
+    let Some(processor) = processor.as_ref() else {
+        return ptr::null_mut();
+    };
+
+    let mut csv = Vec::new();
+
+    if processor.processor.write_accounts(&mut csv, None).is_err() {
+        return ptr::null_mut();
+    }
+
+    CString::new(csv).map_or(ptr::null_mut(), CString::into_raw)
+}
+
+/// # Safety
+///
+/// `string` must be null or from `payments_export_accounts`, and isn't valid afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn payments_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
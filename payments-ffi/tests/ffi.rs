@@ -0,0 +1,42 @@
+use payments_ffi::*;
+use std::ffi::{CStr, CString};
+
+#[test]
+fn process_and_export() {
+    unsafe {
+        let processor = payments_processor_new();
+        let process = |row: &str| {
+            let row = CString::new(row).unwrap();
+            payments_process_row(processor, row.as_ptr())
+        };
+
+        assert_eq!(process("deposit,1,1,10.5"), PAYMENTS_OK);
+        assert!(payments_last_error(processor).is_null());
+        assert_eq!(process("withdrawal,1,2,1.5,USD"), PAYMENTS_OK);
+        assert_eq!(process("dispute,1,1"), PAYMENTS_OK);
+
+        assert_eq!(process("withdrawal,1,3,100"), PAYMENTS_REJECTED);
+        assert_eq!(
+            CStr::from_ptr(payments_last_error(processor))
+                .to_str()
+                .unwrap(),
+            "insufficient available funds"
+        );
+
+        assert_eq!(process("deposit,,4,1"), PAYMENTS_MALFORMED);
+        assert_eq!(process(""), PAYMENTS_MALFORMED);
+        assert_eq!(
+            payments_process_row(processor, std::ptr::null()),
+            PAYMENTS_INVALID
+        );
+
+        let accounts = payments_export_accounts(processor);
+        assert_eq!(
+            CStr::from_ptr(accounts).to_str().unwrap(),
+            "client,available,held,total,locked,currency\n1,-1.5000,10.5000,9.0000,false,USD\n"
+        );
+
+        payments_string_free(accounts);
+        payments_processor_free(processor);
+    }
+}
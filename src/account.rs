@@ -1,126 +1,646 @@
-use crate::transaction::{DisputedState, Transaction, TransactionRecord};
-use rust_decimal::Decimal;
-use std::collections::HashMap;
+use crate::client::ClientId;
+use crate::config::{Config, DisputePolicy, LockedPolicy};
+use crate::currency::Currency;
+use crate::error::TransactionError;
+use crate::ledger::{LedgerAccount, Posting};
+use crate::money::Money;
+use crate::store::TransactionStore;
+use crate::transaction::{
+    DisputedState, RecordKind, Transaction, TransactionRecord, TransactionType,
+};
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
-#[derive(Debug, PartialEq, Eq)]
+/// A client's funds in one currency.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Balance {
+    /// Funds available for withdrawal.
+    pub available: Money,
+    /// Funds held while a deposit is disputed.
+    pub held: Money,
+    /// Disputed funds that couldn't be held as they'd already been spent, with `DisputePolicy::Clamp`.
+    /// Still owed after a chargeback.
+    pub shortfall: Money,
+}
+
+impl Balance {
+    /// Total funds, available + held.
+    pub fn total(&self) -> Money {
+        &self.available + &self.held
+    }
+}
+
+/// A client's balances.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct Account {
-    pub client: u16,
-    pub available: Decimal,
-    pub held: Decimal,
+    pub client: ClientId,
+    /// A balance for every currency the client has used.
+    pub balances: BTreeMap<Currency, Balance>,
+    /// Set when a chargeback occurs.
     pub locked: bool,
 }
 
 impl Account {
-    pub fn new(client: u16) -> Self {
+    pub fn new(client: ClientId) -> Self {
         Self {
             client,
-            available: Decimal::ZERO,
-            held: Decimal::ZERO,
+            balances: BTreeMap::new(),
             locked: false,
         }
     }
 
-    pub fn process(
+    /// Zero if the client has never used `currency`.
+    pub fn balance(&self, currency: Currency) -> Balance {
+        self.balances.get(&currency).cloned().unwrap_or_default()
+    }
+
+    /// Fees from `config` are charged as part of the transaction. Transactions without a currency are
+    /// in the base currency, disputes, reversals etc. are in the currency of the transaction they reference.
+    /// `withdrawn_today` is what the client has withdrawn in the transaction's currency on its day, for
+    /// `ClientLimit::daily_out_limit`. The transaction's postings are added to `postings`.
+    pub(crate) fn process<S: TransactionStore>(
         &mut self,
         transaction: &Transaction,
-        transactions: &mut HashMap<u32, TransactionRecord>,
-    ) -> Result<(), ()> {
+        transactions: &mut S,
+        config: &Config,
+        withdrawn_today: Decimal,
+        postings: &mut Vec<Posting>,
+    ) -> Result<(), TransactionError> {
         use Transaction::*;
 
-        if let Deposit { client, tx, amount } = *transaction {
-            transactions.insert(
-                tx,
-                TransactionRecord {
-                    amount,
-                    client,
-                    disputed: DisputedState::Undisputed,
-                },
-            );
+        let currency = transaction.currency().unwrap_or(config.base_currency);
+        let credit_limit = config.credit_limits.limit(self.client);
+        let limit = config.limits.limit(self.client);
+
+        // Deposits and withdrawals are kept, so reusing their tx would overwrite the earlier record
+        if let Deposit { tx, .. } | Withdrawal { tx, .. } = *transaction {
+            if transactions.get(tx)?.is_some() {
+                return Err(TransactionError::DuplicateTransaction);
+            }
+        }
+
+        if self.locked && !self.allowed_while_locked(transaction, transactions, config)? {
+            return Err(TransactionError::AccountLocked);
         }
 
         match *transaction {
-            Deposit { amount, .. } => self.deposit(amount)?,
-            Withdrawal { amount, .. } => self.withdrawal(amount)?,
-            Dispute { client, tx } => {
-                let dependent_transaction = transactions.get_mut(&tx).ok_or(())?;
+            Deposit {
+                client,
+                tx,
+                amount,
+                timestamp,
+                ..
+            } => {
+                if limit.max_balance.is_some_and(|max| {
+                    self.balance(currency)
+                        .total()
+                        .checked_add(&amount.into())
+                        .is_none_or(|total| total > max)
+                }) {
+                    return Err(TransactionError::BalanceLimitExceeded);
+                }
 
-                if dependent_transaction.disputed != DisputedState::Undisputed
-                    || dependent_transaction.client != client
+                self.deposit(postings, LedgerAccount::Suspense, currency, amount)?;
+                self.charge(
+                    postings,
+                    currency,
+                    config.fees.fee(TransactionType::Deposit, amount),
+                )?;
+                transactions.insert(
+                    tx,
+                    TransactionRecord {
+                        amount,
+                        client,
+                        disputed: DisputedState::Undisputed,
+                        kind: RecordKind::Deposit,
+                        currency,
+                        shortfall: Decimal::ZERO,
+                        timestamp,
+                        disputed_at: None,
+                    },
+                )?;
+            }
+            Withdrawal {
+                client,
+                tx,
+                amount,
+                timestamp,
+                ..
+            } => {
+                if limit.max_withdrawal.is_some_and(|max| amount > max) {
+                    return Err(TransactionError::WithdrawalLimitExceeded);
+                }
+
+                if limit
+                    .daily_out_limit
+                    .is_some_and(|max| withdrawn_today + amount > max)
                 {
-                    return Err(());
+                    return Err(TransactionError::DailyLimitExceeded);
                 }
 
-                self.dispute(dependent_transaction.amount);
+                self.withdrawal(
+                    postings,
+                    LedgerAccount::Suspense,
+                    currency,
+                    amount,
+                    config.fees.fee(TransactionType::Withdrawal, amount),
+                    credit_limit,
+                )?;
+                transactions.insert(
+                    tx,
+                    TransactionRecord {
+                        amount,
+                        client,
+                        disputed: DisputedState::Undisputed,
+                        kind: RecordKind::Withdrawal,
+                        currency,
+                        shortfall: Decimal::ZERO,
+                        timestamp,
+                        disputed_at: None,
+                    },
+                )?;
+            }
+            Dispute {
+                client,
+                tx,
+                timestamp,
+            } => {
+                let mut dependent_transaction = transactions
+                    .get(tx)?
+                    .filter(|record| record.kind == RecordKind::Deposit)
+                    .ok_or(TransactionError::UnknownTransaction)?;
+
+                if dependent_transaction.client != client {
+                    return Err(TransactionError::ClientMismatch);
+                }
+
+                match dependent_transaction.disputed {
+                    DisputedState::Undisputed => {}
+                    DisputedState::Resolved if config.redispute_resolved => {}
+                    _ => return Err(TransactionError::AlreadyDisputed),
+                }
+
+                // Disputes can only be checked against the window if both rows have a timestamp
+                if let (Some(window), Some(deposited), Some(disputed)) = (
+                    config.dispute_window,
+                    dependent_transaction.timestamp,
+                    timestamp,
+                ) {
+                    if disputed.saturating_sub(deposited) > window.as_secs() {
+                        return Err(TransactionError::DisputeWindowExpired);
+                    }
+                }
+
+                dependent_transaction.shortfall = self.dispute_shortfall(
+                    dependent_transaction.currency,
+                    dependent_transaction.amount,
+                    config.dispute_policy,
+                )?;
                 dependent_transaction.disputed = DisputedState::Disputed;
+                dependent_transaction.disputed_at = timestamp;
+                self.dispute(postings, &dependent_transaction)?;
+                transactions.insert(tx, dependent_transaction)?;
             }
-            Resolve { .. } => {
-                let dependent_transaction =
-                    dependent_transaction(transaction, transactions).ok_or(())?;
-                self.resolve(dependent_transaction.amount);
+            Resolve { tx, .. } => {
+                let mut dependent_transaction = dependent_transaction(transaction, transactions)?;
                 dependent_transaction.disputed = DisputedState::Resolved;
+                self.resolve(postings, &dependent_transaction)?;
+                transactions.insert(tx, dependent_transaction)?;
             }
-            Chargeback { .. } => {
-                let dependent_transaction =
-                    dependent_transaction(transaction, transactions).ok_or(())?;
-                self.chargeback(dependent_transaction.amount);
+            Chargeback { tx, .. } => {
+                let mut dependent_transaction = dependent_transaction(transaction, transactions)?;
                 dependent_transaction.disputed = DisputedState::Chargebacked;
+                self.chargeback(postings, &dependent_transaction)?;
+                self.charge(
+                    postings,
+                    dependent_transaction.currency,
+                    config
+                        .fees
+                        .fee(TransactionType::Chargeback, dependent_transaction.amount),
+                )?;
+                transactions.insert(tx, dependent_transaction)?;
+            }
+            Unlock { .. } => self.unlock()?,
+            ChargebackReversal { client, tx, .. } => {
+                let mut reversed = transactions
+                    .get(tx)?
+                    .filter(|record| record.kind == RecordKind::Deposit)
+                    .ok_or(TransactionError::UnknownTransaction)?;
+
+                if reversed.client != client {
+                    return Err(TransactionError::ClientMismatch);
+                }
+
+                if reversed.disputed != DisputedState::Chargebacked {
+                    return Err(TransactionError::NotChargedBack);
+                }
+
+                reversed.disputed = DisputedState::ChargebackReversed;
+                self.reverse_chargeback(postings, &reversed)?;
+                transactions.insert(tx, reversed)?;
+
+                if config.unlock_on_chargeback_reversal {
+                    self.locked = false;
+                }
+            }
+            Interest { amount, .. } => {
+                self.deposit(postings, LedgerAccount::Interest, currency, amount)?
+            }
+            Fee { amount, .. } => {
+                if amount < Decimal::ZERO {
+                    return Err(TransactionError::NegativeAmount);
+                }
+
+                self.charge(postings, currency, amount)?;
+            }
+            Reversal { client, tx, .. } => {
+                let mut reversed = transactions
+                    .get(tx)?
+                    .ok_or(TransactionError::UnknownTransaction)?;
+
+                if reversed.client != client {
+                    return Err(TransactionError::ClientMismatch);
+                }
+
+                if !matches!(
+                    reversed.disputed,
+                    DisputedState::Undisputed | DisputedState::Resolved
+                ) {
+                    return Err(TransactionError::NotReversible);
+                }
+
+                if reversed.kind == RecordKind::Deposit
+                    && self.balance(reversed.currency).available < reversed.amount
+                {
+                    return Err(TransactionError::InsufficientFunds);
+                }
+
+                reversed.disputed = DisputedState::Reversed;
+                self.reverse(postings, &reversed)?;
+                transactions.insert(tx, reversed)?;
+            }
+            Convert {
+                amount,
+                to_currency,
+                ..
+            } => {
+                let rate = config
+                    .rates
+                    .rate(currency, to_currency)
+                    .ok_or(TransactionError::UnknownRate)?;
+
+                self.withdrawal(
+                    postings,
+                    LedgerAccount::Conversion,
+                    currency,
+                    amount,
+                    config.fees.fee(TransactionType::Convert, amount),
+                    credit_limit,
+                )?;
+                let converted = amount
+                    .checked_mul(rate)
+                    .ok_or(TransactionError::AmountTooLarge)?;
+                self.post(
+                    postings,
+                    LedgerAccount::Conversion,
+                    LedgerAccount::Available(self.client),
+                    to_currency,
+                    converted.round_dp_with_strategy(
+                        config.output_precision(),
+                        RoundingStrategy::MidpointNearestEven,
+                    ),
+                )?;
+            }
+            Transfer { .. } => {
+                unreachable!("transfers need both accounts so are applied by TransactionProcessor")
             }
         }
 
         Ok(())
     }
 
-    fn deposit(&mut self, amount: Decimal) -> Result<(), ()> {
-        if amount >= Decimal::ZERO {
-            self.available += amount;
-            Ok(())
-        } else {
-            Err(())
+    /// Whether `config.locked_policy` lets the transaction be applied to this account, as it's locked.
+    fn allowed_while_locked<S: TransactionStore>(
+        &self,
+        transaction: &Transaction,
+        transactions: &S,
+        config: &Config,
+    ) -> Result<bool, TransactionError> {
+        use Transaction::*;
+
+        let allowed = match (config.locked_policy, *transaction) {
+            (_, Unlock { .. } | ChargebackReversal { .. }) | (LockedPolicy::Allow, _) => true,
+            (LockedPolicy::Reject, _) => false,
+            (LockedPolicy::RejectDebits, Withdrawal { .. } | Fee { .. } | Convert { .. }) => false,
+            (LockedPolicy::RejectDebits, Reversal { tx, .. }) => !transactions
+                .get(tx)?
+                .is_some_and(|record| record.kind == RecordKind::Deposit),
+            (LockedPolicy::RejectDebits, _) => true,
+        };
+
+        Ok(allowed)
+    }
+
+    /// Books a posting, applying this account's sides of it. Balances the processor's `Ledger` with the
+    /// system accounts' sides once the transaction succeeds.
+    fn post(
+        &mut self,
+        postings: &mut Vec<Posting>,
+        debit: LedgerAccount,
+        credit: LedgerAccount,
+        currency: Currency,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        let amount = Money::from_decimal(amount).ok_or(TransactionError::AmountTooLarge)?;
+        let posting = Posting::new(debit, credit, currency, amount);
+        self.apply(&posting)?;
+        postings.push(posting);
+        Ok(())
+    }
+
+    /// Applies the sides of `posting` that are this client's accounts. Nothing is applied if a balance, or the
+    /// total of the available and held funds, would overflow.
+    pub(crate) fn apply(&mut self, posting: &Posting) -> Result<(), TransactionError> {
+        let mut balance = self.balance(posting.currency);
+        let mut applied = false;
+
+        for (account, amount) in [
+            (posting.debit, -&posting.amount),
+            (posting.credit, posting.amount.clone()),
+        ] {
+            if account.client() != Some(self.client) {
+                continue;
+            }
+
+            let (side, amount) = match account {
+                LedgerAccount::Available(_) => (&mut balance.available, amount),
+                LedgerAccount::Held(_) => (&mut balance.held, amount),
+                LedgerAccount::Shortfall(_) => (&mut balance.shortfall, -amount),
+                _ => unreachable!("only clients' accounts have a client"),
+            };
+            *side = side
+                .checked_add(&amount)
+                .ok_or(TransactionError::AmountTooLarge)?;
+            applied = true;
         }
+
+        if applied {
+            balance
+                .available
+                .checked_add(&balance.held)
+                .ok_or(TransactionError::AmountTooLarge)?;
+            self.balances.insert(posting.currency, balance);
+        }
+
+        Ok(())
     }
 
-    fn withdrawal(&mut self, amount: Decimal) -> Result<(), ()> {
-        if amount >= Decimal::ZERO && self.available >= amount {
-            self.available -= amount;
-            Ok(())
-        } else {
-            Err(())
+    fn deposit(
+        &mut self,
+        postings: &mut Vec<Posting>,
+        from: LedgerAccount,
+        currency: Currency,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        if amount < Decimal::ZERO {
+            return Err(TransactionError::NegativeAmount);
+        }
+
+        self.post(
+            postings,
+            from,
+            LedgerAccount::Available(self.client),
+            currency,
+            amount,
+        )
+    }
+
+    /// The available funds have to cover the fee as well, available funds can go as far below zero as `credit_limit`.
+    fn withdrawal(
+        &mut self,
+        postings: &mut Vec<Posting>,
+        to: LedgerAccount,
+        currency: Currency,
+        amount: Decimal,
+        fee: Decimal,
+        credit_limit: Decimal,
+    ) -> Result<(), TransactionError> {
+        if amount < Decimal::ZERO {
+            return Err(TransactionError::NegativeAmount);
+        }
+
+        if self.balance(currency).available + credit_limit < amount + fee {
+            return Err(TransactionError::InsufficientFunds);
+        }
+
+        self.post(
+            postings,
+            LedgerAccount::Available(self.client),
+            to,
+            currency,
+            amount,
+        )?;
+        self.charge(postings, currency, fee)
+    }
+
+    /// Fails if either account is locked or this account lacks the available funds.
+    pub(crate) fn transfer(
+        &mut self,
+        to: &mut Account,
+        postings: &mut Vec<Posting>,
+        currency: Currency,
+        amount: Decimal,
+        fee: Decimal,
+        credit_limit: Decimal,
+    ) -> Result<(), TransactionError> {
+        if self.locked || to.locked {
+            return Err(TransactionError::AccountLocked);
+        }
+
+        let transferred = postings.len();
+        self.withdrawal(
+            postings,
+            LedgerAccount::Available(to.client),
+            currency,
+            amount,
+            fee,
+            credit_limit,
+        )?;
+        to.apply(&postings[transferred])
+    }
+
+    /// Fees are owed whatever the balance, so unlike withdrawals they can take available funds negative.
+    fn charge(
+        &mut self,
+        postings: &mut Vec<Posting>,
+        currency: Currency,
+        fee: Decimal,
+    ) -> Result<(), TransactionError> {
+        if fee == Decimal::ZERO {
+            return Ok(());
+        }
+
+        self.post(
+            postings,
+            LedgerAccount::Available(self.client),
+            LedgerAccount::Fees,
+            currency,
+            fee,
+        )
+    }
+
+    /// How much of a dispute can't be held because the available funds have already been spent.
+    fn dispute_shortfall(
+        &self,
+        currency: Currency,
+        amount: Decimal,
+        policy: DisputePolicy,
+    ) -> Result<Decimal, TransactionError> {
+        let available = self.balance(currency).available;
+
+        match policy {
+            DisputePolicy::AllowNegative => Ok(Decimal::ZERO),
+            DisputePolicy::Clamp if available >= amount => Ok(Decimal::ZERO),
+            // Less than the amount, so it only doesn't fit in a `Decimal` when it's far overdrawn
+            DisputePolicy::Clamp => Ok(amount
+                - available
+                    .to_decimal()
+                    .map_or(Decimal::ZERO, |available| available.max(Decimal::ZERO))),
+            DisputePolicy::Reject if available < amount => Err(TransactionError::InsufficientFunds),
+            DisputePolicy::Reject => Ok(Decimal::ZERO),
         }
     }
 
-    fn dispute(&mut self, amount: Decimal) {
-        // withdrawals fail if there are insufficient available funds I've not done that here
-        // I'm not sure that clients should be able to have negative available balances
-        // but I'm not sure I should fail the dispute if the client lacks available funds.
-        self.available -= amount;
-        self.held += amount;
+    /// Only the part of the deposit that isn't a shortfall is held, the shortfall is owed against the loss if
+    /// it's charged back.
+    fn dispute(
+        &mut self,
+        postings: &mut Vec<Posting>,
+        record: &TransactionRecord,
+    ) -> Result<(), TransactionError> {
+        self.post(
+            postings,
+            LedgerAccount::Available(self.client),
+            LedgerAccount::Held(self.client),
+            record.currency,
+            record.amount - record.shortfall,
+        )?;
+        self.post_shortfall(postings, record, true)
     }
 
-    fn resolve(&mut self, amount: Decimal) {
-        self.held -= amount;
-        self.available += amount;
+    fn resolve(
+        &mut self,
+        postings: &mut Vec<Posting>,
+        record: &TransactionRecord,
+    ) -> Result<(), TransactionError> {
+        self.post(
+            postings,
+            LedgerAccount::Held(self.client),
+            LedgerAccount::Available(self.client),
+            record.currency,
+            record.amount - record.shortfall,
+        )?;
+        self.post_shortfall(postings, record, false)
     }
 
-    fn chargeback(&mut self, amount: Decimal) {
-        self.held -= amount;
+    /// What was held leaves the system, the shortfall is still owed.
+    fn chargeback(
+        &mut self,
+        postings: &mut Vec<Posting>,
+        record: &TransactionRecord,
+    ) -> Result<(), TransactionError> {
+        self.post(
+            postings,
+            LedgerAccount::Held(self.client),
+            LedgerAccount::Suspense,
+            record.currency,
+            record.amount - record.shortfall,
+        )?;
         self.locked = true;
+        Ok(())
+    }
+
+    /// What was held is available again, and the shortfall is no longer owed.
+    fn reverse_chargeback(
+        &mut self,
+        postings: &mut Vec<Posting>,
+        record: &TransactionRecord,
+    ) -> Result<(), TransactionError> {
+        self.post(
+            postings,
+            LedgerAccount::Suspense,
+            LedgerAccount::Available(self.client),
+            record.currency,
+            record.amount - record.shortfall,
+        )?;
+        self.post_shortfall(postings, record, false)
+    }
+
+    /// Books `record`'s shortfall as owed, or no longer owed.
+    fn post_shortfall(
+        &mut self,
+        postings: &mut Vec<Posting>,
+        record: &TransactionRecord,
+        owed: bool,
+    ) -> Result<(), TransactionError> {
+        if record.shortfall == Decimal::ZERO {
+            return Ok(());
+        }
+
+        let (debit, credit) = (
+            LedgerAccount::Shortfall(self.client),
+            LedgerAccount::ChargebackLoss,
+        );
+        let (debit, credit) = if owed {
+            (debit, credit)
+        } else {
+            (credit, debit)
+        };
+        self.post(postings, debit, credit, record.currency, record.shortfall)
+    }
+
+    fn reverse(
+        &mut self,
+        postings: &mut Vec<Posting>,
+        record: &TransactionRecord,
+    ) -> Result<(), TransactionError> {
+        let available = LedgerAccount::Available(self.client);
+        let (debit, credit) = match record.kind {
+            RecordKind::Deposit => (available, LedgerAccount::Suspense),
+            RecordKind::Withdrawal => (LedgerAccount::Suspense, available),
+        };
+
+        self.post(postings, debit, credit, record.currency, record.amount)
+    }
+
+    fn unlock(&mut self) -> Result<(), TransactionError> {
+        if !self.locked {
+            return Err(TransactionError::NotLocked);
+        }
+
+        self.locked = false;
+        Ok(())
     }
 }
 
 /// Gets a transaction, ensures transaction is disputed and clients match
-fn dependent_transaction<'a>(
+fn dependent_transaction<S: TransactionStore>(
     transaction: &Transaction,
-    transactions: &'a mut HashMap<u32, TransactionRecord>,
-) -> Option<&'a mut TransactionRecord> {
-    if let Some(existing_transaction) = transactions.get_mut(&transaction.tx()) {
-        let transaction_is_disputed = existing_transaction.disputed == DisputedState::Disputed;
+    transactions: &S,
+) -> Result<TransactionRecord, TransactionError> {
+    let existing_transaction = transactions
+        .get(transaction.tx())?
+        .filter(|record| record.kind == RecordKind::Deposit)
+        .ok_or(TransactionError::UnknownTransaction)?;
 
-        let matching_clients = transaction.client() == existing_transaction.client;
+    if transaction.client() != existing_transaction.client {
+        return Err(TransactionError::ClientMismatch);
+    }
 
-        if transaction_is_disputed && matching_clients {
-            return Some(existing_transaction);
-        }
+    if existing_transaction.disputed != DisputedState::Disputed {
+        return Err(TransactionError::NotDisputed);
     }
 
-    None
+    Ok(existing_transaction)
 }
@@ -0,0 +1,442 @@
+use crate::account::Account;
+use crate::client::ClientId;
+use crate::config::Config;
+use crate::error::TransactionError;
+use crate::handler::CustomTransaction;
+use crate::input::{CsvOptions, Row};
+use crate::rejects::Rejection;
+use crate::throttle::Throttle;
+use crate::transaction::{self, SortBy, Transaction, TransactionProcessor};
+use crate::tx::TxId;
+use std::{
+    collections::{hash_map::RandomState, BTreeMap, HashSet},
+    hash::BuildHasher,
+    io::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, Condvar, Mutex, MutexGuard,
+    },
+    thread::{self, JoinHandle},
+};
+
+/// How many messages can be queued for a shard before senders wait for it to catch up.
+const SHARD_QUEUE: usize = 1024;
+
+/// Bits in each shard's `TxFilter`, a MiB each. Past a few million txs a shard is asked about more and more
+/// txs it doesn't have.
+const TX_FILTER_BITS: usize = 1 << 23;
+
+/// How many locks the txs being claimed are spread over, see `claim`.
+const CLAIM_STRIPES: usize = 64;
+
+/// A row sent to a shard.
+pub(crate) enum Queued {
+    /// With when it takes effect.
+    Transaction(Transaction, Option<u64>),
+    Custom(CustomTransaction),
+}
+
+enum Message {
+    /// Applied, with a rejection sent back on `rejected` if it fails.
+    Row {
+        line: u64,
+        row: String,
+        metadata: BTreeMap<String, String>,
+        queued: Queued,
+        rejected: mpsc::Sender<Rejection>,
+    },
+    Run(Box<dyn FnOnce(&mut TransactionProcessor) + Send>),
+}
+
+/// Clients sharded across threads, each owning its shard's accounts and transaction records and applying the
+/// transactions it's sent over a channel in the order they arrive. Unrelated clients never wait on each
+/// other's locks, and each client's transactions are applied in order as they all go to one thread. As with
+/// `TransactionProcessor::process_reader_sharded`, which is built on this, a transaction can't reference
+/// another shard's client or its transactions.
+pub struct ClientActors {
+    senders: Vec<mpsc::SyncSender<Message>>,
+    handles: Vec<JoinHandle<TransactionProcessor>>,
+    /// The txs each shard has been sent a deposit or withdrawal with, or has a record of, see `claim`.
+    filters: Vec<TxFilter>,
+    /// The txs being claimed, by the hash of each tx, and woken when one is released.
+    claiming: Vec<(Mutex<HashSet<TxId>>, Condvar)>,
+    hasher: RandomState,
+    config: Config,
+    keep_metadata: bool,
+    custom_types: HashSet<String>,
+    csv: CsvOptions,
+    throttle: Option<Arc<Throttle>>,
+}
+
+impl ClientActors {
+    /// Moves `transaction_processor`'s accounts and transaction records to `shards` threads, until `join`.
+    pub fn spawn(transaction_processor: &mut TransactionProcessor, shards: usize) -> Self {
+        let hasher = RandomState::new();
+        let mut filters = Vec::new();
+        let (senders, handles) = transaction_processor
+            .split_shards(shards)
+            .into_iter()
+            .map(|mut processor| {
+                let filter = TxFilter::new();
+                for &tx in processor.transactions.keys() {
+                    filter.insert(hasher.hash_one(tx));
+                }
+                filters.push(filter);
+                let (sender, receiver) = mpsc::sync_channel::<Message>(SHARD_QUEUE);
+
+                let handle = thread::spawn(move || {
+                    for message in receiver {
+                        match message {
+                            Message::Row {
+                                line,
+                                row,
+                                metadata,
+                                queued,
+                                rejected,
+                            } => {
+                                if let Err(error) = processor.process_queued(&queued, &metadata) {
+                                    // Whoever's waiting for the row's rejections may have gone
+                                    let _ = rejected.send(Rejection {
+                                        line,
+                                        row,
+                                        reason: error.into(),
+                                        metadata,
+                                    });
+                                }
+                            }
+                            Message::Run(f) => f(&mut processor),
+                        }
+                    }
+
+                    processor
+                });
+
+                (sender, handle)
+            })
+            .unzip();
+
+        Self {
+            senders,
+            handles,
+            filters,
+            claiming: (0..CLAIM_STRIPES)
+                .map(|_| (Mutex::new(HashSet::new()), Condvar::new()))
+                .collect(),
+            hasher,
+            config: transaction_processor.config().clone(),
+            keep_metadata: transaction_processor.keeps_metadata(),
+            custom_types: transaction_processor.custom_types(),
+            csv: transaction_processor.csv_options().clone(),
+            throttle: transaction_processor.throttle(),
+        }
+    }
+
+    pub fn shards(&self) -> usize {
+        self.senders.len()
+    }
+
+    /// How the processor read csv when it was spawned.
+    pub fn csv_options(&self) -> &CsvOptions {
+        &self.csv
+    }
+
+    /// The processor's throttle when it was spawned.
+    pub fn throttle(&self) -> Option<Arc<Throttle>> {
+        self.throttle.clone()
+    }
+
+    /// Sends a row to its client's shard, waiting if the shard's queue is full. If it fails to apply the
+    /// rejection is sent to `rejected`. Rows that aren't transactions, are transfers to another shard or reuse
+    /// a tx another shard has recorded are rejected straight away.
+    pub(crate) fn dispatch(
+        &self,
+        row: &Row,
+        rejected: &mpsc::Sender<Rejection>,
+    ) -> Result<(), Rejection> {
+        let metadata = match self.keep_metadata {
+            true => row.metadata(),
+            false => BTreeMap::new(),
+        };
+
+        let (client, queued) = match (&row.transaction, &row.custom) {
+            (Ok(transaction), _) => {
+                if let Transaction::Transfer { to, .. } = *transaction {
+                    if to.shard(self.shards()) != transaction.client().shard(self.shards()) {
+                        return Err(Rejection {
+                            line: row.line,
+                            row: row.raw(),
+                            reason: TransactionError::CrossShardTransfer.into(),
+                            metadata,
+                        });
+                    }
+                }
+
+                (
+                    transaction.client(),
+                    Queued::Transaction(*transaction, row.effective),
+                )
+            }
+            (Err(_), Some(transaction)) if self.custom_types.contains(&transaction.r#type) => {
+                (transaction.client, Queued::Custom(transaction.clone()))
+            }
+            (Err(reason), _) => {
+                return Err(Rejection {
+                    line: row.line,
+                    row: row.raw(),
+                    reason: reason.clone(),
+                    metadata,
+                })
+            }
+        };
+
+        let claim = match &queued {
+            Queued::Transaction(transaction, _) => match self.claim(transaction) {
+                Ok(claim) => claim,
+                Err(error) => {
+                    return Err(Rejection {
+                        line: row.line,
+                        row: row.raw(),
+                        reason: error.into(),
+                        metadata,
+                    })
+                }
+            },
+            Queued::Custom(_) => None,
+        };
+
+        self.send(
+            client,
+            Message::Row {
+                line: row.line,
+                row: row.raw(),
+                metadata,
+                queued,
+                rejected: rejected.clone(),
+            },
+        );
+        drop(claim);
+
+        Ok(())
+    }
+
+    /// Applies `transaction` on its client's shard and waits for the result.
+    pub fn process(&self, transaction: Transaction) -> Result<(), TransactionError> {
+        if let Transaction::Transfer { to, .. } = transaction {
+            if to.shard(self.shards()) != transaction.client().shard(self.shards()) {
+                return Err(TransactionError::CrossShardTransfer);
+            }
+        }
+
+        let claim = self.claim(&transaction)?;
+        let result = self.request(
+            transaction.client().shard(self.shards()),
+            move |processor| processor.process(&transaction),
+        );
+        drop(claim);
+
+        Self::wait(result)
+    }
+
+    /// Deposits and withdrawals are only checked against their own shard's records, so one whose tx may have
+    /// been used by a deposit or withdrawal on another shard is checked here, to be rejected as a
+    /// `DuplicateTransaction` as it would be on one thread. Each other shard whose filter has the tx is asked
+    /// once it's applied what it was sent before, so the tx is only taken if none of them has a record of it.
+    /// Hold on to the claim until the transaction has been sent, so ones with the same tx are checked in the
+    /// order they're applied. Only claims of the same tx wait on each other, and no lock is held while
+    /// waiting on a shard.
+    fn claim(&self, transaction: &Transaction) -> Result<Option<Claim<'_>>, TransactionError> {
+        let (Transaction::Deposit { tx, .. } | Transaction::Withdrawal { tx, .. }) = *transaction
+        else {
+            return Ok(None);
+        };
+
+        let shard = transaction.client().shard(self.shards());
+        let hash = self.hasher.hash_one(tx);
+        let stripe = (hash % CLAIM_STRIPES as u64) as usize;
+        let (claiming, released) = &self.claiming[stripe];
+
+        let mut claimed = lock(claiming);
+        while !claimed.insert(tx) {
+            claimed = released
+                .wait(claimed)
+                .unwrap_or_else(|poison| poison.into_inner());
+        }
+        drop(claimed);
+
+        // Released however this returns
+        let claim = Claim {
+            actors: self,
+            tx,
+            stripe,
+        };
+
+        for (other, filter) in self.filters.iter().enumerate() {
+            if other != shard
+                && filter.contains(hash)
+                && self.run_on(other, move |processor| {
+                    processor.transactions.contains_key(&tx)
+                })
+            {
+                return Err(TransactionError::DuplicateTransaction);
+            }
+        }
+
+        self.filters[shard].insert(hash);
+
+        Ok(Some(claim))
+    }
+
+    /// Runs `f` on `client`'s shard, after what was sent to it before, and waits for the result.
+    pub fn run<T, F>(&self, client: ClientId, f: F) -> T
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut TransactionProcessor) -> T + Send + 'static,
+    {
+        self.run_on(client.shard(self.shards()), f)
+    }
+
+    /// The account on `client`'s shard.
+    pub fn account(&self, client: ClientId) -> Option<Account> {
+        self.run(client, move |processor| processor.account(client).cloned())
+    }
+
+    /// Every shard's accounts, each shard's as of when it got to the request.
+    pub fn accounts(&self) -> Vec<Account> {
+        (0..self.shards())
+            .flat_map(|shard| {
+                self.run_on(shard, |processor| {
+                    processor.accounts().cloned().collect::<Vec<_>>()
+                })
+            })
+            .collect()
+    }
+
+    /// `TransactionProcessor::write_accounts` for every shard's accounts.
+    pub fn write_accounts<W: Write>(
+        &self,
+        writer: W,
+        sort: Option<SortBy>,
+    ) -> Result<(), csv::Error> {
+        let currencies_named = (0..self.shards())
+            .any(|shard| self.run_on(shard, |processor| processor.currencies_named()));
+
+        transaction::write_accounts(
+            &self.accounts(),
+            &self.config,
+            currencies_named,
+            writer,
+            sort,
+        )
+    }
+
+    /// Waits for the shards to apply everything they've been sent, then moves their accounts and transaction
+    /// records back to `transaction_processor`. A panic on a shard is resumed here.
+    pub fn join(self, transaction_processor: &mut TransactionProcessor) {
+        drop(self.senders);
+
+        let processors = self
+            .handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+            })
+            .collect();
+
+        transaction_processor.join_shards(processors);
+    }
+
+    fn run_on<T, F>(&self, shard: usize, f: F) -> T
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut TransactionProcessor) -> T + Send + 'static,
+    {
+        Self::wait(self.request(shard, f))
+    }
+
+    /// Sends `f` to `shard` without waiting for it, see `wait`.
+    fn request<T, F>(&self, shard: usize, f: F) -> mpsc::Receiver<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut TransactionProcessor) -> T + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        self.send_to(
+            shard,
+            Message::Run(Box::new(move |processor| {
+                let _ = sender.send(f(processor));
+            })),
+        );
+
+        receiver
+    }
+
+    fn wait<T>(result: mpsc::Receiver<T>) -> T {
+        result
+            .recv()
+            .expect("a client shard panicked, it's resumed when they're joined")
+    }
+
+    fn send(&self, client: ClientId, message: Message) {
+        self.send_to(client.shard(self.shards()), message);
+    }
+
+    fn send_to(&self, shard: usize, message: Message) {
+        // A shard only hangs up if it panicked, which is resumed when it's joined
+        let _ = self.senders[shard].send(message);
+    }
+}
+
+/// A tx claimed for a deposit or withdrawal, released when it's dropped, see `ClientActors::claim`.
+struct Claim<'a> {
+    actors: &'a ClientActors,
+    tx: TxId,
+    stripe: usize,
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        let (claiming, released) = &self.actors.claiming[self.stripe];
+        lock(claiming).remove(&self.tx);
+        released.notify_all();
+    }
+}
+
+/// A bloom filter of txs, so it takes the same memory however many txs a shard has. It can say a shard has a
+/// tx it doesn't, which costs asking the shard, but never the other way around.
+struct TxFilter {
+    bits: Vec<AtomicU64>,
+}
+
+impl TxFilter {
+    fn new() -> Self {
+        Self {
+            bits: (0..TX_FILTER_BITS / 64)
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+        }
+    }
+
+    fn insert(&self, hash: u64) {
+        for bit in Self::bits(hash) {
+            self.bits[bit / 64].fetch_or(1 << (bit % 64), Ordering::Relaxed);
+        }
+    }
+
+    fn contains(&self, hash: u64) -> bool {
+        Self::bits(hash)
+            .all(|bit| self.bits[bit / 64].load(Ordering::Relaxed) & (1 << (bit % 64)) != 0)
+    }
+
+    /// Three bits from the two halves of the hash.
+    fn bits(hash: u64) -> impl Iterator<Item = usize> {
+        let (first, step) = ((hash >> 32) as usize, (hash as u32 | 1) as usize);
+        (0..3).map(move |i| first.wrapping_add(i * step) % TX_FILTER_BITS)
+    }
+}
+
+/// Only ever changed a whole entry at a time, so carry on if a thread panicked.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poison| poison.into_inner())
+}
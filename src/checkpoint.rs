@@ -0,0 +1,91 @@
+use crate::account::Account;
+use crate::client::ClientId;
+use crate::transaction::{Timeline, TransactionProcessor, TransactionRecord};
+use crate::tx::TxId;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufReader, BufWriter, Write},
+    path::Path,
+};
+
+/// A run's state and how far through its inputs it got, so a stopped run can carry on where it left off.
+pub struct Checkpoint {
+    /// Index of the input being processed.
+    pub input: usize,
+    /// Rows of that input that have already been processed.
+    pub rows: u64,
+    pub transaction_processor: TransactionProcessor,
+}
+
+#[derive(Serialize)]
+struct SavedCheckpoint<'a> {
+    input: usize,
+    rows: u64,
+    accounts: &'a HashMap<ClientId, Account>,
+    transactions: &'a HashMap<TxId, TransactionRecord>,
+}
+
+#[derive(Deserialize)]
+struct LoadedCheckpoint {
+    input: usize,
+    rows: u64,
+    accounts: HashMap<ClientId, Account>,
+    transactions: HashMap<TxId, TransactionRecord>,
+}
+
+impl Checkpoint {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, std::io::Error> {
+        let mut file = BufReader::new(File::open(path)?);
+        let checkpoint: LoadedCheckpoint =
+            bincode::deserialize_from(&mut file).map_err(std::io::Error::other)?;
+
+        let mut transaction_processor = TransactionProcessor::new();
+        transaction_processor.accounts = checkpoint.accounts;
+        transaction_processor.transactions = checkpoint.transactions;
+        transaction_processor.set_timeline(Timeline::read(file)?)?;
+        transaction_processor.open_ledger();
+
+        Ok(Self {
+            input: checkpoint.input,
+            rows: checkpoint.rows,
+            transaction_processor,
+        })
+    }
+
+    /// Written to a temporary file first and renamed, so a crash while saving leaves the last checkpoint intact.
+    /// The clock and scheduled transactions are written after the rest, see `Timeline`.
+    pub fn save<P: AsRef<Path>>(
+        path: P,
+        transaction_processor: &TransactionProcessor,
+        input: usize,
+        rows: u64,
+    ) -> Result<(), std::io::Error> {
+        let checkpoint = SavedCheckpoint {
+            input,
+            rows,
+            accounts: &transaction_processor.accounts,
+            transactions: &transaction_processor.transactions,
+        };
+
+        save_atomically(
+            path.as_ref(),
+            &(checkpoint, transaction_processor.timeline()?),
+        )
+    }
+}
+
+/// Writes `value` with bincode to a temporary file and renames it to `path`, so a crash part way through leaves
+/// whatever was at `path` intact.
+pub(crate) fn save_atomically<T: Serialize>(path: &Path, value: &T) -> Result<(), std::io::Error> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+
+    let mut file = BufWriter::new(File::create(&temporary)?);
+    bincode::serialize_into(&mut file, value).map_err(std::io::Error::other)?;
+    file.flush()?;
+    file.get_ref().sync_all()?;
+
+    fs::rename(temporary, path)
+}
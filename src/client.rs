@@ -0,0 +1,183 @@
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+/// A client's id, a number up to `u64::MAX`. With the `string-client-ids` feature it's a string of up to
+/// 32 bytes instead, e.g. `acme-0042`.
+#[cfg(not(feature = "string-client-ids"))]
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default,
+)]
+#[serde(transparent)]
+pub struct ClientId(u64);
+
+/// A client's id, any string of up to `ClientId::MAX_LEN` bytes, e.g. `acme-0042`. Kept inline, padded with
+/// zeros, so transactions are still `Copy`, and ordered byte by byte. In json it has to be a string.
+#[cfg(feature = "string-client-ids")]
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default,
+)]
+#[serde(try_from = "String", into = "String")]
+pub struct ClientId([u8; MAX_LEN]);
+
+#[cfg(feature = "string-client-ids")]
+const MAX_LEN: usize = 32;
+
+#[cfg(not(feature = "string-client-ids"))]
+impl ClientId {
+    /// Bytes taken by `to_bytes`.
+    pub(crate) const BYTES: usize = 8;
+
+    /// The shard of `shards` the client's transactions are applied on.
+    pub(crate) fn shard(self, shards: usize) -> usize {
+        (self.0 % shards as u64) as usize
+    }
+
+    pub(crate) fn to_bytes(self) -> [u8; Self::BYTES] {
+        self.0.to_be_bytes()
+    }
+
+    pub(crate) fn from_bytes(bytes: [u8; Self::BYTES]) -> Result<Self, String> {
+        Ok(ClientId(u64::from_be_bytes(bytes)))
+    }
+}
+
+#[cfg(feature = "string-client-ids")]
+impl ClientId {
+    pub const MAX_LEN: usize = MAX_LEN;
+
+    /// Bytes taken by `to_bytes`.
+    pub(crate) const BYTES: usize = Self::MAX_LEN;
+
+    pub fn as_str(&self) -> &str {
+        let len = self
+            .0
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(Self::MAX_LEN);
+        std::str::from_utf8(&self.0[..len]).expect("client ids are utf8")
+    }
+
+    /// The shard of `shards` the client's transactions are applied on, from an FNV-1a hash of the id so it's
+    /// the same from run to run.
+    pub(crate) fn shard(self, shards: usize) -> usize {
+        let hash = self
+            .0
+            .iter()
+            .fold(0xcbf2_9ce4_8422_2325_u64, |hash, &byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+            });
+
+        (hash % shards as u64) as usize
+    }
+
+    pub(crate) fn to_bytes(self) -> [u8; Self::BYTES] {
+        self.0
+    }
+
+    pub(crate) fn from_bytes(bytes: [u8; Self::BYTES]) -> Result<Self, String> {
+        let len = bytes
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(Self::MAX_LEN);
+        std::str::from_utf8(&bytes[..len]).map_err(|error| error.to_string())?;
+        Ok(ClientId(bytes))
+    }
+}
+
+#[cfg(not(feature = "string-client-ids"))]
+impl From<u64> for ClientId {
+    fn from(id: u64) -> Self {
+        ClientId(id)
+    }
+}
+
+/// The number written out, so generated and numbered clients work either way.
+#[cfg(feature = "string-client-ids")]
+impl From<u64> for ClientId {
+    fn from(id: u64) -> Self {
+        id.to_string().parse().expect("numbers are short enough")
+    }
+}
+
+#[cfg(not(feature = "string-client-ids"))]
+impl FromStr for ClientId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse()
+            .map(ClientId)
+            .map_err(|_| format!("Invalid client {s}"))
+    }
+}
+
+#[cfg(feature = "string-client-ids")]
+impl FromStr for ClientId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() || s.len() > Self::MAX_LEN || s.contains('\0') {
+            return Err(format!("Invalid client {s}"));
+        }
+
+        let mut bytes = [0; Self::MAX_LEN];
+        bytes[..s.len()].copy_from_slice(s.as_bytes());
+        Ok(ClientId(bytes))
+    }
+}
+
+#[cfg(feature = "string-client-ids")]
+impl From<ClientId> for String {
+    fn from(client: ClientId) -> Self {
+        client.as_str().to_owned()
+    }
+}
+
+#[cfg(feature = "string-client-ids")]
+impl TryFrom<String> for ClientId {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for ClientId {
+    #[cfg(not(feature = "string-client-ids"))]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+
+    #[cfg(feature = "string-client-ids")]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Numbers are stored as integers, strings as text.
+#[cfg(feature = "sqlite")]
+impl rusqlite::types::ToSql for ClientId {
+    #[cfg(not(feature = "string-client-ids"))]
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+        rusqlite::types::ToSql::to_sql(&self.0)
+    }
+
+    #[cfg(feature = "string-client-ids")]
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+        rusqlite::types::ToSql::to_sql(self.as_str())
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl rusqlite::types::FromSql for ClientId {
+    #[cfg(not(feature = "string-client-ids"))]
+    fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
+        <u64 as rusqlite::types::FromSql>::column_result(value).map(ClientId)
+    }
+
+    #[cfg(feature = "string-client-ids")]
+    fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
+        <String as rusqlite::types::FromSql>::column_result(value)?
+            .parse()
+            .map_err(|error: String| rusqlite::types::FromSqlError::Other(error.into()))
+    }
+}
@@ -0,0 +1,84 @@
+use flate2::bufread::MultiGzDecoder;
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    str::FromStr,
+};
+
+/// What gzip files start with.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// What zstd files start with.
+#[cfg(feature = "zstd")]
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// How journals and outputs are compressed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            #[cfg(feature = "zstd")]
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!("Unknown compression {s}")),
+        }
+    }
+}
+
+impl Compression {
+    /// Compresses what's written to `writer`. The compressed stream is finished when the returned writer is
+    /// dropped, so drop it before flushing `writer`.
+    pub fn writer<'a, W: Write + Send + 'a>(
+        self,
+        writer: W,
+    ) -> Result<Box<dyn Write + Send + 'a>, std::io::Error> {
+        match self {
+            Compression::None => Ok(Box::new(writer)),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Ok(Box::new(zstd::Encoder::new(writer, 0)?.auto_finish())),
+        }
+    }
+}
+
+/// The input, decompressed if it starts with the magic bytes of a compressed format.
+pub(crate) enum Decompressed<R: Read> {
+    Plain(BufReader<R>),
+    Gzip(MultiGzDecoder<BufReader<R>>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::Decoder<'static, BufReader<R>>),
+}
+
+impl<R: Read> Read for Decompressed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Decompressed::Plain(reader) => reader.read(buf),
+            Decompressed::Gzip(reader) => reader.read(buf),
+            #[cfg(feature = "zstd")]
+            Decompressed::Zstd(reader) => reader.read(buf),
+        }
+    }
+}
+
+pub(crate) fn decompress<R: Read>(reader: R) -> Result<Decompressed<R>, std::io::Error> {
+    let mut reader = BufReader::new(reader);
+    let start = reader.fill_buf()?;
+
+    if start.starts_with(&GZIP_MAGIC) {
+        return Ok(Decompressed::Gzip(MultiGzDecoder::new(reader)));
+    }
+
+    #[cfg(feature = "zstd")]
+    if start.starts_with(&ZSTD_MAGIC) {
+        return Ok(Decompressed::Zstd(zstd::Decoder::with_buffer(reader)?));
+    }
+
+    Ok(Decompressed::Plain(reader))
+}
@@ -0,0 +1,93 @@
+use crate::currency::Currency;
+use crate::fees::FeeSchedule;
+use crate::interest::Interest;
+use crate::limits::{ClientLimits, CreditLimits};
+use crate::rates::ExchangeRates;
+use crate::rules::Rules;
+use std::{str::FromStr, time::Duration};
+
+/// Decimal places balances are written with if `Config::precision` isn't set.
+pub(crate) const DEFAULT_PRECISION: u32 = 4;
+
+/// What happens when a disputed deposit is more than the client's available funds, as they've spent some of it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DisputePolicy {
+    /// The whole deposit is held and available funds go negative.
+    #[default]
+    AllowNegative,
+    /// Only what's available is held, the rest is tracked as the balance's shortfall.
+    Clamp,
+    /// The dispute fails.
+    Reject,
+}
+
+impl FromStr for DisputePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow-negative" => Ok(DisputePolicy::AllowNegative),
+            "clamp" => Ok(DisputePolicy::Clamp),
+            "reject" => Ok(DisputePolicy::Reject),
+            _ => Err(format!("Unknown dispute policy {s}")),
+        }
+    }
+}
+
+/// What happens to transactions on an account that's been locked by a chargeback. Unlocks and chargeback
+/// reversals are always applied, and transfers to or from a locked account are always rejected.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LockedPolicy {
+    /// Everything is applied as if the account wasn't locked.
+    #[default]
+    Allow,
+    /// Withdrawals, conversions, fees and reversals of deposits are rejected, deposits and disputes are applied.
+    RejectDebits,
+    /// Everything is rejected.
+    Reject,
+}
+
+impl FromStr for LockedPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(LockedPolicy::Allow),
+            "reject-debits" => Ok(LockedPolicy::RejectDebits),
+            "reject" => Ok(LockedPolicy::Reject),
+            _ => Err(format!("Unknown locked policy {s}")),
+        }
+    }
+}
+
+/// How transactions are applied, the defaults match the original rules.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Config {
+    pub fees: FeeSchedule,
+    /// Currency of transactions that don't have one.
+    pub base_currency: Currency,
+    pub rates: ExchangeRates,
+    pub credit_limits: CreditLimits,
+    pub limits: ClientLimits,
+    pub dispute_policy: DisputePolicy,
+    pub locked_policy: LockedPolicy,
+    /// Whether a chargeback reversal unlocks the account, rather than it staying locked until an unlock.
+    pub unlock_on_chargeback_reversal: bool,
+    /// Whether a resolved deposit can be disputed again, for schemes with more than one dispute cycle.
+    pub redispute_resolved: bool,
+    /// How long after a deposit it can be disputed, going by the rows' `timestamp` column.
+    pub dispute_window: Option<Duration>,
+    /// Interest on available funds, accrued as time passes by the rows' `timestamp` column.
+    pub interest: Option<Interest>,
+    pub rules: Rules,
+    /// Decimal places balances are written with, and converted amounts and interest are rounded to, 4 if
+    /// `None`. If it's set, amounts with more decimal places are rejected.
+    pub precision: Option<u32>,
+}
+
+impl Config {
+    /// Decimal places balances are written with.
+    pub fn output_precision(&self) -> u32 {
+        self.precision.unwrap_or(DEFAULT_PRECISION)
+    }
+}
@@ -0,0 +1,309 @@
+use crate::account::Account;
+use crate::client::ClientId;
+use crate::currency::Currency;
+use crate::error::RejectReason;
+use crate::events::{Chargeback, Event};
+use crate::money::Money;
+use crate::rejects::Rejection;
+use crate::store::TransactionStore;
+use crate::transaction::TransactionProcessor;
+use ratatui::{
+    backend::CrosstermBackend,
+    crossterm::{
+        cursor,
+        terminal::{EnterAlternateScreen, LeaveAlternateScreen},
+        ExecutableCommand,
+    },
+    layout::{Constraint, Layout},
+    style::{Modifier, Style},
+    text::Line,
+    widgets::{Block, Borders, Paragraph, Row, Table},
+    Frame, Terminal,
+};
+use rust_decimal::Decimal;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::Stderr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Accounts shown in the top held funds table.
+const TOP_ACCOUNTS: usize = 10;
+
+/// Chargebacks shown, most recent first.
+const RECENT_CHARGEBACKS: usize = 10;
+
+/// Throughput is averaged over this long, so it doesn't jump about between redraws.
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(5);
+
+/// How often the dashboard is redrawn.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A terminal dashboard for the long running modes, showing throughput, the accounts with the most funds held,
+/// recent chargebacks and why rows were rejected. Drawn on stderr so it doesn't get in the way of accounts
+/// written to stdout.
+#[derive(Debug, Default)]
+pub struct Dashboard {
+    applied: AtomicU64,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Times and applied counts of the last few redraws, for throughput.
+    samples: VecDeque<(Instant, u64)>,
+    /// Most held first, only as fresh as the last `update`.
+    top_held: Vec<(ClientId, Currency, Money)>,
+    /// Most recent first.
+    chargebacks: VecDeque<Chargeback>,
+    /// Rejected rows by reason, with malformed rows counted together whatever was wrong with them.
+    rejected: BTreeMap<String, u64>,
+}
+
+impl Dashboard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts the transactions `transaction_processor` applies and fails, and keeps its chargebacks, from now
+    /// on. Rows rejected for other reasons have to be passed to `rejected_row`.
+    pub fn record<S: TransactionStore>(
+        self: &Arc<Self>,
+        transaction_processor: &mut TransactionProcessor<S>,
+    ) {
+        let dashboard = self.clone();
+        transaction_processor.subscribe(move |event| match event {
+            Event::Applied(_) => {
+                dashboard.applied.fetch_add(1, Ordering::Relaxed);
+            }
+            Event::Chargeback(chargeback) => {
+                let mut state = dashboard.state();
+                state.chargebacks.push_front(*chargeback);
+                state.chargebacks.truncate(RECENT_CHARGEBACKS);
+            }
+            _ => {}
+        });
+
+        let dashboard = self.clone();
+        transaction_processor.on_rejected(move |_, error| {
+            *dashboard
+                .state()
+                .rejected
+                .entry(error.to_string())
+                .or_default() += 1;
+        });
+    }
+
+    /// Call with every row rejected while reading, only the ones that couldn't be read as a transaction are
+    /// counted here, `record` counts the rest.
+    pub fn rejected_row(&self, rejection: &Rejection) {
+        if let RejectReason::Malformed(_) = rejection.reason {
+            *self
+                .state()
+                .rejected
+                .entry("malformed row".to_owned())
+                .or_default() += 1;
+        }
+    }
+
+    /// Recalculates the accounts with the most funds held, these are only as fresh as the last update.
+    pub fn update<'a, I>(&self, accounts: I)
+    where
+        I: IntoIterator<Item = &'a Account>,
+    {
+        let mut held: Vec<_> = accounts
+            .into_iter()
+            .flat_map(|account| {
+                account
+                    .balances
+                    .iter()
+                    .filter(|(_, balance)| balance.held > Decimal::ZERO)
+                    .map(|(&currency, balance)| (account.client, currency, balance.held.clone()))
+            })
+            .collect();
+        held.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| (a.0, a.1).cmp(&(b.0, b.1))));
+        held.truncate(TOP_ACCOUNTS);
+
+        self.state().top_held = held;
+    }
+
+    /// Takes over the terminal and redraws the dashboard on a background thread until the returned handle is
+    /// stopped or dropped, which gives the terminal back.
+    pub fn spawn(self: &Arc<Self>) -> Result<DashboardHandle, std::io::Error> {
+        let mut stderr = std::io::stderr();
+        stderr.execute(EnterAlternateScreen)?;
+        stderr.execute(cursor::Hide)?;
+        let mut terminal = Terminal::new(CrosstermBackend::new(stderr))?;
+        terminal.clear()?;
+
+        let dashboard = self.clone();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let thread = thread::spawn(move || {
+            while !stopped.load(Ordering::SeqCst) {
+                if let Err(error) = terminal.draw(|frame| dashboard.draw(frame)) {
+                    tracing::error!(%error, "Couldn't draw the dashboard");
+                }
+
+                thread::sleep(REDRAW_INTERVAL);
+            }
+
+            restore(terminal);
+        });
+
+        Ok(DashboardHandle {
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    pub(crate) fn draw(&self, frame: &mut Frame) {
+        let applied = self.applied.load(Ordering::Relaxed);
+        let mut state = self.state();
+        let now = Instant::now();
+
+        state.samples.push_back((now, applied));
+        while state
+            .samples
+            .front()
+            .is_some_and(|&(at, _)| now.duration_since(at) > THROUGHPUT_WINDOW)
+        {
+            state.samples.pop_front();
+        }
+
+        let throughput = match state.samples.front() {
+            Some(&(at, since)) if now > at => {
+                (applied - since) as f64 / now.duration_since(at).as_secs_f64()
+            }
+            _ => 0.0,
+        };
+        let rejected: u64 = state.rejected.values().sum();
+
+        let [header, tables, rejects] = Layout::vertical([
+            Constraint::Length(3),
+            Constraint::Min(TOP_ACCOUNTS as u16 + 3),
+            Constraint::Length(state.rejected.len().max(1) as u16 + 2),
+        ])
+        .areas(frame.area());
+        let [held, chargebacks] =
+            Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)])
+                .areas(tables);
+
+        frame.render_widget(
+            Paragraph::new(Line::from(format!(
+                "{throughput:.0} tx/s    {applied} applied    {rejected} rejected"
+            )))
+            .block(Block::default().borders(Borders::ALL).title("payments")),
+            header,
+        );
+
+        let bold = Style::default().add_modifier(Modifier::BOLD);
+        let widths = [
+            Constraint::Length(12),
+            Constraint::Length(8),
+            Constraint::Fill(1),
+            Constraint::Length(8),
+        ];
+
+        frame.render_widget(
+            Table::new(
+                state.top_held.iter().map(|(client, currency, held)| {
+                    Row::new([client.to_string(), currency.to_string(), held.to_string()])
+                }),
+                &widths[..3],
+            )
+            .header(Row::new(["client", "currency", "held"]).style(bold))
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title("Most funds held"),
+            ),
+            held,
+        );
+
+        frame.render_widget(
+            Table::new(
+                state.chargebacks.iter().map(|chargeback| {
+                    Row::new([
+                        chargeback.client.to_string(),
+                        chargeback.currency.to_string(),
+                        chargeback.amount.to_string(),
+                        chargeback.tx.to_string(),
+                    ])
+                }),
+                widths,
+            )
+            .header(Row::new(["client", "currency", "amount", "tx"]).style(bold))
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title("Recent chargebacks"),
+            ),
+            chargebacks,
+        );
+
+        frame.render_widget(
+            Table::new(
+                state
+                    .rejected
+                    .iter()
+                    .map(|(reason, count)| Row::new([count.to_string(), reason.clone()])),
+                [Constraint::Length(10), Constraint::Fill(1)],
+            )
+            .block(Block::default().borders(Borders::ALL).title("Rejected")),
+            rejects,
+        );
+    }
+
+    /// Drawing carries on if a thread panicked.
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poison| poison.into_inner())
+    }
+}
+
+/// Stops the dashboard and gives the terminal back when it's stopped or dropped.
+#[derive(Debug)]
+pub struct DashboardHandle {
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl DashboardHandle {
+    pub fn stop(mut self) {
+        self.join();
+    }
+
+    fn join(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for DashboardHandle {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
+/// Failing to restore the terminal is logged, the process is on its way out anyway.
+fn restore(mut terminal: Terminal<CrosstermBackend<Stderr>>) {
+    let backend = terminal.backend_mut();
+    let result = backend
+        .execute(cursor::Show)
+        .and_then(|backend| backend.execute(LeaveAlternateScreen))
+        .map(|_| ());
+
+    if let Err(error) = result {
+        tracing::error!(%error, "Couldn't restore the terminal");
+    }
+}
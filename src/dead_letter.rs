@@ -0,0 +1,99 @@
+use crate::error::RejectReason;
+use crate::rejects::Rejection;
+use crate::store::TransactionStore;
+use crate::transaction::{IntermediateTransaction, TransactionProcessor};
+use serde::Serialize;
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+/// Writes the transactions that failed to a jsonl file, with why, so they can be repaired and replayed with
+/// `--format jsonl`. Rows that couldn't be read as a transaction are written as they came in. Each line is
+/// written as soon as it fails, so nothing's lost if a long running mode is killed.
+pub struct DeadLetters {
+    file: Mutex<File>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum DeadLetter<'a> {
+    /// In the same schema as jsonl input, the reason is ignored when it's replayed. The amount is rounded like
+    /// the accounts, so it's written the same however many decimal places it had in the input.
+    Transaction {
+        #[serde(flatten)]
+        transaction: IntermediateTransaction,
+        reason: String,
+    },
+    Malformed {
+        row: &'a str,
+        reason: String,
+    },
+}
+
+impl DeadLetters {
+    /// Appends to `path`, creating it if it doesn't exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, std::io::Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Writes every transaction `transaction_processor` fails from now on, rows rejected for other reasons have
+    /// to be passed to `rejected_row`.
+    pub fn record<S: TransactionStore>(
+        self: &Arc<Self>,
+        transaction_processor: &mut TransactionProcessor<S>,
+    ) {
+        let dead_letters = self.clone();
+        let precision = transaction_processor.config().output_precision();
+        transaction_processor.on_rejected(move |transaction, error| {
+            let mut transaction = IntermediateTransaction::from(*transaction);
+            transaction.amount = transaction.amount.map(|amount| {
+                let mut amount = amount.round_dp(precision);
+                amount.rescale(precision);
+                amount
+            });
+            dead_letters.write(&DeadLetter::Transaction {
+                transaction,
+                reason: error.to_string(),
+            });
+        });
+    }
+
+    /// Call with every row rejected while reading, only the ones that couldn't be read as a transaction are
+    /// written here, `record` writes the rest.
+    pub fn rejected_row(&self, rejection: &Rejection) {
+        if let RejectReason::Malformed(_) = rejection.reason {
+            self.write(&DeadLetter::Malformed {
+                row: &rejection.row,
+                reason: rejection.reason.to_string(),
+            });
+        }
+    }
+
+    /// Failing to write is logged, there's nothing else a listener can do with it.
+    fn write(&self, dead_letter: &DeadLetter) {
+        let result = serde_json::to_vec(dead_letter)
+            .map_err(std::io::Error::other)
+            .and_then(|mut line| {
+                line.push(b'\n');
+                self.file().write_all(&line)
+            });
+
+        if let Err(error) = result {
+            tracing::error!(%error, "Couldn't write a dead letter");
+        }
+    }
+
+    /// Writing carries on if a thread panicked.
+    fn file(&self) -> MutexGuard<'_, File> {
+        self.file
+            .lock()
+            .unwrap_or_else(|poison| poison.into_inner())
+    }
+}
@@ -0,0 +1,106 @@
+use crate::client::ClientId;
+use crate::compression::decompress;
+use crate::config::DEFAULT_PRECISION;
+use crate::currency::Currency;
+use crate::money::Money;
+use crate::transaction::format_amount;
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{Read, Write},
+};
+
+/// A row of the accounts csv written by `TransactionProcessor::write_accounts`, total is left out as it's
+/// available + held.
+#[derive(Deserialize)]
+struct AccountRow {
+    client: ClientId,
+    available: Money,
+    held: Money,
+    locked: bool,
+    #[serde(default)]
+    currency: Currency,
+}
+
+#[derive(Default)]
+struct Snapshot {
+    balances: BTreeMap<(ClientId, Currency), (Money, Money)>,
+    locked: BTreeSet<ClientId>,
+}
+
+impl Snapshot {
+    fn read<R: Read>(accounts: R) -> Result<Self, std::io::Error> {
+        let mut snapshot = Self::default();
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(decompress(accounts)?);
+
+        for row in reader.deserialize() {
+            let row: AccountRow = row?;
+            snapshot
+                .balances
+                .insert((row.client, row.currency), (row.available, row.held));
+
+            if row.locked {
+                snapshot.locked.insert(row.client);
+            }
+        }
+
+        Ok(snapshot)
+    }
+}
+
+/// Writes what changed between two accounts csvs written by `payments process`, as csv with a row for every
+/// client and currency whose balances changed, by how much, sorted by client then currency. `lock` is
+/// `locked` if the client was locked in between, `unlocked` if they were unlocked and empty otherwise.
+/// Clients missing from one of them have zero balances in it. Compressed csvs are decompressed as they're
+/// read.
+pub fn write_diff<O: Read, N: Read, W: Write>(
+    old: O,
+    new: N,
+    writer: W,
+) -> Result<(), std::io::Error> {
+    let old = Snapshot::read(old)?;
+    let new = Snapshot::read(new)?;
+
+    let mut writer = csv::Writer::from_writer(writer);
+    writer.write_record(["client", "available", "held", "total", "lock", "currency"])?;
+
+    let keys: BTreeSet<_> = old.balances.keys().chain(new.balances.keys()).collect();
+
+    for &(client, currency) in keys {
+        let (old_available, old_held) = old
+            .balances
+            .get(&(client, currency))
+            .cloned()
+            .unwrap_or_default();
+        let (new_available, new_held) = new
+            .balances
+            .get(&(client, currency))
+            .cloned()
+            .unwrap_or_default();
+        let (available, held) = (new_available - old_available, new_held - old_held);
+        let total = &available + &held;
+
+        let lock = match (old.locked.contains(&client), new.locked.contains(&client)) {
+            (false, true) => "locked",
+            (true, false) => "unlocked",
+            _ => "",
+        };
+
+        if available.is_zero() && held.is_zero() && lock.is_empty() {
+            continue;
+        }
+
+        writer.write_record([
+            client.to_string(),
+            format_amount(available, DEFAULT_PRECISION),
+            format_amount(held, DEFAULT_PRECISION),
+            format_amount(total, DEFAULT_PRECISION),
+            lock.to_string(),
+            currency.to_string(),
+        ])?;
+    }
+
+    writer.flush()
+}
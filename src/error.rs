@@ -0,0 +1,238 @@
+use crate::client::ClientId;
+use crate::rejects::Rejection;
+use crate::retry::Transient;
+use crate::rules::Rule;
+use std::fmt;
+
+/// Why a transaction was rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransactionError {
+    /// Deposits and withdrawals must not be negative.
+    NegativeAmount,
+    /// The withdrawal is more than the available funds.
+    InsufficientFunds,
+    /// The referenced transaction doesn't exist.
+    UnknownTransaction,
+    /// The transaction's client doesn't match the client of the transaction it references.
+    ClientMismatch,
+    /// The referenced transaction has already been disputed.
+    AlreadyDisputed,
+    /// Resolves and chargebacks must reference a disputed transaction.
+    NotDisputed,
+    /// The account has been locked by a chargeback.
+    AccountLocked,
+    /// Only locked accounts can be unlocked.
+    NotLocked,
+    /// There's no exchange rate between the currencies being converted.
+    UnknownRate,
+    /// A deposit or withdrawal reused the tx of an earlier deposit or withdrawal.
+    DuplicateTransaction,
+    /// A row with the same type and tx has already been processed, see `TransactionProcessor::track_applied`.
+    AlreadyApplied,
+    /// Reversals must reference a transaction that isn't disputed, charged back or already reversed.
+    NotReversible,
+    /// Chargeback reversals must reference a charged back deposit.
+    NotChargedBack,
+    /// Transfers must be between different clients.
+    SelfTransfer,
+    /// When processing is sharded by client, transfers must be between clients on the same shard.
+    CrossShardTransfer,
+    /// When streaming the accounts of input partitioned by client, the client's rows have already ended.
+    ClientFinished,
+    /// The disputed deposit is older than `Config::dispute_window`.
+    DisputeWindowExpired,
+    /// The withdrawal is more than the client's `ClientLimit::max_withdrawal`.
+    WithdrawalLimitExceeded,
+    /// The deposit would take the balance over the client's `ClientLimit::max_balance`.
+    BalanceLimitExceeded,
+    /// The withdrawal would take what the client has withdrawn today over their `ClientLimit::daily_out_limit`.
+    DailyLimitExceeded,
+    /// The amount has more decimal places than `Config::precision`, or than the 4 balances are kept to with the
+    /// `minor-units` feature.
+    TooPrecise,
+    /// The amount is too big for the balances with the `minor-units` feature, or the transaction would take a
+    /// balance, a client's or a system account's in the ledger, past what it can hold.
+    AmountTooLarge,
+    /// The transaction broke one of `Config::rules` and `RuleAction::Reject` is set.
+    RuleBroken(Rule),
+    /// A `TransactionHandler` rejected the transaction, or there isn't one for its type.
+    Rejected(String),
+    /// The transaction store couldn't be read or written, the transaction wasn't applied.
+    Store(StoreError),
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use TransactionError::*;
+
+        let message = match self {
+            NegativeAmount => "amount is negative",
+            InsufficientFunds => "insufficient available funds",
+            UnknownTransaction => "referenced transaction does not exist",
+            ClientMismatch => "client does not match the referenced transaction",
+            AlreadyDisputed => "referenced transaction has already been disputed",
+            NotDisputed => "referenced transaction is not disputed",
+            AccountLocked => "account is locked",
+            NotLocked => "account is not locked",
+            UnknownRate => "no exchange rate between the currencies",
+            DuplicateTransaction => "transaction id has already been used",
+            AlreadyApplied => "transaction has already been applied",
+            NotReversible => "referenced transaction is disputed, charged back or already reversed",
+            NotChargedBack => "referenced transaction is not charged back",
+            SelfTransfer => "transfer to the same client",
+            CrossShardTransfer => "transfer between clients on different shards",
+            ClientFinished => "client's rows have already ended",
+            DisputeWindowExpired => "referenced transaction is older than the dispute window",
+            WithdrawalLimitExceeded => "withdrawal is over the client's limit",
+            BalanceLimitExceeded => "deposit would take the balance over the client's limit",
+            DailyLimitExceeded => {
+                "withdrawal would take the day's withdrawals over the client's limit"
+            }
+            TooPrecise => "amount has more decimal places than allowed",
+            AmountTooLarge => "amount is too large",
+            RuleBroken(rule) => return write!(f, "breaks the {} rule", rule.as_str()),
+            Rejected(message) => message,
+            Store(error) => return fmt::Display::fmt(error, f),
+        };
+
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for TransactionError {}
+
+impl From<StoreError> for TransactionError {
+    fn from(error: StoreError) -> Self {
+        TransactionError::Store(error)
+    }
+}
+
+/// A `TransactionStore` failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoreError {
+    message: String,
+    transient: bool,
+}
+
+impl StoreError {
+    /// A permanent failure, retrying won't help.
+    pub fn new<S: Into<String>>(message: S) -> Self {
+        Self {
+            message: message.into(),
+            transient: false,
+        }
+    }
+
+    /// A failure that might not happen again, like a timeout, so it's worth retrying with `Retry`.
+    pub fn transient<S: Into<String>>(message: S) -> Self {
+        Self {
+            message: message.into(),
+            transient: true,
+        }
+    }
+
+    pub fn is_transient(&self) -> bool {
+        self.transient
+    }
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "transaction store failed: {}", self.message)
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<std::io::Error> for StoreError {
+    fn from(error: std::io::Error) -> Self {
+        if error.is_transient() {
+            StoreError::transient(error.to_string())
+        } else {
+            StoreError::new(error.to_string())
+        }
+    }
+}
+
+#[cfg(feature = "rocksdb")]
+impl From<rocksdb::Error> for StoreError {
+    fn from(error: rocksdb::Error) -> Self {
+        use rocksdb::ErrorKind::*;
+
+        match error.kind() {
+            Busy | TimedOut | TryAgain | Incomplete => StoreError::transient(error.into_string()),
+            _ => StoreError::new(error.into_string()),
+        }
+    }
+}
+
+/// Processors being merged both had an account for `client`, see `TransactionProcessor::merge`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeError {
+    pub client: ClientId,
+}
+
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "client {} is in more than one of the merged states",
+            self.client
+        )
+    }
+}
+
+impl std::error::Error for MergeError {}
+
+/// Why a row from the input was rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RejectReason {
+    /// The row couldn't be read as a transaction.
+    Malformed(String),
+    /// The transaction failed.
+    Transaction(TransactionError),
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectReason::Malformed(message) => write!(f, "malformed row: {message}"),
+            RejectReason::Transaction(error) => fmt::Display::fmt(error, f),
+        }
+    }
+}
+
+impl From<TransactionError> for RejectReason {
+    fn from(error: TransactionError) -> Self {
+        RejectReason::Transaction(error)
+    }
+}
+
+/// Why a row yielded by `TransactionProcessor::process_iter` wasn't applied.
+#[derive(Debug)]
+pub enum ProcessError {
+    /// The row was badly formatted or its transaction failed, the rows after it are still processed.
+    Rejected(Rejection),
+    /// The input couldn't be read or the store failed, nothing more is processed.
+    Io(std::io::Error),
+}
+
+impl fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProcessError::Rejected(rejection) => {
+                write!(f, "line {}: {}", rejection.line, rejection.reason)
+            }
+            ProcessError::Io(error) => fmt::Display::fmt(error, f),
+        }
+    }
+}
+
+impl std::error::Error for ProcessError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProcessError::Rejected(_) => None,
+            ProcessError::Io(error) => Some(error),
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use crate::client::ClientId;
+use crate::currency::Currency;
+use crate::error::TransactionError;
+use crate::journal::JournalEntry;
+use crate::rules::SuspiciousActivity;
+use crate::transaction::Transaction;
+use crate::tx::TxId;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::sync::Arc;
+
+/// Something that happened to an account, passed to the listeners added with
+/// `TransactionProcessor::subscribe`.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// A transaction was applied, with the balances it left, the same as it's journaled.
+    Applied(JournalEntry),
+    /// A disputed deposit was charged back, sent before the account is `Locked`.
+    Chargeback(Chargeback),
+    /// The client's account was locked, by a chargeback.
+    Locked {
+        client: ClientId,
+        tx: TxId,
+    },
+    Unlocked {
+        client: ClientId,
+        tx: TxId,
+    },
+    /// A transaction broke one of `Config::rules`, sent before it's applied or rejected.
+    Suspicious(SuspiciousActivity),
+}
+
+impl Event {
+    /// Whether the event is about `client`'s account, transfers are about both accounts.
+    pub fn involves(&self, client: ClientId) -> bool {
+        match self {
+            Event::Applied(entry) => entry.client == client || entry.to == Some(client),
+            Event::Chargeback(chargeback) => chargeback.client == client,
+            Event::Suspicious(activity) => activity.client == client,
+            Event::Locked { client: locked, .. } | Event::Unlocked { client: locked, .. } => {
+                *locked == client
+            }
+        }
+    }
+}
+
+/// The deposit that was charged back.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chargeback {
+    pub client: ClientId,
+    pub tx: TxId,
+    #[serde(with = "rust_decimal::serde::str")]
+    pub amount: Decimal,
+    pub currency: Currency,
+}
+
+pub(crate) type Listener = Arc<dyn Fn(&Event) + Send + Sync>;
+
+pub(crate) type RejectedListener = Arc<dyn Fn(&Transaction, &TransactionError) + Send + Sync>;
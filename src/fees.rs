@@ -0,0 +1,71 @@
+use crate::transaction::TransactionType;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::{collections::HashMap, fs::File, io::Read, path::Path};
+
+/// What's charged for one type of transaction, `rate` is a fraction of the amount so 0.01 is 1%.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Fee {
+    pub rate: Decimal,
+    pub flat: Decimal,
+}
+
+impl Fee {
+    pub fn amount(&self, amount: Decimal) -> Decimal {
+        self.flat + self.rate * amount
+    }
+}
+
+/// Fees taken from a client's available funds when their transactions are applied.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FeeSchedule {
+    fees: HashMap<TransactionType, Fee>,
+}
+
+#[derive(Deserialize)]
+struct FeeRow {
+    r#type: TransactionType,
+    #[serde(default, with = "rust_decimal::serde::str_option")]
+    rate: Option<Decimal>,
+    #[serde(default, with = "rust_decimal::serde::str_option")]
+    flat: Option<Decimal>,
+}
+
+impl FeeSchedule {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, csv::Error> {
+        Self::from_reader(File::open(path)?)
+    }
+
+    /// Reads csv with a row per charged transaction type, e.g. `withdrawal,0.01,` or `chargeback,,15`.
+    /// Headers are `type,rate,flat`, empty columns are zero.
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self, csv::Error> {
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+        let mut fee_schedule = Self::default();
+
+        for row in reader.deserialize() {
+            let row: FeeRow = row?;
+            fee_schedule.set(
+                row.r#type,
+                Fee {
+                    rate: row.rate.unwrap_or_default(),
+                    flat: row.flat.unwrap_or_default(),
+                },
+            );
+        }
+
+        Ok(fee_schedule)
+    }
+
+    pub fn set(&mut self, transaction_type: TransactionType, fee: Fee) {
+        self.fees.insert(transaction_type, fee);
+    }
+
+    /// The fee for a transaction of `amount`, zero if the type isn't charged.
+    pub fn fee(&self, transaction_type: TransactionType, amount: Decimal) -> Decimal {
+        self.fees
+            .get(&transaction_type)
+            .map_or(Decimal::ZERO, |fee| fee.amount(amount))
+    }
+}
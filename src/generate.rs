@@ -0,0 +1,148 @@
+use rust_decimal::Decimal;
+use std::io::Write;
+
+/// Deposits disputes are picked from, older ones are replaced at random once it's full.
+const RECENT_DEPOSITS: usize = 100_000;
+
+/// Writes reproducible synthetic transactions as csv, for benchmarking and testing. Deposits and withdrawals
+/// get increasing tx ids, withdrawals stay within what the client has available and disputes are later
+/// resolved or, a quarter of the time, charged back. Clients that have been charged back stop transacting.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Generator {
+    pub rows: u64,
+    /// Clients are numbered from 1.
+    pub clients: u16,
+    /// Chance of each row disputing a recent deposit, and of it closing an open dispute.
+    pub dispute_rate: f64,
+    pub seed: u64,
+}
+
+impl Default for Generator {
+    fn default() -> Self {
+        Self {
+            rows: 1000,
+            clients: 100,
+            dispute_rate: 0.01,
+            seed: 0,
+        }
+    }
+}
+
+struct Deposit {
+    client: u16,
+    tx: u32,
+    cents: i64,
+}
+
+impl Generator {
+    pub fn write<W: Write>(&self, writer: W) -> Result<(), csv::Error> {
+        let mut writer = csv::Writer::from_writer(writer);
+        writer.write_record(["type", "client", "tx", "amount"])?;
+
+        let mut rng = Rng(self.seed);
+        let clients = self.clients.max(1);
+        // Available funds and whether they're locked, by client - 1
+        let mut available = vec![0i64; usize::from(clients)];
+        let mut locked = vec![false; usize::from(clients)];
+        let mut deposits: Vec<Deposit> = Vec::new();
+        let mut disputed: Vec<Deposit> = Vec::new();
+        let mut tx = 0u32;
+
+        for _ in 0..self.rows {
+            if !disputed.is_empty() && rng.chance(self.dispute_rate) {
+                let deposit = disputed.swap_remove(rng.below(disputed.len() as u64) as usize);
+                let index = usize::from(deposit.client - 1);
+
+                let r#type = if rng.chance(0.25) {
+                    locked[index] = true;
+                    "chargeback"
+                } else {
+                    available[index] += deposit.cents;
+                    "resolve"
+                };
+
+                writer.serialize((r#type, deposit.client, deposit.tx, ""))?;
+                continue;
+            }
+
+            if !deposits.is_empty() && rng.chance(self.dispute_rate) {
+                let candidate = rng.below(deposits.len() as u64) as usize;
+                let deposit = &deposits[candidate];
+                let index = usize::from(deposit.client - 1);
+
+                // Only disputes that would go through, so the chains are plausible
+                if !locked[index] && available[index] >= deposit.cents {
+                    let deposit = deposits.swap_remove(candidate);
+                    available[index] -= deposit.cents;
+                    writer.serialize(("dispute", deposit.client, deposit.tx, ""))?;
+                    disputed.push(deposit);
+                    continue;
+                }
+            }
+
+            let client = self.client(&mut rng, &locked);
+            let index = usize::from(client - 1);
+            tx = tx.wrapping_add(1);
+
+            if available[index] > 0 && rng.chance(0.3) {
+                let cents = rng.below(available[index] as u64) as i64 + 1;
+                available[index] -= cents;
+                writer.serialize(("withdrawal", client, tx, Decimal::new(cents, 2).to_string()))?;
+            } else {
+                let cents = rng.below(100_000) as i64 + 1;
+                available[index] += cents;
+                writer.serialize(("deposit", client, tx, Decimal::new(cents, 2).to_string()))?;
+
+                let deposit = Deposit { client, tx, cents };
+
+                if deposits.len() < RECENT_DEPOSITS {
+                    deposits.push(deposit);
+                } else {
+                    let replaced = rng.below(deposits.len() as u64) as usize;
+                    deposits[replaced] = deposit;
+                }
+            }
+        }
+
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    /// A client to transact, trying a few times to find one that isn't locked.
+    fn client(&self, rng: &mut Rng, locked: &[bool]) -> u16 {
+        let clients = self.clients.max(1);
+        let mut client = 1;
+
+        for _ in 0..8 {
+            client = rng.below(u64::from(clients)) as u16 + 1;
+
+            if !locked[usize::from(client - 1)] {
+                break;
+            }
+        }
+
+        client
+    }
+}
+
+/// splitmix64, so the same seed gives the same rows everywhere.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+}
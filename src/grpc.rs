@@ -0,0 +1,156 @@
+use crate::account::Account;
+use crate::client::ClientId;
+use crate::error::TransactionError;
+use crate::transaction::{lock, IntermediateTransaction, SharedProcessor, Transaction};
+use proto::payments_server::{Payments, PaymentsServer};
+use std::{net::SocketAddr, pin::Pin};
+use tonic::{Request, Response, Status};
+
+/// Generated from `proto/payments.proto`.
+pub mod proto {
+    tonic::include_proto!("payments");
+}
+
+/// The `Payments` gRPC service, backed by the same processor as the HTTP API.
+pub struct PaymentsService {
+    transaction_processor: SharedProcessor,
+}
+
+impl PaymentsService {
+    pub fn new(transaction_processor: SharedProcessor) -> Self {
+        Self {
+            transaction_processor,
+        }
+    }
+}
+
+/// Serves the `Payments` service until `shutdown` completes.
+pub async fn serve_grpc<F>(
+    addr: SocketAddr,
+    transaction_processor: SharedProcessor,
+    shutdown: F,
+) -> Result<(), tonic::transport::Error>
+where
+    F: std::future::Future<Output = ()>,
+{
+    tonic::transport::Server::builder()
+        .add_service(PaymentsServer::new(PaymentsService::new(
+            transaction_processor,
+        )))
+        .serve_with_shutdown(addr, shutdown)
+        .await
+}
+
+#[tonic::async_trait]
+impl Payments for PaymentsService {
+    async fn submit_transaction(
+        &self,
+        request: Request<proto::Transaction>,
+    ) -> Result<Response<proto::SubmitTransactionResponse>, Status> {
+        let transaction = transaction(request.into_inner())?;
+        let throttle = lock(&self.transaction_processor).throttle();
+
+        if let Some(throttle) = throttle {
+            tokio::time::sleep(throttle.reserve()).await;
+        }
+
+        let result = lock(&self.transaction_processor).process(&transaction);
+
+        match result {
+            Ok(()) => Ok(Response::new(proto::SubmitTransactionResponse {
+                tx: transaction.tx().to_string(),
+            })),
+            Err(error @ TransactionError::Store(_)) => Err(Status::internal(error.to_string())),
+            Err(error) => Err(Status::failed_precondition(error.to_string())),
+        }
+    }
+
+    async fn get_account(
+        &self,
+        request: Request<proto::GetAccountRequest>,
+    ) -> Result<Response<proto::Account>, Status> {
+        let client: ClientId = request
+            .into_inner()
+            .client
+            .parse()
+            .map_err(Status::invalid_argument)?;
+
+        lock(&self.transaction_processor)
+            .account(client)
+            .map(|account| Response::new(account.into()))
+            .ok_or_else(|| Status::not_found(format!("client {client} doesn't have an account")))
+    }
+
+    type StreamAccountsStream =
+        Pin<Box<dyn tokio_stream::Stream<Item = Result<proto::Account, Status>> + Send>>;
+
+    /// A snapshot of the accounts when the request was made.
+    async fn stream_accounts(
+        &self,
+        _: Request<proto::StreamAccountsRequest>,
+    ) -> Result<Response<Self::StreamAccountsStream>, Status> {
+        let transaction_processor = lock(&self.transaction_processor);
+        let mut accounts: Vec<&Account> = transaction_processor.accounts().collect();
+        accounts.sort_by_key(|account| account.client);
+        let accounts: Vec<_> = accounts.into_iter().map(proto::Account::from).collect();
+
+        Ok(Response::new(Box::pin(tokio_stream::iter(
+            accounts.into_iter().map(Ok),
+        ))))
+    }
+}
+
+fn transaction(transaction: proto::Transaction) -> Result<Transaction, Status> {
+    let currency = |currency: Option<String>| {
+        currency
+            .map(|currency| currency.parse().map_err(Status::invalid_argument))
+            .transpose()
+    };
+
+    Transaction::try_from(IntermediateTransaction {
+        r#type: transaction
+            .r#type
+            .parse()
+            .map_err(Status::invalid_argument)?,
+        client: transaction
+            .client
+            .parse()
+            .map_err(Status::invalid_argument)?,
+        tx: transaction.tx.parse().map_err(Status::invalid_argument)?,
+        to: transaction
+            .to
+            .map(|to| to.parse().map_err(Status::invalid_argument))
+            .transpose()?,
+        amount: transaction
+            .amount
+            .map(|amount| {
+                amount
+                    .parse()
+                    .map_err(|_| Status::invalid_argument(format!("Invalid amount {amount}")))
+            })
+            .transpose()?,
+        currency: currency(transaction.currency)?,
+        to_currency: currency(transaction.to_currency)?,
+        timestamp: transaction.timestamp,
+    })
+    .map_err(Status::invalid_argument)
+}
+
+impl From<&Account> for proto::Account {
+    fn from(account: &Account) -> Self {
+        Self {
+            client: account.client.to_string(),
+            balances: account
+                .balances
+                .iter()
+                .map(|(currency, balance)| proto::Balance {
+                    currency: currency.to_string(),
+                    available: balance.available.to_string(),
+                    held: balance.held.to_string(),
+                    total: balance.total().to_string(),
+                })
+                .collect(),
+            locked: account.locked,
+        }
+    }
+}
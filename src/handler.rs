@@ -0,0 +1,63 @@
+use crate::account::Account;
+use crate::client::ClientId;
+use crate::config::Config;
+use crate::currency::Currency;
+use crate::error::TransactionError;
+use crate::transaction::TransactionType;
+use crate::tx::TxId;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+/// A row with a `type` the processor doesn't know, passed to the handler registered for the type.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CustomTransaction {
+    pub r#type: String,
+    pub client: ClientId,
+    pub tx: TxId,
+    #[serde(
+        default,
+        deserialize_with = "rust_decimal::serde::float_option::deserialize"
+    )]
+    pub amount: Option<Decimal>,
+    /// The base currency if the column is missing or empty.
+    #[serde(default)]
+    pub currency: Option<Currency>,
+}
+
+impl CustomTransaction {
+    /// Only rows that failed to deserialize as a `Transaction` are tried, and only kept if the type isn't
+    /// a known one, so a deposit missing its amount is still rejected as malformed.
+    pub(crate) fn unknown(self) -> Option<Self> {
+        self.r#type
+            .parse::<TransactionType>()
+            .is_err()
+            .then_some(self)
+    }
+}
+
+/// Applies a transaction type that isn't built in, e.g. loyalty credits, see
+/// `TransactionProcessor::register_handler`.
+pub trait TransactionHandler: Send + Sync {
+    /// `account` is the client's account, or a new one if they haven't got one, and is only kept if this
+    /// succeeds. Locked accounts are rejected before the handler is called.
+    fn apply(
+        &self,
+        transaction: &CustomTransaction,
+        account: &mut Account,
+        config: &Config,
+    ) -> Result<(), TransactionError>;
+}
+
+impl<F> TransactionHandler for F
+where
+    F: Fn(&CustomTransaction, &mut Account, &Config) -> Result<(), TransactionError> + Send + Sync,
+{
+    fn apply(
+        &self,
+        transaction: &CustomTransaction,
+        account: &mut Account,
+        config: &Config,
+    ) -> Result<(), TransactionError> {
+        self(transaction, account, config)
+    }
+}
@@ -0,0 +1,27 @@
+use crate::account::{Account, Balance};
+use crate::currency::Currency;
+use crate::transaction::Transaction;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// A transaction applied to an account, kept by `TransactionProcessor::track_history`.
+#[derive(Serialize, Debug, Clone)]
+pub struct AppliedTransaction {
+    /// Milliseconds since the unix epoch.
+    pub timestamp: u64,
+    pub transaction: Transaction,
+    /// The account's balances after the transaction was applied.
+    pub balances: BTreeMap<Currency, Balance>,
+    pub locked: bool,
+}
+
+impl AppliedTransaction {
+    pub(crate) fn new(timestamp: u64, transaction: &Transaction, account: &Account) -> Self {
+        Self {
+            timestamp,
+            transaction: *transaction,
+            balances: account.balances.clone(),
+            locked: account.locked,
+        }
+    }
+}
@@ -0,0 +1,1021 @@
+use crate::client::ClientId;
+use crate::compression::{decompress, Decompressed};
+use crate::currency::Currency;
+use crate::error::RejectReason;
+use crate::handler::CustomTransaction;
+use crate::transaction::{IntermediateTransaction, Transaction, TransactionType};
+use crate::tx::TxId;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, BufReader, ErrorKind, Read},
+    str::FromStr,
+    sync::mpsc,
+    time::Duration,
+};
+
+/// Bytes of csv read at a time by `read_parallel`, split between the threads.
+const PARALLEL_BLOCK: u64 = 16 * 1024 * 1024;
+
+/// Blocks `read_pipelined` queues between its stages.
+const PIPELINE_QUEUE: usize = 2;
+
+/// Columns every transactions csv has, the rest are optional.
+const REQUIRED_COLUMNS: [&str; 3] = ["type", "client", "tx"];
+
+/// Every column that's read, anything else is kept as metadata, see `Row::metadata`.
+const KNOWN_COLUMNS: [&str; 9] = [
+    "type",
+    "client",
+    "tx",
+    "to",
+    "amount",
+    "currency",
+    "to_currency",
+    "timestamp",
+    "effective",
+];
+
+/// Format of the transactions being read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InputFormat {
+    #[default]
+    Csv,
+    /// One json transaction per line.
+    Jsonl,
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(InputFormat::Csv),
+            "jsonl" => Ok(InputFormat::Jsonl),
+            #[cfg(feature = "parquet")]
+            "parquet" => Ok(InputFormat::Parquet),
+            _ => Err(format!("Unknown format {s}")),
+        }
+    }
+}
+
+/// How csv input is laid out, for partners whose files don't follow the usual format. Jsonl and parquet
+/// aren't affected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CsvOptions {
+    /// Byte between fields, e.g. `b';'` or `b'\t'`.
+    pub delimiter: u8,
+    /// Our name for a column to what it's called in the header row, e.g. `type` to `txn_type`, for files with
+    /// their own names for the columns.
+    pub columns: BTreeMap<String, String>,
+    /// Whether amounts are written with a decimal comma, and maybe points between thousands, e.g. `1.234,56`.
+    pub decimal_comma: bool,
+    /// Whether rows are parsed straight from their bytes rather than deserialized, which doesn't allocate so
+    /// is quicker on big files. Rows it can't read are deserialized as usual, so they're rejected the same
+    /// way.
+    pub fast: bool,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        Self {
+            delimiter: b',',
+            columns: BTreeMap::new(),
+            decimal_comma: false,
+            fast: false,
+        }
+    }
+}
+
+/// A row read from the input, the raw row is kept so it can be reported if it's rejected.
+pub(crate) struct Row<'a> {
+    pub line: u64,
+    pub transaction: Result<Transaction, RejectReason>,
+    /// If the row isn't a transaction because its type is unknown, for a `TransactionHandler`.
+    pub custom: Option<CustomTransaction>,
+    /// When the transaction takes effect, seconds since the unix epoch, if it has an `effective` column.
+    pub effective: Option<u64>,
+    raw: Raw<'a>,
+    /// Index and name of the columns that aren't read, only csv has them.
+    extra: &'a [(usize, String)],
+    /// What the fields of a csv row were separated by.
+    delimiter: u8,
+}
+
+/// The `effective` column, read on its own as it isn't part of the transaction.
+#[derive(Deserialize)]
+struct Effective {
+    #[serde(default)]
+    effective: Option<u64>,
+}
+
+/// A csv row as serde reads it. The amount is read as text and parsed here, as csv reads a field that looks like
+/// a number as a float when `IntermediateTransaction` asks for one so json can have numbers, which drops its
+/// trailing zeros and the digits a float can't hold.
+#[derive(Deserialize)]
+struct CsvTransaction<'a> {
+    r#type: TransactionType,
+    client: ClientId,
+    tx: TxId,
+    #[serde(default)]
+    to: Option<ClientId>,
+    #[serde(default, borrow)]
+    amount: Option<&'a str>,
+    #[serde(default)]
+    currency: Option<Currency>,
+    #[serde(default)]
+    to_currency: Option<Currency>,
+    #[serde(default)]
+    timestamp: Option<u64>,
+}
+
+enum Raw<'a> {
+    Record(&'a csv::ByteRecord),
+    Line(&'a [u8]),
+}
+
+impl Row<'_> {
+    pub fn raw(&self) -> String {
+        match self.raw {
+            Raw::Record(record) => record
+                .iter()
+                .map(String::from_utf8_lossy)
+                .collect::<Vec<_>>()
+                .join(&char::from(self.delimiter).to_string()),
+            Raw::Line(line) => String::from_utf8_lossy(line).into_owned(),
+        }
+    }
+
+    /// The row's columns that aren't read, e.g. a partner's merchant id, by name. Empty ones are left out.
+    pub fn metadata(&self) -> BTreeMap<String, String> {
+        let Raw::Record(record) = self.raw else {
+            return BTreeMap::new();
+        };
+
+        self.extra
+            .iter()
+            .filter_map(|(index, name)| {
+                let value = record.get(*index).filter(|value| !value.is_empty())?;
+                Some((name.clone(), String::from_utf8_lossy(value).into_owned()))
+            })
+            .collect()
+    }
+}
+
+/// The columns of a header row that aren't in `KNOWN_COLUMNS`.
+fn extra_columns(headers: &csv::ByteRecord) -> Vec<(usize, String)> {
+    headers
+        .iter()
+        .enumerate()
+        .filter(|(_, header)| {
+            !KNOWN_COLUMNS
+                .iter()
+                .any(|known| known.as_bytes() == *header)
+        })
+        .map(|(index, header)| (index, String::from_utf8_lossy(header).into_owned()))
+        .collect()
+}
+
+/// Reads every row, badly formatted rows are passed on with an error rather than ending the read. Gzip and
+/// zstd input is decompressed as it's read.
+pub(crate) fn read<R, F>(
+    reader: R,
+    format: InputFormat,
+    options: &CsvOptions,
+    mut f: F,
+) -> Result<(), std::io::Error>
+where
+    R: Read,
+    F: FnMut(Row) -> Result<(), std::io::Error>,
+{
+    let mut rows = Rows::new(reader, format, options)?;
+
+    while let Some(result) = rows.next(&mut f) {
+        result??;
+    }
+
+    Ok(())
+}
+
+/// Like `read`, but csv is parsed on `threads` threads a block at a time, each taking a run of whole lines.
+/// Rows are still passed to `f` in order. Rows can't have quoted line breaks, which transactions don't.
+pub(crate) fn read_parallel<R, F>(
+    reader: R,
+    format: InputFormat,
+    options: &CsvOptions,
+    threads: usize,
+    mut f: F,
+) -> Result<(), std::io::Error>
+where
+    R: Read,
+    F: FnMut(Row) -> Result<(), std::io::Error>,
+{
+    if format != InputFormat::Csv || threads <= 1 {
+        return read(reader, format, options, f);
+    }
+
+    let mut reader = BufReader::new(decompress(reader)?);
+    let headers = read_headers(&mut reader, options)?;
+    let extra = extra_columns(&headers);
+    let columns = Columns::new(&headers, options);
+
+    // Lines before the block, starting with the header
+    let mut lines = 1;
+    let mut carried = Vec::new();
+
+    while let Some(block) = next_block(&mut reader, &mut carried)? {
+        let parsed = parse_block(&block, &headers, columns.as_ref(), options, threads);
+        lines = apply_block(parsed, lines, &extra, options, &mut f)?;
+    }
+
+    Ok(())
+}
+
+/// Like `read_parallel`, but reading blocks, parsing them and passing their rows to `f` are stages on their
+/// own threads, with a couple of blocks queued between each, so the next blocks are read and parsed while
+/// this one's rows are applied. `f` is still called on this thread.
+pub(crate) fn read_pipelined<R, F>(
+    reader: R,
+    format: InputFormat,
+    options: &CsvOptions,
+    threads: usize,
+    mut f: F,
+) -> Result<(), std::io::Error>
+where
+    R: Read + Send,
+    F: FnMut(Row) -> Result<(), std::io::Error>,
+{
+    if format != InputFormat::Csv {
+        return read(reader, format, options, f);
+    }
+
+    let mut reader = BufReader::new(decompress(reader)?);
+    let headers = read_headers(&mut reader, options)?;
+    let extra = extra_columns(&headers);
+    let columns = Columns::new(&headers, options);
+
+    std::thread::scope(|scope| {
+        let (blocks_sender, blocks) = mpsc::sync_channel(PIPELINE_QUEUE);
+        let (parsed_sender, parsed) = mpsc::sync_channel(PIPELINE_QUEUE);
+
+        // Each stage stops when the next hangs up, which it does if it fails or `f` does
+        scope.spawn(move || {
+            let mut carried = Vec::new();
+
+            while let Some(block) = next_block(&mut reader, &mut carried).transpose() {
+                let failed = block.is_err();
+
+                if blocks_sender.send(block).is_err() || failed {
+                    return;
+                }
+            }
+        });
+
+        let (headers, columns) = (&headers, columns.as_ref());
+        scope.spawn(move || {
+            for block in blocks {
+                let parsed =
+                    block.map(|block| parse_block(&block, headers, columns, options, threads));
+
+                if parsed_sender.send(parsed).is_err() {
+                    return;
+                }
+            }
+        });
+
+        // Lines before the block, starting with the header
+        let mut lines = 1;
+
+        for parsed in parsed {
+            lines = apply_block(parsed?, lines, &extra, options, &mut f)?;
+        }
+
+        Ok(())
+    })
+}
+
+/// The header row of csv whose rows are read a block at a time, with our names for its columns.
+fn read_headers<R: BufRead>(
+    reader: &mut R,
+    options: &CsvOptions,
+) -> Result<csv::ByteRecord, std::io::Error> {
+    let mut header = Vec::new();
+    reader.read_until(b'\n', &mut header)?;
+
+    let headers = csv_reader(header.as_slice(), options)
+        .byte_records()
+        .next()
+        .transpose()?
+        .unwrap_or_default();
+    let headers = rename_headers(headers, options)?;
+    check_headers(&headers)?;
+
+    Ok(headers)
+}
+
+/// The next `PARALLEL_BLOCK` or so bytes of whole lines, or `None` at the end of the input. Whatever's after
+/// the last line break is left in `carried` for the next block.
+fn next_block<R: Read>(
+    reader: &mut R,
+    carried: &mut Vec<u8>,
+) -> Result<Option<Vec<u8>>, std::io::Error> {
+    loop {
+        let read = reader.by_ref().take(PARALLEL_BLOCK).read_to_end(carried)?;
+
+        let rest = match carried.iter().rposition(|&byte| byte == b'\n') {
+            Some(end) if read > 0 => carried.split_off(end + 1),
+            None if read > 0 => continue,
+            _ => Vec::new(),
+        };
+        let block = std::mem::replace(carried, rest);
+
+        return Ok(Some(block).filter(|block| !block.is_empty()));
+    }
+}
+
+/// A block's rows parsed on `threads` threads, a run of whole lines each, with how many lines each run has.
+fn parse_block(
+    block: &[u8],
+    headers: &csv::ByteRecord,
+    columns: Option<&Columns>,
+    options: &CsvOptions,
+    threads: usize,
+) -> Vec<(u64, Vec<Result<Parsed, csv::Error>>)> {
+    let chunks = split_lines(block, threads);
+
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = chunks
+            .iter()
+            .map(|&chunk| scope.spawn(move || parse_chunk(chunk, headers, columns, options)))
+            .collect();
+
+        chunks
+            .iter()
+            .zip(handles)
+            .map(|(chunk, handle)| {
+                let lines = chunk.iter().filter(|&&byte| byte == b'\n').count() as u64;
+                (lines, handle.join().expect("csv parsing panicked"))
+            })
+            .collect()
+    })
+}
+
+/// Passes a parsed block's rows to `f`, returning the lines before the next block given the `lines` before
+/// this one.
+fn apply_block<F>(
+    parsed: Vec<(u64, Vec<Result<Parsed, csv::Error>>)>,
+    mut lines: u64,
+    extra: &[(usize, String)],
+    options: &CsvOptions,
+    f: &mut F,
+) -> Result<u64, std::io::Error>
+where
+    F: FnMut(Row) -> Result<(), std::io::Error>,
+{
+    for (chunk_lines, rows) in parsed {
+        for parsed in rows {
+            let (record, transaction, custom, effective) = parsed?;
+            f(Row {
+                line: lines + record.position().map_or(0, |position| position.line()),
+                transaction,
+                custom,
+                effective,
+                raw: Raw::Record(&record),
+                extra,
+                delimiter: options.delimiter,
+            })?;
+        }
+
+        lines += chunk_lines;
+    }
+
+    Ok(lines)
+}
+
+/// Reads csv like `tail -f`, at the end it waits `poll` and reads whatever's been appended since. Rows are
+/// only read once their line is finished, so they can't have quoted line breaks. `f` is called with each row,
+/// and with `None` after each run of new rows and every `poll` while there aren't any. Only stops if `f` or
+/// reading fails. It isn't decompressed.
+pub(crate) fn follow<R, F>(
+    reader: R,
+    options: &CsvOptions,
+    poll: Duration,
+    mut f: F,
+) -> Result<(), std::io::Error>
+where
+    R: Read,
+    F: FnMut(Option<Row>) -> Result<(), std::io::Error>,
+{
+    let mut reader = BufReader::new(reader);
+    let mut header = Vec::new();
+
+    while header.last() != Some(&b'\n') {
+        if reader.read_until(b'\n', &mut header)? == 0 {
+            f(None)?;
+            std::thread::sleep(poll);
+        }
+    }
+
+    let headers = read_headers(&mut header.as_slice(), options)?;
+    let extra = extra_columns(&headers);
+    let columns = Columns::new(&headers, options);
+
+    // Lines before the block, starting with the header
+    let mut lines = 1;
+    let mut block = Vec::new();
+
+    loop {
+        reader
+            .by_ref()
+            .take(PARALLEL_BLOCK)
+            .read_to_end(&mut block)?;
+
+        // Whatever is after the last line break is carried over until the rest of its line is appended
+        let Some(end) = block.iter().rposition(|&byte| byte == b'\n') else {
+            f(None)?;
+            std::thread::sleep(poll);
+            continue;
+        };
+        let carried = block.split_off(end + 1);
+
+        for parsed in parse_chunk(&block, &headers, columns.as_ref(), options) {
+            let (record, transaction, custom, effective) = parsed?;
+            f(Some(Row {
+                line: lines + record.position().map_or(0, |position| position.line()),
+                transaction,
+                custom,
+                effective,
+                raw: Raw::Record(&record),
+                extra: &extra,
+                delimiter: options.delimiter,
+            }))?;
+        }
+
+        lines += block.iter().filter(|&&byte| byte == b'\n').count() as u64;
+        f(None)?;
+        block = carried;
+    }
+}
+
+/// `block` split into about `parts` runs of whole lines.
+fn split_lines(block: &[u8], parts: usize) -> Vec<&[u8]> {
+    let mut chunks = Vec::with_capacity(parts);
+    let mut rest = block;
+
+    for remaining in (1..=parts).rev() {
+        if rest.is_empty() {
+            break;
+        }
+
+        let end = rest[rest.len() / remaining..]
+            .iter()
+            .position(|&byte| byte == b'\n')
+            .map_or(rest.len(), |newline| rest.len() / remaining + newline + 1);
+        let (chunk, tail) = rest.split_at(end);
+        chunks.push(chunk);
+        rest = tail;
+    }
+
+    chunks
+}
+
+type Parsed = (
+    csv::ByteRecord,
+    Result<Transaction, RejectReason>,
+    Option<CustomTransaction>,
+    Option<u64>,
+);
+
+/// Parses every row of a run of whole lines without a header row, their positions are from the start of it.
+/// Stops at the first error, after the rows before it.
+fn parse_chunk(
+    chunk: &[u8],
+    headers: &csv::ByteRecord,
+    columns: Option<&Columns>,
+    options: &CsvOptions,
+) -> Vec<Result<Parsed, csv::Error>> {
+    let mut reader = csv_reader(chunk, options);
+    let mut rows = Vec::new();
+    let mut record = csv::ByteRecord::new();
+
+    loop {
+        match reader.read_byte_record(&mut record) {
+            Ok(true) => {
+                let (transaction, custom, effective) =
+                    parse_row(&record, headers, columns, options);
+                rows.push(Ok((record.clone(), transaction, custom, effective)));
+            }
+            Ok(false) => return rows,
+            Err(error) => {
+                rows.push(Err(error));
+                return rows;
+            }
+        }
+    }
+}
+
+fn csv_reader<R: Read>(reader: R, options: &CsvOptions) -> csv::Reader<R> {
+    csv::ReaderBuilder::new()
+        .delimiter(options.delimiter)
+        .trim(csv::Trim::All)
+        .flexible(true)
+        .has_headers(false)
+        .from_reader(reader)
+}
+
+/// `columns` is only given if rows are parsed by hand, see `CsvOptions::fast`.
+fn parse_row(
+    record: &csv::ByteRecord,
+    headers: &csv::ByteRecord,
+    columns: Option<&Columns>,
+    options: &CsvOptions,
+) -> (
+    Result<Transaction, RejectReason>,
+    Option<CustomTransaction>,
+    Option<u64>,
+) {
+    let with_point;
+    let record = match with_decimal_point(record, headers, options) {
+        Some(record) => {
+            with_point = record;
+            &with_point
+        }
+        None => record,
+    };
+
+    if let Some((transaction, effective)) =
+        columns.and_then(|columns| parse_fast(record, headers, columns))
+    {
+        return (Ok(transaction), None, effective);
+    }
+
+    let (transaction, effective) = with_effective(parse_record(record, headers), || {
+        record.deserialize::<Effective>(Some(headers))
+    });
+    let custom = match transaction {
+        Err(_) => record
+            .deserialize::<CustomTransaction>(Some(headers))
+            .ok()
+            .and_then(CustomTransaction::unknown),
+        Ok(_) => None,
+    };
+
+    (transaction, custom, effective)
+}
+
+/// The record with its amount rewritten from e.g. `1.234,56` to `1234.56`, so it can be deserialized, if the
+/// input has decimal commas. `None` if there's nothing to rewrite.
+fn with_decimal_point(
+    record: &csv::ByteRecord,
+    headers: &csv::ByteRecord,
+    options: &CsvOptions,
+) -> Option<csv::ByteRecord> {
+    if !options.decimal_comma {
+        return None;
+    }
+
+    let amount = headers.iter().position(|header| header == b"amount")?;
+
+    Some(
+        record
+            .iter()
+            .enumerate()
+            .map(|(index, field)| {
+                if index != amount {
+                    return field.to_vec();
+                }
+
+                field
+                    .iter()
+                    .filter(|&&byte| byte != b'.')
+                    .map(|&byte| if byte == b',' { b'.' } else { byte })
+                    .collect()
+            })
+            .collect(),
+    )
+}
+
+/// Rows read one at a time as the caller asks for them, rather than passed to a callback like `read`.
+pub(crate) enum Rows<R: Read> {
+    Csv {
+        reader: Box<csv::Reader<Decompressed<R>>>,
+        headers: csv::ByteRecord,
+        extra: Vec<(usize, String)>,
+        columns: Option<Columns>,
+        record: csv::ByteRecord,
+        options: CsvOptions,
+    },
+    Jsonl {
+        lines: std::io::Split<BufReader<Decompressed<R>>>,
+        line: u64,
+    },
+    /// Parquet has to be read whole anyway, so its rows are converted to json up front to go through the
+    /// same deserialization as jsonl.
+    #[cfg(feature = "parquet")]
+    Parquet {
+        rows: std::vec::IntoIter<serde_json::Value>,
+        line: u64,
+    },
+}
+
+impl<R: Read> Rows<R> {
+    /// Fails if the input can't be decompressed or, for csv, the header row isn't a transactions header.
+    pub(crate) fn new(
+        reader: R,
+        format: InputFormat,
+        options: &CsvOptions,
+    ) -> Result<Self, std::io::Error> {
+        let reader = decompress(reader)?;
+
+        match format {
+            InputFormat::Csv => {
+                let mut reader = csv::ReaderBuilder::new()
+                    .delimiter(options.delimiter)
+                    .trim(csv::Trim::All)
+                    .flexible(true)
+                    .from_reader(reader);
+
+                let headers = rename_headers(reader.byte_headers()?.clone(), options)?;
+                check_headers(&headers)?;
+                let extra = extra_columns(&headers);
+                let columns = Columns::new(&headers, options);
+
+                Ok(Rows::Csv {
+                    reader: Box::new(reader),
+                    headers,
+                    extra,
+                    columns,
+                    record: csv::ByteRecord::new(),
+                    options: options.clone(),
+                })
+            }
+            InputFormat::Jsonl => Ok(Rows::Jsonl {
+                lines: BufReader::new(reader).split(b'\n'),
+                line: 0,
+            }),
+            #[cfg(feature = "parquet")]
+            InputFormat::Parquet => Ok(Rows::Parquet {
+                rows: parquet_rows(reader)?.into_iter(),
+                line: 0,
+            }),
+        }
+    }
+
+    /// Passes the next row to `f`, returning what it returns, or `None` at the end of the input.
+    pub(crate) fn next<T, F>(&mut self, f: F) -> Option<Result<T, std::io::Error>>
+    where
+        F: FnOnce(Row) -> T,
+    {
+        match self {
+            Rows::Csv {
+                reader,
+                headers,
+                extra,
+                columns,
+                record,
+                options,
+            } => {
+                match reader.read_byte_record(record) {
+                    Ok(true) => {}
+                    Ok(false) => return None,
+                    Err(error) => return Some(Err(error.into())),
+                }
+
+                let (transaction, custom, effective) =
+                    parse_row(record, headers, columns.as_ref(), options);
+
+                Some(Ok(f(Row {
+                    line: record.position().map_or(0, |position| position.line()),
+                    transaction,
+                    custom,
+                    effective,
+                    raw: Raw::Record(record),
+                    extra,
+                    delimiter: options.delimiter,
+                })))
+            }
+            Rows::Jsonl { lines, line } => loop {
+                let bytes = match lines.next()? {
+                    Ok(bytes) => bytes,
+                    Err(error) => return Some(Err(error)),
+                };
+                *line += 1;
+                let bytes = bytes.strip_suffix(b"\r").unwrap_or(&bytes);
+
+                if bytes.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+
+                let (transaction, effective) = with_effective(
+                    serde_json::from_slice(bytes)
+                        .map_err(|error| RejectReason::Malformed(error.to_string())),
+                    || serde_json::from_slice::<Effective>(bytes),
+                );
+                let custom = match transaction {
+                    Err(_) => serde_json::from_slice::<CustomTransaction>(bytes)
+                        .ok()
+                        .and_then(CustomTransaction::unknown),
+                    Ok(_) => None,
+                };
+
+                return Some(Ok(f(Row {
+                    line: *line,
+                    transaction,
+                    custom,
+                    effective,
+                    raw: Raw::Line(bytes),
+                    extra: &[],
+                    delimiter: b',',
+                })));
+            },
+            #[cfg(feature = "parquet")]
+            Rows::Parquet { rows, line } => {
+                let row = rows.next()?;
+                *line += 1;
+                let raw = row.to_string();
+                let (transaction, effective) = with_effective(
+                    serde_json::from_value(row.clone())
+                        .map_err(|error| RejectReason::Malformed(error.to_string())),
+                    || serde_json::from_value::<Effective>(row.clone()),
+                );
+                let custom = match transaction {
+                    Err(_) => serde_json::from_value::<CustomTransaction>(row)
+                        .ok()
+                        .and_then(CustomTransaction::unknown),
+                    Ok(_) => None,
+                };
+
+                Some(Ok(f(Row {
+                    line: *line,
+                    transaction,
+                    custom,
+                    effective,
+                    raw: Raw::Line(raw.as_bytes()),
+                    extra: &[],
+                    delimiter: b',',
+                })))
+            }
+        }
+    }
+}
+
+/// Every row of a parquet file as json.
+#[cfg(feature = "parquet")]
+fn parquet_rows<R: Read>(mut reader: R) -> Result<Vec<serde_json::Value>, std::io::Error> {
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    let invalid_data =
+        |error: parquet::errors::ParquetError| std::io::Error::new(ErrorKind::InvalidData, error);
+
+    // The footer has to be read first so parquet needs random access, not just a Read
+    let mut buffer = Vec::new();
+    reader.read_to_end(&mut buffer)?;
+    let reader = SerializedFileReader::new(bytes::Bytes::from(buffer)).map_err(invalid_data)?;
+
+    reader
+        .get_row_iter(None)
+        .map_err(invalid_data)?
+        .map(|row| Ok(row.map_err(invalid_data)?.to_json_value()))
+        .collect()
+}
+
+/// The header row with the input's names for columns replaced by ours, see `CsvOptions::columns`. Fails if
+/// a column that isn't read is mapped, as it's probably a typo.
+fn rename_headers(
+    headers: csv::ByteRecord,
+    options: &CsvOptions,
+) -> Result<csv::ByteRecord, std::io::Error> {
+    if let Some(column) = options
+        .columns
+        .keys()
+        .find(|column| !KNOWN_COLUMNS.contains(&column.as_str()))
+    {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("can't map {column}, it isn't one of the columns that's read"),
+        ));
+    }
+
+    Ok(headers
+        .iter()
+        .map(|header| {
+            options
+                .columns
+                .iter()
+                .find(|(_, theirs)| theirs.as_bytes() == header)
+                .map_or(header, |(ours, _)| ours.as_bytes())
+        })
+        .collect())
+}
+
+/// Fails if the header row doesn't have the required columns, as the file probably isn't transactions and
+/// every row would be rejected. An empty file has no rows to reject so is fine.
+fn check_headers(headers: &csv::ByteRecord) -> Result<(), std::io::Error> {
+    if headers.is_empty() {
+        return Ok(());
+    }
+
+    let missing: Vec<_> = REQUIRED_COLUMNS
+        .into_iter()
+        .filter(|column| !headers.iter().any(|header| header == column.as_bytes()))
+        .collect();
+
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    Err(std::io::Error::new(
+        ErrorKind::InvalidData,
+        format!(
+            "not a transactions file, the header row is missing the {} column{}",
+            missing.join(", "),
+            if missing.len() == 1 { "" } else { "s" }
+        ),
+    ))
+}
+
+/// Reads the `effective` column of a row that's a transaction, a bad one rejects the row.
+fn with_effective<E, F>(
+    transaction: Result<Transaction, RejectReason>,
+    effective: F,
+) -> (Result<Transaction, RejectReason>, Option<u64>)
+where
+    E: ToString,
+    F: FnOnce() -> Result<Effective, E>,
+{
+    match transaction {
+        Ok(transaction) => match effective() {
+            Ok(Effective { effective }) => (Ok(transaction), effective),
+            Err(error) => (Err(RejectReason::Malformed(error.to_string())), None),
+        },
+        Err(reason) => (Err(reason), None),
+    }
+}
+
+/// Deserializes a row, the reader is flexible so rows with the wrong number of fields are rejected here.
+/// Fields that can't be read are named by their column, with what was in them.
+fn parse_record(
+    record: &csv::ByteRecord,
+    headers: &csv::ByteRecord,
+) -> Result<Transaction, RejectReason> {
+    if record.len() != headers.len() {
+        return Err(RejectReason::Malformed(format!(
+            "expected {} fields but found {}",
+            headers.len(),
+            record.len()
+        )));
+    }
+
+    let row: CsvTransaction = record.deserialize(Some(headers)).map_err(|error| {
+        let message = match error.kind() {
+            csv::ErrorKind::Deserialize { err, .. } => match err.field() {
+                Some(field) => format!(
+                    "field {} {:?}: {}",
+                    String::from_utf8_lossy(headers.get(field as usize).unwrap_or_default()),
+                    String::from_utf8_lossy(record.get(field as usize).unwrap_or_default()),
+                    err.kind()
+                ),
+                None => err.kind().to_string(),
+            },
+            _ => error.to_string(),
+        };
+
+        RejectReason::Malformed(message)
+    })?;
+
+    let amount = row
+        .amount
+        .map(|amount| {
+            Decimal::from_str(amount)
+                .or_else(|error| Decimal::from_scientific(amount).map_err(|_| error))
+                .map_err(|error| {
+                    RejectReason::Malformed(format!("field amount {amount:?}: {error}"))
+                })
+        })
+        .transpose()?;
+
+    IntermediateTransaction {
+        r#type: row.r#type,
+        client: row.client,
+        tx: row.tx,
+        to: row.to,
+        amount,
+        currency: row.currency,
+        to_currency: row.to_currency,
+        timestamp: row.timestamp,
+    }
+    .try_into()
+    .map_err(|error: &str| RejectReason::Malformed(error.to_string()))
+}
+
+/// Where the columns that are read are in the header row, for parsing rows by hand.
+pub(crate) struct Columns {
+    r#type: usize,
+    client: usize,
+    tx: usize,
+    to: Option<usize>,
+    amount: Option<usize>,
+    currency: Option<usize>,
+    to_currency: Option<usize>,
+    timestamp: Option<usize>,
+    effective: Option<usize>,
+}
+
+impl Columns {
+    /// `None` unless `CsvOptions::fast`, or if a required column is missing or any column is repeated, which
+    /// is left to serde to make sense of.
+    fn new(headers: &csv::ByteRecord, options: &CsvOptions) -> Option<Self> {
+        if !options.fast {
+            return None;
+        }
+
+        let position = |column: &str| {
+            let mut found = headers
+                .iter()
+                .enumerate()
+                .filter(|(_, header)| *header == column.as_bytes())
+                .map(|(index, _)| index);
+
+            match (found.next(), found.next()) {
+                (_, Some(_)) => None,
+                (index, None) => Some(index),
+            }
+        };
+
+        Some(Self {
+            r#type: position("type")??,
+            client: position("client")??,
+            tx: position("tx")??,
+            to: position("to")?,
+            amount: position("amount")?,
+            currency: position("currency")?,
+            to_currency: position("to_currency")?,
+            timestamp: position("timestamp")?,
+            effective: position("effective")?,
+        })
+    }
+}
+
+/// Parses a row straight from its bytes without allocating, with when it takes effect. `None` if anything
+/// about it isn't right, e.g. a field that can't be read or a type that's handled elsewhere, so
+/// `parse_record` can reject it with the usual message or it can be a `CustomTransaction`.
+fn parse_fast(
+    record: &csv::ByteRecord,
+    headers: &csv::ByteRecord,
+    columns: &Columns,
+) -> Option<(Transaction, Option<u64>)> {
+    fn parse<T: FromStr>(field: Option<&str>) -> Option<Option<T>> {
+        field.map(str::parse).transpose().ok()
+    }
+
+    if record.len() != headers.len() {
+        return None;
+    }
+
+    let field = |index: usize| std::str::from_utf8(&record[index]).ok();
+    // Missing and empty columns are `None`, like serde's defaults
+    let optional = |index: Option<usize>| match index.map(|index| &record[index]) {
+        None | Some([]) => Some(None),
+        Some(bytes) => std::str::from_utf8(bytes).ok().map(Some),
+    };
+
+    let transaction = IntermediateTransaction {
+        r#type: field(columns.r#type)?.parse().ok()?,
+        client: field(columns.client)?.parse().ok()?,
+        tx: field(columns.tx)?.parse().ok()?,
+        to: parse(optional(columns.to)?)?,
+        amount: match optional(columns.amount)? {
+            Some(amount) => Some(parse_amount(amount)?),
+            None => None,
+        },
+        currency: parse(optional(columns.currency)?)?,
+        to_currency: parse(optional(columns.to_currency)?)?,
+        timestamp: parse(optional(columns.timestamp)?)?,
+    };
+
+    Some((
+        transaction.try_into().ok()?,
+        parse(optional(columns.effective)?)?,
+    ))
+}
+
+/// An amount of just digits with an optional minus sign and decimal point, anything else, e.g. `1e3`, is
+/// left to `parse_record`. Read exactly, as `parse_record` reads it, so both give the same amount.
+fn parse_amount(amount: &str) -> Option<Decimal> {
+    let digits = amount.strip_prefix('-').unwrap_or(amount);
+    let points = digits.bytes().filter(|&byte| byte == b'.').count();
+
+    if !digits.bytes().any(|byte| byte.is_ascii_digit())
+        || !digits
+            .bytes()
+            .all(|byte| byte.is_ascii_digit() || byte == b'.')
+        || points > 1
+    {
+        return None;
+    }
+
+    Decimal::from_str(amount).ok()
+}
@@ -0,0 +1,48 @@
+use rust_decimal::Decimal;
+use std::str::FromStr;
+
+/// How a year's interest is divided into days.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DayCount {
+    /// A day's interest is 1/365 of the annual rate.
+    #[default]
+    Actual365,
+    /// A day's interest is 1/360 of the annual rate.
+    Actual360,
+}
+
+impl FromStr for DayCount {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "act/365" => Ok(DayCount::Actual365),
+            "act/360" => Ok(DayCount::Actual360),
+            _ => Err(format!("Unknown day count {s}")),
+        }
+    }
+}
+
+/// Simple interest on available funds, see `TransactionProcessor::accrue_interest`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Interest {
+    /// Annual rate, 0.05 is 5%.
+    pub rate: Decimal,
+    pub day_count: DayCount,
+}
+
+impl Interest {
+    /// Interest on `available` for `days`, zero for negative balances. It's rounded when it's posted.
+    pub fn accrue(&self, available: Decimal, days: u64) -> Decimal {
+        if available <= Decimal::ZERO {
+            return Decimal::ZERO;
+        }
+
+        let days_per_year = match self.day_count {
+            DayCount::Actual365 => Decimal::from(365),
+            DayCount::Actual360 => Decimal::from(360),
+        };
+
+        available * self.rate * Decimal::from(days) / days_per_year
+    }
+}
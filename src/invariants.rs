@@ -0,0 +1,182 @@
+use crate::account::Account;
+use crate::config::{Config, LockedPolicy};
+use crate::store::TransactionStore;
+use crate::transaction::{DisputedState, RecordKind, Transaction, TransactionRecord};
+use rust_decimal::Decimal;
+use std::fmt;
+
+/// An invariant a transaction broke, with the accounts it touched before and after it and the record it
+/// referenced, for working out how.
+#[derive(Debug)]
+pub(crate) struct Violation {
+    invariant: String,
+    transaction: Transaction,
+    before: Vec<Account>,
+    after: Vec<Account>,
+    record: Option<TransactionRecord>,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "invariant broken: {}", self.invariant)?;
+        writeln!(f, "  transaction: {:?}", self.transaction)?;
+
+        for (before, after) in self.before.iter().zip(&self.after) {
+            writeln!(f, "  before: {before:?}")?;
+            writeln!(f, "  after: {after:?}")?;
+        }
+
+        write!(f, "  record: {:?}", self.record)
+    }
+}
+
+/// Checks what should always hold once `transaction` has been applied. `before` and `after` are the accounts it
+/// touched, the client's and then the receiver's for a transfer.
+pub(crate) fn check<S: TransactionStore>(
+    transaction: &Transaction,
+    before: &[Account],
+    after: &[Account],
+    transactions: &S,
+    config: &Config,
+) -> Result<(), Box<Violation>> {
+    use Transaction::*;
+
+    let record = match transaction {
+        Transfer { .. } | Unlock { .. } | Interest { .. } | Fee { .. } | Convert { .. } => None,
+        _ => transactions.get(transaction.tx()).ok().flatten(),
+    };
+
+    let broken = balances(after)
+        .or_else(|| locked(transaction, before, after, record.as_ref(), config))
+        .or_else(|| referenced(transaction, record.as_ref()));
+
+    match broken {
+        Some(invariant) => Err(Box::new(Violation {
+            invariant,
+            transaction: *transaction,
+            before: before.to_vec(),
+            after: after.to_vec(),
+            record,
+        })),
+        None => Ok(()),
+    }
+}
+
+/// Held funds and shortfalls are never negative, and nothing is negative zero, which decimals can represent
+/// but nothing should produce.
+fn balances(accounts: &[Account]) -> Option<String> {
+    for account in accounts {
+        for (currency, balance) in &account.balances {
+            for (name, amount) in [
+                ("available", &balance.available),
+                ("held", &balance.held),
+                ("shortfall", &balance.shortfall),
+            ] {
+                if amount.is_zero() && amount.is_sign_negative() {
+                    return Some(format!(
+                        "client {}'s {name} {currency} is negative zero",
+                        account.client
+                    ));
+                }
+
+                if name != "available" && *amount < Decimal::ZERO {
+                    return Some(format!(
+                        "client {}'s {name} {currency} is negative, {amount}",
+                        account.client
+                    ));
+                }
+            }
+        }
+    }
+
+    None
+}
+
+/// Locked accounts are only unlocked by unlocks and chargeback reversals, and only changed by the transactions
+/// `Config::locked_policy` allows.
+fn locked(
+    transaction: &Transaction,
+    before: &[Account],
+    after: &[Account],
+    record: Option<&TransactionRecord>,
+    config: &Config,
+) -> Option<String> {
+    use Transaction::*;
+
+    let unlocks = matches!(transaction, Unlock { .. } | ChargebackReversal { .. });
+    let allowed = match (config.locked_policy, transaction) {
+        (_, Transfer { .. }) => false,
+        _ if unlocks => true,
+        (LockedPolicy::Allow, _) => true,
+        (LockedPolicy::Reject, _) => false,
+        (LockedPolicy::RejectDebits, Withdrawal { .. } | Fee { .. } | Convert { .. }) => false,
+        (LockedPolicy::RejectDebits, Reversal { .. }) => {
+            record.is_none_or(|record| record.kind != RecordKind::Deposit)
+        }
+        (LockedPolicy::RejectDebits, _) => true,
+    };
+
+    for (before, after) in before.iter().zip(after) {
+        if !before.locked {
+            continue;
+        }
+
+        if !after.locked && !unlocks {
+            return Some(format!(
+                "client {}'s account was unlocked by a {}",
+                after.client,
+                transaction.transaction_type()
+            ));
+        }
+
+        if !allowed && before != after {
+            return Some(format!(
+                "client {}'s locked account was changed by a {} under the {:?} policy",
+                after.client,
+                transaction.transaction_type(),
+                config.locked_policy
+            ));
+        }
+    }
+
+    None
+}
+
+/// Deposits and withdrawals are kept, and disputes, resolves, chargebacks and reversals reference one of the
+/// client's records and leave it in the state they're for.
+fn referenced(transaction: &Transaction, record: Option<&TransactionRecord>) -> Option<String> {
+    use Transaction::*;
+
+    let (expected, deposits_only) = match transaction {
+        Deposit { .. } | Withdrawal { .. } => (DisputedState::Undisputed, false),
+        Dispute { .. } => (DisputedState::Disputed, true),
+        Resolve { .. } => (DisputedState::Resolved, true),
+        Chargeback { .. } => (DisputedState::Chargebacked, true),
+        ChargebackReversal { .. } => (DisputedState::ChargebackReversed, true),
+        Reversal { .. } => (DisputedState::Reversed, false),
+        _ => return None,
+    };
+    let tx = transaction.tx();
+
+    let Some(record) = record else {
+        return Some(format!("tx {tx} doesn't map to a record"));
+    };
+
+    if record.client != transaction.client() {
+        Some(format!("tx {tx} is client {}'s", record.client))
+    } else if deposits_only && record.kind != RecordKind::Deposit {
+        Some(format!("tx {tx} isn't a deposit"))
+    } else if record.disputed != expected {
+        Some(format!(
+            "tx {tx} is {:?} rather than {expected:?}",
+            record.disputed
+        ))
+    } else if record.shortfall < Decimal::ZERO || record.shortfall > record.amount {
+        Some(format!(
+            "tx {tx}'s shortfall of {} is outside 0 to its amount, {}",
+            record.shortfall, record.amount
+        ))
+    } else {
+        None
+    }
+}
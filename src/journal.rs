@@ -0,0 +1,189 @@
+use crate::account::Account;
+use crate::client::ClientId;
+use crate::compression::Compression;
+use crate::currency::Currency;
+use crate::money::Money;
+use crate::transaction::{IntermediateTransaction, Transaction, TransactionType};
+use crate::tx::TxId;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::OpenOptions,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// An applied transaction and the balances it left its accounts with, a line of json in the journal.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct JournalEntry {
+    /// Milliseconds since the unix epoch.
+    pub timestamp: u64,
+    pub r#type: TransactionType,
+    pub client: ClientId,
+    pub tx: TxId,
+    #[serde(with = "rust_decimal::serde::str_option")]
+    pub amount: Option<Decimal>,
+    /// As it was in the input, `None` is the base currency.
+    pub currency: Option<Currency>,
+    pub to: Option<ClientId>,
+    pub to_currency: Option<Currency>,
+    /// The transaction's `timestamp` column, seconds since the unix epoch.
+    #[serde(default)]
+    pub transaction_timestamp: Option<u64>,
+    /// Every balance of the accounts the transaction changed, after it was applied.
+    pub balances: Vec<JournalBalance>,
+    /// The row's extra columns, if the processor is keeping them, see `TransactionProcessor::keep_metadata`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct JournalBalance {
+    pub client: ClientId,
+    pub currency: Currency,
+    pub available: Money,
+    pub held: Money,
+    pub total: Money,
+    pub locked: bool,
+}
+
+impl JournalEntry {
+    /// `accounts` are the accounts the transaction changed.
+    pub(crate) fn new<'a, I>(transaction: &Transaction, accounts: I) -> Self
+    where
+        I: IntoIterator<Item = &'a Account>,
+    {
+        let columns = IntermediateTransaction::from(*transaction);
+
+        let balances = accounts
+            .into_iter()
+            .flat_map(|account| {
+                account
+                    .balances
+                    .iter()
+                    .map(move |(currency, balance)| JournalBalance {
+                        client: account.client,
+                        currency: *currency,
+                        available: rounded(&balance.available),
+                        held: rounded(&balance.held),
+                        total: rounded(&balance.total()),
+                        locked: account.locked,
+                    })
+            })
+            .collect();
+
+        Self {
+            timestamp: timestamp(),
+            r#type: columns.r#type,
+            client: columns.client,
+            tx: columns.tx,
+            amount: columns.amount,
+            currency: columns.currency,
+            to: columns.to,
+            to_currency: columns.to_currency,
+            transaction_timestamp: columns.timestamp,
+            balances,
+            metadata: BTreeMap::new(),
+        }
+    }
+
+    /// The transaction that was applied, so the journal can be replayed.
+    pub fn transaction(&self) -> Result<Transaction, &'static str> {
+        Transaction::try_from(IntermediateTransaction {
+            r#type: self.r#type,
+            client: self.client,
+            tx: self.tx,
+            to: self.to,
+            amount: self.amount,
+            currency: self.currency,
+            to_currency: self.to_currency,
+            timestamp: self.transaction_timestamp,
+        })
+    }
+}
+
+/// Rounded to 4 decimal places and written with all of them, like the accounts csv, so a balance is written
+/// the same however many places the amounts that made it up had.
+fn rounded(amount: &Money) -> Money {
+    amount.round_dp(4)
+}
+
+/// Milliseconds since the unix epoch.
+pub(crate) fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Append-only record of every applied transaction. Shared between threads, so write errors are kept
+/// and returned by the next `flush` rather than failing the transaction.
+pub struct Journal {
+    writer: Mutex<JournalWriter>,
+}
+
+struct JournalWriter {
+    writer: BufWriter<Box<dyn Write + Send>>,
+    error: Option<std::io::Error>,
+}
+
+impl Journal {
+    /// Adds to the end of the journal, creating it if it doesn't exist.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, std::io::Error> {
+        Self::from_path_compressed(path, Compression::None)
+    }
+
+    /// Each run adds its own compressed stream to the end, which is finished when the journal is dropped.
+    pub fn from_path_compressed<P: AsRef<Path>>(
+        path: P,
+        compression: Compression,
+    ) -> Result<Self, std::io::Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::new(compression.writer(file)?))
+    }
+
+    pub fn new<W: Write + Send + 'static>(writer: W) -> Self {
+        Self {
+            writer: Mutex::new(JournalWriter {
+                writer: BufWriter::new(Box::new(writer)),
+                error: None,
+            }),
+        }
+    }
+
+    pub(crate) fn record(&self, entry: &JournalEntry) {
+        let mut journal = self
+            .writer
+            .lock()
+            .unwrap_or_else(|poison| poison.into_inner());
+
+        if journal.error.is_some() {
+            return;
+        }
+
+        let result = serde_json::to_writer(&mut journal.writer, entry)
+            .map_err(std::io::Error::from)
+            .and_then(|()| journal.writer.write_all(b"\n"));
+
+        if let Err(error) = result {
+            journal.error = Some(error);
+        }
+    }
+
+    /// Fails if any entry since the last flush couldn't be written.
+    pub fn flush(&self) -> Result<(), std::io::Error> {
+        let mut journal = self
+            .writer
+            .lock()
+            .unwrap_or_else(|poison| poison.into_inner());
+
+        if let Some(error) = journal.error.take() {
+            return Err(error);
+        }
+
+        journal.writer.flush()
+    }
+}
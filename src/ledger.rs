@@ -0,0 +1,350 @@
+use crate::account::{Account, Balance};
+use crate::client::ClientId;
+use crate::currency::Currency;
+use crate::error::TransactionError;
+use crate::money::Money;
+use crate::transaction::TransactionType;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt};
+
+/// An account in the books. Clients' balances are split into their available, held and shortfall accounts,
+/// and everything else a transaction touches is a system account, so every change to a client's balances has
+/// another side that says where the money came from or went.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum LedgerAccount {
+    /// `Balance::available`.
+    Available(ClientId),
+    /// `Balance::held`.
+    Held(ClientId),
+    /// `Balance::shortfall`, what the client owes rather than what they're owed, so it's debited as it grows.
+    Shortfall(ClientId),
+    /// Money coming into and going out of the system: deposits, withdrawals, chargebacks and reversals of
+    /// them. Changes a `TransactionHandler` makes to balances are booked here too, as there's no telling
+    /// where they came from.
+    Suspense,
+    /// The other side of clients' shortfalls, disputed funds that couldn't be held and are lost if the deposit
+    /// is charged back and the client doesn't pay them.
+    ChargebackLoss,
+    Fees,
+    Interest,
+    /// The other side of conversions, in both currencies.
+    Conversion,
+    /// Balances brought forward from a saved state, which only keeps clients' balances.
+    Opening,
+}
+
+impl LedgerAccount {
+    /// The client whose balances the account is part of, `None` for system accounts.
+    pub fn client(&self) -> Option<ClientId> {
+        match *self {
+            LedgerAccount::Available(client)
+            | LedgerAccount::Held(client)
+            | LedgerAccount::Shortfall(client) => Some(client),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for LedgerAccount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LedgerAccount::Available(client) => write!(f, "available:{client}"),
+            LedgerAccount::Held(client) => write!(f, "held:{client}"),
+            LedgerAccount::Shortfall(client) => write!(f, "shortfall:{client}"),
+            LedgerAccount::Suspense => f.write_str("suspense"),
+            LedgerAccount::ChargebackLoss => f.write_str("chargeback_loss"),
+            LedgerAccount::Fees => f.write_str("fees"),
+            LedgerAccount::Interest => f.write_str("interest"),
+            LedgerAccount::Conversion => f.write_str("conversion"),
+            LedgerAccount::Opening => f.write_str("opening"),
+        }
+    }
+}
+
+/// Moves `amount` of `currency` from `debit` to `credit`. Both sides are the same amount, so the books balance
+/// after every posting. Amounts are never negative, a posting the other way round swaps the accounts.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Posting {
+    pub debit: LedgerAccount,
+    pub credit: LedgerAccount,
+    pub currency: Currency,
+    pub amount: Money,
+}
+
+impl Posting {
+    pub fn new(
+        debit: LedgerAccount,
+        credit: LedgerAccount,
+        currency: Currency,
+        amount: Money,
+    ) -> Self {
+        if amount < Decimal::ZERO {
+            return Self::new(credit, debit, currency, -amount);
+        }
+
+        Self {
+            debit,
+            credit,
+            currency,
+            amount,
+        }
+    }
+}
+
+/// A system account's balance from one type of transaction, `None` for custom transactions and opening
+/// balances.
+type Key = (LedgerAccount, Currency, Option<TransactionType>);
+
+/// The balances of the system accounts, credits less debits, kept up to date with the postings of every
+/// transaction applied and split by the type of transaction that posted them. Clients' accounts are the
+/// `Account`s themselves.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Ledger {
+    balances: BTreeMap<Key, Money>,
+}
+
+impl Ledger {
+    /// Books `accounts`' balances against `LedgerAccount::Opening`, for accounts loaded from a saved state.
+    pub(crate) fn opening<'a, I>(accounts: I) -> Self
+    where
+        I: IntoIterator<Item = &'a Account>,
+    {
+        let mut ledger = Self::default();
+
+        for account in accounts {
+            for (&currency, balance) in &account.balances {
+                *ledger
+                    .balances
+                    .entry((LedgerAccount::Opening, currency, None))
+                    .or_default() -= client_total(balance);
+            }
+        }
+
+        ledger
+    }
+
+    /// Applies the sides of the postings that are system accounts, `Account::apply` does the clients' sides.
+    /// Nothing is posted if a balance would overflow.
+    pub(crate) fn post(
+        &mut self,
+        r#type: Option<TransactionType>,
+        postings: &[Posting],
+    ) -> Result<(), TransactionError> {
+        let posted = self.posted(r#type, postings)?;
+        self.balances.extend(posted);
+        Ok(())
+    }
+
+    /// The balances of the system accounts the postings change, as they'd be after them.
+    pub(crate) fn posted(
+        &self,
+        r#type: Option<TransactionType>,
+        postings: &[Posting],
+    ) -> Result<Vec<(Key, Money)>, TransactionError> {
+        let mut posted: Vec<(Key, Money)> = Vec::new();
+
+        for posting in postings {
+            for (account, amount) in [
+                (posting.debit, -&posting.amount),
+                (posting.credit, posting.amount.clone()),
+            ] {
+                if account.client().is_some() {
+                    continue;
+                }
+
+                let key = (account, posting.currency, r#type);
+                let index = match posted.iter().position(|(posted, _)| *posted == key) {
+                    Some(index) => index,
+                    None => {
+                        posted.push((key, self.balances.get(&key).cloned().unwrap_or_default()));
+                        posted.len() - 1
+                    }
+                };
+                let balance = &mut posted[index].1;
+                *balance = balance
+                    .checked_add(&amount)
+                    .ok_or(TransactionError::AmountTooLarge)?;
+            }
+        }
+
+        Ok(posted)
+    }
+
+    /// Adds another ledger's balances, for accounts merged from another processor.
+    pub(crate) fn extend(&mut self, other: Ledger) {
+        for (key, balance) in other.balances {
+            *self.balances.entry(key).or_default() += balance;
+        }
+    }
+
+    /// A system account's balance, credits less debits, so money that came into the system through suspense
+    /// leaves it negative.
+    pub fn balance(&self, account: LedgerAccount, currency: Currency) -> Money {
+        self.balances
+            .range((account, currency, None)..)
+            .take_while(|((posted, posted_currency, _), _)| {
+                (*posted, *posted_currency) == (account, currency)
+            })
+            .map(|(_, balance)| balance)
+            .sum()
+    }
+
+    /// Every system account that's been posted to, with its balance from each type of transaction.
+    pub fn balances(
+        &self,
+    ) -> impl Iterator<Item = (LedgerAccount, Currency, Option<TransactionType>, Money)> + '_ {
+        self.balances
+            .iter()
+            .map(|(&(account, currency, r#type), balance)| {
+                (account, currency, r#type, balance.clone())
+            })
+    }
+
+    /// How far out the books are in each currency, clients' balances plus the system accounts, leaving out
+    /// the currencies that balance. Empty unless something changed balances without posting.
+    pub fn trial_balance<'a, I>(&self, accounts: I) -> BTreeMap<Currency, Money>
+    where
+        I: IntoIterator<Item = &'a Account>,
+    {
+        self.reconcile(accounts)
+            .into_iter()
+            .map(|reconciliation| (reconciliation.currency, reconciliation.difference()))
+            .filter(|(_, difference)| !difference.is_zero())
+            .collect()
+    }
+
+    /// Breaks down how clients' balances in each currency came about, from the system accounts, to check them
+    /// against what the clients actually have.
+    pub fn reconcile<'a, I>(&self, accounts: I) -> Vec<Reconciliation>
+    where
+        I: IntoIterator<Item = &'a Account>,
+    {
+        let mut reconciliations = BTreeMap::<Currency, Reconciliation>::new();
+
+        for account in accounts {
+            for (&currency, balance) in &account.balances {
+                let reconciliation = reconciliations
+                    .entry(currency)
+                    .or_insert_with(|| Reconciliation::new(currency));
+                reconciliation.available += &balance.available;
+                reconciliation.held += &balance.held;
+                reconciliation.shortfall += &balance.shortfall;
+            }
+        }
+
+        for (&(account, currency, r#type), balance) in &self.balances {
+            // What a system account's been credited has come out of clients' balances, and the other way round
+            if !balance.is_zero() {
+                reconciliations
+                    .entry(currency)
+                    .or_insert_with(|| Reconciliation::new(currency))
+                    .flows
+                    .push((account, r#type, -balance));
+            }
+        }
+
+        reconciliations.into_values().collect()
+    }
+}
+
+/// One currency's books from `Ledger::reconcile`: what went into clients' balances against what they have.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reconciliation {
+    pub currency: Currency,
+    /// What each system account paid into clients' balances by the type of transaction, negative for what it
+    /// took out of them. Deposits are paid in from suspense, and withdrawals and chargebacks paid out to it.
+    pub flows: Vec<(LedgerAccount, Option<TransactionType>, Money)>,
+    /// Clients' available funds added up.
+    pub available: Money,
+    pub held: Money,
+    /// Clients' shortfalls, which come out of their balances along with `chargeback_loss`.
+    pub shortfall: Money,
+}
+
+impl Reconciliation {
+    fn new(currency: Currency) -> Self {
+        Self {
+            currency,
+            flows: Vec::new(),
+            available: Money::default(),
+            held: Money::default(),
+            shortfall: Money::default(),
+        }
+    }
+
+    /// What the flows add up to, what clients' available and held funds less their shortfalls should be.
+    pub fn expected(&self) -> Money {
+        self.flows.iter().map(|(_, _, amount)| amount).sum()
+    }
+
+    /// How far clients' balances are from `expected`, zero when the books balance.
+    pub fn difference(&self) -> Money {
+        &self.available + &self.held - &self.shortfall - self.expected()
+    }
+
+    pub fn balances(&self) -> bool {
+        self.difference().is_zero()
+    }
+}
+
+impl fmt::Display for Reconciliation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.currency)?;
+
+        for (account, r#type, amount) in &self.flows {
+            let label = match (account, r#type) {
+                (account, Some(r#type)) => format!("{account} from {}", r#type.as_str()),
+                (LedgerAccount::Opening, None) => account.to_string(),
+                (account, None) => format!("{account} from custom transactions"),
+            };
+            writeln!(f, "  {label:<40}{amount:>24}")?;
+        }
+
+        writeln!(f, "  {:<40}{:>24}", "expected", self.expected())?;
+        writeln!(f, "  {:<40}{:>24}", "available", self.available)?;
+        writeln!(f, "  {:<40}{:>24}", "held", self.held)?;
+        writeln!(f, "  {:<40}{:>24}", "less shortfall", self.shortfall)?;
+        writeln!(f, "  {:<40}{:>24}", "out by", self.difference())
+    }
+}
+
+/// The postings that take `before`'s balances to `after`'s, against suspense, for changes made without
+/// postings by a `TransactionHandler`.
+pub(crate) fn adjustments(before: &Account, after: &Account) -> Vec<Posting> {
+    let client = after.client;
+    let mut postings = Vec::new();
+
+    for (&currency, balance) in &after.balances {
+        let was = before.balance(currency);
+
+        for (account, change) in [
+            (
+                LedgerAccount::Available(client),
+                &balance.available - &was.available,
+            ),
+            (LedgerAccount::Held(client), &balance.held - &was.held),
+            (
+                LedgerAccount::Shortfall(client),
+                &was.shortfall - &balance.shortfall,
+            ),
+        ] {
+            if !change.is_zero() {
+                postings.push(Posting::new(
+                    LedgerAccount::Suspense,
+                    account,
+                    currency,
+                    change,
+                ));
+            }
+        }
+    }
+
+    postings
+}
+
+/// A client's balance as the books see it, credits less debits across their accounts.
+fn client_total(balance: &Balance) -> Money {
+    balance.total() - &balance.shortfall
+}
@@ -0,0 +1,127 @@
+//! A simple payments engine.
+//!
+//! Reads a stream of deposits, withdrawals, disputes, resolves and chargebacks and
+//! keeps track of the resulting client accounts.
+//!
+//! ```no_run
+//! use payments::TransactionProcessor;
+//! use std::fs::File;
+//!
+//! let mut transaction_processor = TransactionProcessor::new();
+//! transaction_processor.process_transactions(File::open("transactions.csv")?)?;
+//!
+//! for account in transaction_processor.accounts() {
+//!     for (currency, balance) in &account.balances {
+//!         println!("{}: {} {}", account.client, balance.total(), currency);
+//!     }
+//! }
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+mod account;
+mod actor;
+mod checkpoint;
+mod client;
+mod compression;
+mod config;
+mod currency;
+#[cfg(feature = "tui")]
+mod dashboard;
+mod dead_letter;
+mod diff;
+mod error;
+mod events;
+mod fees;
+mod generate;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod handler;
+mod history;
+mod input;
+mod interest;
+mod invariants;
+mod journal;
+mod ledger;
+mod limits;
+mod metrics;
+mod money;
+mod negative;
+mod rates;
+mod rejects;
+mod retry;
+mod risk;
+mod rules;
+#[cfg(feature = "s3")]
+mod s3;
+#[cfg(feature = "server")]
+mod server;
+mod simulation;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod statement;
+mod store;
+mod summary;
+mod tcp;
+mod throttle;
+mod transaction;
+mod tx;
+#[cfg(feature = "io-uring")]
+mod uring;
+mod wal;
+mod webhook;
+
+pub use account::{Account, Balance};
+pub use actor::ClientActors;
+pub use checkpoint::Checkpoint;
+pub use client::ClientId;
+pub use compression::Compression;
+pub use config::{Config, DisputePolicy, LockedPolicy};
+pub use currency::Currency;
+#[cfg(feature = "tui")]
+pub use dashboard::{Dashboard, DashboardHandle};
+pub use dead_letter::DeadLetters;
+pub use diff::write_diff;
+pub use error::{MergeError, ProcessError, RejectReason, StoreError, TransactionError};
+pub use events::{Chargeback, Event};
+pub use fees::{Fee, FeeSchedule};
+pub use generate::Generator;
+#[cfg(feature = "grpc")]
+pub use grpc::{proto, serve_grpc, PaymentsService};
+pub use handler::{CustomTransaction, TransactionHandler};
+pub use history::AppliedTransaction;
+pub use input::{CsvOptions, InputFormat};
+pub use interest::{DayCount, Interest};
+pub use journal::{Journal, JournalBalance, JournalEntry};
+pub use ledger::{Ledger, LedgerAccount, Posting, Reconciliation};
+pub use limits::{ClientLimit, ClientLimits, CreditLimits};
+pub use metrics::{serve_metrics, Metrics};
+pub use money::Money;
+pub use negative::{NegativeBalance, NegativeBalances};
+pub use rates::ExchangeRates;
+pub use rejects::{Rejection, RejectsWriter};
+pub use retry::{Retry, Transient};
+pub use risk::{BasicRiskScorer, RiskContext, RiskDecision, RiskScorer};
+pub use rules::{Rule, RuleAction, Rules, SuspiciousActivity, SuspiciousActivityWriter};
+#[cfg(feature = "s3")]
+pub use s3::{write_s3, S3Reader};
+#[cfg(feature = "server")]
+pub use server::{http_router, http_router_sharded, serve_http, serve_router};
+pub use simulation::{Divergence, Model, ReferenceModel, Simulation};
+#[cfg(feature = "sqlite")]
+pub use sqlite::{ClientState, SqliteState};
+pub use statement::write_statement;
+#[cfg(feature = "rocksdb")]
+pub use store::RocksDbStore;
+pub use store::{RetryStore, SpillStore, TransactionStore};
+pub use summary::{Summary, SummaryReport};
+pub use tcp::{ingest_tcp, ingest_tcp_sharded};
+pub use throttle::Throttle;
+pub use transaction::{
+    AccountStream, Applied, DisputedState, RecordKind, RunReport, SharedProcessor, SortBy,
+    Transaction, TransactionProcessor, TransactionRecord, TransactionType,
+};
+pub use tx::TxId;
+#[cfg(feature = "io-uring")]
+pub use uring::UringReader;
+pub use wal::{Wal, WalEntry};
+pub use webhook::{WebhookEvent, WebhookUrl, Webhooks};
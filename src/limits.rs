@@ -0,0 +1,119 @@
+use crate::client::ClientId;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::{collections::HashMap, fs::File, io::Read, path::Path};
+
+/// How far withdrawals can take each client's available funds below zero.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CreditLimits {
+    /// For clients without a limit of their own.
+    pub default: Decimal,
+    clients: HashMap<ClientId, Decimal>,
+}
+
+#[derive(Deserialize)]
+struct LimitRow {
+    client: ClientId,
+    #[serde(with = "rust_decimal::serde::str")]
+    limit: Decimal,
+}
+
+impl CreditLimits {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, csv::Error> {
+        Self::from_reader(File::open(path)?)
+    }
+
+    /// Reads csv with headers `client,limit`.
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self, csv::Error> {
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+        let mut limits = Self::default();
+
+        for row in reader.deserialize() {
+            let row: LimitRow = row?;
+            limits.set(row.client, row.limit);
+        }
+
+        Ok(limits)
+    }
+
+    pub fn set(&mut self, client: ClientId, limit: Decimal) {
+        self.clients.insert(client, limit);
+    }
+
+    pub fn limit(&self, client: ClientId) -> Decimal {
+        self.clients.get(&client).copied().unwrap_or(self.default)
+    }
+}
+
+/// A client's limits, `None` is no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClientLimit {
+    /// Largest single withdrawal.
+    pub max_withdrawal: Option<Decimal>,
+    /// Most a deposit can take the total balance in its currency to.
+    pub max_balance: Option<Decimal>,
+    /// Most that can be withdrawn in each currency in a day, going by the `timestamp` column.
+    pub daily_out_limit: Option<Decimal>,
+}
+
+/// Limits on each client's withdrawals and balances, clients without a row have none.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientLimits {
+    clients: HashMap<ClientId, ClientLimit>,
+}
+
+#[derive(Deserialize)]
+struct ClientLimitRow {
+    client: ClientId,
+    #[serde(default, with = "rust_decimal::serde::str_option")]
+    max_withdrawal: Option<Decimal>,
+    #[serde(default, with = "rust_decimal::serde::str_option")]
+    max_balance: Option<Decimal>,
+    #[serde(default, with = "rust_decimal::serde::str_option")]
+    daily_out_limit: Option<Decimal>,
+}
+
+impl ClientLimits {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, csv::Error> {
+        Self::from_reader(File::open(path)?)
+    }
+
+    /// Reads csv with headers `client,max_withdrawal,max_balance,daily_out_limit`, empty columns are no limit.
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self, csv::Error> {
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+        let mut limits = Self::default();
+
+        for row in reader.deserialize() {
+            let row: ClientLimitRow = row?;
+            limits.set(
+                row.client,
+                ClientLimit {
+                    max_withdrawal: row.max_withdrawal,
+                    max_balance: row.max_balance,
+                    daily_out_limit: row.daily_out_limit,
+                },
+            );
+        }
+
+        Ok(limits)
+    }
+
+    pub fn set(&mut self, client: ClientId, limit: ClientLimit) {
+        self.clients.insert(client, limit);
+    }
+
+    pub fn limit(&self, client: ClientId) -> ClientLimit {
+        self.clients.get(&client).copied().unwrap_or_default()
+    }
+
+    /// Whether what each client withdraws in a day has to be kept.
+    pub(crate) fn has_daily_limits(&self) -> bool {
+        self.clients
+            .values()
+            .any(|limit| limit.daily_out_limit.is_some())
+    }
+}
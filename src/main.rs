@@ -1,20 +1,2284 @@
-mod account;
-mod transaction;
+use clap::{ArgAction, Parser, Subcommand};
+use payments::{
+    ingest_tcp, ingest_tcp_sharded, serve_metrics, write_diff, write_statement, AccountStream,
+    BasicRiskScorer, Checkpoint, ClientActors, ClientId, ClientLimits, Compression, Config,
+    CreditLimits, CsvOptions, Currency, DayCount, DeadLetters, DisputePolicy, Event, ExchangeRates,
+    FeeSchedule, Generator, InputFormat, Interest, Journal, LockedPolicy, Metrics,
+    NegativeBalances, RejectReason, Rejection, RejectsWriter, Retry, RuleAction, Rules,
+    SharedProcessor, SortBy, SpillStore, Summary, SuspiciousActivityWriter, Throttle,
+    TransactionProcessor, TransactionRecord, TransactionStore, TxId, Wal, WebhookEvent, WebhookUrl,
+    Webhooks,
+};
+#[cfg(feature = "tui")]
+use payments::{Dashboard, DashboardHandle};
+use rust_decimal::Decimal;
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
-use std::io::ErrorKind;
-use transaction::TransactionProcessor;
+/// Applies deposits, withdrawals, disputes etc. to client accounts.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+    /// Logs more to stderr, -v for each file, -vv for each transaction. RUST_LOG overrides it.
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+    /// Logs less to stderr, -q for errors only, -qq for nothing.
+    #[arg(short, long, action = ArgAction::Count, global = true, conflicts_with = "verbose")]
+    quiet: u8,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Processes transaction files and writes the accounts to stdout as csv.
+    Process(Box<ProcessArgs>),
+    /// Rebuilds the accounts from a journal and writes them to stdout as csv.
+    Replay(ReplayArgs),
+    /// Applies transactions posted over HTTP, with the server feature.
+    Serve(ServeArgs),
+    /// Applies transactions streamed over TCP as they arrive.
+    Ingest(IngestArgs),
+    /// Processes files as they're dropped into a directory, moving them to processed or failed.
+    Watch(WatchArgs),
+    /// Writes a client's statement as csv from a journal.
+    Statement(StatementArgs),
+    /// Writes reproducible synthetic transactions to stdout as csv.
+    Generate(GenerateArgs),
+    /// Combines states saved by `process --save-state` for different clients and writes the accounts as csv.
+    Merge(MergeArgs),
+    /// Writes how each client's balances changed between two accounts csvs as csv.
+    Diff(DiffArgs),
+    /// Writes a client's balances, lock and open disputes from a `process --state` database as json.
+    Query(QueryArgs),
+}
+
+impl Command {
+    /// Whether a dashboard is taking over the terminal, which logging would draw over.
+    fn tui(&self) -> bool {
+        match self {
+            Command::Serve(args) => args.tui,
+            Command::Ingest(args) => args.tui,
+            Command::Watch(args) => args.tui,
+            _ => false,
+        }
+    }
+}
+
+#[derive(clap::Args)]
+struct ProcessArgs {
+    /// Files or glob patterns, processed in order into the same accounts. `-` reads from stdin.
+    #[arg(required = true, value_name = "FILES")]
+    files: Vec<String>,
+    /// Input format: csv, jsonl or parquet (with the parquet feature).
+    #[arg(long, default_value = "csv")]
+    format: InputFormat,
+    #[command(flatten)]
+    csv: CsvArgs,
+    /// Writes rejected rows and why they were rejected to this csv.
+    #[arg(long, value_name = "FILE")]
+    rejects: Option<String>,
+    /// Keeps the csv columns that aren't read, e.g. a partner's merchant id, and writes them to the journal
+    /// and a metadata column of the rejects.
+    #[arg(long, conflicts_with = "resume")]
+    keep_columns: bool,
+    /// Checks invariants after every transaction, e.g. held funds never going negative, and aborts with the
+    /// transaction and the accounts before and after it at the first that's broken. Slower, for debugging.
+    #[arg(long)]
+    check_invariants: bool,
+    /// SQLite database accounts are loaded from and saved back to (with the sqlite feature).
+    #[arg(long, value_name = "FILE")]
+    state: Option<String>,
+    #[command(flatten)]
+    retry: RetryArgs,
+    /// Applies transactions on this many threads, sharded by client.
+    #[arg(long, default_value_t = 1)]
+    threads: usize,
+    /// Parses csv on this many threads, a block of lines each, while still applying transactions in order.
+    #[arg(long, default_value_t = 1)]
+    parse_threads: usize,
+    /// Keeps about this many MB of deposits and withdrawals in memory for disputes, spilling the rest to a
+    /// temporary file.
+    #[arg(long, value_name = "MB")]
+    max_memory: Option<u64>,
+    /// Maps input files into memory and parses them straight from the mapping (with the mmap feature).
+    #[arg(long)]
+    mmap: bool,
+    /// Reads input files with io_uring, a --read-buffer at a time with a few queued ahead (with the io-uring
+    /// feature, on Linux).
+    #[arg(long, conflicts_with = "mmap")]
+    io_uring: bool,
+    /// Bytes read from each input file at a time, e.g. `8M`. Bigger reads are quicker on network filesystems.
+    #[arg(long, value_name = "SIZE", default_value = "64K", value_parser = size)]
+    read_buffer: usize,
+    /// Bytes of the accounts buffered before they're written, e.g. `1M`.
+    #[arg(long, value_name = "SIZE", default_value = "64K", value_parser = size)]
+    write_buffer: usize,
+    /// Reads, parses and applies csv on separate threads, so reading and parsing overlap with applying.
+    #[arg(long, conflicts_with_all = ["threads", "checkpoint", "resume", "follow"])]
+    pipeline: bool,
+    /// Parses csv rows straight from their bytes rather than deserializing them, quicker on big files.
+    #[arg(long)]
+    fast_csv: bool,
+    /// Saves progress to this file so an interrupted run can be resumed.
+    #[arg(long, value_name = "FILE")]
+    checkpoint: Option<String>,
+    /// Rows between checkpoints.
+    #[arg(long, default_value_t = 100_000, value_name = "ROWS")]
+    checkpoint_every: u64,
+    /// Carries on from a checkpoint, pass the same files in the same order.
+    #[arg(long, value_name = "FILE", conflicts_with = "state")]
+    resume: Option<String>,
+    /// Keeps reading the last file as rows are appended to it, like `tail -f`, writing the accounts every
+    /// --snapshot-every seconds. Ctrl-c stops following and finishes the run.
+    #[arg(long, conflicts_with_all = ["threads", "checkpoint", "resume", "mmap", "io_uring"])]
+    follow: bool,
+    /// Seconds between writing the accounts while following.
+    #[arg(
+        long,
+        default_value_t = 60,
+        value_name = "SECONDS",
+        requires = "follow"
+    )]
+    snapshot_every: u64,
+    /// Loads accounts and transactions saved with --save-state before processing.
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["state", "resume"])]
+    load_state: Option<String>,
+    /// Saves accounts and transactions to this file after processing, for --load-state.
+    #[arg(long, value_name = "FILE")]
+    save_state: Option<String>,
+    #[command(flatten)]
+    config: ConfigArgs,
+    /// Sorts the output by client, currency, available, held, total or locked.
+    #[arg(long, value_name = "COLUMN")]
+    sort: Option<SortBy>,
+    /// Writes each client's accounts as soon as their rows end, for input grouped by client, rather than
+    /// keeping every account until the end. Later rows for a finished client are rejected.
+    #[arg(long, conflicts_with_all = [
+        "sort", "threads", "pipeline", "checkpoint", "resume", "follow", "state", "save_state", "held_funds",
+        "summary", "dry_run", "reconcile",
+    ])]
+    stream: bool,
+    /// Serves Prometheus metrics at http://ADDR/metrics while processing, e.g. 127.0.0.1:9000.
+    #[arg(long, value_name = "ADDR")]
+    metrics_addr: Option<String>,
+    /// Appends a line of json to this file for every transaction applied.
+    #[arg(long, value_name = "FILE")]
+    journal: Option<String>,
+    /// Write-ahead log, transactions are synced to it before they're applied and replayed from it after a crash.
+    #[arg(long, value_name = "FILE", requires = "state")]
+    wal: Option<String>,
+    /// Writes the transactions that took a balance below zero, with the balance they left, to this csv.
+    #[arg(long, value_name = "FILE")]
+    negative_balances: Option<PathBuf>,
+    /// Writes the disputes that haven't been resolved or charged back, with how long their funds have been held
+    /// by the last row's `timestamp`, to this csv.
+    #[arg(long, value_name = "FILE")]
+    held_funds: Option<PathBuf>,
+    /// Writes a json summary of the run to this file, or to stderr if no file is given.
+    #[arg(long, value_name = "FILE", num_args = 0..=1, default_missing_value = "-")]
+    summary: Option<String>,
+    /// Checks clients' balances against the deposits, withdrawals, chargebacks etc. applied once everything's
+    /// been written, failing the run with a breakdown of each currency that doesn't balance.
+    #[arg(long)]
+    reconcile: bool,
+    /// Posts this many days of interest at the end of the run, with --interest-rate.
+    #[arg(long, value_name = "DAYS", requires = "interest_rate")]
+    accrue_days: Option<u64>,
+    /// Starts the clock at this time, seconds since the unix epoch, so rows with an earlier `effective` time
+    /// are applied. Row timestamps move it on from there.
+    #[arg(long, value_name = "TIMESTAMP")]
+    as_of: Option<u64>,
+    /// Writes the transactions that haven't taken effect yet to this csv.
+    #[arg(long, value_name = "FILE")]
+    pending: Option<PathBuf>,
+    /// Writes the accounts to this file rather than stdout. It's replaced in one go once the run has finished,
+    /// so it's never left half written.
+    #[arg(short, long, value_name = "FILE")]
+    output: Option<PathBuf>,
+    /// Compresses the accounts and the journal: none or zstd (with the zstd feature).
+    #[arg(long, default_value = "none")]
+    compress: Compression,
+    /// Writes the transactions that broke a rule to this csv.
+    #[arg(long, value_name = "FILE")]
+    suspicious_activity: Option<PathBuf>,
+    /// Stops with an error at the first row that can't be read as a transaction, rather than rejecting it.
+    #[arg(long)]
+    strict: bool,
+    /// What fails the run, once everything's been written: io for I/O errors only, any-rejected for any
+    /// rejected row, or a percentage, e.g. 5%, for more than that many of the rows being rejected. Runs
+    /// failed for rejected rows exit with 3, I/O errors always exit with 1.
+    #[arg(long, default_value = "io", value_name = "POLICY", value_parser = fail_on)]
+    fail_on: FailOn,
+    /// Processes the files and writes the rejects, summary, suspicious activity, negative balances and held funds,
+    /// but not the accounts, pending transactions or state, to vet a file before ingesting it.
+    #[arg(long, conflicts_with_all = ["checkpoint", "wal", "journal"])]
+    dry_run: bool,
+    /// Scores transactions out of 100 from the client's chargebacks, disputes and velocity.
+    #[arg(long)]
+    risk_scoring: bool,
+    /// Risk score transactions are flagged at.
+    #[arg(
+        long,
+        default_value_t = 50,
+        value_name = "SCORE",
+        requires = "risk_scoring"
+    )]
+    risk_flag_at: u32,
+    /// Risk score transactions are rejected at.
+    #[arg(
+        long,
+        default_value_t = 80,
+        value_name = "SCORE",
+        requires = "risk_scoring"
+    )]
+    risk_reject_at: u32,
+}
+
+#[derive(clap::Args)]
+struct ReplayArgs {
+    /// Journal written by `process --journal`.
+    #[arg(value_name = "FILE")]
+    journal: String,
+    /// Stops after the first transaction with this tx.
+    #[arg(long, value_name = "TX")]
+    until: Option<TxId>,
+    #[command(flatten)]
+    config: ConfigArgs,
+    /// Sorts the output by client, currency, available, held, total or locked.
+    #[arg(long, value_name = "COLUMN")]
+    sort: Option<SortBy>,
+}
+
+#[derive(clap::Args)]
+struct StatementArgs {
+    /// Journal written by `process --journal`.
+    #[arg(value_name = "FILE")]
+    journal: String,
+    #[arg(long)]
+    client: ClientId,
+    /// Where to write the statement, stdout if not given.
+    #[arg(long, value_name = "FILE")]
+    output: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct GenerateArgs {
+    #[arg(long, default_value_t = 1000)]
+    rows: u64,
+    #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u16).range(1..))]
+    clients: u16,
+    /// Chance of each row disputing a recent deposit, and of it closing an open dispute.
+    #[arg(long, default_value_t = 0.01)]
+    dispute_rate: f64,
+    /// The same seed always gives the same rows.
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+}
+
+#[derive(clap::Args)]
+struct MergeArgs {
+    /// States saved with `process --save-state`, with no client in more than one.
+    #[arg(required = true, value_name = "FILES")]
+    states: Vec<PathBuf>,
+    /// Writes the accounts to this file rather than stdout, replaced in one go.
+    #[arg(short, long, value_name = "FILE")]
+    output: Option<PathBuf>,
+    /// Compresses the accounts: none or zstd (with the zstd feature).
+    #[arg(long, default_value = "none")]
+    compress: Compression,
+    /// Sorts the output by client, currency, available, held, total or locked.
+    #[arg(long, value_name = "COLUMN")]
+    sort: Option<SortBy>,
+}
+
+#[derive(clap::Args)]
+struct DiffArgs {
+    /// Accounts written by `process`, e.g. before a day's file.
+    #[arg(value_name = "OLD")]
+    old: PathBuf,
+    /// Accounts written by `process` afterwards.
+    #[arg(value_name = "NEW")]
+    new: PathBuf,
+    /// Where to write the changes, stdout if not given.
+    #[arg(long, value_name = "FILE")]
+    output: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+#[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+struct QueryArgs {
+    /// SQLite database written by `process --state` (with the sqlite feature).
+    #[arg(long, value_name = "FILE")]
+    state: PathBuf,
+    #[arg(long)]
+    client: ClientId,
+}
+
+#[derive(clap::Args)]
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+struct ServeArgs {
+    /// Address to listen on.
+    #[arg(long, default_value = "127.0.0.1:8080", value_name = "ADDR")]
+    listen: SocketAddr,
+    /// Also serves the gRPC service in proto/payments.proto on this address, with the grpc feature.
+    #[arg(long, value_name = "ADDR")]
+    grpc_listen: Option<SocketAddr>,
+    #[command(flatten)]
+    config: ConfigArgs,
+    /// Appends a line of json to this file for every transaction applied.
+    #[arg(long, value_name = "FILE")]
+    journal: Option<String>,
+    /// Serves Prometheus metrics at http://ADDR/metrics.
+    #[arg(long, value_name = "ADDR")]
+    metrics_addr: Option<String>,
+    #[command(flatten)]
+    webhooks: WebhookArgs,
+    /// Appends transactions that fail, and rows that can't be read, to this jsonl file with why, so they can
+    /// be repaired and replayed.
+    #[arg(long, value_name = "FILE")]
+    dead_letter: Option<PathBuf>,
+    /// Applies at most this many transactions a second, across every connection, so a replay doesn't overwhelm
+    /// a shared database downstream.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    max_tps: Option<u32>,
+    /// Applies transactions on this many threads, each owning the accounts of the clients sharded to it, so
+    /// requests for unrelated clients don't wait on each other.
+    #[arg(
+        long,
+        default_value_t = 1,
+        value_name = "N",
+        conflicts_with = "grpc_listen"
+    )]
+    shards: usize,
+    /// Shows a dashboard of throughput, the accounts with the most funds held, recent chargebacks and rejects
+    /// on stderr rather than logging (with the tui feature).
+    #[arg(long)]
+    tui: bool,
+}
+
+/// How transactions are applied, the same for every subcommand.
+#[derive(clap::Args)]
+struct ConfigArgs {
+    /// Fee schedule csv with headers `type,rate,flat`.
+    #[arg(long, value_name = "FILE")]
+    fees: Option<String>,
+    /// Currency of rows without one.
+    #[arg(long, default_value = "USD", value_name = "CURRENCY")]
+    base_currency: Currency,
+    /// Exchange rates csv with headers `from,to,rate`.
+    #[arg(long, value_name = "FILE")]
+    rates: Option<String>,
+    /// How far below zero withdrawals, transfers and conversions can take available funds.
+    #[arg(long, default_value = "0", value_name = "AMOUNT")]
+    credit_limit: Decimal,
+    /// Per client credit limits csv with headers `client,limit`.
+    #[arg(long, value_name = "FILE")]
+    credit_limits: Option<String>,
+    /// Per client limits csv with headers `client,max_withdrawal,max_balance,daily_out_limit`.
+    #[arg(long, value_name = "FILE")]
+    limits: Option<String>,
+    /// What to do when a dispute is for more than the available funds: allow-negative, clamp or reject.
+    #[arg(long, default_value = "allow-negative", value_name = "POLICY")]
+    dispute_policy: DisputePolicy,
+    /// What to do with transactions on a locked account: allow, reject-debits or reject.
+    #[arg(long, default_value = "allow", value_name = "POLICY")]
+    locked_policy: LockedPolicy,
+    /// Unlocks accounts when a chargeback is reversed, rather than waiting for an unlock row.
+    #[arg(long)]
+    unlock_on_chargeback_reversal: bool,
+    /// Lets resolved deposits be disputed again.
+    #[arg(long)]
+    redispute_resolved: bool,
+    /// Rejects disputes of deposits more than this many days old, going by the timestamp column.
+    #[arg(long, value_name = "DAYS")]
+    dispute_window: Option<u64>,
+    /// Annual interest rate on available funds, e.g. 0.05, accrued daily going by the timestamp column.
+    #[arg(long, value_name = "RATE")]
+    interest_rate: Option<Decimal>,
+    /// How a year's interest is divided into days: act/365 or act/360.
+    #[arg(long, default_value = "act/365", value_name = "CONVENTION")]
+    day_count: DayCount,
+    /// Flags a client's deposits after this many in a day.
+    #[arg(long, value_name = "COUNT")]
+    max_deposits_per_day: Option<u32>,
+    /// Flags withdrawals of more than this.
+    #[arg(long, value_name = "AMOUNT")]
+    max_withdrawal: Option<Decimal>,
+    /// Flags deposits that take a client's total deposits in a currency over this.
+    #[arg(long, value_name = "AMOUNT")]
+    max_total_in: Option<Decimal>,
+    /// Flags withdrawals that take a client's total withdrawals in a currency over this.
+    #[arg(long, value_name = "AMOUNT")]
+    max_total_out: Option<Decimal>,
+    /// Decimal places balances are written with, amounts with more are rejected. 4 if not given, and
+    /// amounts can have any.
+    #[arg(long, value_name = "N")]
+    precision: Option<u32>,
+    /// What to do with transactions that break a rule: flag or reject.
+    #[arg(long, default_value = "flag", value_name = "ACTION")]
+    rule_action: RuleAction,
+}
+
+impl ConfigArgs {
+    fn config(&self) -> Result<Config, std::io::Error> {
+        let mut config = Config {
+            fees: match &self.fees {
+                Some(fees) => FeeSchedule::from_path(fees)?,
+                None => FeeSchedule::default(),
+            },
+            base_currency: self.base_currency,
+            rates: match &self.rates {
+                Some(rates) => ExchangeRates::from_path(rates)?,
+                None => ExchangeRates::default(),
+            },
+            credit_limits: match &self.credit_limits {
+                Some(credit_limits) => CreditLimits::from_path(credit_limits)?,
+                None => CreditLimits::default(),
+            },
+            limits: match &self.limits {
+                Some(limits) => ClientLimits::from_path(limits)?,
+                None => ClientLimits::default(),
+            },
+            dispute_policy: self.dispute_policy,
+            locked_policy: self.locked_policy,
+            unlock_on_chargeback_reversal: self.unlock_on_chargeback_reversal,
+            redispute_resolved: self.redispute_resolved,
+            dispute_window: self
+                .dispute_window
+                .map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+            interest: self.interest_rate.map(|rate| Interest {
+                rate,
+                day_count: self.day_count,
+            }),
+            rules: Rules {
+                max_deposits_per_day: self.max_deposits_per_day,
+                max_withdrawal: self.max_withdrawal,
+                max_total_in: self.max_total_in,
+                max_total_out: self.max_total_out,
+                action: self.rule_action,
+            },
+            precision: self.precision,
+        };
+        config.credit_limits.default = self.credit_limit;
+
+        Ok(config)
+    }
+}
+
+/// How csv input is laid out.
+#[derive(clap::Args)]
+struct CsvArgs {
+    /// Character between the fields of csv input, e.g. `;`, or `tab`.
+    #[arg(long, default_value = ",", value_parser = delimiter)]
+    delimiter: u8,
+    /// Header names to read columns from for files that name them differently, e.g.
+    /// `type=txn_type,client=customer_id`.
+    #[arg(long, value_name = "COLUMN=HEADER", value_delimiter = ',', value_parser = column)]
+    columns: Vec<(String, String)>,
+    /// Decimal separator of amounts: `.` or `,`, with points between thousands ignored, e.g. `1.234,56`.
+    #[arg(long, default_value = ".", value_parser = [".", ","])]
+    decimal_separator: String,
+}
+
+impl CsvArgs {
+    fn options(&self) -> CsvOptions {
+        CsvOptions {
+            delimiter: self.delimiter,
+            columns: self.columns.iter().cloned().collect(),
+            decimal_comma: self.decimal_separator == ",",
+            fast: false,
+        }
+    }
+}
+
+/// Alerts for the long running modes.
+#[derive(clap::Args)]
+struct WebhookArgs {
+    /// Posts json to this http:// URL on chargebacks, locks and large withdrawals. Can be given more than once.
+    #[arg(long, value_name = "URL")]
+    webhook: Vec<WebhookUrl>,
+    /// Events posted to --webhook: chargeback, locked and large-withdrawal.
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "chargeback,locked,large-withdrawal",
+        value_name = "EVENTS"
+    )]
+    webhook_events: Vec<WebhookEvent>,
+    /// Withdrawals of this much or more, in any currency, are posted to --webhook as large withdrawals.
+    #[arg(long, value_name = "AMOUNT", requires = "webhook")]
+    large_withdrawal: Option<Decimal>,
+    #[command(flatten)]
+    retry: RetryArgs,
+}
+
+impl WebhookArgs {
+    fn record(self, transaction_processor: &mut TransactionProcessor) {
+        if self.webhook.is_empty() {
+            return;
+        }
+
+        let mut webhooks = Webhooks::new(self.webhook, self.webhook_events);
+
+        if let Some(amount) = self.large_withdrawal {
+            webhooks.set_large_withdrawal(amount);
+        }
+
+        webhooks.set_retry(self.retry.retry());
+
+        webhooks.record(transaction_processor);
+    }
+}
+
+/// Retrying transient failures of --state and --webhook, like a timeout or a busy database.
+#[derive(clap::Args)]
+struct RetryArgs {
+    /// Times a transient failure is retried before giving up.
+    #[arg(long, default_value_t = 3, value_name = "N")]
+    retries: u32,
+    /// Milliseconds before the first retry, doubling after each one. Each wait is randomly shortened by up to
+    /// half so clients don't all retry at once.
+    #[arg(long, default_value_t = 100, value_name = "MS")]
+    retry_delay: u64,
+}
+
+impl RetryArgs {
+    fn retry(&self) -> Retry {
+        Retry::new(self.retries, Duration::from_millis(self.retry_delay))
+    }
+}
+
+/// One ascii character, or `tab`. Not a quote or line break, as no row could be read.
+fn delimiter(s: &str) -> Result<u8, String> {
+    match s.as_bytes() {
+        b"tab" | b"\\t" => Ok(b'\t'),
+        [byte] if !matches!(byte, b'"' | b'\n' | b'\r') => Ok(*byte),
+        _ => Err(format!("Invalid delimiter {s}")),
+    }
+}
+
+/// Bytes, or KiB, MiB or GiB with a `K`, `M` or `G`, e.g. `8M`.
+fn size(s: &str) -> Result<usize, String> {
+    let (number, multiplier) = match s.as_bytes().last() {
+        Some(b'K' | b'k') => (&s[..s.len() - 1], 1 << 10),
+        Some(b'M' | b'm') => (&s[..s.len() - 1], 1 << 20),
+        Some(b'G' | b'g') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+
+    number
+        .parse::<usize>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .filter(|&size| size > 0)
+        .ok_or_else(|| format!("Invalid size {s}"))
+}
+
+fn column(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((column, header)) if !column.trim().is_empty() && !header.trim().is_empty() => {
+            Ok((column.trim().to_owned(), header.trim().to_owned()))
+        }
+        _ => Err(format!(
+            "Invalid column mapping {s}, expected COLUMN=HEADER"
+        )),
+    }
+}
+
+/// What fails `process` on top of I/O errors.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FailOn {
+    Io,
+    AnyRejected,
+    /// More than this percentage of the rows rejected.
+    RejectedPercent(Decimal),
+}
+
+impl FailOn {
+    fn failed(self, rejected: u64, rows: u64) -> bool {
+        match self {
+            FailOn::Io => false,
+            FailOn::AnyRejected => rejected > 0,
+            FailOn::RejectedPercent(percent) => {
+                Decimal::from(rejected) * Decimal::ONE_HUNDRED > percent * Decimal::from(rows)
+            }
+        }
+    }
+}
+
+fn fail_on(s: &str) -> Result<FailOn, String> {
+    match s {
+        "io" => Ok(FailOn::Io),
+        "any-rejected" => Ok(FailOn::AnyRejected),
+        _ => match s.strip_suffix('%').map(Decimal::from_str) {
+            Some(Ok(percent)) if percent >= Decimal::ZERO && percent <= Decimal::ONE_HUNDRED => {
+                Ok(FailOn::RejectedPercent(percent))
+            }
+            _ => Err(format!(
+                "Invalid policy {s}, expected io, any-rejected or a percentage"
+            )),
+        },
+    }
+}
+
+/// Exit code of a run failed by --fail-on.
+const REJECTED_EXIT_CODE: u8 = 3;
+
+/// Exit code of a run failed by --reconcile.
+const UNBALANCED_EXIT_CODE: u8 = 4;
+
+/// Bytes of the accounts buffered before they're written, for commands without --write-buffer.
+const WRITE_BUFFER: usize = 64 * 1024;
+
+/// How often --follow checks for rows appended to the file.
+const FOLLOW_POLL: Duration = Duration::from_millis(500);
+
+/// Exit code of a run stopped by ctrl-c or SIGTERM, the same as a shell's for ctrl-c.
+const INTERRUPTED_EXIT_CODE: u8 = 130;
+
+#[derive(clap::Args)]
+struct WatchArgs {
+    /// Directory files are dropped into, they're moved to processed/ or failed/ inside it once they're read.
+    #[arg(value_name = "DIR")]
+    dir: PathBuf,
+    /// Format of the files: csv or jsonl.
+    #[arg(long, default_value = "csv")]
+    format: InputFormat,
+    #[command(flatten)]
+    csv: CsvArgs,
+    #[command(flatten)]
+    config: ConfigArgs,
+    /// Seconds between checking for new files.
+    #[arg(long, default_value_t = 5, value_name = "SECONDS")]
+    interval: u64,
+    /// Appends rejected rows, with the file they came from, to this csv.
+    #[arg(long, value_name = "FILE")]
+    rejects: Option<String>,
+    /// Appends transactions that fail, and rows that can't be read, to this jsonl file with why, so they can
+    /// be repaired and replayed.
+    #[arg(long, value_name = "FILE")]
+    dead_letter: Option<PathBuf>,
+    /// Writes the accounts to this file rather than stdout after every file.
+    #[arg(short, long, value_name = "FILE")]
+    output: Option<PathBuf>,
+    /// Loads accounts and transactions from this file on startup, if it exists, and saves them to it after
+    /// every file.
+    #[arg(long, value_name = "FILE")]
+    save_state: Option<PathBuf>,
+    /// Shows a dashboard of throughput, the accounts with the most funds held, recent chargebacks and rejects
+    /// on stderr rather than logging (with the tui feature).
+    #[arg(long)]
+    tui: bool,
+}
+
+#[derive(clap::Args)]
+struct IngestArgs {
+    /// Address to listen on.
+    #[arg(long, default_value = "127.0.0.1:9100", value_name = "ADDR")]
+    listen: SocketAddr,
+    /// Format of each connection: csv, starting with a header row, or jsonl.
+    #[arg(long, default_value = "csv")]
+    format: InputFormat,
+    #[command(flatten)]
+    csv: CsvArgs,
+    #[command(flatten)]
+    config: ConfigArgs,
+    /// Writes rejected rows, with the address they came from, to this csv.
+    #[arg(long, value_name = "FILE")]
+    rejects: Option<String>,
+    /// Appends transactions that fail, and rows that can't be read, to this jsonl file with why, so they can
+    /// be repaired and replayed.
+    #[arg(long, value_name = "FILE")]
+    dead_letter: Option<PathBuf>,
+    /// Appends a line of json to this file for every transaction applied.
+    #[arg(long, value_name = "FILE")]
+    journal: Option<String>,
+    /// Serves Prometheus metrics at http://ADDR/metrics.
+    #[arg(long, value_name = "ADDR")]
+    metrics_addr: Option<String>,
+    /// Writes the accounts to this csv every --snapshot-every seconds.
+    #[arg(long, value_name = "FILE")]
+    snapshot: Option<String>,
+    /// Seconds between snapshots, and flushing the journal and rejects.
+    #[arg(long, default_value_t = 60, value_name = "SECONDS")]
+    snapshot_every: u64,
+    /// Applies at most this many transactions a second, across every connection, so a replay doesn't overwhelm
+    /// a shared database downstream.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    max_tps: Option<u32>,
+    /// Applies transactions on this many threads, each owning the accounts of the clients sharded to it, so
+    /// connections for unrelated clients don't wait on each other.
+    #[arg(long, default_value_t = 1, value_name = "N")]
+    shards: usize,
+    #[command(flatten)]
+    webhooks: WebhookArgs,
+    /// Shows a dashboard of throughput, the accounts with the most funds held, recent chargebacks and rejects
+    /// on stderr rather than logging (with the tui feature).
+    #[arg(long)]
+    tui: bool,
+}
+
+/// How often the accounts with the most funds held are recalculated for --tui.
+#[cfg(feature = "tui")]
+const DASHBOARD_REFRESH: Duration = Duration::from_secs(1);
+
+/// How often the server updates the metrics gauges and flushes the journal.
+#[cfg(feature = "server")]
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(5);
+
+type SharedRejects = Arc<Mutex<RejectsWriter<File>>>;
+
+/// Where a long running mode's accounts are kept.
+#[derive(Clone)]
+enum Running {
+    Locked(SharedProcessor),
+    /// Spread across threads with `ingest --shards`.
+    Actors(Arc<ClientActors>),
+}
+
+/// Periodic work for the long running modes.
+struct Housekeeping {
+    transaction_processor: Running,
+    journal: Option<Arc<Journal>>,
+    metrics: Option<Arc<Metrics>>,
+    rejects: Option<SharedRejects>,
+    /// Written atomically, by writing a temporary file and renaming it.
+    snapshot: Option<String>,
+}
+
+impl Housekeeping {
+    fn spawn(self, interval: Duration) {
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+
+            if let Err(error) = self.run() {
+                tracing::error!(%error, "Housekeeping failed");
+            }
+        });
+    }
+
+    fn run(&self) -> Result<(), std::io::Error> {
+        if let Some(journal) = &self.journal {
+            journal.flush()?;
+        }
+
+        if let Some(rejects) = &self.rejects {
+            rejects
+                .lock()
+                .unwrap_or_else(|poison| poison.into_inner())
+                .flush()?;
+        }
+
+        match &self.transaction_processor {
+            Running::Locked(transaction_processor) => {
+                let transaction_processor = transaction_processor
+                    .lock()
+                    .unwrap_or_else(|poison| poison.into_inner());
+
+                if let Some(metrics) = &self.metrics {
+                    metrics.update(transaction_processor.accounts());
+                }
+
+                if let Some(snapshot) = &self.snapshot {
+                    write_atomically(Path::new(snapshot), |writer| {
+                        Ok(transaction_processor.write_accounts(writer, Some(SortBy::Client))?)
+                    })?;
+                }
+            }
+            Running::Actors(actors) => {
+                if let Some(metrics) = &self.metrics {
+                    metrics.update(&actors.accounts());
+                }
+
+                if let Some(snapshot) = &self.snapshot {
+                    write_atomically(Path::new(snapshot), |writer| {
+                        Ok(actors.write_accounts(writer, Some(SortBy::Client))?)
+                    })?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+type Rejects = RefCell<Option<RejectsWriter<File>>>;
+
+fn main() -> Result<ExitCode, std::io::Error> {
+    let cli = Cli::parse();
+
+    if cli.command.tui() {
+        logging(0, u8::MAX);
+    } else {
+        logging(cli.verbose, cli.quiet);
+    }
+
+    match cli.command {
+        Command::Process(args) => return process(*args),
+        Command::Replay(args) => replay(args),
+        Command::Serve(args) => serve(args),
+        Command::Ingest(args) => ingest(args),
+        Command::Watch(args) => return watch(args),
+        Command::Statement(args) => statement(args),
+        Command::Generate(args) => generate(args),
+        Command::Merge(args) => merge(args),
+        Command::Diff(args) => diff(args),
+        Command::Query(args) => query(args),
+    }?;
+
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Runs until it's killed, the snapshot, journal and rejects are written every --snapshot-every seconds.
+fn ingest(args: IngestArgs) -> Result<(), std::io::Error> {
+    #[cfg(not(feature = "tui"))]
+    if args.tui {
+        return Err(invalid("--tui requires building with the tui feature"));
+    }
 
-fn main() -> Result<(), std::io::Error> {
-    let filename = filename()?;
     let mut transaction_processor = TransactionProcessor::new();
-    transaction_processor.process_transactions(filename)?;
-    transaction_processor.print_accounts()?;
+    transaction_processor.set_config(args.config.config()?);
+    transaction_processor.set_csv_options(args.csv.options());
+    let journal = journal(
+        args.journal.as_deref(),
+        Compression::None,
+        &mut transaction_processor,
+    )?;
+    let metrics = metrics(args.metrics_addr.as_deref(), &mut transaction_processor)?;
+    let dead_letters = dead_letters(args.dead_letter.as_deref(), &mut transaction_processor)?;
+    #[cfg(feature = "tui")]
+    let dashboard = dashboard(args.tui, &mut transaction_processor);
+    args.webhooks.record(&mut transaction_processor);
+
+    if let Some(max_tps) = args.max_tps {
+        transaction_processor.set_throttle(Throttle::new(max_tps));
+    }
+
+    let running = match args.shards {
+        0 | 1 => Running::Locked(Arc::new(Mutex::new(transaction_processor))),
+        shards => Running::Actors(Arc::new(ClientActors::spawn(
+            &mut transaction_processor,
+            shards,
+        ))),
+    };
+    let rejects = args
+        .rejects
+        .as_deref()
+        .map(RejectsWriter::from_path)
+        .transpose()?
+        .map(|rejects| Arc::new(Mutex::new(rejects)));
+
+    Housekeeping {
+        transaction_processor: running.clone(),
+        journal,
+        metrics: metrics.clone(),
+        rejects: rejects.clone(),
+        snapshot: args.snapshot,
+    }
+    .spawn(Duration::from_secs(args.snapshot_every));
+
+    // Nothing returns from here, so ctrl-c gives the terminal back before exiting
+    #[cfg(feature = "tui")]
+    if let Some(dashboard) = &dashboard {
+        let shown = Mutex::new(Some(show_dashboard(dashboard, running.clone())?));
+
+        ctrlc::set_handler(move || {
+            if let Some(shown) = shown
+                .lock()
+                .unwrap_or_else(|poison| poison.into_inner())
+                .take()
+            {
+                shown.stop();
+            }
+
+            std::process::exit(INTERRUPTED_EXIT_CODE.into());
+        })
+        .map_err(std::io::Error::other)?;
+    }
+
+    let listener = TcpListener::bind(args.listen)?;
+    tracing::info!(listen = %args.listen, "Listening");
+
+    let on_reject = move |peer: SocketAddr, rejection: Rejection| -> Result<(), std::io::Error> {
+        tracing::info!(
+            %peer,
+            line = rejection.line,
+            row = %rejection.row,
+            reason = %rejection.reason,
+            "rejected row"
+        );
+
+        if let Some(metrics) = &metrics {
+            metrics.rejected();
+        }
+
+        if let Some(dead_letters) = &dead_letters {
+            dead_letters.rejected_row(&rejection);
+        }
+
+        #[cfg(feature = "tui")]
+        if let Some(dashboard) = &dashboard {
+            dashboard.rejected_row(&rejection);
+        }
+
+        match &rejects {
+            Some(rejects) => Ok(rejects
+                .lock()
+                .unwrap_or_else(|poison| poison.into_inner())
+                .write(&peer.to_string(), &rejection)?),
+            None => Ok(()),
+        }
+    };
+
+    match running {
+        Running::Locked(transaction_processor) => {
+            ingest_tcp(listener, transaction_processor, args.format, on_reject)
+        }
+        Running::Actors(actors) => ingest_tcp_sharded(listener, actors, args.format, on_reject),
+    }
+}
+
+/// Runs until it's interrupted. A file is read once it's stopped growing between checks, so one that's still
+/// being uploaded isn't read half written. The state is saved and the accounts written before a file's moved.
+fn watch(args: WatchArgs) -> Result<ExitCode, std::io::Error> {
+    #[cfg(not(feature = "tui"))]
+    if args.tui {
+        return Err(invalid("--tui requires building with the tui feature"));
+    }
+
+    let config = args.config.config()?;
+    let interrupt = on_interrupt()?;
+    let dead_letters = args
+        .dead_letter
+        .as_deref()
+        .map(DeadLetters::open)
+        .transpose()?
+        .map(Arc::new);
+    #[cfg(feature = "tui")]
+    let dashboard = args.tui.then(|| Arc::new(Dashboard::new()));
+    let load = || -> Result<TransactionProcessor, std::io::Error> {
+        let mut transaction_processor = match &args.save_state {
+            Some(path) if path.exists() => TransactionProcessor::load(path)?,
+            _ => TransactionProcessor::new(),
+        };
+        transaction_processor.set_config(config.clone());
+        transaction_processor.set_csv_options(args.csv.options());
+        transaction_processor.set_interrupt(interrupt.clone());
+
+        if let Some(dead_letters) = &dead_letters {
+            dead_letters.record(&mut transaction_processor);
+        }
+
+        #[cfg(feature = "tui")]
+        if let Some(dashboard) = &dashboard {
+            dashboard.record(&mut transaction_processor);
+            dashboard.update(transaction_processor.accounts());
+        }
+
+        Ok(transaction_processor)
+    };
+
+    let mut transaction_processor = load()?;
+    #[cfg(feature = "tui")]
+    let _shown = dashboard
+        .as_ref()
+        .map(|dashboard| dashboard.spawn())
+        .transpose()?;
+    let mut rejects = args
+        .rejects
+        .as_deref()
+        .map(RejectsWriter::append)
+        .transpose()?;
+    let processed = args.dir.join("processed");
+    let failed = args.dir.join("failed");
+    std::fs::create_dir_all(&processed)?;
+    std::fs::create_dir_all(&failed)?;
+
+    // Sizes of the files found by the last check
+    let mut sizes = HashMap::new();
+
+    tracing::info!(dir = %args.dir.display(), "Watching");
+
+    while !interrupt.load(Ordering::SeqCst) {
+        let mut files = Vec::new();
+
+        for entry in std::fs::read_dir(&args.dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+
+            if metadata.is_file() && !entry.file_name().to_string_lossy().starts_with('.') {
+                files.push((entry.path(), metadata.len()));
+            }
+        }
+
+        files.sort();
+        let settled: Vec<PathBuf> = files
+            .iter()
+            .filter(|(path, size)| sizes.get(path) == Some(size))
+            .map(|(path, _)| path.clone())
+            .collect();
+        sizes = files.into_iter().collect();
+
+        for path in settled {
+            let file = path.display().to_string();
+            let _span = tracing::info_span!("file", file = %file).entered();
+            tracing::info!("processing");
+
+            let result = input(&path).and_then(|input| {
+                transaction_processor.process_reader(input, args.format, |rejection| {
+                    tracing::info!(
+                        line = rejection.line,
+                        row = %rejection.row,
+                        reason = %rejection.reason,
+                        "rejected row"
+                    );
+
+                    if let Some(dead_letters) = &dead_letters {
+                        dead_letters.rejected_row(&rejection);
+                    }
+
+                    #[cfg(feature = "tui")]
+                    if let Some(dashboard) = &dashboard {
+                        dashboard.rejected_row(&rejection);
+                    }
+
+                    match &mut rejects {
+                        Some(rejects) => Ok(rejects.write(&file, &rejection)?),
+                        None => Ok(()),
+                    }
+                })
+            });
+
+            if let Some(rejects) = &mut rejects {
+                rejects.flush()?;
+            }
+
+            let moved_to = match result {
+                // Left where it is to be processed again, the state from before it is loaded next time
+                Err(error) if error.kind() == ErrorKind::Interrupted => {
+                    tracing::warn!("Interrupted, the file will be processed again");
+                    return Ok(ExitCode::from(INTERRUPTED_EXIT_CODE));
+                }
+                Ok(()) => {
+                    if let Some(path) = &args.save_state {
+                        transaction_processor.save(path)?;
+                    }
+
+                    #[cfg(feature = "tui")]
+                    if let Some(dashboard) = &dashboard {
+                        dashboard.update(transaction_processor.accounts());
+                    }
+
+                    write_output(
+                        args.output.as_deref(),
+                        Compression::None,
+                        Some(SortBy::Client),
+                        WRITE_BUFFER,
+                        &transaction_processor,
+                    )?;
+                    &processed
+                }
+                Err(error) => {
+                    tracing::error!(%error, "Failed");
+
+                    // Without a saved state the rows before the failure stay applied
+                    if args.save_state.is_some() {
+                        transaction_processor = load()?;
+                    }
+
+                    &failed
+                }
+            };
+
+            if let Some(name) = path.file_name() {
+                std::fs::rename(&path, moved_to.join(name))?;
+            }
+
+            sizes.remove(&path);
+        }
+
+        std::thread::sleep(Duration::from_secs(args.interval));
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(not(feature = "server"))]
+fn serve(_: ServeArgs) -> Result<(), std::io::Error> {
+    Err(invalid("serve requires building with the server feature"))
+}
+
+/// Accounts are kept in memory, `--journal` keeps a record that `replay` can rebuild them from.
+#[cfg(feature = "server")]
+fn serve(args: ServeArgs) -> Result<(), std::io::Error> {
+    #[cfg(not(feature = "tui"))]
+    if args.tui {
+        return Err(invalid("--tui requires building with the tui feature"));
+    }
+
+    #[cfg(not(feature = "grpc"))]
+    if args.grpc_listen.is_some() {
+        return Err(invalid(
+            "--grpc-listen requires building with the grpc feature",
+        ));
+    }
+
+    let mut transaction_processor = TransactionProcessor::new();
+    transaction_processor.set_config(args.config.config()?);
+    let journal = journal(
+        args.journal.as_deref(),
+        Compression::None,
+        &mut transaction_processor,
+    )?;
+    let metrics = metrics(args.metrics_addr.as_deref(), &mut transaction_processor)?;
+    dead_letters(args.dead_letter.as_deref(), &mut transaction_processor)?;
+    #[cfg(feature = "tui")]
+    let dashboard = dashboard(args.tui, &mut transaction_processor);
+    args.webhooks.record(&mut transaction_processor);
+
+    if let Some(max_tps) = args.max_tps {
+        transaction_processor.set_throttle(Throttle::new(max_tps));
+    }
+
+    let (router, running) = match args.shards {
+        0 | 1 => {
+            let transaction_processor = Arc::new(Mutex::new(transaction_processor));
+            let router = payments::http_router(transaction_processor.clone());
+            (router, Running::Locked(transaction_processor))
+        }
+        shards => {
+            let (router, actors) =
+                payments::http_router_sharded(&mut transaction_processor, shards);
+            (router, Running::Actors(actors))
+        }
+    };
+
+    Housekeeping {
+        transaction_processor: running.clone(),
+        journal: journal.clone(),
+        metrics,
+        rejects: None,
+        snapshot: None,
+    }
+    .spawn(HOUSEKEEPING_INTERVAL);
+
+    #[cfg(feature = "tui")]
+    let _shown = dashboard
+        .as_ref()
+        .map(|dashboard| show_dashboard(dashboard, running.clone()))
+        .transpose()?;
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+
+    runtime.block_on(async {
+        let listener = tokio::net::TcpListener::bind(args.listen).await?;
+        tracing::info!(listen = %args.listen, "Serving");
+
+        // --shards conflicts with --grpc-listen, the gRPC service needs the accounts behind one lock
+        #[cfg(feature = "grpc")]
+        if let (Some(grpc_listen), Running::Locked(transaction_processor)) =
+            (args.grpc_listen, running)
+        {
+            tracing::info!(listen = %grpc_listen, "Serving gRPC");
+
+            let grpc = payments::serve_grpc(grpc_listen, transaction_processor, shutdown());
+            let http = payments::serve_router(listener, router, shutdown());
+
+            return tokio::try_join!(async { grpc.await.map_err(std::io::Error::other) }, http)
+                .map(|_| ());
+        }
+
+        payments::serve_router(listener, router, shutdown()).await
+    })?;
+
+    match journal {
+        Some(journal) => journal.flush(),
+        None => Ok(()),
+    }
+}
+
+/// Servers stop accepting requests and finish the ones in progress on ctrl-c.
+#[cfg(feature = "server")]
+async fn shutdown() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+fn replay(args: ReplayArgs) -> Result<(), std::io::Error> {
+    let mut transaction_processor = TransactionProcessor::new();
+    transaction_processor.set_config(args.config.config()?);
+    transaction_processor.replay_journal(input(Path::new(&args.journal))?, args.until)?;
+    transaction_processor.write_accounts(std::io::stdout(), args.sort)?;
 
     Ok(())
 }
 
-fn filename() -> Result<String, std::io::Error> {
-    std::env::args()
-        .nth(1)
-        .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidData, "Missing filepath argument"))
+fn statement(args: StatementArgs) -> Result<(), std::io::Error> {
+    let journal = input(Path::new(&args.journal))?;
+
+    match &args.output {
+        Some(output) => write_statement(journal, args.client, File::create(output)?),
+        None => write_statement(journal, args.client, std::io::stdout()),
+    }
+}
+
+fn generate(args: GenerateArgs) -> Result<(), std::io::Error> {
+    if !(0.0..=1.0).contains(&args.dispute_rate) {
+        return Err(invalid("--dispute-rate must be between 0 and 1"));
+    }
+
+    let generator = Generator {
+        rows: args.rows,
+        clients: args.clients,
+        dispute_rate: args.dispute_rate,
+        seed: args.seed,
+    };
+
+    Ok(generator.write(std::io::stdout().lock())?)
+}
+
+fn diff(args: DiffArgs) -> Result<(), std::io::Error> {
+    let (old, new) = (input(&args.old)?, input(&args.new)?);
+
+    match &args.output {
+        Some(output) => write_diff(old, new, File::create(output)?),
+        None => write_diff(old, new, std::io::stdout()),
+    }
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn query(_: QueryArgs) -> Result<(), std::io::Error> {
+    Err(invalid("query requires building with the sqlite feature"))
+}
+
+/// Reads only the client's rows, so it's quick on a big database and doesn't need the run's config.
+#[cfg(feature = "sqlite")]
+fn query(args: QueryArgs) -> Result<(), std::io::Error> {
+    // Opening creates the database, which would hide a mistyped path
+    if !args.state.exists() {
+        return Err(std::io::Error::new(
+            ErrorKind::NotFound,
+            format!("No database at {}", args.state.display()),
+        ));
+    }
+
+    let state = payments::SqliteState::open(&args.state).map_err(std::io::Error::other)?;
+    let client = state
+        .client(args.client)
+        .map_err(std::io::Error::other)?
+        .ok_or_else(|| {
+            std::io::Error::new(
+                ErrorKind::NotFound,
+                format!("No client {} in {}", args.client, args.state.display()),
+            )
+        })?;
+
+    let balances: Vec<_> = client
+        .account
+        .balances
+        .iter()
+        .map(|(currency, balance)| {
+            serde_json::json!({
+                "currency": currency,
+                "available": balance.available.to_string(),
+                "held": balance.held.to_string(),
+                "total": balance.total().to_string(),
+            })
+        })
+        .collect();
+    let disputes: Vec<_> = client
+        .disputes
+        .iter()
+        .map(|(tx, record)| {
+            serde_json::json!({
+                "tx": tx,
+                "amount": record.amount.to_string(),
+                "currency": record.currency,
+                "disputed_at": record.disputed_at,
+            })
+        })
+        .collect();
+
+    let mut stdout = std::io::stdout().lock();
+    serde_json::to_writer_pretty(
+        &mut stdout,
+        &serde_json::json!({
+            "client": client.account.client,
+            "locked": client.account.locked,
+            "balances": balances,
+            "open_disputes": disputes,
+        }),
+    )?;
+    writeln!(stdout)
+}
+
+fn merge(args: MergeArgs) -> Result<(), std::io::Error> {
+    let mut transaction_processor = TransactionProcessor::new();
+
+    for state in &args.states {
+        transaction_processor
+            .merge(TransactionProcessor::load(state)?)
+            .map_err(|error| invalid(&format!("{}: {error}", state.display())))?;
+    }
+
+    write_output(
+        args.output.as_deref(),
+        args.compress,
+        args.sort,
+        WRITE_BUFFER,
+        &transaction_processor,
+    )
+}
+
+fn process(args: ProcessArgs) -> Result<ExitCode, std::io::Error> {
+    let files = files(&args.files)?;
+
+    if (args.checkpoint.is_some() || args.resume.is_some()) && args.threads > 1 {
+        return Err(invalid(
+            "--checkpoint and --resume can't be used with --threads",
+        ));
+    }
+
+    if args.wal.is_some() && args.threads > 1 {
+        return Err(invalid("--wal can't be used with --threads"));
+    }
+
+    if args.follow
+        && (args.format != InputFormat::Csv
+            || files
+                .last()
+                .is_some_and(|file| file == Path::new("-") || s3_url(file).is_some()))
+    {
+        return Err(invalid("--follow only reads csv from a local file"));
+    }
+
+    if args.max_memory.is_some()
+        && (args.threads > 1
+            || args.checkpoint.is_some()
+            || args.resume.is_some()
+            || args.state.is_some()
+            || args.load_state.is_some()
+            || args.save_state.is_some()
+            || args.held_funds.is_some())
+    {
+        return Err(invalid(
+            "--max-memory can't be used with --threads, --checkpoint, --resume, --state, --load-state, --save-state or --held-funds",
+        ));
+    }
+
+    let config = args.config.config()?;
+
+    let checkpoint = args.resume.as_deref().map(Checkpoint::load).transpose()?;
+    let rejects: Rejects = RefCell::new(match (&args.rejects, &checkpoint) {
+        (Some(rejects), Some(_)) => Some(RejectsWriter::append(rejects)?),
+        (Some(rejects), None) if args.keep_columns => {
+            Some(RejectsWriter::with_metadata(File::create(rejects)?)?)
+        }
+        (Some(rejects), None) => Some(RejectsWriter::from_path(rejects)?),
+        (None, _) => None,
+    });
+
+    #[cfg(feature = "sqlite")]
+    let state = args
+        .state
+        .as_deref()
+        .map(payments::SqliteState::open)
+        .transpose()
+        .map_err(std::io::Error::other)?;
+    #[cfg(not(feature = "mmap"))]
+    if args.mmap {
+        return Err(invalid("--mmap requires building with the mmap feature"));
+    }
+
+    #[cfg(not(feature = "io-uring"))]
+    if args.io_uring {
+        return Err(invalid(
+            "--io-uring requires building with the io-uring feature",
+        ));
+    }
+
+    #[cfg(not(feature = "sqlite"))]
+    if args.state.is_some() {
+        return Err(invalid("--state requires building with the sqlite feature"));
+    }
+
+    let (transaction_processor, resume_input, resume_rows) = match checkpoint {
+        Some(checkpoint) => (
+            checkpoint.transaction_processor,
+            checkpoint.input,
+            checkpoint.rows,
+        ),
+        None => match &args.load_state {
+            Some(path) => (TransactionProcessor::load(path)?, 0, 0),
+            #[cfg(feature = "sqlite")]
+            None => match &state {
+                Some(state) => (
+                    args.retry
+                        .retry()
+                        .run(|| state.load())
+                        .map_err(std::io::Error::other)?,
+                    0,
+                    0,
+                ),
+                None => (TransactionProcessor::new(), 0, 0),
+            },
+            #[cfg(not(feature = "sqlite"))]
+            None => (TransactionProcessor::new(), 0, 0),
+        },
+    };
+
+    match args.max_memory {
+        Some(mb) => {
+            let store = SpillStore::with_max_memory(mb.saturating_mul(1024 * 1024))
+                .map_err(std::io::Error::other)?;
+
+            process_files(
+                &args,
+                &files,
+                TransactionProcessor::with_store(store),
+                config,
+                rejects,
+                #[cfg(feature = "sqlite")]
+                state,
+                0,
+                0,
+            )
+        }
+        None => process_files(
+            &args,
+            &files,
+            transaction_processor,
+            config,
+            rejects,
+            #[cfg(feature = "sqlite")]
+            state,
+            resume_input,
+            resume_rows,
+        ),
+    }
+}
+
+/// What `process_files` needs on top of `TransactionStore`. Checkpoints, threads and saved state only work
+/// with everything in memory, they're refused with --max-memory.
+trait ProcessStore: TransactionStore + Sized {
+    fn in_memory(transaction_processor: &TransactionProcessor<Self>) -> &TransactionProcessor;
+
+    fn in_memory_mut(
+        transaction_processor: &mut TransactionProcessor<Self>,
+    ) -> &mut TransactionProcessor;
+}
+
+impl ProcessStore for HashMap<TxId, TransactionRecord> {
+    fn in_memory(transaction_processor: &TransactionProcessor<Self>) -> &TransactionProcessor {
+        transaction_processor
+    }
+
+    fn in_memory_mut(
+        transaction_processor: &mut TransactionProcessor<Self>,
+    ) -> &mut TransactionProcessor {
+        transaction_processor
+    }
+}
+
+impl ProcessStore for SpillStore {
+    fn in_memory(_: &TransactionProcessor<Self>) -> &TransactionProcessor {
+        unreachable!(
+            "--max-memory is refused with --checkpoint, --resume, --threads and saved state"
+        )
+    }
+
+    fn in_memory_mut(_: &mut TransactionProcessor<Self>) -> &mut TransactionProcessor {
+        unreachable!(
+            "--max-memory is refused with --checkpoint, --resume, --threads and saved state"
+        )
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn process_files<S: ProcessStore>(
+    args: &ProcessArgs,
+    files: &[PathBuf],
+    mut transaction_processor: TransactionProcessor<S>,
+    config: Config,
+    rejects: Rejects,
+    #[cfg(feature = "sqlite")] mut state: Option<payments::SqliteState>,
+    resume_input: usize,
+    resume_rows: u64,
+) -> Result<ExitCode, std::io::Error> {
+    transaction_processor.set_config(config);
+    transaction_processor.set_parse_threads(args.parse_threads);
+    transaction_processor.set_csv_options(CsvOptions {
+        fast: args.fast_csv,
+        ..args.csv.options()
+    });
+    transaction_processor.set_interrupt(on_interrupt()?);
+
+    if args.keep_columns {
+        transaction_processor.keep_metadata();
+    }
+
+    if args.check_invariants {
+        transaction_processor.check_invariants();
+    }
+
+    if let Some(wal) = &args.wal {
+        let (wal, entries) = Wal::open(wal)?;
+
+        if !entries.is_empty() {
+            tracing::warn!(
+                entries = entries.len(),
+                "Replaying the write-ahead log from a run that didn't finish"
+            );
+        }
+
+        transaction_processor
+            .replay_wal(&entries)
+            .map_err(std::io::Error::other)?;
+        transaction_processor.set_wal(wal);
+    }
+
+    let journal = journal(
+        args.journal.as_deref(),
+        args.compress,
+        &mut transaction_processor,
+    )?;
+    let metrics = metrics(args.metrics_addr.as_deref(), &mut transaction_processor)?;
+    let summary =
+        (args.summary.is_some() || args.fail_on != FailOn::Io).then(|| Arc::new(Summary::new()));
+    let negative_balances = args
+        .negative_balances
+        .as_ref()
+        .map(|_| Arc::new(NegativeBalances::new()));
+
+    if args.risk_scoring {
+        transaction_processor.set_risk_scorer(BasicRiskScorer {
+            flag_at: args.risk_flag_at,
+            reject_at: args.risk_reject_at,
+        });
+    }
+
+    let suspicious_activity = suspicious_activity(
+        args.suspicious_activity.as_deref(),
+        &mut transaction_processor,
+    )?;
+
+    if let Some(summary) = &summary {
+        summary.record(&mut transaction_processor);
+    }
+
+    if let Some(negative_balances) = &negative_balances {
+        negative_balances.record(&mut transaction_processor);
+    }
+
+    if let Some(as_of) = args.as_of {
+        transaction_processor
+            .advance_clock(as_of)
+            .map_err(std::io::Error::other)?;
+    }
+
+    let checkpoint = args.checkpoint.as_ref().or(args.resume.as_ref());
+    let mut stream = args.stream.then(|| account_stream(args)).transpose()?;
+
+    for (index, file) in files.iter().enumerate().skip(resume_input) {
+        #[cfg(feature = "sqlite")]
+        let fingerprint = match &state {
+            Some(state) if file != Path::new("-") && s3_url(file).is_none() => {
+                let fingerprint = fingerprint(file)?;
+
+                if state
+                    .processed(&fingerprint)
+                    .map_err(std::io::Error::other)?
+                {
+                    tracing::warn!("Skipping {}, it has already been processed", file.display());
+                    continue;
+                }
+
+                Some(fingerprint)
+            }
+            _ => None,
+        };
+
+        let input = open_input(args, file)?;
+        let file = file.display().to_string();
+        let _span = tracing::info_span!("file", file = %file).entered();
+        tracing::info!("processing");
+        let rejected = Cell::new(0u64);
+
+        let on_reject = |rejection: Rejection| -> Result<(), std::io::Error> {
+            // Malformed rows are more likely a problem with the input than with the client
+            match rejection.reason {
+                RejectReason::Malformed(_) => tracing::warn!(
+                    line = rejection.line,
+                    row = %rejection.row,
+                    reason = %rejection.reason,
+                    "malformed row"
+                ),
+                RejectReason::Transaction(_) => tracing::info!(
+                    line = rejection.line,
+                    row = %rejection.row,
+                    reason = %rejection.reason,
+                    "rejected row"
+                ),
+            }
+
+            rejected.set(rejected.get() + 1);
+
+            if let Some(metrics) = &metrics {
+                metrics.rejected();
+            }
+
+            if let Some(summary) = &summary {
+                summary.rejected_row(&rejection);
+            }
+
+            if let Some(rejects) = &mut *rejects.borrow_mut() {
+                rejects.write(&file, &rejection)?;
+            }
+
+            match &rejection.reason {
+                RejectReason::Malformed(_) if args.strict => Err(invalid(&format!(
+                    "{file}:{}: {}: {}",
+                    rejection.line, rejection.reason, rejection.row
+                ))),
+                _ => Ok(()),
+            }
+        };
+
+        let processed = if let Some(checkpoint) = checkpoint {
+            let skip = if index == resume_input {
+                resume_rows
+            } else {
+                0
+            };
+
+            let processed = transaction_processor.process_reader_checkpointed(
+                input,
+                args.format,
+                skip,
+                args.checkpoint_every,
+                on_reject,
+                |transaction_processor, rows| {
+                    flush(&rejects, journal.as_deref())?;
+                    Checkpoint::save(checkpoint, S::in_memory(transaction_processor), index, rows)
+                },
+            );
+
+            if processed.is_ok() {
+                flush(&rejects, journal.as_deref())?;
+                Checkpoint::save(
+                    checkpoint,
+                    S::in_memory(&transaction_processor),
+                    index + 1,
+                    0,
+                )?;
+            }
+
+            processed.map(|_| ())
+        } else if args.follow && index + 1 == files.len() {
+            let mut snapshot = Instant::now();
+            let followed = transaction_processor.process_following(
+                input,
+                FOLLOW_POLL,
+                on_reject,
+                |transaction_processor| {
+                    if args.dry_run || snapshot.elapsed() < Duration::from_secs(args.snapshot_every)
+                    {
+                        return Ok(());
+                    }
+
+                    flush(&rejects, journal.as_deref())?;
+                    write_output(
+                        args.output.as_deref(),
+                        args.compress,
+                        args.sort,
+                        args.write_buffer,
+                        transaction_processor,
+                    )?;
+                    snapshot = Instant::now();
+
+                    Ok(())
+                },
+            );
+
+            match followed {
+                // Following only stops if it fails or is interrupted, which ends the file
+                Err(error) if error.kind() == ErrorKind::Interrupted => Ok(()),
+                followed => followed,
+            }
+        } else if args.threads > 1 {
+            S::in_memory_mut(&mut transaction_processor).process_reader_sharded(
+                input,
+                args.format,
+                args.threads,
+                on_reject,
+            )
+        } else if let Some(stream) = &mut stream {
+            transaction_processor.process_reader_streaming(input, args.format, stream, on_reject)
+        } else if args.pipeline {
+            transaction_processor.process_reader_pipelined(input, args.format, on_reject)
+        } else {
+            transaction_processor.process_reader(input, args.format, on_reject)
+        };
+
+        match processed {
+            Err(error) if error.kind() == ErrorKind::Interrupted => {
+                tracing::warn!(rejected = rejected.get(), "interrupted");
+
+                if let Some(stream) = stream {
+                    transaction_processor.finish_stream(stream)?;
+                }
+
+                return interrupted(args, &transaction_processor, &rejects, journal.as_deref());
+            }
+            processed => processed?,
+        }
+
+        tracing::info!(rejected = rejected.get(), "processed");
+
+        if let Some(metrics) = &metrics {
+            metrics.update(transaction_processor.accounts());
+        }
+
+        #[cfg(feature = "sqlite")]
+        if let (Some(state), Some(fingerprint)) = (&mut state, fingerprint) {
+            state.add_file(fingerprint, file);
+        }
+    }
+
+    if let Some(days) = args.accrue_days {
+        transaction_processor
+            .post_interest(days, None)
+            .map_err(std::io::Error::other)?;
+    }
+
+    flush(&rejects, journal.as_deref())?;
+
+    if let Some(suspicious_activity) = &suspicious_activity {
+        suspicious_activity
+            .lock()
+            .unwrap_or_else(|poison| poison.into_inner())
+            .flush()?;
+    }
+
+    if !args.dry_run {
+        #[cfg(feature = "sqlite")]
+        if let Some(state) = &mut state {
+            args.retry
+                .retry()
+                .run(|| state.save(S::in_memory(&transaction_processor)))
+                .map_err(std::io::Error::other)?;
+
+            if let Some(wal) = transaction_processor.wal_mut() {
+                wal.truncate()?;
+            }
+        }
+
+        if let Some(path) = &args.save_state {
+            S::in_memory(&transaction_processor).save(path)?;
+        }
+
+        match stream {
+            Some(stream) => transaction_processor.finish_stream(stream)?,
+            None => write_output(
+                args.output.as_deref(),
+                args.compress,
+                args.sort,
+                args.write_buffer,
+                &transaction_processor,
+            )?,
+        }
+
+        if let Some(pending) = &args.pending {
+            transaction_processor.write_pending(File::create(pending)?)?;
+        }
+    }
+
+    if let (Some(negative_balances), Some(path)) = (&negative_balances, &args.negative_balances) {
+        negative_balances.write(File::create(path)?)?;
+    }
+
+    if let Some(held_funds) = &args.held_funds {
+        S::in_memory(&transaction_processor).write_held_funds(File::create(held_funds)?)?;
+    }
+
+    let unbalanced = args.reconcile && !reconcile(&transaction_processor)?;
+
+    let Some(summary) = &summary else {
+        return Ok(if unbalanced {
+            ExitCode::from(UNBALANCED_EXIT_CODE)
+        } else {
+            ExitCode::SUCCESS
+        });
+    };
+
+    let report = summary.report(transaction_processor.accounts());
+
+    match args.summary.as_deref() {
+        Some("-") => serde_json::to_writer_pretty(std::io::stderr(), &report)?,
+        Some(path) => serde_json::to_writer_pretty(File::create(path)?, &report)?,
+        None => {}
+    }
+
+    if unbalanced {
+        return Ok(ExitCode::from(UNBALANCED_EXIT_CODE));
+    }
+
+    if args.fail_on.failed(report.rejected_rows, report.rows) {
+        tracing::error!(
+            rejected = report.rejected_rows,
+            rows = report.rows,
+            "Failing the run, too many rows were rejected"
+        );
+
+        return Ok(ExitCode::from(REJECTED_EXIT_CODE));
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Whether the books balance, writing a breakdown of each currency that doesn't to stderr.
+fn reconcile<S: TransactionStore>(
+    transaction_processor: &TransactionProcessor<S>,
+) -> Result<bool, std::io::Error> {
+    let unbalanced: Vec<_> = transaction_processor
+        .reconcile()
+        .into_iter()
+        .filter(|reconciliation| !reconciliation.balances())
+        .collect();
+
+    if unbalanced.is_empty() {
+        tracing::info!("reconciled");
+        return Ok(true);
+    }
+
+    let mut stderr = std::io::stderr().lock();
+
+    for reconciliation in &unbalanced {
+        write!(stderr, "{reconciliation}")?;
+    }
+
+    tracing::error!(
+        currencies = unbalanced.len(),
+        "Failing the run, the balances don't reconcile"
+    );
+    Ok(false)
+}
+
+/// Set by ctrl-c or SIGTERM so `process` stops between rows and writes what it has, a second one exits
+/// straight away.
+fn on_interrupt() -> Result<Arc<AtomicBool>, std::io::Error> {
+    let interrupt = Arc::new(AtomicBool::new(false));
+    let set = interrupt.clone();
+
+    ctrlc::set_handler(move || {
+        if set.swap(true, Ordering::SeqCst) {
+            std::process::exit(INTERRUPTED_EXIT_CODE.into());
+        }
+
+        tracing::warn!("Interrupted, stopping after the current row");
+    })
+    .map_err(std::io::Error::other)?;
+
+    Ok(interrupt)
+}
+
+/// Flushes the rejects and journal of an interrupted run and writes the accounts as they are, to `--output`
+/// with `.partial` on the end so they aren't mistaken for a finished run's. Accounts on stdout can't be marked
+/// that way, so that's warned about on stderr. The state isn't saved, so the file that was interrupted is
+/// processed from the start next time. With --stream the accounts have already been written.
+fn interrupted<S: TransactionStore>(
+    args: &ProcessArgs,
+    transaction_processor: &TransactionProcessor<S>,
+    rejects: &Rejects,
+    journal: Option<&Journal>,
+) -> Result<ExitCode, std::io::Error> {
+    flush(rejects, journal)?;
+
+    if !args.dry_run && !args.stream {
+        let output = args.output.as_ref().map(|output| {
+            let mut partial = output.clone().into_os_string();
+            partial.push(".partial");
+            PathBuf::from(partial)
+        });
+
+        write_output(
+            output.as_deref(),
+            args.compress,
+            args.sort,
+            args.write_buffer,
+            transaction_processor,
+        )?;
+    }
+
+    if !args.dry_run {
+        match &args.output {
+            None => tracing::warn!("Interrupted, the accounts written to stdout are partial"),
+            Some(output) if args.stream => tracing::warn!(
+                "Interrupted, the accounts written to {} are partial",
+                output.display()
+            ),
+            Some(_) => {}
+        }
+    }
+
+    Ok(ExitCode::from(INTERRUPTED_EXIT_CODE))
+}
+
+/// Where --stream writes the accounts as clients finish, `--output` or stdout. The file isn't replaced in one
+/// go, as it's written a bit at a time.
+fn account_stream(
+    args: &ProcessArgs,
+) -> Result<AccountStream<Box<dyn Write + Send>>, std::io::Error> {
+    let writer: Box<dyn Write + Send> = match &args.output {
+        Some(output) if s3_url(output).is_some() => {
+            return Err(invalid(
+                "--stream can't write to s3, as objects are written in one go",
+            ))
+        }
+        Some(output) => Box::new(BufWriter::with_capacity(
+            args.write_buffer,
+            File::create(output)?,
+        )),
+        None => Box::new(BufWriter::with_capacity(
+            args.write_buffer,
+            std::io::stdout(),
+        )),
+    };
+
+    Ok(AccountStream::new(args.compress.writer(writer)?)?)
+}
+
+/// Writes the accounts to `output`, or stdout if there isn't one.
+fn write_output<S: TransactionStore>(
+    output: Option<&Path>,
+    compression: Compression,
+    sort: Option<SortBy>,
+    buffer: usize,
+    transaction_processor: &TransactionProcessor<S>,
+) -> Result<(), std::io::Error> {
+    match output {
+        Some(output) => match s3_url(output) {
+            // Objects are replaced in one go anyway
+            Some(url) => {
+                let mut bytes = Vec::new();
+                transaction_processor.write_accounts(compression.writer(&mut bytes)?, sort)?;
+                upload(url, bytes)
+            }
+            None => write_atomically(output, |writer| {
+                let mut writer = BufWriter::with_capacity(buffer, writer);
+                transaction_processor.write_accounts(compression.writer(&mut writer)?, sort)?;
+                writer.flush()
+            }),
+        },
+        None => {
+            let mut stdout = BufWriter::with_capacity(buffer, std::io::stdout());
+            transaction_processor.write_accounts(compression.writer(&mut stdout)?, sort)?;
+            stdout.flush()
+        }
+    }
+}
+
+fn journal<S: TransactionStore>(
+    path: Option<&str>,
+    compression: Compression,
+    transaction_processor: &mut TransactionProcessor<S>,
+) -> Result<Option<Arc<Journal>>, std::io::Error> {
+    let journal = path
+        .map(|path| Journal::from_path_compressed(path, compression))
+        .transpose()?
+        .map(Arc::new);
+
+    if let Some(journal) = &journal {
+        transaction_processor.set_journal(journal.clone());
+    }
+
+    Ok(journal)
+}
+
+fn dead_letters<S: TransactionStore>(
+    path: Option<&Path>,
+    transaction_processor: &mut TransactionProcessor<S>,
+) -> Result<Option<Arc<DeadLetters>>, std::io::Error> {
+    let dead_letters = path.map(DeadLetters::open).transpose()?.map(Arc::new);
+
+    if let Some(dead_letters) = &dead_letters {
+        dead_letters.record(transaction_processor);
+    }
+
+    Ok(dead_letters)
+}
+
+fn metrics<S: TransactionStore>(
+    addr: Option<&str>,
+    transaction_processor: &mut TransactionProcessor<S>,
+) -> Result<Option<Arc<Metrics>>, std::io::Error> {
+    let Some(addr) = addr else {
+        return Ok(None);
+    };
+    let metrics = Arc::new(Metrics::new());
+
+    metrics.update(transaction_processor.accounts());
+    serve_metrics(metrics.clone(), addr)?;
+    transaction_processor.set_metrics(metrics.clone());
+
+    Ok(Some(metrics))
+}
+
+#[cfg(feature = "tui")]
+fn dashboard<S: TransactionStore>(
+    tui: bool,
+    transaction_processor: &mut TransactionProcessor<S>,
+) -> Option<Arc<Dashboard>> {
+    let dashboard = tui.then(|| Arc::new(Dashboard::new()))?;
+    dashboard.record(transaction_processor);
+
+    Some(dashboard)
+}
+
+/// Shows the dashboard, recalculating the accounts with the most funds held every `DASHBOARD_REFRESH` for as
+/// long as the process runs.
+#[cfg(feature = "tui")]
+fn show_dashboard(
+    dashboard: &Arc<Dashboard>,
+    running: Running,
+) -> Result<DashboardHandle, std::io::Error> {
+    let refreshed = dashboard.clone();
+    std::thread::spawn(move || loop {
+        match &running {
+            Running::Locked(transaction_processor) => refreshed.update(
+                transaction_processor
+                    .lock()
+                    .unwrap_or_else(|poison| poison.into_inner())
+                    .accounts(),
+            ),
+            Running::Actors(actors) => refreshed.update(&actors.accounts()),
+        }
+
+        std::thread::sleep(DASHBOARD_REFRESH);
+    });
+
+    dashboard.spawn()
+}
+
+/// Warnings by default, each -v or -q is a level more or less. RUST_LOG takes precedence.
+fn logging(verbose: u8, quiet: u8) {
+    let level = match i16::from(verbose) - i16::from(quiet) {
+        i16::MIN..=-2 => "off",
+        -1 => "error",
+        0 => "warn",
+        1 => "info",
+        2 => "debug",
+        _ => "trace",
+    };
+
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new(level));
+
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::IsTerminal::is_terminal(&std::io::stderr()))
+        .init();
+}
+
+/// Writes every transaction that breaks a rule to the report, failures are logged as it's written from a
+/// listener.
+fn suspicious_activity<S: TransactionStore>(
+    path: Option<&Path>,
+    transaction_processor: &mut TransactionProcessor<S>,
+) -> Result<Option<Arc<Mutex<SuspiciousActivityWriter<File>>>>, std::io::Error> {
+    let Some(path) = path else {
+        return Ok(None);
+    };
+
+    let writer = Arc::new(Mutex::new(SuspiciousActivityWriter::from_path(path)?));
+    let listener = writer.clone();
+
+    transaction_processor.subscribe(move |event| {
+        if let Event::Suspicious(activity) = event {
+            let mut writer = listener.lock().unwrap_or_else(|poison| poison.into_inner());
+
+            if let Err(error) = writer.write(activity) {
+                tracing::warn!(%error, "Couldn't write suspicious activity");
+            }
+        }
+    });
+
+    Ok(Some(writer))
+}
+
+fn flush(rejects: &Rejects, journal: Option<&Journal>) -> Result<(), std::io::Error> {
+    if let Some(journal) = journal {
+        journal.flush()?;
+    }
+
+    match &mut *rejects.borrow_mut() {
+        Some(rejects) => rejects.flush(),
+        None => Ok(()),
+    }
+}
+
+/// Expands glob patterns, files matching a pattern are processed in alphabetical order.
+fn files(args: &[String]) -> Result<Vec<PathBuf>, std::io::Error> {
+    let mut files = Vec::new();
+
+    for arg in args {
+        if !arg.contains(['*', '?', '[']) {
+            files.push(PathBuf::from(arg));
+            continue;
+        }
+
+        let paths =
+            glob::glob(arg).map_err(|error| invalid(&format!("Bad pattern {arg}: {error}")))?;
+        let matched = files.len();
+
+        for path in paths {
+            files.push(path.map_err(std::io::Error::from)?);
+        }
+
+        if files.len() == matched {
+            return Err(invalid(&format!("No files match {arg}")));
+        }
+    }
+
+    Ok(files)
+}
+
+/// Identifies a file by its contents, FNV-1a so it's the same between builds.
+#[cfg(feature = "sqlite")]
+fn fingerprint(file: &Path) -> Result<String, std::io::Error> {
+    let mut reader = BufReader::new(File::open(file)?);
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut length: u64 = 0;
+
+    loop {
+        let buffer = std::io::BufRead::fill_buf(&mut reader)?;
+
+        if buffer.is_empty() {
+            break;
+        }
+
+        for byte in buffer {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+
+        let read = buffer.len();
+        length += read as u64;
+        std::io::BufRead::consume(&mut reader, read);
+    }
+
+    Ok(format!("{length}-{hash:016x}"))
+}
+
+/// `-` reads from stdin.
+fn input(file: &Path) -> Result<Box<dyn Read + Send>, std::io::Error> {
+    if file == Path::new("-") {
+        return Ok(Box::new(std::io::stdin()));
+    }
+
+    match s3_url(file) {
+        #[cfg(feature = "s3")]
+        Some(url) => Ok(Box::new(payments::S3Reader::open(url)?)),
+        #[cfg(not(feature = "s3"))]
+        Some(url) => Err(invalid(&format!(
+            "{url} needs building with the s3 feature"
+        ))),
+        None => Ok(Box::new(File::open(file)?)),
+    }
+}
+
+/// An input file for `process`, mapped, read with io_uring or read a --read-buffer at a time.
+fn open_input(args: &ProcessArgs, file: &Path) -> Result<Box<dyn Read + Send>, std::io::Error> {
+    #[cfg(feature = "mmap")]
+    if args.mmap {
+        return mapped(file);
+    }
+
+    #[cfg(feature = "io-uring")]
+    if args.io_uring && file != Path::new("-") && s3_url(file).is_none() {
+        return Ok(Box::new(payments::UringReader::open(
+            file,
+            args.read_buffer,
+        )?));
+    }
+
+    Ok(Box::new(BufReader::with_capacity(
+        args.read_buffer,
+        input(file)?,
+    )))
+}
+
+/// The url if `file` is an `s3://bucket/key` object rather than a local file.
+fn s3_url(file: &Path) -> Option<&str> {
+    file.to_str().filter(|file| file.starts_with("s3://"))
+}
+
+/// Writes `bytes` to an `s3://bucket/key` object.
+#[cfg(feature = "s3")]
+fn upload(url: &str, bytes: Vec<u8>) -> Result<(), std::io::Error> {
+    payments::write_s3(url, bytes)
+}
+
+#[cfg(not(feature = "s3"))]
+fn upload(url: &str, _: Vec<u8>) -> Result<(), std::io::Error> {
+    Err(invalid(&format!(
+        "{url} needs building with the s3 feature"
+    )))
+}
+
+/// Reads the file from a mapping rather than through a buffer, stdin and s3 are read as usual.
+#[cfg(feature = "mmap")]
+fn mapped(file: &Path) -> Result<Box<dyn Read + Send>, std::io::Error> {
+    if file == Path::new("-") || s3_url(file).is_some() {
+        return input(file);
+    }
+
+    // SAFETY: the file mustn't be truncated while it's being processed, the same as any input
+    let map = unsafe { memmap2::Mmap::map(&File::open(file)?)? };
+
+    Ok(Box::new(std::io::Cursor::new(map)))
+}
+
+/// Written to a temporary file first and renamed, so anything reading `path` sees either the old file or the
+/// whole new one.
+fn write_atomically<F>(path: &Path, write: F) -> Result<(), std::io::Error>
+where
+    F: FnOnce(&mut BufWriter<File>) -> Result<(), std::io::Error>,
+{
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+
+    let result = File::create(&temporary).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        write(&mut writer)?;
+        writer.into_inner()?.sync_all()
+    });
+
+    match result {
+        Ok(()) => std::fs::rename(temporary, path),
+        Err(error) => {
+            let _ = std::fs::remove_file(temporary);
+            Err(error)
+        }
+    }
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, message)
 }
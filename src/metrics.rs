@@ -0,0 +1,156 @@
+use crate::account::Account;
+use crate::currency::Currency;
+use crate::money::Money;
+use crate::transaction::TransactionType;
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+/// Counters and gauges for a run, written in the Prometheus text format by `render`.
+/// Shared with `Arc` so they can be scraped while transactions are being processed.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Applied transactions, indexed by `TransactionType`.
+    applied: [AtomicU64; TransactionType::ALL.len()],
+    rejected: AtomicU64,
+    gauges: Mutex<Gauges>,
+}
+
+#[derive(Debug, Default)]
+struct Gauges {
+    accounts: usize,
+    locked: usize,
+    held: BTreeMap<Currency, Money>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn applied(&self, transaction_type: TransactionType) {
+        self.applied[transaction_type as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a rejected row, whether it was badly formatted or its transaction failed.
+    pub fn rejected(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Recalculates the account counts and held funds, these are only as fresh as the last update.
+    pub fn update<'a, I>(&self, accounts: I)
+    where
+        I: IntoIterator<Item = &'a Account>,
+    {
+        let mut gauges = Gauges::default();
+
+        for account in accounts {
+            gauges.accounts += 1;
+            gauges.locked += usize::from(account.locked);
+
+            for (currency, balance) in &account.balances {
+                *gauges.held.entry(*currency).or_default() += &balance.held;
+            }
+        }
+
+        *self
+            .gauges
+            .lock()
+            .unwrap_or_else(|poison| poison.into_inner()) = gauges;
+    }
+
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+
+        output.push_str("# HELP payments_transactions_total Transactions applied, by type.\n");
+        output.push_str("# TYPE payments_transactions_total counter\n");
+        for transaction_type in TransactionType::ALL {
+            let _ = writeln!(
+                output,
+                "payments_transactions_total{{type=\"{transaction_type}\"}} {}",
+                self.applied[transaction_type as usize].load(Ordering::Relaxed)
+            );
+        }
+
+        output
+            .push_str("# HELP payments_rejected_total Rows that were badly formatted or failed.\n");
+        output.push_str("# TYPE payments_rejected_total counter\n");
+        let _ = writeln!(
+            output,
+            "payments_rejected_total {}",
+            self.rejected.load(Ordering::Relaxed)
+        );
+
+        let gauges = self
+            .gauges
+            .lock()
+            .unwrap_or_else(|poison| poison.into_inner());
+
+        output.push_str("# HELP payments_accounts Client accounts.\n");
+        output.push_str("# TYPE payments_accounts gauge\n");
+        let _ = writeln!(output, "payments_accounts {}", gauges.accounts);
+
+        output
+            .push_str("# HELP payments_locked_accounts Client accounts locked by a chargeback.\n");
+        output.push_str("# TYPE payments_locked_accounts gauge\n");
+        let _ = writeln!(output, "payments_locked_accounts {}", gauges.locked);
+
+        output.push_str("# HELP payments_held Funds held by disputes, by currency.\n");
+        output.push_str("# TYPE payments_held gauge\n");
+        for (currency, held) in &gauges.held {
+            // Normalized so the value doesn't depend on how many decimal places the amounts were written with
+            let _ = writeln!(
+                output,
+                "payments_held{{currency=\"{currency}\"}} {}",
+                held.normalize()
+            );
+        }
+
+        output
+    }
+}
+
+/// Serves `metrics` at `/metrics` on a background thread for as long as the process runs.
+pub fn serve_metrics<A: ToSocketAddrs>(
+    metrics: Arc<Metrics>,
+    addr: A,
+) -> Result<thread::JoinHandle<()>, std::io::Error> {
+    let listener = TcpListener::bind(addr)?;
+
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else { continue };
+            let mut request_line = String::new();
+
+            if BufReader::new(&stream)
+                .read_line(&mut request_line)
+                .is_err()
+            {
+                continue;
+            }
+
+            let response = match request_line.split_whitespace().nth(1) {
+                Some("/metrics") => {
+                    let body = metrics.render();
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                        body.len()
+                    )
+                }
+                _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_string(),
+            };
+
+            // The scraper hanging up early isn't our problem
+            let _ = stream.write_all(response.as_bytes());
+        }
+    }))
+}
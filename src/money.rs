@@ -0,0 +1,555 @@
+use crate::error::TransactionError;
+use rust_decimal::Decimal;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::cmp::Ordering;
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Neg, Sub, SubAssign};
+use std::str::FromStr;
+
+#[cfg(not(any(feature = "bigdecimal", feature = "minor-units")))]
+type Repr = Decimal;
+#[cfg(feature = "bigdecimal")]
+type Repr = bigdecimal::BigDecimal;
+#[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+type Repr = MinorUnits;
+
+/// What balances are kept in. Amounts are read as `Decimal`, which has 28 significant digits, but balances add
+/// them up, so with the `bigdecimal` feature they're kept to arbitrary precision instead. With the
+/// `minor-units` feature they're whole ten-thousandths in an `i64`, which is quicker when amounts have no more
+/// than 4 decimal places, `bigdecimal` wins if both are on. It isn't `Copy` with any backend, so the same code
+/// builds with all of them. Written as a string, like amounts.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Money(Repr);
+
+impl Money {
+    pub fn is_zero(&self) -> bool {
+        self == &Decimal::ZERO
+    }
+
+    /// Only a `Decimal` can be negative zero.
+    #[cfg(not(any(feature = "bigdecimal", feature = "minor-units")))]
+    pub fn is_sign_negative(&self) -> bool {
+        self.0.is_sign_negative()
+    }
+
+    #[cfg(feature = "bigdecimal")]
+    pub fn is_sign_negative(&self) -> bool {
+        self.0.sign() == bigdecimal::num_bigint::Sign::Minus
+    }
+
+    #[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+    pub fn is_sign_negative(&self) -> bool {
+        self.0.units < 0
+    }
+
+    /// Rounded half to even to exactly `dp` decimal places. Overdrawn balances that round to zero aren't -0.
+    #[cfg(not(any(feature = "bigdecimal", feature = "minor-units")))]
+    pub fn round_dp(&self, dp: u32) -> Money {
+        let mut amount = self.0.round_dp(dp);
+        amount.rescale(dp);
+
+        if amount.is_zero() {
+            amount.set_sign_positive(true);
+        }
+
+        Money(amount)
+    }
+
+    #[cfg(feature = "bigdecimal")]
+    pub fn round_dp(&self, dp: u32) -> Money {
+        Money(
+            self.0
+                .with_scale_round(dp.into(), bigdecimal::RoundingMode::HalfEven),
+        )
+    }
+
+    #[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+    pub fn round_dp(&self, dp: u32) -> Money {
+        Money(self.0.round_dp(dp))
+    }
+
+    /// Without trailing zeros, so it's written the same however many decimal places it was added up from.
+    #[cfg(not(any(feature = "bigdecimal", feature = "minor-units")))]
+    pub fn normalize(&self) -> Money {
+        Money(self.0.normalize())
+    }
+
+    #[cfg(feature = "bigdecimal")]
+    pub fn normalize(&self) -> Money {
+        Money(self.0.normalized())
+    }
+
+    #[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+    pub fn normalize(&self) -> Money {
+        Money(self.0.normalize())
+    }
+
+    /// Rounded to `Decimal`'s 28 decimal places, `None` if it's too big for it.
+    #[cfg(not(any(feature = "bigdecimal", feature = "minor-units")))]
+    pub fn to_decimal(&self) -> Option<Decimal> {
+        Some(self.0)
+    }
+
+    #[cfg(feature = "bigdecimal")]
+    pub fn to_decimal(&self) -> Option<Decimal> {
+        let scale = self.0.fractional_digit_count().clamp(0, 28);
+        let (digits, scale) = self
+            .0
+            .with_scale_round(scale, bigdecimal::RoundingMode::HalfEven)
+            .into_bigint_and_scale();
+
+        Decimal::try_from_i128_with_scale(i128::try_from(digits).ok()?, scale as u32).ok()
+    }
+
+    #[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+    pub fn to_decimal(&self) -> Option<Decimal> {
+        Some(self.0.to_decimal())
+    }
+
+    /// `None` if the sum is too big for the backend, which is never with `BigDecimal`. Balances are added to
+    /// with this, so a transaction that would overflow one is rejected rather than panicking.
+    #[cfg(not(any(feature = "bigdecimal", feature = "minor-units")))]
+    pub(crate) fn checked_add(&self, other: &Money) -> Option<Money> {
+        self.0.checked_add(other.0).map(Money)
+    }
+
+    #[cfg(feature = "bigdecimal")]
+    pub(crate) fn checked_add(&self, other: &Money) -> Option<Money> {
+        Some(Money(&self.0 + &other.0))
+    }
+
+    #[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+    pub(crate) fn checked_add(&self, other: &Money) -> Option<Money> {
+        self.0.checked_add(other.0).map(Money)
+    }
+
+    /// Like `From<Decimal>`, but `None` rather than panicking if it's too big for `MinorUnits`.
+    #[cfg(not(all(feature = "minor-units", not(feature = "bigdecimal"))))]
+    pub(crate) fn from_decimal(amount: Decimal) -> Option<Money> {
+        Some(Money(repr(amount)))
+    }
+
+    #[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+    pub(crate) fn from_decimal(amount: Decimal) -> Option<Money> {
+        MinorUnits::from_decimal(amount).map(Money)
+    }
+
+    /// Whether a transaction's `amount` can be added to balances. Always with `Decimal` and `BigDecimal`, with
+    /// `MinorUnits` it mustn't have more than 4 decimal places or be too big for an `i64` of them.
+    #[cfg(not(all(feature = "minor-units", not(feature = "bigdecimal"))))]
+    pub(crate) fn check(_amount: Decimal) -> Result<(), TransactionError> {
+        Ok(())
+    }
+
+    #[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+    pub(crate) fn check(amount: Decimal) -> Result<(), TransactionError> {
+        if amount.normalize().scale() > MinorUnits::SCALE {
+            Err(TransactionError::TooPrecise)
+        } else if MinorUnits::from_decimal(amount).is_none() {
+            Err(TransactionError::AmountTooLarge)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(not(any(feature = "bigdecimal", feature = "minor-units")))]
+fn repr(amount: Decimal) -> Repr {
+    amount
+}
+
+#[cfg(feature = "bigdecimal")]
+fn repr(amount: Decimal) -> Repr {
+    bigdecimal::BigDecimal::new(amount.mantissa().into(), amount.scale().into())
+}
+
+/// Amounts with more than 4 decimal places, fees worked out from a rate, are rounded half to even as they're
+/// added. Ones that are too big panic, like a `Decimal` that overflows, `Money::check` rejects them first.
+#[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+fn repr(amount: Decimal) -> Repr {
+    MinorUnits::from_decimal(amount).expect("amount is too big for minor units")
+}
+
+#[cfg(not(any(feature = "bigdecimal", feature = "minor-units")))]
+fn compare(money: &Repr, amount: &Decimal) -> Ordering {
+    money.cmp(amount)
+}
+
+#[cfg(feature = "bigdecimal")]
+fn compare(money: &Repr, amount: &Decimal) -> Ordering {
+    money.cmp(&repr(*amount))
+}
+
+/// Compared exactly rather than after rounding `amount` to 4 places.
+#[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+fn compare(money: &Repr, amount: &Decimal) -> Ordering {
+    money.to_decimal().cmp(amount)
+}
+
+impl From<Decimal> for Money {
+    fn from(amount: Decimal) -> Self {
+        Money(repr(amount))
+    }
+}
+
+impl FromStr for Money {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Repr::from_str(s)
+            .map(Money)
+            .map_err(|error| format!("Invalid amount {s:?}: {error}"))
+    }
+}
+
+#[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0.to_decimal(), f)
+    }
+}
+
+#[cfg(not(any(feature = "bigdecimal", feature = "minor-units")))]
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+/// Written out in full with all its decimal places, like a `Decimal`, rather than with an exponent.
+#[cfg(feature = "bigdecimal")]
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match f.precision() {
+            Some(_) => fmt::Display::fmt(&self.0, f),
+            None => f.pad(&self.0.to_plain_string()),
+        }
+    }
+}
+
+impl Serialize for Money {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+impl PartialEq<Decimal> for Money {
+    fn eq(&self, other: &Decimal) -> bool {
+        compare(&self.0, other) == Ordering::Equal
+    }
+}
+
+impl PartialEq<Money> for Decimal {
+    fn eq(&self, other: &Money) -> bool {
+        other == self
+    }
+}
+
+impl PartialOrd<Decimal> for Money {
+    fn partial_cmp(&self, other: &Decimal) -> Option<Ordering> {
+        Some(compare(&self.0, other))
+    }
+}
+
+impl PartialOrd<Money> for Decimal {
+    fn partial_cmp(&self, other: &Money) -> Option<Ordering> {
+        other.partial_cmp(self).map(Ordering::reverse)
+    }
+}
+
+macro_rules! ops {
+    ($($op:ident $method:ident $op_assign:ident $method_assign:ident),*) => {$(
+        impl $op for Money {
+            type Output = Money;
+
+            fn $method(self, rhs: Money) -> Money {
+                Money(self.0.$method(rhs.0))
+            }
+        }
+
+        impl $op<&Money> for Money {
+            type Output = Money;
+
+            fn $method(self, rhs: &Money) -> Money {
+                Money(self.0.$method(&rhs.0))
+            }
+        }
+
+        impl $op<&Money> for &Money {
+            type Output = Money;
+
+            fn $method(self, rhs: &Money) -> Money {
+                Money((&self.0).$method(&rhs.0))
+            }
+        }
+
+        impl $op<Decimal> for Money {
+            type Output = Money;
+
+            fn $method(self, rhs: Decimal) -> Money {
+                Money(self.0.$method(repr(rhs)))
+            }
+        }
+
+        impl $op<Decimal> for &Money {
+            type Output = Money;
+
+            fn $method(self, rhs: Decimal) -> Money {
+                Money((&self.0).$method(repr(rhs)))
+            }
+        }
+
+        impl $op_assign for Money {
+            fn $method_assign(&mut self, rhs: Money) {
+                self.0.$method_assign(rhs.0);
+            }
+        }
+
+        impl $op_assign<&Money> for Money {
+            fn $method_assign(&mut self, rhs: &Money) {
+                self.0.$method_assign(&rhs.0);
+            }
+        }
+
+        impl $op_assign<Decimal> for Money {
+            fn $method_assign(&mut self, rhs: Decimal) {
+                self.0.$method_assign(repr(rhs));
+            }
+        }
+    )*};
+}
+
+ops!(Add add AddAssign add_assign, Sub sub SubAssign sub_assign);
+
+impl Neg for Money {
+    type Output = Money;
+
+    fn neg(self) -> Money {
+        Money(-self.0)
+    }
+}
+
+impl Neg for &Money {
+    type Output = Money;
+
+    fn neg(self) -> Money {
+        Money(-&self.0)
+    }
+}
+
+impl Sum for Money {
+    fn sum<I: Iterator<Item = Money>>(iter: I) -> Self {
+        Money(iter.map(|money| money.0).sum())
+    }
+}
+
+impl<'a> Sum<&'a Money> for Money {
+    fn sum<I: Iterator<Item = &'a Money>>(iter: I) -> Self {
+        Money(iter.map(|money| &money.0).sum())
+    }
+}
+
+/// A balance in whole ten-thousandths, with the decimal places it's written with like a `Decimal`'s scale, so
+/// it's written the same as it would be with that backend. Arithmetic panics rather than wrapping if an `i64`
+/// overflows, balances are added to with `checked_add` so transactions that would overflow one are rejected.
+#[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+#[derive(Debug, Clone, Copy, Default)]
+struct MinorUnits {
+    units: i64,
+    scale: u32,
+}
+
+#[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+impl MinorUnits {
+    const SCALE: u32 = 4;
+
+    /// Rounded half to even to 4 decimal places, `None` if it's too big.
+    fn from_decimal(amount: Decimal) -> Option<Self> {
+        let amount = amount.round_dp(Self::SCALE);
+        let mut units = amount;
+        units.rescale(Self::SCALE);
+
+        if units.scale() != Self::SCALE {
+            return None;
+        }
+
+        Some(Self {
+            units: i64::try_from(units.mantissa()).ok()?,
+            scale: amount.scale(),
+        })
+    }
+
+    fn to_decimal(self) -> Decimal {
+        let mut amount = Decimal::new(self.units, Self::SCALE);
+        amount.rescale(self.scale);
+        amount
+    }
+
+    fn round_dp(self, dp: u32) -> Self {
+        let rounded = Self::checked(
+            Self::from_decimal(self.to_decimal().round_dp(dp)).map(|rounded| rounded.units),
+        );
+
+        Self {
+            units: rounded,
+            scale: dp,
+        }
+    }
+
+    fn normalize(self) -> Self {
+        Self {
+            units: self.units,
+            scale: self.to_decimal().normalize().scale(),
+        }
+    }
+
+    fn checked_add(self, other: Self) -> Option<Self> {
+        Some(Self {
+            units: self.units.checked_add(other.units)?,
+            scale: self.scale.max(other.scale),
+        })
+    }
+
+    fn checked(units: Option<i64>) -> i64 {
+        units.expect("balance overflowed minor units")
+    }
+}
+
+#[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+impl PartialEq for MinorUnits {
+    fn eq(&self, other: &Self) -> bool {
+        self.units == other.units
+    }
+}
+
+#[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+impl Eq for MinorUnits {}
+
+#[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+impl PartialOrd for MinorUnits {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+impl Ord for MinorUnits {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.units.cmp(&other.units)
+    }
+}
+
+/// Exactly, an amount with more than 4 decimal places isn't a balance.
+#[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+impl FromStr for MinorUnits {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let amount = Decimal::from_str(s).map_err(|error| error.to_string())?;
+
+        if amount.normalize().scale() > Self::SCALE {
+            return Err("more than 4 decimal places".to_string());
+        }
+
+        Self::from_decimal(amount).ok_or_else(|| "too big for minor units".to_string())
+    }
+}
+
+#[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+macro_rules! minor_units_ops {
+    ($($op:ident $method:ident $checked:ident $op_assign:ident $method_assign:ident),*) => {$(
+        impl $op for MinorUnits {
+            type Output = MinorUnits;
+
+            fn $method(self, rhs: MinorUnits) -> MinorUnits {
+                MinorUnits {
+                    units: Self::checked(self.units.$checked(rhs.units)),
+                    scale: self.scale.max(rhs.scale),
+                }
+            }
+        }
+
+        impl $op<&MinorUnits> for MinorUnits {
+            type Output = MinorUnits;
+
+            fn $method(self, rhs: &MinorUnits) -> MinorUnits {
+                self.$method(*rhs)
+            }
+        }
+
+        impl $op<MinorUnits> for &MinorUnits {
+            type Output = MinorUnits;
+
+            fn $method(self, rhs: MinorUnits) -> MinorUnits {
+                (*self).$method(rhs)
+            }
+        }
+
+        impl $op<&MinorUnits> for &MinorUnits {
+            type Output = MinorUnits;
+
+            fn $method(self, rhs: &MinorUnits) -> MinorUnits {
+                (*self).$method(*rhs)
+            }
+        }
+
+        impl $op_assign for MinorUnits {
+            fn $method_assign(&mut self, rhs: MinorUnits) {
+                *self = (*self).$method(rhs);
+            }
+        }
+
+        impl $op_assign<&MinorUnits> for MinorUnits {
+            fn $method_assign(&mut self, rhs: &MinorUnits) {
+                *self = (*self).$method(*rhs);
+            }
+        }
+    )*};
+}
+
+#[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+minor_units_ops!(Add add checked_add AddAssign add_assign, Sub sub checked_sub SubAssign sub_assign);
+
+#[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+impl Neg for MinorUnits {
+    type Output = MinorUnits;
+
+    fn neg(self) -> MinorUnits {
+        MinorUnits {
+            units: Self::checked(self.units.checked_neg()),
+            scale: self.scale,
+        }
+    }
+}
+
+#[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+impl Neg for &MinorUnits {
+    type Output = MinorUnits;
+
+    fn neg(self) -> MinorUnits {
+        -*self
+    }
+}
+
+#[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+impl Sum for MinorUnits {
+    fn sum<I: Iterator<Item = MinorUnits>>(iter: I) -> Self {
+        iter.fold(MinorUnits::default(), Add::add)
+    }
+}
+
+#[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+impl<'a> Sum<&'a MinorUnits> for MinorUnits {
+    fn sum<I: Iterator<Item = &'a MinorUnits>>(iter: I) -> Self {
+        iter.fold(MinorUnits::default(), Add::add)
+    }
+}
@@ -0,0 +1,122 @@
+use crate::client::ClientId;
+use crate::currency::Currency;
+use crate::events::Event;
+use crate::money::Money;
+use crate::store::TransactionStore;
+use crate::transaction::{format_amount, TransactionProcessor, TransactionType};
+use crate::tx::TxId;
+use rust_decimal::Decimal;
+use std::{
+    collections::HashMap,
+    io::Write,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+/// Finds the transactions that took a balance below zero during a run, for exposure monitoring. Balances
+/// that were already negative aren't reported again until they've been back to zero or above.
+#[derive(Default)]
+pub struct NegativeBalances {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Whether each balance's available and total funds are below zero.
+    negative: HashMap<(ClientId, Currency), (bool, bool)>,
+    found: Vec<NegativeBalance>,
+    /// Decimal places balances are written with, the processor's like the accounts.
+    precision: u32,
+}
+
+/// A transaction that took the client's available or total funds in a currency below zero, with the balance
+/// it left.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NegativeBalance {
+    pub client: ClientId,
+    pub currency: Currency,
+    pub tx: TxId,
+    pub r#type: TransactionType,
+    pub available: Money,
+    pub total: Money,
+}
+
+impl NegativeBalances {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Checks the balances left by every transaction `transaction_processor` applies from now on.
+    pub fn record<S: TransactionStore>(
+        self: &Arc<Self>,
+        transaction_processor: &mut TransactionProcessor<S>,
+    ) {
+        self.state().precision = transaction_processor.config().output_precision();
+
+        let negative_balances = self.clone();
+        transaction_processor.subscribe(move |event| {
+            let Event::Applied(entry) = event else {
+                return;
+            };
+
+            let mut state = negative_balances.state();
+
+            for balance in &entry.balances {
+                let now = (
+                    balance.available < Decimal::ZERO,
+                    balance.total < Decimal::ZERO,
+                );
+                let before = state
+                    .negative
+                    .insert((balance.client, balance.currency), now)
+                    .unwrap_or_default();
+
+                if (now.0 && !before.0) || (now.1 && !before.1) {
+                    state.found.push(NegativeBalance {
+                        client: balance.client,
+                        currency: balance.currency,
+                        tx: entry.tx,
+                        r#type: entry.r#type,
+                        available: balance.available.clone(),
+                        total: balance.total.clone(),
+                    });
+                }
+            }
+        });
+    }
+
+    /// Every transaction found so far, by client and currency and then in the order they were applied.
+    pub fn found(&self) -> Vec<NegativeBalance> {
+        let mut found = self.state().found.clone();
+        found.sort_by_key(|negative| (negative.client, negative.currency));
+        found
+    }
+
+    /// Writes `found` as csv.
+    pub fn write<W: Write>(&self, writer: W) -> Result<(), csv::Error> {
+        let mut writer = csv::Writer::from_writer(writer);
+        writer.write_record(["client", "currency", "tx", "type", "available", "total"])?;
+
+        let precision = self.state().precision;
+
+        for negative in self.found() {
+            writer.serialize((
+                negative.client,
+                negative.currency.as_str(),
+                negative.tx,
+                negative.r#type.as_str(),
+                format_amount(negative.available, precision),
+                format_amount(negative.total, precision),
+            ))?;
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Checking carries on if a thread panicked.
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poison| poison.into_inner())
+    }
+}
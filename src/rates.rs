@@ -0,0 +1,55 @@
+use crate::currency::Currency;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::{collections::HashMap, fs::File, io::Read, path::Path};
+
+/// Exchange rates used by conversions.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExchangeRates {
+    rates: HashMap<(Currency, Currency), Decimal>,
+}
+
+#[derive(Deserialize)]
+struct RateRow {
+    from: Currency,
+    to: Currency,
+    #[serde(with = "rust_decimal::serde::str")]
+    rate: Decimal,
+}
+
+impl ExchangeRates {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, csv::Error> {
+        Self::from_reader(File::open(path)?)
+    }
+
+    /// Reads csv with headers `from,to,rate`, e.g. `EUR,USD,1.08` is 1.08 USD to the EUR.
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self, csv::Error> {
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+        let mut rates = Self::default();
+
+        for row in reader.deserialize() {
+            let row: RateRow = row?;
+            rates.set(row.from, row.to, row.rate);
+        }
+
+        Ok(rates)
+    }
+
+    pub fn set(&mut self, from: Currency, to: Currency, rate: Decimal) {
+        self.rates.insert((from, to), rate);
+    }
+
+    /// How much `to` one `from` buys. If only the opposite rate is known its inverse is used.
+    pub fn rate(&self, from: Currency, to: Currency) -> Option<Decimal> {
+        if from == to {
+            return Some(Decimal::ONE);
+        }
+
+        match self.rates.get(&(from, to)) {
+            Some(rate) => Some(*rate),
+            None => Decimal::ONE.checked_div(*self.rates.get(&(to, from))?),
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use crate::error::RejectReason;
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+/// A row that was badly formatted or whose transaction failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejection {
+    /// Line the row starts on, 1 based.
+    pub line: u64,
+    /// The row as it appeared in the input.
+    pub row: String,
+    pub reason: RejectReason,
+    /// The row's extra columns, if the processor is keeping them, see `TransactionProcessor::keep_metadata`.
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// Writes rejections as csv so they can be reconciled with the payment partner.
+pub struct RejectsWriter<W: Write> {
+    writer: csv::Writer<W>,
+    /// Whether there's a metadata column.
+    metadata: bool,
+}
+
+impl RejectsWriter<File> {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, csv::Error> {
+        Self::new(File::create(path)?)
+    }
+
+    /// Adds to an existing rejects file, used when resuming a run.
+    pub fn append<P: AsRef<Path>>(path: P) -> Result<Self, csv::Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        if file.metadata()?.len() == 0 {
+            Self::new(file)
+        } else {
+            Ok(Self {
+                writer: csv::Writer::from_writer(file),
+                metadata: false,
+            })
+        }
+    }
+}
+
+impl<W: Write> RejectsWriter<W> {
+    pub fn new(writer: W) -> Result<Self, csv::Error> {
+        let mut writer = csv::Writer::from_writer(writer);
+        writer.write_record(["file", "line", "row", "reason"])?;
+
+        Ok(Self {
+            writer,
+            metadata: false,
+        })
+    }
+
+    /// Like `new` with a metadata column, the rejection's metadata as a json object.
+    pub fn with_metadata(writer: W) -> Result<Self, csv::Error> {
+        let mut writer = csv::Writer::from_writer(writer);
+        writer.write_record(["file", "line", "row", "reason", "metadata"])?;
+
+        Ok(Self {
+            writer,
+            metadata: true,
+        })
+    }
+
+    /// `file` is the input the rejection came from.
+    pub fn write(&mut self, file: &str, rejection: &Rejection) -> Result<(), csv::Error> {
+        let mut record = vec![
+            file.to_string(),
+            rejection.line.to_string(),
+            rejection.row.clone(),
+            rejection.reason.to_string(),
+        ];
+
+        if self.metadata {
+            record.push(serde_json::to_string(&rejection.metadata).map_err(std::io::Error::from)?);
+        }
+
+        self.writer.write_record(&record)
+    }
+
+    pub fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.writer.flush()
+    }
+}
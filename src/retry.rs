@@ -0,0 +1,117 @@
+use crate::error::StoreError;
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    io::ErrorKind,
+    thread,
+    time::Duration,
+};
+
+/// Errors that might not happen again if the operation is retried, like a timeout or a busy database.
+pub trait Transient {
+    fn is_transient(&self) -> bool;
+}
+
+impl Transient for std::io::Error {
+    fn is_transient(&self) -> bool {
+        matches!(
+            self.kind(),
+            ErrorKind::Interrupted
+                | ErrorKind::TimedOut
+                | ErrorKind::WouldBlock
+                | ErrorKind::ConnectionRefused
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::NotConnected
+                | ErrorKind::BrokenPipe
+                | ErrorKind::UnexpectedEof
+        )
+    }
+}
+
+impl Transient for StoreError {
+    fn is_transient(&self) -> bool {
+        StoreError::is_transient(self)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl Transient for rusqlite::Error {
+    fn is_transient(&self) -> bool {
+        use rusqlite::ErrorCode::*;
+
+        match self {
+            rusqlite::Error::SqliteFailure(error, _) => {
+                matches!(error.code, DatabaseBusy | DatabaseLocked)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// How often a store or sink operation is retried when it fails with a transient error. The delay doubles
+/// after each attempt up to `max_delay`, and each wait is somewhere between half and all of the delay so
+/// processes retrying the same backend don't all come back at once. Permanent errors are returned straight
+/// away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retry {
+    /// Retries after the first attempt, 0 doesn't retry.
+    pub retries: u32,
+    pub delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for Retry {
+    /// 3 retries, starting at 100ms.
+    fn default() -> Self {
+        Self::new(3, Duration::from_millis(100))
+    }
+}
+
+impl Retry {
+    /// Doesn't wait more than 10 seconds between attempts.
+    pub fn new(retries: u32, delay: Duration) -> Self {
+        Self {
+            retries,
+            delay,
+            max_delay: Duration::from_secs(10),
+        }
+    }
+
+    /// Never retries.
+    pub fn none() -> Self {
+        Self::new(0, Duration::ZERO)
+    }
+
+    /// Calls `f` until it succeeds, fails with a permanent error or runs out of retries, returning the last
+    /// result.
+    pub fn run<T, E, F>(&self, mut f: F) -> Result<T, E>
+    where
+        E: Transient + std::fmt::Display,
+        F: FnMut() -> Result<T, E>,
+    {
+        let mut delay = self.delay;
+
+        for retry in 1..=self.retries {
+            match f() {
+                Err(error) if error.is_transient() => {
+                    let wait = jitter(delay);
+                    tracing::warn!(%error, retry, ?wait, "Retrying");
+                    thread::sleep(wait);
+                    delay = delay.saturating_mul(2).min(self.max_delay);
+                }
+                result => return result,
+            }
+        }
+
+        f()
+    }
+}
+
+/// Somewhere between half and all of `delay`.
+fn jitter(delay: Duration) -> Duration {
+    // Every RandomState has different keys, which is random enough to spread retries out
+    let random = RandomState::new().build_hasher().finish();
+
+    delay / 2 + delay.mul_f64((random % 1024) as f64 / 2048.0)
+}
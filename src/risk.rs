@@ -0,0 +1,138 @@
+use crate::account::Account;
+use crate::currency::Currency;
+use crate::money::Money;
+use crate::rules::Velocity;
+use crate::transaction::Transaction;
+use rust_decimal::Decimal;
+
+/// What a `RiskScorer` decided to do with a transaction.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RiskDecision {
+    #[default]
+    Allow,
+    /// It's applied and reported as suspicious, breaking the `risk-score` rule.
+    Flag,
+    /// It's rejected and reported as suspicious, breaking the `risk-score` rule.
+    Reject,
+}
+
+/// What's known about the client when their transaction is scored, from before it's applied.
+#[derive(Debug, Clone)]
+pub struct RiskContext<'a> {
+    /// The client's account, if they have one yet.
+    pub account: Option<&'a Account>,
+    /// Available funds in the transaction's currency.
+    pub available: Money,
+    /// Deposits the client has made on the transaction's day, going by the `timestamp` column.
+    pub deposits_today: u32,
+    /// Total the client has deposited in the transaction's currency.
+    pub total_in: Decimal,
+    /// Total the client has withdrawn in the transaction's currency.
+    pub total_out: Decimal,
+    pub disputes: u32,
+    pub chargebacks: u32,
+}
+
+impl<'a> RiskContext<'a> {
+    pub(crate) fn new(
+        transaction: &Transaction,
+        currency: Currency,
+        account: Option<&'a Account>,
+        velocity: Option<&Velocity>,
+    ) -> Self {
+        Self {
+            account,
+            available: account.map_or_else(Money::default, |account| {
+                account.balance(currency).available
+            }),
+            deposits_today: velocity.map_or(0, |velocity| velocity.deposits_on(transaction)),
+            total_in: velocity.map_or(Decimal::ZERO, |velocity| velocity.total_in(currency)),
+            total_out: velocity.map_or(Decimal::ZERO, |velocity| velocity.total_out(currency)),
+            disputes: velocity.map_or(0, |velocity| velocity.disputes),
+            chargebacks: velocity.map_or(0, |velocity| velocity.chargebacks),
+        }
+    }
+}
+
+/// Decides whether each transaction is allowed, flagged or rejected before it's applied, see
+/// `TransactionProcessor::set_risk_scorer`.
+pub trait RiskScorer: Send + Sync {
+    fn score(&self, transaction: &Transaction, context: &RiskContext) -> RiskDecision;
+}
+
+impl<F> RiskScorer for F
+where
+    F: Fn(&Transaction, &RiskContext) -> RiskDecision + Send + Sync,
+{
+    fn score(&self, transaction: &Transaction, context: &RiskContext) -> RiskDecision {
+        self(transaction, context)
+    }
+}
+
+/// Scores deposits, withdrawals, transfers and conversions out of 100 from the client's history, and flags
+/// or rejects them at a threshold. Disputes, resolves etc. come from the partner so are always allowed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BasicRiskScorer {
+    pub flag_at: u32,
+    pub reject_at: u32,
+}
+
+impl Default for BasicRiskScorer {
+    fn default() -> Self {
+        Self {
+            flag_at: 50,
+            reject_at: 80,
+        }
+    }
+}
+
+impl BasicRiskScorer {
+    /// 40 for each chargeback, 10 for each dispute, 30 if the account is locked, 20 for taking out more than
+    /// half the available funds and 10 for each deposit in a day after the fifth, at most 100.
+    pub fn points(&self, transaction: &Transaction, context: &RiskContext) -> u32 {
+        let mut points = context
+            .chargebacks
+            .saturating_mul(40)
+            .saturating_add(context.disputes.saturating_mul(10));
+
+        if context.account.is_some_and(|account| account.locked) {
+            points += 30;
+        }
+
+        match *transaction {
+            Transaction::Deposit { .. } => {
+                points = points.saturating_add(10 * context.deposits_today.saturating_sub(4));
+            }
+            Transaction::Withdrawal { amount, .. }
+            | Transaction::Transfer { amount, .. }
+            | Transaction::Convert { amount, .. }
+                if amount * Decimal::TWO > context.available =>
+            {
+                points += 20;
+            }
+            _ => {}
+        }
+
+        points.min(100)
+    }
+}
+
+impl RiskScorer for BasicRiskScorer {
+    fn score(&self, transaction: &Transaction, context: &RiskContext) -> RiskDecision {
+        if !matches!(
+            transaction,
+            Transaction::Deposit { .. }
+                | Transaction::Withdrawal { .. }
+                | Transaction::Transfer { .. }
+                | Transaction::Convert { .. }
+        ) {
+            return RiskDecision::Allow;
+        }
+
+        match self.points(transaction, context) {
+            points if points >= self.reject_at => RiskDecision::Reject,
+            points if points >= self.flag_at => RiskDecision::Flag,
+            _ => RiskDecision::Allow,
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use crate::client::ClientId;
+use crate::currency::Currency;
+use crate::transaction::{Transaction, TransactionType, SECONDS_PER_DAY};
+use crate::tx::TxId;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::{collections::BTreeMap, fs::File, io::Write, path::Path, str::FromStr};
+
+/// What happens to a transaction that breaks one of the `Rules`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RuleAction {
+    /// It's applied and reported as suspicious.
+    #[default]
+    Flag,
+    /// It's rejected and reported as suspicious.
+    Reject,
+}
+
+impl FromStr for RuleAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "flag" => Ok(RuleAction::Flag),
+            "reject" => Ok(RuleAction::Reject),
+            _ => Err(format!("Unknown rule action {s}")),
+        }
+    }
+}
+
+/// Velocity and AML limits checked before each deposit and withdrawal is applied, `None` is no limit.
+/// Days go by the `timestamp` column, rows without one all count towards the same day.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Rules {
+    pub max_deposits_per_day: Option<u32>,
+    /// Largest single withdrawal.
+    pub max_withdrawal: Option<Decimal>,
+    /// Most a client can deposit in each currency, in total.
+    pub max_total_in: Option<Decimal>,
+    /// Most a client can withdraw in each currency, in total.
+    pub max_total_out: Option<Decimal>,
+    pub action: RuleAction,
+}
+
+/// One of the `Rules`.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Rule {
+    DepositsPerDay,
+    MaxWithdrawal,
+    TotalIn,
+    TotalOut,
+    /// The `RiskScorer` flagged or rejected it.
+    RiskScore,
+}
+
+impl Rule {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Rule::DepositsPerDay => "deposits-per-day",
+            Rule::MaxWithdrawal => "max-withdrawal",
+            Rule::TotalIn => "total-in",
+            Rule::TotalOut => "total-out",
+            Rule::RiskScore => "risk-score",
+        }
+    }
+}
+
+/// A transaction that broke a rule, for the suspicious activity report.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SuspiciousActivity {
+    pub client: ClientId,
+    pub tx: TxId,
+    pub r#type: TransactionType,
+    #[serde(with = "rust_decimal::serde::str")]
+    pub amount: Decimal,
+    pub currency: Currency,
+    pub rule: Rule,
+    /// Whether it was rejected rather than flagged.
+    pub rejected: bool,
+}
+
+/// What a client has done so far, for checking the `Rules` and scoring their transactions.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Velocity {
+    day: Option<u64>,
+    deposits_today: u32,
+    out_today: BTreeMap<Currency, Decimal>,
+    total_in: BTreeMap<Currency, Decimal>,
+    total_out: BTreeMap<Currency, Decimal>,
+    pub disputes: u32,
+    pub chargebacks: u32,
+}
+
+impl Rules {
+    /// Whether there are any rules to check.
+    pub(crate) fn enabled(&self) -> bool {
+        self.max_deposits_per_day.is_some()
+            || self.max_withdrawal.is_some()
+            || self.max_total_in.is_some()
+            || self.max_total_out.is_some()
+    }
+
+    /// The rules `transaction` would break, `velocity` is what the client has done so far.
+    pub(crate) fn check(
+        &self,
+        transaction: &Transaction,
+        currency: Currency,
+        velocity: Option<&Velocity>,
+    ) -> Vec<Rule> {
+        let mut broken = Vec::new();
+
+        match *transaction {
+            Transaction::Deposit { amount, .. } => {
+                let deposits_today =
+                    velocity.map_or(0, |velocity| velocity.deposits_on(transaction));
+                let total =
+                    velocity.map_or(Decimal::ZERO, |velocity| velocity.total_in(currency)) + amount;
+
+                if self
+                    .max_deposits_per_day
+                    .is_some_and(|max| deposits_today >= max)
+                {
+                    broken.push(Rule::DepositsPerDay);
+                }
+
+                if self.max_total_in.is_some_and(|max| total > max) {
+                    broken.push(Rule::TotalIn);
+                }
+            }
+            Transaction::Withdrawal { amount, .. } => {
+                let total = velocity.map_or(Decimal::ZERO, |velocity| velocity.total_out(currency))
+                    + amount;
+
+                if self.max_withdrawal.is_some_and(|max| amount > max) {
+                    broken.push(Rule::MaxWithdrawal);
+                }
+
+                if self.max_total_out.is_some_and(|max| total > max) {
+                    broken.push(Rule::TotalOut);
+                }
+            }
+            _ => {}
+        }
+
+        broken
+    }
+}
+
+impl Velocity {
+    /// Called once `transaction` has been applied.
+    pub(crate) fn record(&mut self, transaction: &Transaction, currency: Currency) {
+        if let Transaction::Deposit { .. } | Transaction::Withdrawal { .. } = transaction {
+            let day = day(transaction);
+
+            if self.day != day {
+                self.day = day;
+                self.deposits_today = 0;
+                self.out_today.clear();
+            }
+        }
+
+        match *transaction {
+            Transaction::Deposit { amount, .. } => {
+                self.deposits_today += 1;
+                *self.total_in.entry(currency).or_default() += amount;
+            }
+            Transaction::Withdrawal { amount, .. } => {
+                *self.out_today.entry(currency).or_default() += amount;
+                *self.total_out.entry(currency).or_default() += amount;
+            }
+            Transaction::Dispute { .. } => self.disputes += 1,
+            Transaction::Chargeback { .. } => self.chargebacks += 1,
+            _ => {}
+        }
+    }
+
+    /// Deposits made on the day of `transaction`.
+    pub(crate) fn deposits_on(&self, transaction: &Transaction) -> u32 {
+        if self.day == day(transaction) {
+            self.deposits_today
+        } else {
+            0
+        }
+    }
+
+    /// Withdrawn in `currency` on the day of `transaction`.
+    pub(crate) fn withdrawn_on(&self, transaction: &Transaction, currency: Currency) -> Decimal {
+        if self.day == day(transaction) {
+            self.out_today.get(&currency).copied().unwrap_or_default()
+        } else {
+            Decimal::ZERO
+        }
+    }
+
+    pub(crate) fn total_in(&self, currency: Currency) -> Decimal {
+        self.total_in.get(&currency).copied().unwrap_or_default()
+    }
+
+    pub(crate) fn total_out(&self, currency: Currency) -> Decimal {
+        self.total_out.get(&currency).copied().unwrap_or_default()
+    }
+}
+
+fn day(transaction: &Transaction) -> Option<u64> {
+    transaction
+        .timestamp()
+        .map(|timestamp| timestamp / SECONDS_PER_DAY)
+}
+
+/// Writes suspicious activity as csv, for compliance to review.
+pub struct SuspiciousActivityWriter<W: Write> {
+    writer: csv::Writer<W>,
+}
+
+impl SuspiciousActivityWriter<File> {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, csv::Error> {
+        Self::new(File::create(path)?)
+    }
+}
+
+impl<W: Write> SuspiciousActivityWriter<W> {
+    pub fn new(writer: W) -> Result<Self, csv::Error> {
+        let mut writer = csv::Writer::from_writer(writer);
+        writer.write_record([
+            "client", "tx", "type", "amount", "currency", "rule", "action",
+        ])?;
+
+        Ok(Self { writer })
+    }
+
+    pub fn write(&mut self, activity: &SuspiciousActivity) -> Result<(), csv::Error> {
+        self.writer.serialize((
+            activity.client,
+            activity.tx,
+            activity.r#type.as_str(),
+            activity.amount.to_string(),
+            activity.currency.as_str(),
+            activity.rule.as_str(),
+            if activity.rejected {
+                "rejected"
+            } else {
+                "flagged"
+            },
+        ))
+    }
+
+    pub fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.writer.flush()
+    }
+}
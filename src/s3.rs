@@ -0,0 +1,83 @@
+use bytes::Bytes;
+use futures::{stream::BoxStream, StreamExt};
+use object_store::{aws::AmazonS3Builder, path::Path, ObjectStore, PutPayload};
+use std::io::{ErrorKind, Read};
+use tokio::runtime::Runtime;
+
+/// Reads an `s3://bucket/key` object as it's downloaded, rather than downloading it first. Credentials and the
+/// region come from the usual `AWS_*` environment variables. Don't use it from inside an async runtime.
+pub struct S3Reader {
+    runtime: Runtime,
+    stream: BoxStream<'static, object_store::Result<Bytes>>,
+    chunk: Bytes,
+}
+
+impl S3Reader {
+    pub fn open(url: &str) -> Result<Self, std::io::Error> {
+        let runtime = runtime()?;
+        let (store, path) = store(url)?;
+        let stream = runtime
+            .block_on(store.get(&path))
+            .map_err(std::io::Error::other)?
+            .into_stream();
+
+        Ok(Self {
+            runtime,
+            stream,
+            chunk: Bytes::new(),
+        })
+    }
+}
+
+impl Read for S3Reader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.runtime.block_on(self.stream.next()) {
+                Some(chunk) => self.chunk = chunk.map_err(std::io::Error::other)?,
+                None => return Ok(0),
+            }
+        }
+
+        let read = buf.len().min(self.chunk.len());
+        buf[..read].copy_from_slice(&self.chunk.split_to(read));
+
+        Ok(read)
+    }
+}
+
+/// Uploads `bytes` as the `s3://bucket/key` object, replacing it in one go. Credentials and the region come
+/// from the usual `AWS_*` environment variables.
+pub fn write_s3(url: &str, bytes: Vec<u8>) -> Result<(), std::io::Error> {
+    let (store, path) = store(url)?;
+    runtime()?
+        .block_on(store.put(&path, PutPayload::from(bytes)))
+        .map_err(std::io::Error::other)?;
+
+    Ok(())
+}
+
+fn runtime() -> Result<Runtime, std::io::Error> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+}
+
+fn store(url: &str) -> Result<(impl ObjectStore, Path), std::io::Error> {
+    let (bucket, key) = url
+        .strip_prefix("s3://")
+        .and_then(|rest| rest.split_once('/'))
+        .filter(|(bucket, key)| !bucket.is_empty() && !key.is_empty())
+        .ok_or_else(|| {
+            std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{url} isn't an s3://bucket/key url"),
+            )
+        })?;
+
+    let store = AmazonS3Builder::from_env()
+        .with_bucket_name(bucket)
+        .build()
+        .map_err(std::io::Error::other)?;
+
+    Ok((store, Path::from(key)))
+}
@@ -0,0 +1,235 @@
+use crate::account::Account;
+use crate::actor::ClientActors;
+use crate::client::ClientId;
+use crate::error::TransactionError;
+use crate::events::Event;
+use crate::transaction::{lock, SharedProcessor, Transaction, TransactionProcessor};
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path, Query, State,
+    },
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Events not yet sent to a slow websocket before it starts missing them.
+const EVENT_BUFFER: usize = 1024;
+
+/// Where the API's accounts are kept.
+#[derive(Clone)]
+enum Accounts {
+    Locked(SharedProcessor),
+    Actors(Arc<ClientActors>),
+}
+
+#[derive(Clone)]
+struct AppState {
+    accounts: Accounts,
+    events: broadcast::Sender<Event>,
+}
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    client: Option<ClientId>,
+}
+
+/// `POST /transactions` applies a transaction in the same schema as `--format jsonl`, `GET /accounts` lists
+/// the accounts and `GET /accounts/:client` gets one. `GET /events` is a websocket sending every event as
+/// json, or only the events about one client's account with `?client=`.
+pub fn http_router(transaction_processor: SharedProcessor) -> Router {
+    let events = events(&mut lock(&transaction_processor));
+
+    router(AppState {
+        accounts: Accounts::Locked(transaction_processor),
+        events,
+    })
+}
+
+/// Like `http_router` but `transaction_processor`'s accounts are moved to `ClientActors` on `shards` threads,
+/// so requests for unrelated clients don't wait on each other. The actors are returned too, for snapshots and
+/// the like. A transfer between clients on different shards is rejected.
+pub fn http_router_sharded(
+    transaction_processor: &mut TransactionProcessor,
+    shards: usize,
+) -> (Router, Arc<ClientActors>) {
+    // Before spawning, so every shard gets the listener
+    let events = events(transaction_processor);
+    let actors = Arc::new(ClientActors::spawn(transaction_processor, shards));
+
+    let router = router(AppState {
+        accounts: Accounts::Actors(actors.clone()),
+        events,
+    });
+
+    (router, actors)
+}
+
+fn events(transaction_processor: &mut TransactionProcessor) -> broadcast::Sender<Event> {
+    let (events, _) = broadcast::channel(EVENT_BUFFER);
+    let sender = events.clone();
+    transaction_processor.subscribe(move |event| {
+        // Fails when nobody is subscribed.
+        let _ = sender.send(event.clone());
+    });
+
+    events
+}
+
+fn router(state: AppState) -> Router {
+    Router::new()
+        .route("/transactions", post(submit_transaction))
+        .route("/accounts", get(accounts))
+        .route("/accounts/:client", get(account))
+        .route("/events", get(events_socket))
+        .with_state(state)
+}
+
+/// Serves `http_router` until `shutdown` completes.
+pub async fn serve_http<F>(
+    listener: tokio::net::TcpListener,
+    transaction_processor: SharedProcessor,
+    shutdown: F,
+) -> Result<(), std::io::Error>
+where
+    F: std::future::Future<Output = ()> + Send + 'static,
+{
+    serve_router(listener, http_router(transaction_processor), shutdown).await
+}
+
+/// Serves `router`, e.g. from `http_router_sharded`, until `shutdown` completes.
+pub async fn serve_router<F>(
+    listener: tokio::net::TcpListener,
+    router: Router,
+    shutdown: F,
+) -> Result<(), std::io::Error>
+where
+    F: std::future::Future<Output = ()> + Send + 'static,
+{
+    axum::serve(listener, router)
+        .with_graceful_shutdown(shutdown)
+        .await
+}
+
+/// Runs `f` on a blocking thread, as the actors are waited for on a channel.
+async fn blocking<T, F>(actors: Arc<ClientActors>, f: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce(&ClientActors) -> T + Send + 'static,
+{
+    tokio::task::spawn_blocking(move || f(&actors))
+        .await
+        .unwrap_or_else(|error| std::panic::resume_unwind(error.into_panic()))
+}
+
+async fn submit_transaction(
+    State(AppState { accounts, .. }): State<AppState>,
+    Json(transaction): Json<Transaction>,
+) -> Response {
+    let throttle = match &accounts {
+        Accounts::Locked(transaction_processor) => lock(transaction_processor).throttle(),
+        Accounts::Actors(actors) => actors.throttle(),
+    };
+
+    if let Some(throttle) = throttle {
+        tokio::time::sleep(throttle.reserve()).await;
+    }
+
+    let result = match accounts {
+        Accounts::Locked(transaction_processor) => {
+            lock(&transaction_processor).process(&transaction)
+        }
+        Accounts::Actors(actors) => {
+            blocking(actors, move |actors| actors.process(transaction)).await
+        }
+    };
+
+    match result {
+        Ok(()) => (StatusCode::OK, Json(json!({ "tx": transaction.tx() }))).into_response(),
+        Err(error @ TransactionError::Store(_)) => {
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, &error)
+        }
+        Err(error) => error_response(StatusCode::UNPROCESSABLE_ENTITY, &error),
+    }
+}
+
+async fn accounts(State(AppState { accounts, .. }): State<AppState>) -> Json<Vec<Account>> {
+    let mut accounts: Vec<_> = match accounts {
+        Accounts::Locked(transaction_processor) => {
+            lock(&transaction_processor).accounts().cloned().collect()
+        }
+        Accounts::Actors(actors) => blocking(actors, ClientActors::accounts).await,
+    };
+    accounts.sort_by_key(|account| account.client);
+
+    Json(accounts)
+}
+
+async fn account(
+    State(AppState { accounts, .. }): State<AppState>,
+    Path(client): Path<ClientId>,
+) -> Response {
+    let account = match accounts {
+        Accounts::Locked(transaction_processor) => {
+            lock(&transaction_processor).account(client).cloned()
+        }
+        Accounts::Actors(actors) => blocking(actors, move |actors| actors.account(client)).await,
+    };
+
+    match account {
+        Some(account) => Json(account).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn events_socket(
+    State(AppState { events, .. }): State<AppState>,
+    Query(query): Query<EventsQuery>,
+    socket: WebSocketUpgrade,
+) -> Response {
+    let events = events.subscribe();
+    socket.on_upgrade(move |socket| send_events(socket, events, query.client))
+}
+
+async fn send_events(
+    mut socket: WebSocket,
+    mut events: broadcast::Receiver<Event>,
+    client: Option<ClientId>,
+) {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                tracing::warn!(missed, "Websocket is too slow, skipped events");
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+
+        if client.is_some_and(|client| !event.involves(client)) {
+            continue;
+        }
+
+        let message = match serde_json::to_string(&event) {
+            Ok(message) => message,
+            Err(error) => {
+                tracing::warn!(%error, "Couldn't serialize event");
+                continue;
+            }
+        };
+
+        if socket.send(Message::Text(message)).await.is_err() {
+            break;
+        }
+    }
+}
+
+fn error_response(status: StatusCode, error: &TransactionError) -> Response {
+    (status, Json(json!({ "error": error.to_string() }))).into_response()
+}
@@ -0,0 +1,286 @@
+use crate::account::{Account, Balance};
+use crate::client::ClientId;
+use crate::currency::Currency;
+use crate::store::TransactionStore;
+use crate::transaction::{Transaction, TransactionProcessor, TransactionRecord};
+use crate::tx::TxId;
+use rust_decimal::Decimal;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+};
+
+/// What the engine is expected to do, checked against it by a `Simulation`.
+pub trait Model {
+    /// Applies `transaction`, returning whether the engine should accept it.
+    fn apply(&mut self, transaction: &Transaction) -> bool;
+
+    /// What `client`'s account should be, `None` is the same as an account without any funds.
+    fn account(&self, client: ClientId) -> Option<Account>;
+}
+
+/// Where a `Simulation` found the engine and the model disagree, or an invariant broken.
+#[derive(Debug, Clone)]
+pub struct Divergence {
+    /// Index of the transaction in the sequence.
+    pub step: usize,
+    pub transaction: Transaction,
+    pub message: String,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "step {} ({} client {} tx {}): {}",
+            self.step,
+            self.transaction.transaction_type(),
+            self.transaction.client(),
+            self.transaction.tx(),
+            self.message
+        )
+    }
+}
+
+impl std::error::Error for Divergence {}
+
+/// Applies the same transactions to a `TransactionProcessor` and a `Model`. After each one it checks they
+/// agree on whether it was accepted and on the accounts it touched, that a rejected transaction didn't change
+/// anything and that no held funds are negative. For property testing extensions against the engine.
+pub struct Simulation<M, S = HashMap<TxId, TransactionRecord>> {
+    processor: TransactionProcessor<S>,
+    model: M,
+    step: usize,
+}
+
+impl<M: Model> Simulation<M> {
+    pub fn new(model: M) -> Self {
+        Self::with_processor(TransactionProcessor::new(), model)
+    }
+}
+
+impl<M: Model, S: TransactionStore> Simulation<M, S> {
+    /// For checking a processor with its own config, store, handlers etc.
+    pub fn with_processor(processor: TransactionProcessor<S>, model: M) -> Self {
+        Self {
+            processor,
+            model,
+            step: 0,
+        }
+    }
+
+    /// Stops at the first divergence.
+    pub fn run<I>(&mut self, transactions: I) -> Result<(), Box<Divergence>>
+    where
+        I: IntoIterator<Item = Transaction>,
+    {
+        transactions
+            .into_iter()
+            .try_for_each(|transaction| self.step(&transaction))
+    }
+
+    /// Applies `transaction` to both and checks them.
+    pub fn step(&mut self, transaction: &Transaction) -> Result<(), Box<Divergence>> {
+        let step = self.step;
+        self.step += 1;
+
+        let divergence = |message: String| {
+            Box::new(Divergence {
+                step,
+                transaction: *transaction,
+                message,
+            })
+        };
+
+        let clients = match *transaction {
+            Transaction::Transfer { client, to, .. } => vec![client, to],
+            _ => vec![transaction.client()],
+        };
+        let before: Vec<_> = clients
+            .iter()
+            .map(|&client| funds(self.processor.account(client)))
+            .collect();
+
+        let result = self.processor.process(transaction);
+
+        match (&result, self.model.apply(transaction)) {
+            (Ok(()), false) => {
+                return Err(divergence(
+                    "the engine accepted it but the model rejected it".to_string(),
+                ));
+            }
+            (Err(error), true) => {
+                return Err(divergence(format!(
+                    "the engine rejected it ({error}) but the model accepted it"
+                )));
+            }
+            _ => {}
+        }
+
+        for (&client, before) in clients.iter().zip(before) {
+            let after = funds(self.processor.account(client));
+
+            if result.is_err() && after != before {
+                return Err(divergence(format!(
+                    "client {client}'s account changed although it was rejected"
+                )));
+            }
+
+            if let Some((currency, _)) = after
+                .1
+                .iter()
+                .find(|(_, balance)| balance.held < Decimal::ZERO)
+            {
+                return Err(divergence(format!(
+                    "client {client}'s held {currency} funds are negative"
+                )));
+            }
+
+            let expected = funds(self.model.account(client).as_ref());
+
+            if after != expected {
+                return Err(divergence(format!(
+                    "client {client}'s account is {after:?} but the model expects {expected:?}"
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn processor(&self) -> &TransactionProcessor<S> {
+        &self.processor
+    }
+
+    pub fn model(&self) -> &M {
+        &self.model
+    }
+}
+
+/// Whether the account is locked and its balances, leaving out empty ones so they compare the same as none.
+fn funds(account: Option<&Account>) -> (bool, BTreeMap<Currency, Balance>) {
+    account.map_or_else(Default::default, |account| {
+        (
+            account.locked,
+            account
+                .balances
+                .iter()
+                .filter(|(_, balance)| **balance != Balance::default())
+                .map(|(&currency, balance)| (currency, balance.clone()))
+                .collect(),
+        )
+    })
+}
+
+/// The original rules on their own: deposits, withdrawals, disputes, resolves and chargebacks with the
+/// default `Config`. Other transactions aren't modelled so are expected to be rejected, wrap it in a
+/// `Model` of your own to cover them.
+#[derive(Debug, Clone, Default)]
+pub struct ReferenceModel {
+    accounts: HashMap<ClientId, Account>,
+    records: HashMap<TxId, Record>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Record {
+    client: ClientId,
+    amount: Decimal,
+    currency: Currency,
+    deposit: bool,
+    disputed: bool,
+    /// Resolved or charged back, it can't be disputed again.
+    closed: bool,
+}
+
+impl Model for ReferenceModel {
+    fn apply(&mut self, transaction: &Transaction) -> bool {
+        let client = transaction.client();
+        let currency = transaction.currency().unwrap_or_default();
+
+        match *transaction {
+            Transaction::Deposit { tx, amount, .. }
+            | Transaction::Withdrawal { tx, amount, .. } => {
+                let deposit = matches!(transaction, Transaction::Deposit { .. });
+                let account = self
+                    .accounts
+                    .entry(client)
+                    .or_insert_with(|| Account::new(client));
+                let balance = account.balances.entry(currency).or_default();
+
+                if self.records.contains_key(&tx)
+                    || amount < Decimal::ZERO
+                    || (!deposit && balance.available < amount)
+                {
+                    return false;
+                }
+
+                if deposit {
+                    balance.available += amount;
+                } else {
+                    balance.available -= amount;
+                }
+
+                self.records.insert(
+                    tx,
+                    Record {
+                        client,
+                        amount,
+                        currency,
+                        deposit,
+                        disputed: false,
+                        closed: false,
+                    },
+                );
+            }
+            Transaction::Dispute { tx, .. }
+            | Transaction::Resolve { tx, .. }
+            | Transaction::Chargeback { tx, .. } => {
+                let dispute = matches!(transaction, Transaction::Dispute { .. });
+                let Some(record) = self.records.get_mut(&tx).filter(|record| {
+                    record.deposit
+                        && record.client == client
+                        && if dispute {
+                            !record.disputed && !record.closed
+                        } else {
+                            record.disputed
+                        }
+                }) else {
+                    return false;
+                };
+
+                let account = self
+                    .accounts
+                    .entry(client)
+                    .or_insert_with(|| Account::new(client));
+                let balance = account.balances.entry(record.currency).or_default();
+
+                match *transaction {
+                    Transaction::Dispute { .. } => {
+                        balance.available -= record.amount;
+                        balance.held += record.amount;
+                        record.disputed = true;
+                    }
+                    Transaction::Resolve { .. } => {
+                        balance.held -= record.amount;
+                        balance.available += record.amount;
+                        record.disputed = false;
+                        record.closed = true;
+                    }
+                    _ => {
+                        balance.held -= record.amount;
+                        account.locked = true;
+                        record.disputed = false;
+                        record.closed = true;
+                    }
+                }
+            }
+            _ => return false,
+        }
+
+        true
+    }
+
+    fn account(&self, client: ClientId) -> Option<Account> {
+        self.accounts.get(&client).cloned()
+    }
+}
@@ -0,0 +1,434 @@
+use crate::account::{Account, Balance};
+use crate::client::ClientId;
+use crate::currency::Currency;
+use crate::transaction::{
+    DisputedState, RecordKind, Timeline, TransactionProcessor, TransactionRecord, TransactionType,
+};
+use crate::tx::TxId;
+use rusqlite::{params, types::Type, Connection, OptionalExtension, Row};
+use std::{collections::HashSet, path::Path, str::FromStr};
+
+/// Type of the client columns, text keeps string ids' leading zeros.
+#[cfg(not(feature = "string-client-ids"))]
+const CLIENT_TYPE: &str = "INTEGER";
+#[cfg(feature = "string-client-ids")]
+const CLIENT_TYPE: &str = "TEXT";
+
+/// Type of the tx columns, UUIDs are text.
+#[cfg(not(feature = "uuid-tx-ids"))]
+const TX_TYPE: &str = "INTEGER";
+#[cfg(feature = "uuid-tx-ids")]
+const TX_TYPE: &str = "TEXT";
+
+/// A client's account and their open disputes, see `SqliteState::client`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientState {
+    pub account: Account,
+    /// Deposits that are disputed and not yet resolved or charged back, oldest tx first.
+    pub disputes: Vec<(TxId, TransactionRecord)>,
+}
+
+/// Accounts and transaction records kept in SQLite so each run applies on top of the last.
+/// Processed rows and files are kept too, so feeding an input again doesn't apply it twice.
+pub struct SqliteState {
+    connection: Connection,
+    /// Fingerprints and names of files processed since the last save.
+    files: Vec<(String, String)>,
+}
+
+impl SqliteState {
+    /// Opens the database, creating it if it doesn't exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> rusqlite::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS accounts (
+                client {CLIENT_TYPE} PRIMARY KEY,
+                locked INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS balances (
+                client {CLIENT_TYPE} NOT NULL,
+                currency TEXT NOT NULL,
+                available TEXT NOT NULL,
+                held TEXT NOT NULL,
+                shortfall TEXT NOT NULL,
+                PRIMARY KEY (client, currency)
+            );
+            CREATE TABLE IF NOT EXISTS transactions (
+                tx {TX_TYPE} PRIMARY KEY,
+                client {CLIENT_TYPE} NOT NULL,
+                amount TEXT NOT NULL,
+                disputed TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                currency TEXT NOT NULL,
+                shortfall TEXT NOT NULL,
+                timestamp INTEGER,
+                disputed_at INTEGER
+            );
+            CREATE TABLE IF NOT EXISTS applied (
+                type TEXT NOT NULL,
+                tx {TX_TYPE} NOT NULL,
+                cycle INTEGER NOT NULL,
+                PRIMARY KEY (type, tx, cycle)
+            );
+            CREATE TABLE IF NOT EXISTS clock (
+                id INTEGER PRIMARY KEY CHECK (id = 0),
+                clock INTEGER,
+                accrued_until INTEGER
+            );
+            CREATE TABLE IF NOT EXISTS scheduled (
+                position INTEGER PRIMARY KEY,
+                effective INTEGER NOT NULL,
+                json TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS files (
+                fingerprint TEXT PRIMARY KEY,
+                file TEXT NOT NULL
+            );"
+        ))?;
+
+        Ok(Self {
+            connection,
+            files: Vec::new(),
+        })
+    }
+
+    /// Whether a file with this fingerprint has been processed by this or an earlier run.
+    pub fn processed(&self, fingerprint: &str) -> rusqlite::Result<bool> {
+        if self
+            .files
+            .iter()
+            .any(|(processed, _)| processed == fingerprint)
+        {
+            return Ok(true);
+        }
+
+        self.connection
+            .query_row(
+                "SELECT 1 FROM files WHERE fingerprint = ?1",
+                [fingerprint],
+                |_| Ok(()),
+            )
+            .optional()
+            .map(|found| found.is_some())
+    }
+
+    /// Recorded as processed when the state is next saved.
+    pub fn add_file(&mut self, fingerprint: String, file: String) {
+        self.files.push((fingerprint, file));
+    }
+
+    pub fn load(&self) -> rusqlite::Result<TransactionProcessor> {
+        let mut transaction_processor = TransactionProcessor::new();
+
+        let mut statement = self
+            .connection
+            .prepare("SELECT client, locked FROM accounts")?;
+        let accounts = statement.query_map([], |row| {
+            let mut account = Account::new(row.get(0)?);
+            account.locked = row.get(1)?;
+            Ok(account)
+        })?;
+
+        for account in accounts {
+            let account = account?;
+            transaction_processor
+                .accounts
+                .insert(account.client, account);
+        }
+
+        let mut statement = self
+            .connection
+            .prepare("SELECT client, currency, available, held, shortfall FROM balances")?;
+        let balances = statement.query_map([], |row| {
+            let balance = Balance {
+                available: decimal(row, 2)?,
+                held: decimal(row, 3)?,
+                shortfall: decimal(row, 4)?,
+            };
+
+            Ok((row.get::<_, ClientId>(0)?, currency(row, 1)?, balance))
+        })?;
+
+        for balance in balances {
+            let (client, currency, balance) = balance?;
+            transaction_processor
+                .accounts
+                .entry(client)
+                .or_insert_with(|| Account::new(client))
+                .balances
+                .insert(currency, balance);
+        }
+
+        let mut statement = self.connection.prepare(
+            "SELECT tx, client, amount, disputed, kind, currency, shortfall, timestamp, disputed_at
+            FROM transactions",
+        )?;
+        let records = statement.query_map([], record)?;
+
+        for record in records {
+            let (tx, record) = record?;
+            transaction_processor.transactions.insert(tx, record);
+        }
+
+        let mut statement = self
+            .connection
+            .prepare("SELECT type, tx, cycle FROM applied")?;
+        let applied = statement.query_map([], |row| {
+            Ok((
+                transaction_type(row, 0)?,
+                row.get::<_, TxId>(1)?,
+                row.get(2)?,
+            ))
+        })?;
+        transaction_processor.applied = Some(applied.collect::<rusqlite::Result<HashSet<_>>>()?);
+
+        let (clock, accrued_until) = self
+            .connection
+            .query_row("SELECT clock, accrued_until FROM clock", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .optional()?
+            .unwrap_or_default();
+        let mut statement = self
+            .connection
+            .prepare("SELECT effective, json FROM scheduled ORDER BY position")?;
+        let scheduled = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        transaction_processor
+            .set_timeline(Timeline {
+                accrued_until,
+                clock,
+                scheduled,
+            })
+            .map_err(|error| {
+                rusqlite::Error::FromSqlConversionFailure(1, Type::Text, error.into())
+            })?;
+        transaction_processor.open_ledger();
+
+        Ok(transaction_processor)
+    }
+
+    /// `client`'s account and open disputes, read without loading the rest of the state. `None` if the client
+    /// has no account.
+    pub fn client(&self, client: ClientId) -> rusqlite::Result<Option<ClientState>> {
+        let Some(locked) = self
+            .connection
+            .query_row(
+                "SELECT locked FROM accounts WHERE client = ?1",
+                params![client],
+                |row| row.get(0),
+            )
+            .optional()?
+        else {
+            return Ok(None);
+        };
+
+        let mut account = Account::new(client);
+        account.locked = locked;
+
+        let mut statement = self.connection.prepare(
+            "SELECT currency, available, held, shortfall FROM balances WHERE client = ?1",
+        )?;
+        let balances = statement.query_map(params![client], |row| {
+            let balance = Balance {
+                available: decimal(row, 1)?,
+                held: decimal(row, 2)?,
+                shortfall: decimal(row, 3)?,
+            };
+
+            Ok((currency(row, 0)?, balance))
+        })?;
+
+        for balance in balances {
+            let (currency, balance) = balance?;
+            account.balances.insert(currency, balance);
+        }
+
+        let mut statement = self.connection.prepare(
+            "SELECT tx, client, amount, disputed, kind, currency, shortfall, timestamp, disputed_at
+            FROM transactions WHERE client = ?1 AND disputed = ?2 ORDER BY tx",
+        )?;
+        let disputes = statement
+            .query_map(
+                params![client, disputed_name(&DisputedState::Disputed)],
+                record,
+            )?
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(Some(ClientState { account, disputes }))
+    }
+
+    /// Writes every account, transaction record, processed row and file, the clock and the scheduled
+    /// transactions in a single transaction.
+    pub fn save(&mut self, transaction_processor: &TransactionProcessor) -> rusqlite::Result<()> {
+        let timeline = transaction_processor
+            .timeline()
+            .map_err(|error| rusqlite::Error::ToSqlConversionFailure(error.into()))?;
+        let transaction = self.connection.transaction()?;
+
+        {
+            let mut statement = transaction
+                .prepare("INSERT OR REPLACE INTO accounts (client, locked) VALUES (?1, ?2)")?;
+
+            for account in transaction_processor.accounts.values() {
+                statement.execute(params![account.client, account.locked])?;
+            }
+
+            let mut statement = transaction.prepare(
+                "INSERT OR REPLACE INTO balances (client, currency, available, held, shortfall)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+
+            for account in transaction_processor.accounts.values() {
+                for (currency, balance) in &account.balances {
+                    statement.execute(params![
+                        account.client,
+                        currency.as_str(),
+                        balance.available.to_string(),
+                        balance.held.to_string(),
+                        balance.shortfall.to_string(),
+                    ])?;
+                }
+            }
+
+            let mut statement = transaction.prepare(
+                "INSERT OR REPLACE INTO transactions
+                (tx, client, amount, disputed, kind, currency, shortfall, timestamp, disputed_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            )?;
+
+            for (tx, record) in &transaction_processor.transactions {
+                statement.execute(params![
+                    tx,
+                    record.client,
+                    record.amount.to_string(),
+                    disputed_name(&record.disputed),
+                    kind_name(&record.kind),
+                    record.currency.as_str(),
+                    record.shortfall.to_string(),
+                    record.timestamp,
+                    record.disputed_at,
+                ])?;
+            }
+
+            let mut statement = transaction
+                .prepare("INSERT OR IGNORE INTO applied (type, tx, cycle) VALUES (?1, ?2, ?3)")?;
+
+            for (transaction_type, tx, cycle) in transaction_processor.applied.iter().flatten() {
+                statement.execute(params![transaction_type.as_str(), tx, cycle])?;
+            }
+
+            transaction.execute(
+                "INSERT OR REPLACE INTO clock (id, clock, accrued_until) VALUES (0, ?1, ?2)",
+                params![timeline.clock, timeline.accrued_until],
+            )?;
+
+            // Released transactions are gone from the processor, so the table's replaced
+            transaction.execute("DELETE FROM scheduled", [])?;
+            let mut statement = transaction
+                .prepare("INSERT INTO scheduled (position, effective, json) VALUES (?1, ?2, ?3)")?;
+
+            for (position, (effective, json)) in timeline.scheduled.iter().enumerate() {
+                statement.execute(params![position, effective, json])?;
+            }
+
+            let mut statement = transaction
+                .prepare("INSERT OR IGNORE INTO files (fingerprint, file) VALUES (?1, ?2)")?;
+
+            for (fingerprint, file) in &self.files {
+                statement.execute(params![fingerprint, file])?;
+            }
+        }
+
+        transaction.commit()?;
+        self.files.clear();
+        Ok(())
+    }
+}
+
+/// A row of `SELECT tx, client, amount, disputed, kind, currency, shortfall, timestamp, disputed_at`.
+fn record(row: &Row) -> rusqlite::Result<(TxId, TransactionRecord)> {
+    let record = TransactionRecord {
+        client: row.get(1)?,
+        amount: decimal(row, 2)?,
+        disputed: disputed(row, 3)?,
+        kind: kind(row, 4)?,
+        currency: currency(row, 5)?,
+        shortfall: decimal(row, 6)?,
+        timestamp: row.get(7)?,
+        disputed_at: row.get(8)?,
+    };
+
+    Ok((row.get(0)?, record))
+}
+
+/// Amounts and balances are kept as text so they're stored exactly, however many digits they have.
+fn decimal<T>(row: &Row, index: usize) -> rusqlite::Result<T>
+where
+    T: FromStr,
+    T::Err: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let value: String = row.get(index)?;
+
+    T::from_str(&value)
+        .map_err(|error| rusqlite::Error::FromSqlConversionFailure(index, Type::Text, error.into()))
+}
+
+fn currency(row: &Row, index: usize) -> rusqlite::Result<Currency> {
+    let value: String = row.get(index)?;
+
+    value
+        .parse()
+        .map_err(|_| rusqlite::Error::InvalidColumnType(index, value, Type::Text))
+}
+
+fn transaction_type(row: &Row, index: usize) -> rusqlite::Result<TransactionType> {
+    let value: String = row.get(index)?;
+
+    value
+        .parse()
+        .map_err(|_| rusqlite::Error::InvalidColumnType(index, value, Type::Text))
+}
+
+fn disputed(row: &Row, index: usize) -> rusqlite::Result<DisputedState> {
+    let value: String = row.get(index)?;
+
+    match value.as_str() {
+        "undisputed" => Ok(DisputedState::Undisputed),
+        "disputed" => Ok(DisputedState::Disputed),
+        "resolved" => Ok(DisputedState::Resolved),
+        "chargebacked" => Ok(DisputedState::Chargebacked),
+        "reversed" => Ok(DisputedState::Reversed),
+        "chargeback-reversed" => Ok(DisputedState::ChargebackReversed),
+        _ => Err(rusqlite::Error::InvalidColumnType(index, value, Type::Text)),
+    }
+}
+
+fn disputed_name(disputed: &DisputedState) -> &'static str {
+    match disputed {
+        DisputedState::Undisputed => "undisputed",
+        DisputedState::Disputed => "disputed",
+        DisputedState::Resolved => "resolved",
+        DisputedState::Chargebacked => "chargebacked",
+        DisputedState::Reversed => "reversed",
+        DisputedState::ChargebackReversed => "chargeback-reversed",
+    }
+}
+
+fn kind(row: &Row, index: usize) -> rusqlite::Result<RecordKind> {
+    let value: String = row.get(index)?;
+
+    match value.as_str() {
+        "deposit" => Ok(RecordKind::Deposit),
+        "withdrawal" => Ok(RecordKind::Withdrawal),
+        _ => Err(rusqlite::Error::InvalidColumnType(index, value, Type::Text)),
+    }
+}
+
+fn kind_name(kind: &RecordKind) -> &'static str {
+    match kind {
+        RecordKind::Deposit => "deposit",
+        RecordKind::Withdrawal => "withdrawal",
+    }
+}
@@ -0,0 +1,107 @@
+use crate::client::ClientId;
+use crate::compression::decompress;
+use crate::config::DEFAULT_PRECISION;
+use crate::currency::Currency;
+use crate::journal::JournalEntry;
+use crate::transaction::format_amount;
+use std::{
+    collections::BTreeMap,
+    io::{ErrorKind, Read, Write},
+};
+
+/// Writes `client`'s statement as csv from a journal written with `TransactionProcessor::set_journal`, a row
+/// for every transaction that changed one of their balances in the order they were applied, with the running
+/// balance after it. Transactions without an amount, like disputes and chargebacks, are in the currency
+/// whose balance they changed. Compressed journals are decompressed as they're read.
+pub fn write_statement<R: Read, W: Write>(
+    journal: R,
+    client: ClientId,
+    writer: W,
+) -> Result<(), std::io::Error> {
+    let mut writer = csv::Writer::from_writer(writer);
+    writer.write_record([
+        "date",
+        "type",
+        "tx",
+        "amount",
+        "currency",
+        "available",
+        "held",
+        "total",
+        "locked",
+    ])?;
+
+    let mut balances = BTreeMap::<Currency, _>::new();
+
+    for entry in
+        serde_json::Deserializer::from_reader(decompress(journal)?).into_iter::<JournalEntry>()
+    {
+        let entry = entry.map_err(|error| std::io::Error::new(ErrorKind::InvalidData, error))?;
+
+        if entry.client != client && entry.to != Some(client) {
+            continue;
+        }
+
+        for balance in entry
+            .balances
+            .iter()
+            .filter(|balance| balance.client == client)
+        {
+            let current = (
+                balance.available.clone(),
+                balance.held.clone(),
+                balance.locked,
+            );
+
+            if balances.insert(balance.currency, current.clone()) == Some(current) {
+                continue;
+            }
+
+            writer.write_record([
+                format_timestamp(entry.timestamp),
+                entry.r#type.to_string(),
+                entry.tx.to_string(),
+                entry
+                    .amount
+                    .map(|amount| format_amount(amount, DEFAULT_PRECISION))
+                    .unwrap_or_default(),
+                balance.currency.to_string(),
+                format_amount(balance.available.clone(), DEFAULT_PRECISION),
+                format_amount(balance.held.clone(), DEFAULT_PRECISION),
+                format_amount(balance.total.clone(), DEFAULT_PRECISION),
+                balance.locked.to_string(),
+            ])?;
+        }
+    }
+
+    writer.flush()
+}
+
+/// `YYYY-MM-DD HH:MM:SS` in UTC, from milliseconds since the unix epoch.
+fn format_timestamp(timestamp: u64) -> String {
+    let seconds = timestamp / 1000;
+    let (days, seconds) = (seconds / 86400, seconds % 86400);
+
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
@@ -0,0 +1,375 @@
+use crate::client::ClientId;
+use crate::error::StoreError;
+use crate::retry::Retry;
+use crate::transaction::TransactionRecord;
+use crate::tx::TxId;
+use std::{
+    cell::RefCell,
+    collections::{hash_map::Entry, BTreeMap, HashMap},
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Where deposits and withdrawals are kept so they can be disputed or reversed later.
+pub trait TransactionStore {
+    fn get(&self, tx: TxId) -> Result<Option<TransactionRecord>, StoreError>;
+
+    /// Inserts or replaces the record for `tx`.
+    fn insert(&mut self, tx: TxId, record: TransactionRecord) -> Result<(), StoreError>;
+}
+
+impl TransactionStore for HashMap<TxId, TransactionRecord> {
+    fn get(&self, tx: TxId) -> Result<Option<TransactionRecord>, StoreError> {
+        Ok(HashMap::get(self, &tx).copied())
+    }
+
+    fn insert(&mut self, tx: TxId, record: TransactionRecord) -> Result<(), StoreError> {
+        HashMap::insert(self, tx, record);
+        Ok(())
+    }
+}
+
+/// Reads through to `store` but keeps what's inserted to itself, so a transaction can be tried without
+/// changing the store.
+pub(crate) struct Overlay<'a, S> {
+    store: &'a S,
+    inserted: HashMap<TxId, TransactionRecord>,
+}
+
+impl<'a, S: TransactionStore> Overlay<'a, S> {
+    pub(crate) fn new(store: &'a S) -> Self {
+        Self {
+            store,
+            inserted: HashMap::new(),
+        }
+    }
+}
+
+impl<S: TransactionStore> TransactionStore for Overlay<'_, S> {
+    fn get(&self, tx: TxId) -> Result<Option<TransactionRecord>, StoreError> {
+        match self.inserted.get(&tx) {
+            Some(record) => Ok(Some(*record)),
+            None => self.store.get(tx),
+        }
+    }
+
+    fn insert(&mut self, tx: TxId, record: TransactionRecord) -> Result<(), StoreError> {
+        self.inserted.insert(tx, record);
+        Ok(())
+    }
+}
+
+/// Retries `store`'s transient errors with `retry`, so a database that's briefly unavailable doesn't fail
+/// transactions. Both operations can be retried safely, as an insert replaces the record.
+pub struct RetryStore<S> {
+    store: S,
+    retry: Retry,
+}
+
+impl<S: TransactionStore> RetryStore<S> {
+    pub fn new(store: S, retry: Retry) -> Self {
+        Self { store, retry }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.store
+    }
+}
+
+impl<S: TransactionStore> TransactionStore for RetryStore<S> {
+    fn get(&self, tx: TxId) -> Result<Option<TransactionRecord>, StoreError> {
+        self.retry.run(|| self.store.get(tx))
+    }
+
+    fn insert(&mut self, tx: TxId, record: TransactionRecord) -> Result<(), StoreError> {
+        let store = &mut self.store;
+        self.retry.run(|| store.insert(tx, record))
+    }
+}
+
+/// Keeps transaction records on disk so disputes work on more deposits than fit in memory.
+#[cfg(feature = "rocksdb")]
+pub struct RocksDbStore {
+    db: ::rocksdb::DB,
+}
+
+#[cfg(feature = "rocksdb")]
+impl RocksDbStore {
+    /// Opens the database, creating it if it doesn't exist.
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Self, StoreError> {
+        let mut options = ::rocksdb::Options::default();
+        options.create_if_missing(true);
+
+        Ok(Self {
+            db: ::rocksdb::DB::open(&options, path)?,
+        })
+    }
+}
+
+#[cfg(feature = "rocksdb")]
+impl TransactionStore for RocksDbStore {
+    fn get(&self, tx: TxId) -> Result<Option<TransactionRecord>, StoreError> {
+        self.db
+            .get(tx.to_bytes())?
+            .map(|value| decode(&value))
+            .transpose()
+    }
+
+    fn insert(&mut self, tx: TxId, record: TransactionRecord) -> Result<(), StoreError> {
+        Ok(self.db.put(tx.to_bytes(), encode(&record))?)
+    }
+}
+
+/// Keeps the most recently used records in memory and spills the rest to a temporary file, so disputes work on
+/// more deposits than fit in memory without an embedded database. A record gets the next slot in the file the
+/// first time it's spilled, and which slot each tx is in stays in memory. The file is deleted when the store is
+/// dropped.
+pub struct SpillStore {
+    inner: RefCell<Spill>,
+}
+
+struct Spill {
+    capacity: usize,
+    file: File,
+    path: PathBuf,
+    records: HashMap<TxId, Hot>,
+    /// Slot in the file of every record that's been spilled.
+    slots: HashMap<TxId, u64>,
+    /// When each record in memory was last used, the first is spilled next.
+    order: BTreeMap<u64, TxId>,
+    used: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Hot {
+    record: TransactionRecord,
+    used: u64,
+    /// Whether it's changed since it was last written to the file.
+    dirty: bool,
+}
+
+/// Roughly what each record in memory costs, with the map and ordering overhead.
+const BYTES_PER_RECORD: u64 = 128;
+
+/// Bytes of an encoded record, see `encode`.
+const RECORD: usize = 55 + ClientId::BYTES;
+
+const SLOT: u64 = RECORD as u64;
+
+impl SpillStore {
+    /// Keeps at most `capacity` records in memory.
+    pub fn new(capacity: usize) -> Result<Self, StoreError> {
+        static FILES: AtomicU64 = AtomicU64::new(0);
+
+        let path = std::env::temp_dir().join(format!(
+            "payments-{}-{}.spill",
+            std::process::id(),
+            FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+
+        Ok(Self {
+            inner: RefCell::new(Spill {
+                capacity: capacity.max(1),
+                file,
+                path,
+                records: HashMap::new(),
+                slots: HashMap::new(),
+                order: BTreeMap::new(),
+                used: 0,
+            }),
+        })
+    }
+
+    /// Keeps as many records in memory as fit in about `bytes`.
+    pub fn with_max_memory(bytes: u64) -> Result<Self, StoreError> {
+        Self::new(usize::try_from(bytes / BYTES_PER_RECORD).unwrap_or(usize::MAX))
+    }
+}
+
+impl TransactionStore for SpillStore {
+    fn get(&self, tx: TxId) -> Result<Option<TransactionRecord>, StoreError> {
+        let mut spill = self.inner.borrow_mut();
+
+        if let Some(record) = spill.touch(tx) {
+            return Ok(Some(record));
+        }
+
+        let record = spill.read(tx)?;
+
+        if let Some(record) = record {
+            spill.cache(tx, record, false)?;
+        }
+
+        Ok(record)
+    }
+
+    fn insert(&mut self, tx: TxId, record: TransactionRecord) -> Result<(), StoreError> {
+        self.inner.get_mut().cache(tx, record, true)
+    }
+}
+
+impl Spill {
+    /// The record if it's in memory, making it the most recently used.
+    fn touch(&mut self, tx: TxId) -> Option<TransactionRecord> {
+        let hot = self.records.get_mut(&tx)?;
+        self.used += 1;
+        self.order.remove(&hot.used);
+        self.order.insert(self.used, tx);
+        hot.used = self.used;
+
+        Some(hot.record)
+    }
+
+    fn cache(
+        &mut self,
+        tx: TxId,
+        record: TransactionRecord,
+        dirty: bool,
+    ) -> Result<(), StoreError> {
+        self.used += 1;
+
+        match self.records.entry(tx) {
+            Entry::Occupied(mut entry) => {
+                let hot = entry.get_mut();
+                self.order.remove(&hot.used);
+                hot.record = record;
+                hot.used = self.used;
+                hot.dirty |= dirty;
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(Hot {
+                    record,
+                    used: self.used,
+                    dirty,
+                });
+            }
+        }
+
+        self.order.insert(self.used, tx);
+
+        while self.records.len() > self.capacity {
+            let Some((&used, &tx)) = self.order.first_key_value() else {
+                break;
+            };
+
+            if let Some(hot) = self.records.get(&tx).filter(|hot| hot.dirty) {
+                let next = self.slots.len() as u64;
+                let slot = *self.slots.entry(tx).or_insert(next);
+                self.file.seek(SeekFrom::Start(slot * SLOT))?;
+                self.file.write_all(&encode(&hot.record))?;
+            }
+
+            self.order.remove(&used);
+            self.records.remove(&tx);
+        }
+
+        Ok(())
+    }
+
+    fn read(&mut self, tx: TxId) -> Result<Option<TransactionRecord>, StoreError> {
+        let Some(&slot) = self.slots.get(&tx) else {
+            return Ok(None);
+        };
+
+        let mut bytes = [0; RECORD];
+        self.file.seek(SeekFrom::Start(slot * SLOT))?;
+        self.file.read_exact(&mut bytes)?;
+        decode(&bytes).map(Some)
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// amount (16 bytes) | disputed (1 byte) | kind (1 byte) | currency (3 bytes) | shortfall (16 bytes)
+/// | has timestamp (1 byte) | timestamp (8 bytes) | has disputed at (1 byte) | disputed at (8 bytes)
+/// | client (`ClientId::BYTES`)
+fn encode(record: &TransactionRecord) -> [u8; RECORD] {
+    use crate::transaction::{DisputedState::*, RecordKind};
+
+    let mut bytes = [0; RECORD];
+    bytes[..16].copy_from_slice(&record.amount.serialize());
+    bytes[16] = match record.disputed {
+        Undisputed => 0,
+        Disputed => 1,
+        Resolved => 2,
+        Chargebacked => 3,
+        Reversed => 4,
+        ChargebackReversed => 5,
+    };
+    bytes[17] = match record.kind {
+        RecordKind::Deposit => 0,
+        RecordKind::Withdrawal => 1,
+    };
+    bytes[18..21].copy_from_slice(record.currency.as_str().as_bytes());
+    bytes[21..37].copy_from_slice(&record.shortfall.serialize());
+
+    if let Some(timestamp) = record.timestamp {
+        bytes[37] = 1;
+        bytes[38..46].copy_from_slice(&timestamp.to_be_bytes());
+    }
+
+    if let Some(disputed_at) = record.disputed_at {
+        bytes[46] = 1;
+        bytes[47..55].copy_from_slice(&disputed_at.to_be_bytes());
+    }
+
+    bytes[55..].copy_from_slice(&record.client.to_bytes());
+
+    bytes
+}
+
+fn decode(bytes: &[u8]) -> Result<TransactionRecord, StoreError> {
+    use crate::transaction::{DisputedState::*, RecordKind};
+
+    let bytes: [u8; RECORD] = bytes.try_into().map_err(|_| {
+        StoreError::new(format!("expected {RECORD} bytes but found {}", bytes.len()))
+    })?;
+
+    let mut amount = [0; 16];
+    amount.copy_from_slice(&bytes[..16]);
+    let mut shortfall = [0; 16];
+    shortfall.copy_from_slice(&bytes[21..37]);
+    let mut timestamp = [0; 8];
+    timestamp.copy_from_slice(&bytes[38..46]);
+    let mut disputed_at = [0; 8];
+    disputed_at.copy_from_slice(&bytes[47..55]);
+    let mut client = [0; ClientId::BYTES];
+    client.copy_from_slice(&bytes[55..]);
+
+    Ok(TransactionRecord {
+        amount: rust_decimal::Decimal::deserialize(amount),
+        client: ClientId::from_bytes(client).map_err(StoreError::new)?,
+        disputed: match bytes[16] {
+            0 => Undisputed,
+            1 => Disputed,
+            2 => Resolved,
+            3 => Chargebacked,
+            4 => Reversed,
+            5 => ChargebackReversed,
+            other => return Err(StoreError::new(format!("unknown disputed state {other}"))),
+        },
+        kind: match bytes[17] {
+            0 => RecordKind::Deposit,
+            1 => RecordKind::Withdrawal,
+            other => return Err(StoreError::new(format!("unknown record kind {other}"))),
+        },
+        currency: std::str::from_utf8(&bytes[18..21])
+            .map_err(|error| StoreError::new(error.to_string()))?
+            .parse()
+            .map_err(StoreError::new)?,
+        shortfall: rust_decimal::Decimal::deserialize(shortfall),
+        timestamp: (bytes[37] == 1).then(|| u64::from_be_bytes(timestamp)),
+        disputed_at: (bytes[46] == 1).then(|| u64::from_be_bytes(disputed_at)),
+    })
+}
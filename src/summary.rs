@@ -0,0 +1,153 @@
+use crate::account::Account;
+use crate::currency::Currency;
+use crate::error::RejectReason;
+use crate::events::Event;
+use crate::rejects::Rejection;
+use crate::store::TransactionStore;
+use crate::transaction::{TransactionProcessor, TransactionType};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+/// Counts what happened during a run, for a summary at the end of it.
+pub struct Summary {
+    started: Instant,
+    counts: Mutex<Counts>,
+}
+
+#[derive(Default)]
+struct Counts {
+    rows: u64,
+    malformed: u64,
+    rejected_rows: u64,
+    applied: BTreeMap<&'static str, u64>,
+    rejected: BTreeMap<&'static str, u64>,
+    deposits: BTreeMap<Currency, Decimal>,
+    withdrawals: BTreeMap<Currency, Decimal>,
+}
+
+/// The summary of a run, written as json.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SummaryReport {
+    /// Rows applied or rejected.
+    pub rows: u64,
+    /// Rows that couldn't be read as a transaction.
+    pub malformed: u64,
+    /// Every rejected row: the malformed ones, counted in `malformed`, and those whose transaction failed,
+    /// counted by type in `rejected`.
+    pub rejected_rows: u64,
+    /// Transactions applied, by type.
+    pub applied: BTreeMap<&'static str, u64>,
+    /// Transactions that failed, by type.
+    pub rejected: BTreeMap<&'static str, u64>,
+    /// Total of the deposits applied, by currency.
+    pub deposits: BTreeMap<Currency, Decimal>,
+    /// Total of the withdrawals applied, by currency.
+    pub withdrawals: BTreeMap<Currency, Decimal>,
+    pub locked_accounts: usize,
+    pub elapsed_seconds: f64,
+    pub rows_per_second: f64,
+}
+
+impl Summary {
+    /// The elapsed time is from now.
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            counts: Mutex::new(Counts::default()),
+        }
+    }
+
+    /// Counts the transactions `transaction_processor` applies and fails from now on, rows rejected for other
+    /// reasons have to be passed to `rejected_row`.
+    pub fn record<S: TransactionStore>(
+        self: &Arc<Self>,
+        transaction_processor: &mut TransactionProcessor<S>,
+    ) {
+        let base_currency = transaction_processor.config().base_currency;
+
+        let summary = self.clone();
+        transaction_processor.subscribe(move |event| {
+            if let Event::Applied(entry) = event {
+                let mut counts = summary.counts();
+                counts.rows += 1;
+                *counts.applied.entry(entry.r#type.as_str()).or_default() += 1;
+
+                let totals = match entry.r#type {
+                    TransactionType::Deposit => &mut counts.deposits,
+                    TransactionType::Withdrawal => &mut counts.withdrawals,
+                    _ => return,
+                };
+
+                let currency = entry.currency.unwrap_or(base_currency);
+                *totals.entry(currency).or_default() += entry.amount.unwrap_or_default();
+            }
+        });
+
+        let summary = self.clone();
+        transaction_processor.on_rejected(move |transaction, _| {
+            *summary
+                .counts()
+                .rejected
+                .entry(transaction.transaction_type().as_str())
+                .or_default() += 1;
+        });
+    }
+
+    /// Call with every row rejected while processing.
+    pub fn rejected_row(&self, rejection: &Rejection) {
+        let mut counts = self.counts();
+        counts.rows += 1;
+        counts.rejected_rows += 1;
+
+        if let RejectReason::Malformed(_) = rejection.reason {
+            counts.malformed += 1;
+        }
+    }
+
+    /// `accounts` are the accounts at the end of the run.
+    pub fn report<'a, I>(&self, accounts: I) -> SummaryReport
+    where
+        I: IntoIterator<Item = &'a Account>,
+    {
+        let counts = self.counts();
+        let elapsed = self.started.elapsed().as_secs_f64();
+
+        SummaryReport {
+            rows: counts.rows,
+            malformed: counts.malformed,
+            rejected_rows: counts.rejected_rows,
+            applied: counts.applied.clone(),
+            rejected: counts.rejected.clone(),
+            deposits: counts.deposits.clone(),
+            withdrawals: counts.withdrawals.clone(),
+            locked_accounts: accounts
+                .into_iter()
+                .filter(|account| account.locked)
+                .count(),
+            elapsed_seconds: elapsed,
+            rows_per_second: if elapsed > 0.0 {
+                counts.rows as f64 / elapsed
+            } else {
+                0.0
+            },
+        }
+    }
+
+    /// Counting carries on if a thread panicked.
+    fn counts(&self) -> std::sync::MutexGuard<'_, Counts> {
+        self.counts
+            .lock()
+            .unwrap_or_else(|poison| poison.into_inner())
+    }
+}
+
+impl Default for Summary {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,132 @@
+use crate::actor::ClientActors;
+use crate::input::{self, InputFormat};
+use crate::rejects::Rejection;
+use crate::transaction::{lock, SharedProcessor};
+use std::{
+    net::{SocketAddr, TcpListener},
+    sync::{mpsc, Arc},
+    thread,
+};
+
+/// Applies transactions from every connection to `listener` as they arrive, on a thread per connection.
+/// Each connection is read like a file in `format`, so csv connections start with a header row and are laid
+/// out as the processor's `csv_options`.
+/// `on_reject` is called with the connection's address for every row that is badly formatted or fails.
+/// Rows are paced by the processor's `throttle`, if it has one.
+pub fn ingest_tcp<F>(
+    listener: TcpListener,
+    transaction_processor: SharedProcessor,
+    format: InputFormat,
+    on_reject: F,
+) -> Result<(), std::io::Error>
+where
+    F: Fn(SocketAddr, Rejection) -> Result<(), std::io::Error> + Send + Sync + 'static,
+{
+    let on_reject = Arc::new(on_reject);
+
+    for stream in listener.incoming() {
+        let (peer, stream) = match stream.and_then(|stream| Ok((stream.peer_addr()?, stream))) {
+            Ok(connection) => connection,
+            Err(error) => {
+                tracing::warn!(%error, "Couldn't accept a connection");
+                continue;
+            }
+        };
+
+        let transaction_processor = transaction_processor.clone();
+        let on_reject = on_reject.clone();
+
+        thread::spawn(move || {
+            tracing::info!(%peer, "Connected");
+
+            let (options, throttle) = {
+                let transaction_processor = lock(&transaction_processor);
+                (
+                    transaction_processor.csv_options().clone(),
+                    transaction_processor.throttle(),
+                )
+            };
+            let result = input::read(stream, format, &options, |row| {
+                // Waits without the lock so the other connections and readers aren't held up
+                if let Some(throttle) = &throttle {
+                    throttle.wait();
+                }
+
+                lock(&transaction_processor)
+                    .process_row(&row, &mut |rejection| on_reject(peer, rejection))
+            });
+
+            match result {
+                Ok(()) => tracing::info!(%peer, "Disconnected"),
+                Err(error) => tracing::warn!(%peer, %error, "Connection failed"),
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Like `ingest_tcp` but rows are applied by `actors`, so connections sending transactions for unrelated
+/// clients don't wait on each other. A connection's rows are sent to their shards as they're read, and its
+/// rejections passed to `on_reject` as they come back, the last once the shards have got through its rows.
+/// Rows are paced by the actors' `throttle`, if the processor had one.
+pub fn ingest_tcp_sharded<F>(
+    listener: TcpListener,
+    actors: Arc<ClientActors>,
+    format: InputFormat,
+    on_reject: F,
+) -> Result<(), std::io::Error>
+where
+    F: Fn(SocketAddr, Rejection) -> Result<(), std::io::Error> + Send + Sync + 'static,
+{
+    let on_reject = Arc::new(on_reject);
+
+    for stream in listener.incoming() {
+        let (peer, stream) = match stream.and_then(|stream| Ok((stream.peer_addr()?, stream))) {
+            Ok(connection) => connection,
+            Err(error) => {
+                tracing::warn!(%error, "Couldn't accept a connection");
+                continue;
+            }
+        };
+
+        let actors = actors.clone();
+        let on_reject = on_reject.clone();
+
+        thread::spawn(move || {
+            tracing::info!(%peer, "Connected");
+
+            let throttle = actors.throttle();
+            let (rejected_sender, rejected) = mpsc::channel();
+            let result = input::read(stream, format, actors.csv_options(), |row| {
+                for rejection in rejected.try_iter() {
+                    on_reject(peer, rejection)?;
+                }
+
+                if let Some(throttle) = &throttle {
+                    throttle.wait();
+                }
+
+                match actors.dispatch(&row, &rejected_sender) {
+                    Ok(()) => Ok(()),
+                    Err(rejection) => on_reject(peer, rejection),
+                }
+            });
+
+            drop(rejected_sender);
+
+            // Waits for the shards to get through the connection's rows
+            let result = rejected
+                .into_iter()
+                .try_for_each(|rejection| on_reject(peer, rejection))
+                .and(result);
+
+            match result {
+                Ok(()) => tracing::info!(%peer, "Disconnected"),
+                Err(error) => tracing::warn!(%peer, %error, "Connection failed"),
+            }
+        });
+    }
+
+    Ok(())
+}
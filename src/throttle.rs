@@ -0,0 +1,62 @@
+use std::{
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// A token bucket limiting transactions to `max_tps` a second, so a replay into a long running mode doesn't
+/// overwhelm a shared database downstream. The bucket holds a second's worth of tokens, so a quiet spell
+/// allows a short burst. Callers that find it empty are given a time to wait until their token, in the order
+/// they asked, rather than polling.
+pub struct Throttle {
+    max_tps: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    /// Tokens left, negative when callers are waiting for ones that haven't been added yet.
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Throttle {
+    /// Starts full, `max_tps` is at least 1.
+    pub fn new(max_tps: u32) -> Self {
+        let max_tps = f64::from(max_tps.max(1));
+
+        Self {
+            max_tps,
+            bucket: Mutex::new(Bucket {
+                tokens: max_tps,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes a token, returning how long to wait before using it. Async callers can sleep on it rather than
+    /// blocking.
+    pub fn reserve(&self) -> Duration {
+        let mut bucket = self
+            .bucket
+            .lock()
+            .unwrap_or_else(|poison| poison.into_inner());
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.max_tps).min(self.max_tps) - 1.0;
+        bucket.refilled = now;
+
+        match bucket.tokens {
+            tokens if tokens >= 0.0 => Duration::ZERO,
+            tokens => Duration::from_secs_f64(-tokens / self.max_tps),
+        }
+    }
+
+    /// Blocks until a token is available and takes it.
+    pub fn wait(&self) {
+        let wait = self.reserve();
+
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+}
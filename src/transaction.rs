@@ -1,57 +1,278 @@
-use crate::account::Account;
-use rust_decimal::Decimal;
-use serde::Deserialize;
-use std::{collections::HashMap, fs::File, path::Path};
+use crate::account::{Account, Balance};
+use crate::actor::{ClientActors, Queued};
+use crate::checkpoint;
+use crate::client::ClientId;
+use crate::config::Config;
+use crate::currency::Currency;
+use crate::error::{MergeError, ProcessError, RejectReason, StoreError, TransactionError};
+use crate::events::{Chargeback, Event, Listener, RejectedListener};
+use crate::handler::{CustomTransaction, TransactionHandler};
+use crate::history::AppliedTransaction;
+use crate::input::{self, CsvOptions, InputFormat, Row};
+use crate::invariants;
+use crate::journal::{self, Journal, JournalEntry};
+use crate::ledger::{self, Ledger, Reconciliation};
+use crate::metrics::Metrics;
+use crate::money::Money;
+use crate::rejects::Rejection;
+use crate::risk::{RiskContext, RiskDecision, RiskScorer};
+use crate::rules::{Rule, RuleAction, SuspiciousActivity, Velocity};
+use crate::store::{Overlay, TransactionStore};
+use crate::throttle::Throttle;
+use crate::tx::TxId;
+use crate::wal::{Wal, WalEntry};
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{
+    de::{self, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    fs::File,
+    io::{BufRead, BufReader, Read, Write},
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering as AtomicOrdering},
+        mpsc, Arc, Mutex, MutexGuard,
+    },
+    time::Duration,
+};
+
+pub(crate) const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
 
-#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+/// The `type` column.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[serde(rename_all = "lowercase")]
-enum Type {
+pub enum TransactionType {
     Deposit,
     Withdrawal,
     Dispute,
     Resolve,
     Chargeback,
+    Transfer,
+    Unlock,
+    Fee,
+    Reversal,
+    Convert,
+    Interest,
+    #[serde(rename = "chargeback_reversal")]
+    ChargebackReversal,
+}
+
+impl TransactionType {
+    /// Every type, in declaration order.
+    pub(crate) const ALL: [TransactionType; 12] = {
+        use TransactionType::*;
+
+        [
+            Deposit,
+            Withdrawal,
+            Dispute,
+            Resolve,
+            Chargeback,
+            Transfer,
+            Unlock,
+            Fee,
+            Reversal,
+            Convert,
+            Interest,
+            ChargebackReversal,
+        ]
+    };
+
+    /// As it's written in the `type` column.
+    pub fn as_str(&self) -> &'static str {
+        use TransactionType::*;
+
+        match self {
+            Deposit => "deposit",
+            Withdrawal => "withdrawal",
+            Dispute => "dispute",
+            Resolve => "resolve",
+            Chargeback => "chargeback",
+            Transfer => "transfer",
+            Unlock => "unlock",
+            Fee => "fee",
+            Reversal => "reversal",
+            Convert => "convert",
+            Interest => "interest",
+            ChargebackReversal => "chargeback_reversal",
+        }
+    }
+}
+
+impl FromStr for TransactionType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        TransactionType::ALL
+            .into_iter()
+            .find(|transaction_type| transaction_type.as_str() == s)
+            .ok_or_else(|| format!("Unknown transaction type {s}"))
+    }
+}
+
+impl fmt::Display for TransactionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
 
-#[derive(Deserialize, Debug, Clone, Copy)]
-struct IntermediateTransaction {
-    pub r#type: Type,
-    pub client: u16,
-    pub tx: u32,
-    #[serde(with = "rust_decimal::serde::float_option")]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub(crate) struct IntermediateTransaction {
+    pub r#type: TransactionType,
+    pub client: ClientId,
+    pub tx: TxId,
+    /// Only used by transfers, so the column is optional.
+    #[serde(default)]
+    pub to: Option<ClientId>,
+    /// Written as a string, and read from a string or a number, so the write-ahead log reads back exactly what
+    /// it wrote. Csv rows are read with `input::CsvTransaction` instead, as csv would read the amount as a float.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_amount",
+        serialize_with = "rust_decimal::serde::str_option::serialize"
+    )]
     pub amount: Option<Decimal>,
+    /// The base currency if the column is missing or empty.
+    #[serde(default)]
+    pub currency: Option<Currency>,
+    /// Only used by conversions.
+    #[serde(default)]
+    pub to_currency: Option<Currency>,
+    #[serde(default)]
+    pub timestamp: Option<u64>,
 }
 
-#[derive(Deserialize, Debug, Clone, Copy)]
+/// Like `rust_decimal::serde::float_option::deserialize`, but a unit is `None` too, as json's `null` is once
+/// an untagged enum like the write-ahead log's lines has buffered it.
+fn deserialize_amount<'de, D>(deserializer: D) -> Result<Option<Decimal>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct AmountVisitor;
+
+    impl<'de> Visitor<'de> for AmountVisitor {
+        type Value = Option<Decimal>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("an optional amount")
+        }
+
+        fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_some<D: Deserializer<'de>>(
+            self,
+            deserializer: D,
+        ) -> Result<Self::Value, D::Error> {
+            rust_decimal::serde::float::deserialize(deserializer).map(Some)
+        }
+    }
+
+    deserializer.deserialize_option(AmountVisitor)
+}
+
+/// A row from the input, transactions without a currency are in the base currency. `timestamp` is seconds
+/// since the unix epoch, if the input has the column.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
 // Can't use #[serde(tag = "type")] https://github.com/BurntSushi/rust-csv/issues/211
-#[serde(try_from = "IntermediateTransaction")]
+#[serde(try_from = "IntermediateTransaction", into = "IntermediateTransaction")]
 pub enum Transaction {
     Deposit {
-        client: u16,
-        tx: u32,
+        client: ClientId,
+        tx: TxId,
         amount: Decimal,
+        currency: Option<Currency>,
+        timestamp: Option<u64>,
     },
     Withdrawal {
-        client: u16,
-        tx: u32,
+        client: ClientId,
+        tx: TxId,
         amount: Decimal,
+        currency: Option<Currency>,
+        timestamp: Option<u64>,
     },
     Dispute {
-        client: u16,
-        tx: u32,
+        client: ClientId,
+        tx: TxId,
+        timestamp: Option<u64>,
     },
     Resolve {
-        client: u16,
-        tx: u32,
+        client: ClientId,
+        tx: TxId,
+        timestamp: Option<u64>,
     },
     Chargeback {
-        client: u16,
-        tx: u32,
+        client: ClientId,
+        tx: TxId,
+        timestamp: Option<u64>,
+    },
+    /// Moves funds from `client` to `to`.
+    Transfer {
+        client: ClientId,
+        to: ClientId,
+        tx: TxId,
+        amount: Decimal,
+        currency: Option<Currency>,
+        timestamp: Option<u64>,
+    },
+    /// Clears `locked` after a chargeback has been manually reviewed.
+    Unlock {
+        client: ClientId,
+        tx: TxId,
+        timestamp: Option<u64>,
+    },
+    /// Charges the client directly, for fees that aren't in the `FeeSchedule`.
+    Fee {
+        client: ClientId,
+        tx: TxId,
+        amount: Decimal,
+        currency: Option<Currency>,
+        timestamp: Option<u64>,
+    },
+    /// Undoes the deposit or withdrawal `tx`, without it being disputed.
+    Reversal {
+        client: ClientId,
+        tx: TxId,
+        timestamp: Option<u64>,
+    },
+    /// Exchanges `amount` of the client's `currency` for `to_currency`.
+    Convert {
+        client: ClientId,
+        tx: TxId,
+        amount: Decimal,
+        currency: Option<Currency>,
+        to_currency: Currency,
+        timestamp: Option<u64>,
+    },
+    /// Credits the client's available funds, like a deposit but it can't be disputed or reversed. Posted by
+    /// `TransactionProcessor::accrue_interest`.
+    Interest {
+        client: ClientId,
+        tx: TxId,
+        amount: Decimal,
+        currency: Option<Currency>,
+        timestamp: Option<u64>,
+    },
+    /// Returns the funds of charged back deposit `tx`, when the chargeback is reversed after representment.
+    ChargebackReversal {
+        client: ClientId,
+        tx: TxId,
+        timestamp: Option<u64>,
     },
 }
 
 impl Transaction {
-    pub fn tx(&self) -> u32 {
+    pub fn tx(&self) -> TxId {
         use Transaction::*;
 
         match *self {
@@ -60,10 +281,17 @@ impl Transaction {
             Dispute { tx, .. } => tx,
             Resolve { tx, .. } => tx,
             Chargeback { tx, .. } => tx,
+            Transfer { tx, .. } => tx,
+            Unlock { tx, .. } => tx,
+            Fee { tx, .. } => tx,
+            Reversal { tx, .. } => tx,
+            Convert { tx, .. } => tx,
+            Interest { tx, .. } => tx,
+            ChargebackReversal { tx, .. } => tx,
         }
     }
 
-    pub fn client(&self) -> u16 {
+    pub fn client(&self) -> ClientId {
         use Transaction::*;
 
         match *self {
@@ -72,6 +300,69 @@ impl Transaction {
             Dispute { client, .. } => client,
             Resolve { client, .. } => client,
             Chargeback { client, .. } => client,
+            Transfer { client, .. } => client,
+            Unlock { client, .. } => client,
+            Fee { client, .. } => client,
+            Reversal { client, .. } => client,
+            Convert { client, .. } => client,
+            Interest { client, .. } => client,
+            ChargebackReversal { client, .. } => client,
+        }
+    }
+
+    /// Disputes, resolves etc. are in the currency of the transaction they reference so don't have one.
+    pub fn currency(&self) -> Option<Currency> {
+        use Transaction::*;
+
+        match *self {
+            Deposit { currency, .. } => currency,
+            Withdrawal { currency, .. } => currency,
+            Transfer { currency, .. } => currency,
+            Fee { currency, .. } => currency,
+            Convert { currency, .. } => currency,
+            Interest { currency, .. } => currency,
+            Dispute { .. }
+            | Resolve { .. }
+            | Chargeback { .. }
+            | Unlock { .. }
+            | Reversal { .. }
+            | ChargebackReversal { .. } => None,
+        }
+    }
+
+    pub fn timestamp(&self) -> Option<u64> {
+        use Transaction::*;
+
+        match *self {
+            Deposit { timestamp, .. } => timestamp,
+            Withdrawal { timestamp, .. } => timestamp,
+            Dispute { timestamp, .. } => timestamp,
+            Resolve { timestamp, .. } => timestamp,
+            Chargeback { timestamp, .. } => timestamp,
+            Transfer { timestamp, .. } => timestamp,
+            Unlock { timestamp, .. } => timestamp,
+            Fee { timestamp, .. } => timestamp,
+            Reversal { timestamp, .. } => timestamp,
+            Convert { timestamp, .. } => timestamp,
+            Interest { timestamp, .. } => timestamp,
+            ChargebackReversal { timestamp, .. } => timestamp,
+        }
+    }
+
+    pub fn transaction_type(&self) -> TransactionType {
+        match *self {
+            Transaction::Deposit { .. } => TransactionType::Deposit,
+            Transaction::Withdrawal { .. } => TransactionType::Withdrawal,
+            Transaction::Dispute { .. } => TransactionType::Dispute,
+            Transaction::Resolve { .. } => TransactionType::Resolve,
+            Transaction::Chargeback { .. } => TransactionType::Chargeback,
+            Transaction::Transfer { .. } => TransactionType::Transfer,
+            Transaction::Unlock { .. } => TransactionType::Unlock,
+            Transaction::Fee { .. } => TransactionType::Fee,
+            Transaction::Reversal { .. } => TransactionType::Reversal,
+            Transaction::Convert { .. } => TransactionType::Convert,
+            Transaction::Interest { .. } => TransactionType::Interest,
+            Transaction::ChargebackReversal { .. } => TransactionType::ChargebackReversal,
         }
     }
 }
@@ -80,30 +371,82 @@ impl TryFrom<IntermediateTransaction> for Transaction {
     type Error = &'static str;
 
     fn try_from(value: IntermediateTransaction) -> Result<Self, Self::Error> {
-        use Type::*;
+        use TransactionType::*;
 
         let t = match value.r#type {
             Deposit => Transaction::Deposit {
                 client: value.client,
                 tx: value.tx,
                 amount: value.amount.ok_or("Missing amount")?,
+                currency: value.currency,
+                timestamp: value.timestamp,
             },
             Withdrawal => Transaction::Withdrawal {
                 client: value.client,
                 tx: value.tx,
                 amount: value.amount.ok_or("Missing amount")?,
+                currency: value.currency,
+                timestamp: value.timestamp,
             },
             Dispute => Transaction::Dispute {
                 client: value.client,
                 tx: value.tx,
+                timestamp: value.timestamp,
             },
             Resolve => Transaction::Resolve {
                 client: value.client,
                 tx: value.tx,
+                timestamp: value.timestamp,
             },
             Chargeback => Transaction::Chargeback {
                 client: value.client,
                 tx: value.tx,
+                timestamp: value.timestamp,
+            },
+            Transfer => Transaction::Transfer {
+                client: value.client,
+                to: value.to.ok_or("Missing to")?,
+                tx: value.tx,
+                amount: value.amount.ok_or("Missing amount")?,
+                currency: value.currency,
+                timestamp: value.timestamp,
+            },
+            Unlock => Transaction::Unlock {
+                client: value.client,
+                tx: value.tx,
+                timestamp: value.timestamp,
+            },
+            Fee => Transaction::Fee {
+                client: value.client,
+                tx: value.tx,
+                amount: value.amount.ok_or("Missing amount")?,
+                currency: value.currency,
+                timestamp: value.timestamp,
+            },
+            Reversal => Transaction::Reversal {
+                client: value.client,
+                tx: value.tx,
+                timestamp: value.timestamp,
+            },
+            Convert => Transaction::Convert {
+                client: value.client,
+                tx: value.tx,
+                amount: value.amount.ok_or("Missing amount")?,
+                currency: value.currency,
+                to_currency: value.to_currency.ok_or("Missing to_currency")?,
+                timestamp: value.timestamp,
+            },
+            Interest => Transaction::Interest {
+                client: value.client,
+                tx: value.tx,
+                amount: value.amount.ok_or("Missing amount")?,
+                currency: value.currency,
+                timestamp: value.timestamp,
+            },
+            ChargebackReversal => Transaction::ChargebackReversal {
+                client: value.client,
+                tx: value.tx,
+                timestamp: value.timestamp,
             },
         };
 
@@ -111,412 +454,5760 @@ impl TryFrom<IntermediateTransaction> for Transaction {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+impl From<Transaction> for IntermediateTransaction {
+    fn from(transaction: Transaction) -> Self {
+        let (amount, to, to_currency) = match transaction {
+            Transaction::Deposit { amount, .. }
+            | Transaction::Withdrawal { amount, .. }
+            | Transaction::Fee { amount, .. }
+            | Transaction::Interest { amount, .. } => (Some(amount), None, None),
+            Transaction::Transfer { amount, to, .. } => (Some(amount), Some(to), None),
+            Transaction::Convert {
+                amount,
+                to_currency,
+                ..
+            } => (Some(amount), None, Some(to_currency)),
+            Transaction::Dispute { .. }
+            | Transaction::Resolve { .. }
+            | Transaction::Chargeback { .. }
+            | Transaction::Unlock { .. }
+            | Transaction::Reversal { .. }
+            | Transaction::ChargebackReversal { .. } => (None, None, None),
+        };
+
+        Self {
+            r#type: transaction.transaction_type(),
+            client: transaction.client(),
+            tx: transaction.tx(),
+            to,
+            amount,
+            currency: transaction.currency(),
+            to_currency,
+            timestamp: transaction.timestamp(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DisputedState {
     Undisputed,
     Disputed,
     Resolved,
     Chargebacked,
+    Reversed,
+    /// Charged back, then the chargeback was reversed.
+    ChargebackReversed,
 }
 
+/// Only deposits can be disputed, withdrawals are kept so they can be reversed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordKind {
+    Deposit,
+    Withdrawal,
+}
+
+/// A deposit or withdrawal, kept so it can be disputed or reversed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub struct TransactionRecord {
+    #[serde(with = "rust_decimal::serde::str")]
     pub amount: Decimal,
-    pub client: u16,
+    pub client: ClientId,
     pub disputed: DisputedState,
+    pub kind: RecordKind,
+    pub currency: Currency,
+    /// Part of a disputed deposit that couldn't be held, see `DisputePolicy::Clamp`.
+    #[serde(with = "rust_decimal::serde::str")]
+    pub shortfall: Decimal,
+    /// The row's `timestamp`, for `Config::dispute_window`.
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+    /// The `timestamp` of the dispute, once it's been disputed, for how long funds have been held.
+    #[serde(default)]
+    pub disputed_at: Option<u64>,
+}
+
+/// The clock, how far interest has accrued and the transactions waiting to take effect, saved with the
+/// accounts so a later run carries on from the same time. Transactions are kept as the json the write-ahead log
+/// writes, as their amounts can't be read back with bincode.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub(crate) struct Timeline {
+    pub accrued_until: Option<u64>,
+    pub clock: Option<u64>,
+    /// With when each takes effect, in the order they'll be applied.
+    pub scheduled: Vec<(u64, String)>,
+}
+
+impl Timeline {
+    /// Reads the timeline bincode written after what's been read from `reader`, if there is one. States saved
+    /// before it was kept end first.
+    pub(crate) fn read<R: BufRead>(mut reader: R) -> Result<Self, std::io::Error> {
+        match reader.fill_buf()?.is_empty() {
+            true => Ok(Self::default()),
+            false => bincode::deserialize_from(reader).map_err(std::io::Error::other),
+        }
+    }
+}
+
+/// What `TransactionProcessor::process_bytes` did with a buffer.
+#[derive(Debug)]
+pub struct RunReport {
+    /// Rows applied, or scheduled to be applied when they take effect.
+    pub applied: u64,
+    /// Every row rejected, in order.
+    pub rejected: Vec<Rejection>,
+    /// Why processing stopped before the end of the buffer, e.g. a header row without the type column. The
+    /// rows before it were still processed.
+    pub error: Option<std::io::Error>,
+    /// The accounts as `process` writes them, sorted by client.
+    pub accounts: String,
+}
+
+/// A row that was applied, see `TransactionProcessor::process_iter`.
+#[derive(Debug, Clone)]
+pub struct Applied {
+    /// Line the row starts on, 1 based.
+    pub line: u64,
+    /// `None` if the row was applied by a `TransactionHandler`.
+    pub transaction: Option<Transaction>,
+    /// When the transaction takes effect, if it's been scheduled for later rather than applied yet.
+    pub scheduled: Option<u64>,
+    /// The row's extra columns, if the processor is keeping them, see `TransactionProcessor::keep_metadata`.
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// A processor shared by the servers and connections, transactions are applied one at a time.
+pub type SharedProcessor = Arc<Mutex<TransactionProcessor>>;
+
+/// Accounts are only changed once a transaction can't fail, so carry on if a thread panicked.
+pub(crate) fn lock(
+    transaction_processor: &SharedProcessor,
+) -> MutexGuard<'_, TransactionProcessor> {
+    transaction_processor
+        .lock()
+        .unwrap_or_else(|poison| poison.into_inner())
+}
+
+/// Applies transactions to client accounts.
+pub struct TransactionProcessor<S = HashMap<TxId, TransactionRecord>> {
+    pub(crate) accounts: HashMap<ClientId, Account>,
+    pub(crate) transactions: S,
+    /// The system accounts' sides of every posting, see `ledger`.
+    pub(crate) ledger: Ledger,
+    /// The type, tx and dispute cycle of every row applied, if they're being tracked, see `mark_applied`.
+    pub(crate) applied: Option<HashSet<(TransactionType, TxId, u32)>>,
+    /// Whether a transaction has named its currency, so the accounts are written with a currency column even if
+    /// they're all in the base currency.
+    currencies_named: bool,
+    /// Every transaction applied to each account, if it's being kept.
+    history: Option<HashMap<ClientId, Vec<AppliedTransaction>>>,
+    /// Days since the unix epoch that interest has been accrued to, once a row with a timestamp is processed.
+    accrued_until: Option<u64>,
+    /// The latest `timestamp` processed, or the time the clock was advanced to, seconds since the unix epoch.
+    clock: Option<u64>,
+    /// Transactions waiting for the clock to reach when they take effect, by that time.
+    scheduled: BTreeMap<u64, Vec<Transaction>>,
+    /// What each client has deposited and withdrawn, if there are `Config::rules` to check.
+    velocity: HashMap<ClientId, Velocity>,
+    config: Config,
+    metrics: Option<Arc<Metrics>>,
+    journal: Option<Arc<Journal>>,
+    wal: Option<Wal>,
+    listeners: Vec<Listener>,
+    rejected_listeners: Vec<RejectedListener>,
+    handlers: HashMap<String, Arc<dyn TransactionHandler>>,
+    risk_scorer: Option<Arc<dyn RiskScorer>>,
+    /// Threads csv is parsed on, see `set_parse_threads`.
+    parse_threads: usize,
+    /// How csv is read, see `set_csv_options`.
+    csv: CsvOptions,
+    /// Whether rows' extra columns are kept, see `keep_metadata`.
+    keep_metadata: bool,
+    /// Whether invariants are checked after every transaction, see `check_invariants`.
+    check_invariants: bool,
+    /// The extra columns of the row being applied, for its journal entry.
+    metadata: BTreeMap<String, String>,
+    /// Stops reading once it's set, see `set_interrupt`.
+    interrupt: Option<Arc<AtomicBool>>,
+    /// Paces the long running modes, see `set_throttle`.
+    throttle: Option<Arc<Throttle>>,
 }
 
-pub struct TransactionProcessor {
-    accounts: HashMap<u16, Account>,
-    transactions: HashMap<u32, TransactionRecord>,
+impl Default for TransactionProcessor {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl TransactionProcessor {
     pub fn new() -> Self {
-        Self {
-            accounts: HashMap::new(),
-            transactions: HashMap::new(),
+        Self::with_store(HashMap::new())
+    }
+
+    /// Loads the accounts, transaction records and timeline written by `save`, with everything else as `new`
+    /// leaves it.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, std::io::Error> {
+        let mut file = BufReader::new(File::open(path)?);
+        let (accounts, transactions) =
+            bincode::deserialize_from(&mut file).map_err(std::io::Error::other)?;
+
+        let mut transaction_processor = Self::new();
+        transaction_processor.accounts = accounts;
+        transaction_processor.transactions = transactions;
+        transaction_processor.set_timeline(Timeline::read(file)?)?;
+        transaction_processor.open_ledger();
+
+        Ok(transaction_processor)
+    }
+
+    /// Writes the accounts, transaction records, clock and scheduled transactions to `path` with bincode, so
+    /// they can be loaded with `load` by a later run or on another machine. Config, listeners, the ledger and
+    /// the like aren't saved. Written to a temporary file first and renamed, so a crash while saving leaves the
+    /// last save intact.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), std::io::Error> {
+        checkpoint::save_atomically(
+            path.as_ref(),
+            &(&self.accounts, &self.transactions, self.timeline()?),
+        )
+    }
+
+    /// The clock, interest accrual and scheduled transactions, to be saved.
+    pub(crate) fn timeline(&self) -> Result<Timeline, std::io::Error> {
+        let scheduled = self
+            .scheduled
+            .iter()
+            .flat_map(|(&effective, transactions)| {
+                transactions
+                    .iter()
+                    .map(move |transaction| Ok((effective, serde_json::to_string(transaction)?)))
+            })
+            .collect::<Result<_, serde_json::Error>>()?;
+
+        Ok(Timeline {
+            accrued_until: self.accrued_until,
+            clock: self.clock,
+            scheduled,
+        })
+    }
+
+    /// Puts back a timeline from `timeline`, once it's been loaded.
+    pub(crate) fn set_timeline(&mut self, timeline: Timeline) -> Result<(), std::io::Error> {
+        self.accrued_until = timeline.accrued_until;
+        self.clock = timeline.clock;
+        self.scheduled.clear();
+
+        for (effective, transaction) in timeline.scheduled {
+            self.scheduled
+                .entry(effective)
+                .or_default()
+                .push(serde_json::from_str(&transaction)?);
         }
+
+        Ok(())
     }
 
-    pub fn process_transactions<P>(&mut self, path: P) -> Result<(), std::io::Error>
-    where
-        P: AsRef<Path>,
-    {
-        let file = File::open(path)?;
-        let mut reader = csv::ReaderBuilder::new()
-            .trim(csv::Trim::All)
-            .from_reader(file);
+    /// Adds `other`'s accounts, transaction records and scheduled transactions, for when clients were split
+    /// between processors, e.g. on separate machines. The clock is the later of the two. Fails without merging
+    /// anything if both have an account for the same client, and tx are assumed not to be reused between them.
+    pub fn merge(&mut self, other: TransactionProcessor) -> Result<(), MergeError> {
+        if let Some(&client) = other
+            .accounts
+            .keys()
+            .filter(|client| self.accounts.contains_key(client))
+            .min()
+        {
+            return Err(MergeError { client });
+        }
+
+        self.accounts.extend(other.accounts);
+        self.transactions.extend(other.transactions);
+        self.ledger.extend(other.ledger);
+        self.accrued_until = self.accrued_until.max(other.accrued_until);
+        self.clock = self.clock.max(other.clock);
+        self.currencies_named |= other.currencies_named;
 
-        for transaction in reader.deserialize::<Transaction>().flatten() {
-            // TODO: handle errors with transactions: log, notify payment partner of issues etc.
-            let _ = self.process(&transaction);
+        for (effective, transactions) in other.scheduled {
+            self.scheduled
+                .entry(effective)
+                .or_default()
+                .extend(transactions);
         }
 
         Ok(())
     }
 
-    pub fn process(&mut self, transaction: &Transaction) -> Result<(), ()> {
-        if let Some(account) = self.accounts.get_mut(&transaction.client()) {
-            account.process(transaction, &mut self.transactions)
-        } else {
-            let mut account = Account::new(transaction.client());
-            let result = account.process(transaction, &mut self.transactions);
+    /// Like `process_reader` but transactions are applied on `shards` threads, with each client's
+    /// transactions going to the same thread so they're applied in order. Each shard keeps its own
+    /// transaction records, so a transaction referencing another client's transaction is rejected as
+    /// `UnknownTransaction` rather than `ClientMismatch` and transfers between shards are rejected. Deposits and
+    /// withdrawals reusing a tx from another shard are still rejected as a `DuplicateTransaction`. With
+    /// `track_applied`, rows applied this time are tracked by each shard, so the same type and tx for clients on
+    /// different shards isn't rejected as `AlreadyApplied`.
+    pub fn process_reader_sharded<R, F>(
+        &mut self,
+        reader: R,
+        format: InputFormat,
+        shards: usize,
+        mut on_reject: F,
+    ) -> Result<(), std::io::Error>
+    where
+        R: Read,
+        F: FnMut(Rejection) -> Result<(), std::io::Error>,
+    {
+        let actors = ClientActors::spawn(self, shards);
+        let (rejected_sender, rejected) = mpsc::channel();
+
+        let csv = self.csv.clone();
+        let result = input::read_parallel(reader, format, &csv, self.parse_threads, |row| {
+            for rejection in rejected.try_iter() {
+                on_reject(rejection)?;
+            }
+
+            self.check_interrupt()?;
 
-            if result.is_ok() {
-                self.accounts.insert(transaction.client(), account);
+            match actors.dispatch(&row, &rejected_sender) {
+                Ok(()) => Ok(()),
+                Err(rejection) => on_reject(rejection),
             }
+        });
 
-            result
+        actors.join(self);
+        drop(rejected_sender);
+
+        // Rows the shards rejected before an error or interrupt are still passed on
+        for rejection in rejected {
+            on_reject(rejection)?;
         }
-    }
 
-    pub fn print_accounts(&self) -> Result<(), csv::Error> {
-        let mut wtr = csv::Writer::from_writer(std::io::stdout());
-        wtr.write_record(&["client", "available", "held", "total", "locked"])?;
+        result?;
 
-        for account in self.accounts.values() {
-            wtr.serialize((
-                account.client,
-                format!("{:.4}", account.available.round_dp(4)),
-                format!("{:.4}", account.held.round_dp(4)),
-                format!("{:.4}", (account.available + account.held).round_dp(4)),
-                account.locked,
-            ))?;
+        // Each shard's clock only went as far as its own rows
+        if let Some(clock) = self.clock {
+            self.advance_clock(clock).map_err(std::io::Error::other)?;
         }
 
         Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Moves the accounts, transaction records and the like to `shards` processors by client, each with the
+    /// same config and listeners. The ledger stays here, so the shards' books only balance once they're joined.
+    /// See `join_shards` for putting them back.
+    pub(crate) fn split_shards(&mut self, shards: usize) -> Vec<TransactionProcessor> {
+        let shards = shards.max(1);
+        let mut processors: Vec<_> = (0..shards)
+            .map(|_| {
+                let mut processor = TransactionProcessor::new();
+                processor.set_config(self.config.clone());
+                processor.metrics = self.metrics.clone();
+                processor.journal = self.journal.clone();
+                processor.listeners = self.listeners.clone();
+                processor.rejected_listeners = self.rejected_listeners.clone();
+                processor.handlers = self.handlers.clone();
+                processor.risk_scorer = self.risk_scorer.clone();
+                processor.history = self.history.as_ref().map(|_| HashMap::new());
+                // A row's tracked by its tx rather than its client, so each shard has them all
+                processor.applied = self.applied.clone();
+                processor.accrued_until = self.accrued_until;
+                processor.clock = self.clock;
+                processor.keep_metadata = self.keep_metadata;
+                processor.check_invariants = self.check_invariants;
+                processor.currencies_named = self.currencies_named;
+                processor
+            })
+            .collect();
 
-    #[test]
-    fn deposit() {
-        let mut test = TransactionTest::default();
+        for (client, account) in self.accounts.drain() {
+            processors[client.shard(shards)]
+                .accounts
+                .insert(client, account);
+        }
 
-        test.deposit(0, 0, 1.0, Ok(()));
-        test.deposit(1, 1, 2.0, Ok(()));
-        test.deposit(0, 2, 4.0, Ok(()));
+        for (client, history) in self.history.iter_mut().flat_map(HashMap::drain) {
+            if let Some(shard) = &mut processors[client.shard(shards)].history {
+                shard.insert(client, history);
+            }
+        }
 
-        test.expect(0, 5.0, 0.0, false);
-        test.expect(1, 2.0, 0.0, false);
+        for (client, velocity) in self.velocity.drain() {
+            processors[client.shard(shards)]
+                .velocity
+                .insert(client, velocity);
+        }
 
-        test.run();
+        for (tx, record) in self.transactions.drain() {
+            processors[record.client.shard(shards)]
+                .transactions
+                .insert(tx, record);
+        }
+
+        for (effective, transactions) in std::mem::take(&mut self.scheduled) {
+            for transaction in transactions {
+                processors[transaction.client().shard(shards)]
+                    .scheduled
+                    .entry(effective)
+                    .or_default()
+                    .push(transaction);
+            }
+        }
+
+        processors
     }
 
-    #[test]
-    fn negative_deposit() {
-        let mut test = TransactionTest::default();
+    /// Takes back what `split_shards` moved out, with whatever the shards did to it.
+    pub(crate) fn join_shards(&mut self, processors: Vec<TransactionProcessor>) {
+        for processor in processors {
+            self.accounts.extend(processor.accounts);
+            self.transactions.extend(processor.transactions);
+            self.ledger.extend(processor.ledger);
+            self.velocity.extend(processor.velocity);
 
-        test.deposit(0, 0, -1.0, Err(()));
+            if let (Some(applied), Some(shard)) = (&mut self.applied, processor.applied) {
+                applied.extend(shard);
+            }
 
-        test.run()
-    }
+            if let (Some(history), Some(shard)) = (&mut self.history, processor.history) {
+                history.extend(shard);
+            }
 
-    #[test]
-    fn withdrawal() {
-        let mut test = TransactionTest::default();
+            self.accrued_until = self.accrued_until.max(processor.accrued_until);
+            self.clock = self.clock.max(processor.clock);
+            self.currencies_named |= processor.currencies_named;
 
-        test.deposit(0, 0, 5.0, Ok(()));
-        test.deposit(1, 1, 10.0, Ok(()));
-        test.withdrawal(0, 2, 1.0, Ok(()));
-        test.withdrawal(0, 3, 2.0, Ok(()));
-        test.withdrawal(1, 4, 7.0, Ok(()));
+            for (effective, transactions) in processor.scheduled {
+                self.scheduled
+                    .entry(effective)
+                    .or_default()
+                    .extend(transactions);
+            }
+        }
+    }
 
-        test.expect(0, 2.0, 0.0, false);
-        test.expect(1, 3.0, 0.0, false);
+    /// Deposits that are disputed and not yet resolved or charged back, by client with the longest held first,
+    /// and how many seconds their funds have been held by the clock. The time held is `None` if the dispute
+    /// had no `timestamp`.
+    pub fn held_funds(&self) -> Vec<(TxId, &TransactionRecord, Option<u64>)> {
+        let mut held: Vec<_> = self
+            .transactions
+            .iter()
+            .filter(|(_, record)| record.disputed == DisputedState::Disputed)
+            .map(|(&tx, record)| {
+                let held_for = record
+                    .disputed_at
+                    .zip(self.clock)
+                    .map(|(disputed_at, clock)| clock.saturating_sub(disputed_at));
 
-        test.run();
+                (tx, record, held_for)
+            })
+            .collect();
+
+        held.sort_by_key(|&(tx, record, held_for)| {
+            (record.client, std::cmp::Reverse(held_for), tx)
+        });
+        held
     }
 
-    #[test]
-    fn withdraw_negative() {
-        let mut test = TransactionTest::default();
+    /// Writes `held_funds` as csv, with how long each has been held in seconds and whole days.
+    pub fn write_held_funds<W: Write>(&self, writer: W) -> Result<(), csv::Error> {
+        let mut wtr = csv::Writer::from_writer(writer);
+        wtr.write_record([
+            "client",
+            "tx",
+            "amount",
+            "currency",
+            "disputed_at",
+            "held_seconds",
+            "held_days",
+        ])?;
 
-        test.deposit(0, 0, 5.0, Ok(()));
-        test.withdrawal(0, 2, -1.0, Err(()));
+        let precision = self.config.output_precision();
 
-        test.expect(0, 5.0, 0.0, false);
+        for (tx, record, held_for) in self.held_funds() {
+            wtr.serialize((
+                record.client,
+                tx,
+                format_amount(record.amount, precision),
+                record.currency.as_str(),
+                record.disputed_at,
+                held_for,
+                held_for.map(|seconds| seconds / 86_400),
+            ))?;
+        }
 
-        test.run();
+        wtr.flush()?;
+        Ok(())
     }
+}
 
-    #[test]
-    fn withdrawal_amount_greater_than_available() {
-        let mut test = TransactionTest::default();
+impl<S: TransactionStore> TransactionProcessor<S> {
+    /// Keeps deposits in `transactions` rather than in memory.
+    pub fn with_store(transactions: S) -> Self {
+        Self {
+            accounts: HashMap::new(),
+            transactions,
+            ledger: Ledger::default(),
+            applied: None,
+            currencies_named: false,
+            history: None,
+            accrued_until: None,
+            clock: None,
+            scheduled: BTreeMap::new(),
+            velocity: HashMap::new(),
+            config: Config::default(),
+            metrics: None,
+            journal: None,
+            listeners: Vec::new(),
+            rejected_listeners: Vec::new(),
+            handlers: HashMap::new(),
+            risk_scorer: None,
+            wal: None,
+            parse_threads: 1,
+            csv: CsvOptions::default(),
+            keep_metadata: false,
+            check_invariants: false,
+            metadata: BTreeMap::new(),
+            interrupt: None,
+            throttle: None,
+        }
+    }
 
-        test.deposit(0, 0, 1.0, Ok(()));
-        test.withdrawal(0, 2, 2.0, Err(()));
+    /// From now on rows are rejected as `AlreadyApplied` if a row with the same type and tx has been processed
+    /// before, so an input can be fed again without its transactions being applied twice. With
+    /// `Config::redispute_resolved`, disputes, resolves and chargebacks are only the same as those in the same
+    /// dispute cycle.
+    pub fn track_applied(&mut self) {
+        self.applied.get_or_insert_with(HashSet::new);
+    }
 
-        test.expect(0, 1.0, 0.0, false);
+    /// From now on every transaction applied to an account is kept, for `history`. Uses memory for every
+    /// transaction so is off by default.
+    pub fn track_history(&mut self) {
+        self.history.get_or_insert_with(HashMap::new);
+    }
 
-        test.run()
+    /// From now on the columns of a csv row that aren't read, e.g. a partner's merchant id, are kept as
+    /// metadata in its journal entry and rejection. Transactions held until they take effect lose theirs.
+    pub fn keep_metadata(&mut self) {
+        self.keep_metadata = true;
     }
 
-    #[test]
-    fn dispute() {
-        let mut test = TransactionTest::default();
+    /// From now on what should always hold is checked after every transaction is applied: held funds and
+    /// shortfalls aren't negative, nothing is negative zero, locked accounts only change as
+    /// `Config::locked_policy` allows and disputes, resolves, chargebacks and reversals map to a record in the
+    /// state they're for. Panics with the transaction, the accounts before and after it and the record at the
+    /// first that doesn't, for tests and debugging. Custom transactions aren't checked.
+    pub fn check_invariants(&mut self) {
+        self.check_invariants = true;
+    }
 
-        test.deposit(0, 0, 5.0, Ok(()));
-        test.deposit(1, 1, 10.0, Ok(()));
-        test.deposit(2, 2, 15.0, Ok(()));
-        test.dispute(0, 0, Ok(()));
-        test.dispute(1, 1, Ok(()));
+    pub(crate) fn keeps_metadata(&self) -> bool {
+        self.keep_metadata
+    }
 
-        test.expect(0, 0.0, 5.0, false);
-        test.expect(1, 0.0, 10.0, false);
-        test.expect(2, 15.0, 0.0, false);
+    /// The row's extra columns if they're being kept.
+    fn row_metadata(&self, row: &Row) -> BTreeMap<String, String> {
+        match self.keep_metadata {
+            true => row.metadata(),
+            false => BTreeMap::new(),
+        }
+    }
 
-        test.run();
+    /// Whether a row has been applied before, see `mark_applied`.
+    fn already_applied(&self, transaction: &Transaction) -> bool {
+        self.applied_key(transaction).is_some_and(|key| {
+            self.applied
+                .as_ref()
+                .is_some_and(|applied| applied.contains(&key))
+        })
     }
 
-    #[test]
-    fn dispute_the_same_transaction_multiple_times() {
-        let mut test = TransactionTest::default();
+    /// Records a row as applied, if they're being tracked. Only rows that were applied, or scheduled, are
+    /// recorded, so a rejected row fed again is tried again, as it would be if the input had never been fed.
+    fn mark_applied(&mut self, transaction: &Transaction) {
+        if let (Some(key), Some(applied)) = (self.applied_key(transaction), &mut self.applied) {
+            applied.insert(key);
+        }
+    }
 
-        test.deposit(0, 0, 5.0, Ok(()));
-        test.dispute(0, 0, Ok(()));
-        test.dispute(0, 0, Err(()));
+    /// The type, tx and cycle a row is tracked by, `None` if rows aren't tracked. When resolved deposits can
+    /// be disputed again, a dispute, resolve or chargeback's cycle is how many resolves of its tx were applied
+    /// before it, so the second `dispute` of a tx isn't taken for the first. Everything else is cycle 0. A row
+    /// from a cycle that's since been resolved can't be told from the next cycle's, so isn't caught if it's fed
+    /// again.
+    fn applied_key(&self, transaction: &Transaction) -> Option<(TransactionType, TxId, u32)> {
+        let applied = self.applied.as_ref()?;
 
-        test.expect(0, 0.0, 5.0, false);
+        let r#type = transaction.transaction_type();
+        let tx = transaction.tx();
+        let mut cycle = 0;
 
-        test.run();
+        if self.config.redispute_resolved
+            && matches!(
+                r#type,
+                TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback
+            )
+        {
+            while applied.contains(&(TransactionType::Resolve, tx, cycle)) {
+                cycle += 1;
+            }
+        }
+
+        Some((r#type, tx, cycle))
     }
 
-    #[test]
-    fn dispute_missing_transaction() {
-        let mut test = TransactionTest::default();
+    /// Applies to transactions processed from now on.
+    pub fn set_config(&mut self, config: Config) {
+        self.config = config;
+    }
 
-        test.deposit(0, 0, 5.0, Ok(()));
-        test.dispute(0, 1, Err(()));
+    /// Counts every transaction applied in `metrics`.
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = Some(metrics);
+    }
 
-        test.expect(0, 5.0, 0.0, false);
+    /// Csv read from now on is parsed on `threads` threads, a block of lines each, while transactions are
+    /// still applied in order. Worth it when parsing rather than applying is the bottleneck.
+    pub fn set_parse_threads(&mut self, threads: usize) {
+        self.parse_threads = threads.max(1);
+    }
 
-        test.run();
+    /// Readers stop between rows once `interrupt` is set, e.g. by a signal handler, returning an
+    /// `ErrorKind::Interrupted` error. The rows before it have been applied.
+    pub fn set_interrupt(&mut self, interrupt: Arc<AtomicBool>) {
+        self.interrupt = Some(interrupt);
     }
 
-    #[test]
-    fn dispute_and_disputed_have_different_clients() {
-        let mut test = TransactionTest::default();
+    /// `ingest_tcp` and the HTTP and gRPC APIs wait for `throttle` before applying each transaction. It's
+    /// shared between them, so it limits the total rate rather than each connection's.
+    pub fn set_throttle(&mut self, throttle: Throttle) {
+        self.throttle = Some(Arc::new(throttle));
+    }
 
-        test.deposit(0, 0, 5.0, Ok(()));
-        test.dispute(1, 0, Err(()));
+    pub fn throttle(&self) -> Option<Arc<Throttle>> {
+        self.throttle.clone()
+    }
 
-        test.expect(0, 5.0, 0.0, false);
+    /// How csv read from now on is laid out.
+    pub fn set_csv_options(&mut self, options: CsvOptions) {
+        self.csv = options;
+    }
 
-        test.run();
+    pub fn csv_options(&self) -> &CsvOptions {
+        &self.csv
     }
 
-    #[test]
-    fn resolve() {
-        let mut test = TransactionTest::default();
+    /// Records every transaction applied, and the balances it left, in `journal`.
+    pub fn set_journal(&mut self, journal: Arc<Journal>) {
+        self.journal = Some(journal);
+    }
+
+    /// Rows with `type` set to `r#type` are applied by `handler`, rather than rejected as malformed. Register
+    /// handlers before processing. Built in types can't be overridden, and custom transactions aren't
+    /// written to the write-ahead log, journal or history, or tracked by `track_applied`.
+    pub fn register_handler<T, H>(&mut self, r#type: T, handler: H)
+    where
+        T: Into<String>,
+        H: TransactionHandler + 'static,
+    {
+        self.handlers.insert(r#type.into(), Arc::new(handler));
+    }
+
+    /// Every transaction is scored by `scorer` before it's applied, flagged and rejected transactions are sent
+    /// as `Suspicious` events for the `risk-score` rule.
+    pub fn set_risk_scorer<R: RiskScorer + 'static>(&mut self, scorer: R) {
+        self.risk_scorer = Some(Arc::new(scorer));
+    }
+
+    /// Calls `listener` with every event, on the thread that applied the transaction, so it should be quick.
+    pub fn subscribe<F>(&mut self, listener: F)
+    where
+        F: Fn(&Event) + Send + Sync + 'static,
+    {
+        self.listeners.push(Arc::new(listener));
+    }
+
+    /// Calls `hook` with the client and the tx of the chargeback whenever an account is locked.
+    pub fn on_account_locked<F>(&mut self, hook: F)
+    where
+        F: Fn(ClientId, TxId) + Send + Sync + 'static,
+    {
+        self.subscribe(move |event| {
+            if let Event::Locked { client, tx } = *event {
+                hook(client, tx);
+            }
+        });
+    }
+
+    /// Calls `hook` with every deposit that's charged back.
+    pub fn on_chargeback<F>(&mut self, hook: F)
+    where
+        F: Fn(&Chargeback) + Send + Sync + 'static,
+    {
+        self.subscribe(move |event| {
+            if let Event::Chargeback(chargeback) = event {
+                hook(chargeback);
+            }
+        });
+    }
+
+    /// Calls `hook` with every transaction that fails, and why. Rows that are malformed, already applied or
+    /// custom types aren't transactions so only go to `on_reject`.
+    pub fn on_rejected<F>(&mut self, hook: F)
+    where
+        F: Fn(&Transaction, &TransactionError) + Send + Sync + 'static,
+    {
+        self.rejected_listeners.push(Arc::new(hook));
+    }
+
+    /// Re-applies the transactions in a journal written with `set_journal`, stopping after the first with tx
+    /// `until`. Fails if a transaction fails or leaves different balances to the ones journaled, which means
+    /// the journal was written with a different `Config` or on top of existing accounts. Compressed journals
+    /// are decompressed as they're read.
+    pub fn replay_journal<R: Read>(
+        &mut self,
+        reader: R,
+        until: Option<TxId>,
+    ) -> Result<(), std::io::Error> {
+        let reader = crate::compression::decompress(reader)?;
+
+        for entry in serde_json::Deserializer::from_reader(reader).into_iter::<JournalEntry>() {
+            let entry = entry?;
+            let invalid = |message: &dyn fmt::Display| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("journal entry for tx {}: {message}", entry.tx),
+                )
+            };
+
+            let transaction = entry.transaction().map_err(|error| invalid(&error))?;
+            self.process(&transaction)
+                .map_err(|error| invalid(&error))?;
+
+            for journaled in &entry.balances {
+                let replayed = self.accounts.get(&journaled.client).map(|account| {
+                    let balance = account.balance(journaled.currency);
+                    (balance.available, balance.held, account.locked)
+                });
+
+                if replayed
+                    != Some((
+                        journaled.available.clone(),
+                        journaled.held.clone(),
+                        journaled.locked,
+                    ))
+                {
+                    return Err(invalid(&format_args!(
+                        "client {}'s {} balance is different to the journal",
+                        journaled.client, journaled.currency
+                    )));
+                }
+            }
+
+            if until == Some(entry.tx) {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes every transaction to `wal`, and syncs it, before it's applied.
+    pub fn set_wal(&mut self, wal: Wal) {
+        self.wal = Some(wal);
+    }
+
+    pub fn wal_mut(&mut self) -> Option<&mut Wal> {
+        self.wal.as_mut()
+    }
+
+    /// Applies the entries left in a write-ahead log by a run that didn't save its state, call it before
+    /// `set_wal`. Entries whose type and tx were saved, see `track_applied`, are skipped.
+    pub fn replay_wal(&mut self, entries: &[WalEntry]) -> Result<(), TransactionError> {
+        for entry in entries {
+            if self.already_applied(&entry.transaction) {
+                continue;
+            }
+
+            match self.process(&entry.transaction) {
+                Ok(()) => self.mark_applied(&entry.transaction),
+                Err(error @ TransactionError::Store(_)) => return Err(error),
+                Err(error) => tracing::warn!(
+                    tx = %entry.transaction.tx(),
+                    committed = entry.committed,
+                    %error,
+                    "write-ahead log entry failed when replayed"
+                ),
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Processes every transaction in csv from a file, stdin etc. Badly formatted rows and failed transactions are skipped.
+    pub fn process_transactions<R>(&mut self, reader: R) -> Result<(), std::io::Error>
+    where
+        R: Read,
+    {
+        self.process_transactions_with_rejects(reader, |_| Ok(()))
+    }
+
+    /// Processes every transaction in csv, calling `on_reject` for every row that is badly formatted or fails.
+    pub fn process_transactions_with_rejects<R, F>(
+        &mut self,
+        reader: R,
+        on_reject: F,
+    ) -> Result<(), std::io::Error>
+    where
+        R: Read,
+        F: FnMut(Rejection) -> Result<(), std::io::Error>,
+    {
+        self.process_reader(reader, InputFormat::Csv, on_reject)
+    }
+
+    /// Processes every transaction in the given format, calling `on_reject` for every row that is badly formatted or fails.
+    pub fn process_reader<R, F>(
+        &mut self,
+        reader: R,
+        format: InputFormat,
+        mut on_reject: F,
+    ) -> Result<(), std::io::Error>
+    where
+        R: Read,
+        F: FnMut(Rejection) -> Result<(), std::io::Error>,
+    {
+        let csv = self.csv.clone();
+        input::read_parallel(reader, format, &csv, self.parse_threads, |row| {
+            self.process_row(&row, &mut on_reject)
+        })
+    }
+
+    /// Like `process_reader`, but csv is read, parsed and applied on separate threads with a couple of blocks
+    /// queued between each, so reading and parsing the rest of the input overlaps with applying it. Parsing
+    /// still uses `set_parse_threads` threads. Rows are applied in order on the calling thread.
+    pub fn process_reader_pipelined<R, F>(
+        &mut self,
+        reader: R,
+        format: InputFormat,
+        mut on_reject: F,
+    ) -> Result<(), std::io::Error>
+    where
+        R: Read + Send,
+        F: FnMut(Rejection) -> Result<(), std::io::Error>,
+    {
+        let csv = self.csv.clone();
+        input::read_pipelined(reader, format, &csv, self.parse_threads, |row| {
+            self.process_row(&row, &mut on_reject)
+        })
+    }
+
+    /// Like `process_reader`, for input partitioned by client with each client's rows together. Once a row
+    /// for another client is read the last client is finished, so its accounts are written to `stream` and
+    /// dropped rather than kept until the end, which saves memory with lots of clients and gets the first
+    /// rows out sooner. Finished clients' transaction records are kept as usual, but later rows for them and
+    /// transfers to them are rejected as `ClientFinished`. See `finish_stream` for the accounts left at the
+    /// end.
+    pub fn process_reader_streaming<R, W, F>(
+        &mut self,
+        reader: R,
+        format: InputFormat,
+        stream: &mut AccountStream<W>,
+        mut on_reject: F,
+    ) -> Result<(), std::io::Error>
+    where
+        R: Read,
+        W: Write,
+        F: FnMut(Rejection) -> Result<(), std::io::Error>,
+    {
+        let csv = self.csv.clone();
+        input::read_parallel(reader, format, &csv, self.parse_threads, |row| {
+            let (client, to) = match (&row.transaction, &row.custom) {
+                (Ok(Transaction::Transfer { client, to, .. }), _) => (Some(*client), Some(*to)),
+                (Ok(transaction), _) => (Some(transaction.client()), None),
+                (Err(_), Some(transaction)) => (Some(transaction.client), None),
+                (Err(_), None) => (None, None),
+            };
+
+            if client
+                .into_iter()
+                .chain(to)
+                .any(|client| stream.finished.contains(&client))
+            {
+                return on_reject(Rejection {
+                    line: row.line,
+                    row: row.raw(),
+                    reason: TransactionError::ClientFinished.into(),
+                    metadata: self.row_metadata(&row),
+                });
+            }
+
+            if let Some(client) = client.filter(|&client| stream.current != Some(client)) {
+                if let Some(finished) = stream.current.replace(client) {
+                    if let Some(account) = self.accounts.remove(&finished) {
+                        stream.write(&account, &self.config, self.currencies_named)?;
+                    }
+
+                    stream.finished.insert(finished);
+                }
+            }
+
+            self.process_row(&row, &mut on_reject)
+        })
+    }
+
+    /// Writes the accounts that are left after `process_reader_streaming`, the last client's and any that
+    /// only had transfers to them, by client, and flushes the stream.
+    pub fn finish_stream<W: Write>(
+        &mut self,
+        mut stream: AccountStream<W>,
+    ) -> Result<(), csv::Error> {
+        let mut accounts: Vec<_> = self.accounts.drain().map(|(_, account)| account).collect();
+        accounts.sort_by_key(|account| account.client);
+
+        for account in &accounts {
+            stream.write(account, &self.config, self.currencies_named)?;
+        }
+
+        stream.finish(self.currencies_named)
+    }
+
+    /// Like `process_reader` but the first `skip` rows are skipped, as an earlier run has already processed them,
+    /// and `checkpoint` is called every `every` rows with the number of rows read so far. Returns the number of rows read.
+    pub fn process_reader_checkpointed<R, F, C>(
+        &mut self,
+        reader: R,
+        format: InputFormat,
+        skip: u64,
+        every: u64,
+        mut on_reject: F,
+        mut checkpoint: C,
+    ) -> Result<u64, std::io::Error>
+    where
+        R: Read,
+        F: FnMut(Rejection) -> Result<(), std::io::Error>,
+        C: FnMut(&Self, u64) -> Result<(), std::io::Error>,
+    {
+        let mut rows = 0;
+        let csv = self.csv.clone();
+
+        input::read_parallel(reader, format, &csv, self.parse_threads, |row| {
+            rows += 1;
+
+            if rows <= skip {
+                return Ok(());
+            }
+
+            self.process_row(&row, &mut on_reject)?;
+
+            if every > 0 && rows % every == 0 {
+                checkpoint(self, rows)?;
+            }
+
+            Ok(())
+        })?;
+
+        Ok(rows)
+    }
+
+    /// Processes csv that's still being appended to, like `tail -f`, checking for new rows every `poll`.
+    /// `on_caught_up` is called whenever every row appended so far has been applied, e.g. to write a snapshot
+    /// of the accounts. It only returns if reading or a callback fails, or `set_interrupt`'s flag is set.
+    pub fn process_following<R, F, C>(
+        &mut self,
+        reader: R,
+        poll: Duration,
+        mut on_reject: F,
+        mut on_caught_up: C,
+    ) -> Result<(), std::io::Error>
+    where
+        R: Read,
+        F: FnMut(Rejection) -> Result<(), std::io::Error>,
+        C: FnMut(&Self) -> Result<(), std::io::Error>,
+    {
+        let csv = self.csv.clone();
+
+        input::follow(reader, &csv, poll, |row| match row {
+            Some(row) => self.process_row(&row, &mut on_reject),
+            None => {
+                self.check_interrupt()?;
+                on_caught_up(self)
+            }
+        })
+    }
+
+    /// Processes every transaction in the given format one row at a time as the iterator is advanced, yielding
+    /// what happened to each row. Rows are parsed on the calling thread whatever `set_parse_threads` was
+    /// given. Once it yields `ProcessError::Io` there's nothing more.
+    pub fn process_iter<'a, R>(
+        &'a mut self,
+        reader: R,
+        format: InputFormat,
+    ) -> impl Iterator<Item = Result<Applied, ProcessError>> + 'a
+    where
+        R: Read + 'a,
+    {
+        let mut reader = Some(reader);
+        let mut rows = None;
+
+        std::iter::from_fn(move || {
+            if let Some(reader) = reader.take() {
+                match input::Rows::new(reader, format, &self.csv) {
+                    Ok(opened) => rows = Some(opened),
+                    Err(error) => return Some(Err(ProcessError::Io(error))),
+                }
+            }
+
+            match rows.as_mut()?.next(|row| self.try_row(&row))? {
+                Ok(Ok(Ok(applied))) => Some(Ok(applied)),
+                Ok(Ok(Err(rejection))) => Some(Err(ProcessError::Rejected(rejection))),
+                Ok(Err(error)) | Err(error) => {
+                    rows = None;
+                    Some(Err(ProcessError::Io(error)))
+                }
+            }
+        })
+    }
+
+    /// Runs csv in memory through the same reading, applying and writing as `process` does, never touching the
+    /// filesystem or stopping for a bad row, so arbitrary bytes can be thrown at it, e.g. by a fuzzer.
+    pub fn process_bytes(&mut self, bytes: &[u8]) -> RunReport {
+        let mut report = RunReport {
+            applied: 0,
+            rejected: Vec::new(),
+            error: None,
+            accounts: String::new(),
+        };
+
+        for outcome in self.process_iter(bytes, InputFormat::Csv) {
+            match outcome {
+                Ok(_) => report.applied += 1,
+                Err(ProcessError::Rejected(rejection)) => report.rejected.push(rejection),
+                Err(ProcessError::Io(error)) => report.error = Some(error),
+            }
+        }
+
+        let mut accounts = Vec::new();
+
+        if let Err(error) = self.write_accounts(&mut accounts, Some(SortBy::Client)) {
+            report.error.get_or_insert(error.into());
+        }
+
+        report.accounts = String::from_utf8_lossy(&accounts).into_owned();
+        report
+    }
+
+    /// A failing store ends processing, other failures are rejections.
+    pub(crate) fn process_row<F>(
+        &mut self,
+        row: &Row,
+        on_reject: &mut F,
+    ) -> Result<(), std::io::Error>
+    where
+        F: FnMut(Rejection) -> Result<(), std::io::Error>,
+    {
+        self.check_interrupt()?;
+
+        match self.try_row(row)? {
+            Ok(_) => Ok(()),
+            Err(rejection) => on_reject(rejection),
+        }
+    }
+
+    fn check_interrupt(&self) -> Result<(), std::io::Error> {
+        match &self.interrupt {
+            Some(interrupt) if interrupt.load(AtomicOrdering::SeqCst) => Err(std::io::Error::new(
+                std::io::ErrorKind::Interrupted,
+                "interrupted",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Applies a row, or returns why it was rejected. A failing store is an error rather than a rejection.
+    fn try_row(&mut self, row: &Row) -> Result<Result<Applied, Rejection>, std::io::Error> {
+        let metadata = self.row_metadata(row);
+
+        let reason = match (&row.transaction, &row.custom) {
+            (Ok(transaction), _) if self.already_applied(transaction) => {
+                TransactionError::AlreadyApplied.into()
+            }
+            (Ok(transaction), _) => {
+                self.metadata = metadata.clone();
+
+                match self.process_at(transaction, row.effective) {
+                    Ok(()) => {
+                        self.mark_applied(transaction);
+
+                        return Ok(Ok(Applied {
+                            line: row.line,
+                            transaction: Some(*transaction),
+                            scheduled: row.effective.filter(|&effective| {
+                                self.clock.is_none_or(|clock| effective > clock)
+                            }),
+                            metadata,
+                        }));
+                    }
+                    Err(TransactionError::Store(error)) => {
+                        return Err(std::io::Error::other(error))
+                    }
+                    Err(error) => RejectReason::Transaction(error),
+                }
+            }
+            (Err(_), Some(transaction)) if self.handlers.contains_key(&transaction.r#type) => {
+                match self.process_custom(transaction) {
+                    Ok(()) => {
+                        return Ok(Ok(Applied {
+                            line: row.line,
+                            transaction: None,
+                            scheduled: None,
+                            metadata,
+                        }))
+                    }
+                    Err(TransactionError::Store(error)) => {
+                        return Err(std::io::Error::other(error))
+                    }
+                    Err(error) => RejectReason::Transaction(error),
+                }
+            }
+            (Err(reason), _) => reason.clone(),
+        };
+
+        Ok(Err(Rejection {
+            line: row.line,
+            row: row.raw(),
+            reason,
+            metadata,
+        }))
+    }
+
+    /// Processes transactions as they arrive from an async source, failed transactions are skipped.
+    #[cfg(feature = "tokio")]
+    pub async fn process_stream<T>(&mut self, stream: T)
+    where
+        T: tokio_stream::Stream<Item = Transaction>,
+    {
+        self.process_stream_with_rejects(stream, |_, _| {}).await
+    }
+
+    /// Processes transactions as they arrive from an async source, calling `on_reject` for every transaction that fails.
+    #[cfg(feature = "tokio")]
+    pub async fn process_stream_with_rejects<T, F>(&mut self, stream: T, mut on_reject: F)
+    where
+        T: tokio_stream::Stream<Item = Transaction>,
+        F: FnMut(&Transaction, TransactionError),
+    {
+        use tokio_stream::StreamExt;
+
+        tokio::pin!(stream);
+
+        while let Some(transaction) = stream.next().await {
+            if let Err(error) = self.process(&transaction) {
+                on_reject(&transaction, error);
+            }
+        }
+    }
+
+    /// Posts interest with `Config::interest` on every account's positive available balances, for each whole
+    /// day (UTC) since interest was last accrued up to `until`, seconds since the unix epoch. The first call
+    /// only starts the clock as there's nothing to accrue from. Rows with a `timestamp` accrue interest up to
+    /// it before they're applied.
+    pub fn accrue_interest(&mut self, until: u64) -> Result<(), TransactionError> {
+        if self.config.interest.is_none() {
+            return Ok(());
+        }
+
+        let today = until / SECONDS_PER_DAY;
+        let accrued = self.accrued_until.get_or_insert(today);
+
+        if today <= *accrued {
+            return Ok(());
+        }
+
+        let days = today - *accrued;
+        *accrued = today;
+
+        self.post_interest(days, Some(today * SECONDS_PER_DAY))
+    }
+
+    /// Posts `days` of interest with `Config::interest` on every account's positive available balances, as
+    /// `Interest` transactions with tx 0. Accounts the interest can't be posted to are skipped.
+    pub fn post_interest(
+        &mut self,
+        days: u64,
+        timestamp: Option<u64>,
+    ) -> Result<(), TransactionError> {
+        let Some(interest) = self.config.interest else {
+            return Ok(());
+        };
+        let precision = self.config.output_precision();
+
+        let mut postings: Vec<_> = self
+            .accounts
+            .values()
+            .flat_map(|account| {
+                account.balances.iter().map(move |(currency, balance)| {
+                    (
+                        account.client,
+                        *currency,
+                        // A balance too big for a `Decimal` can't be paid interest in one
+                        balance
+                            .available
+                            .to_decimal()
+                            .map_or(Decimal::ZERO, |available| interest.accrue(available, days))
+                            .round_dp_with_strategy(
+                                precision,
+                                RoundingStrategy::MidpointNearestEven,
+                            ),
+                    )
+                })
+            })
+            .filter(|(_, _, amount)| !amount.is_zero())
+            .collect();
+        postings.sort();
+
+        for (client, currency, amount) in postings {
+            let transaction = Transaction::Interest {
+                client,
+                tx: TxId::from(0),
+                amount,
+                currency: Some(currency),
+                timestamp,
+            };
+
+            match self.process(&transaction) {
+                Ok(()) => {}
+                Err(error @ TransactionError::Store(_)) => return Err(error),
+                Err(error) => tracing::warn!(%client, %error, "Couldn't post interest"),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Moves the clock forward to `now`, seconds since the unix epoch, applying the scheduled transactions
+    /// that have taken effect by then in the order they take effect, and accruing interest along the way.
+    /// Scheduled transactions that fail are logged as they have already been read.
+    pub fn advance_clock(&mut self, now: u64) -> Result<(), TransactionError> {
+        let now = self.clock.map_or(now, |clock| clock.max(now));
+        self.clock = Some(now);
+
+        while let Some(entry) = self.scheduled.first_entry() {
+            if *entry.key() > now {
+                break;
+            }
+
+            let (effective, transactions) = entry.remove_entry();
+            self.accrue_interest(effective)?;
+
+            for transaction in transactions {
+                match self.process(&transaction) {
+                    Ok(()) => {}
+                    Err(error @ TransactionError::Store(_)) => return Err(error),
+                    Err(error) => tracing::warn!(
+                        client = %transaction.client(),
+                        tx = %transaction.tx(),
+                        %error,
+                        "Scheduled transaction failed"
+                    ),
+                }
+            }
+        }
+
+        self.accrue_interest(now)
+    }
+
+    /// Transactions waiting for the clock to reach when they take effect, with that time, in the order
+    /// they'll be applied.
+    pub fn pending(&self) -> impl Iterator<Item = (u64, &Transaction)> {
+        self.scheduled.iter().flat_map(|(effective, transactions)| {
+            transactions
+                .iter()
+                .map(move |transaction| (*effective, transaction))
+        })
+    }
+
+    /// Writes the pending transactions as csv, kept apart from the accounts as they haven't been applied.
+    pub fn write_pending<W: Write>(&self, writer: W) -> Result<(), csv::Error> {
+        let mut wtr = csv::Writer::from_writer(writer);
+        wtr.write_record(["effective", "type", "client", "tx", "amount", "currency"])?;
+
+        let base_currency = self.config.base_currency;
+        let precision = self.config.output_precision();
+
+        for (effective, transaction) in self.pending() {
+            let amount = IntermediateTransaction::from(*transaction).amount;
+
+            wtr.serialize((
+                effective,
+                transaction.transaction_type().as_str(),
+                transaction.client(),
+                transaction.tx(),
+                amount.map(|amount| format_amount(amount, precision)),
+                transaction.currency().unwrap_or(base_currency).as_str(),
+            ))?;
+        }
+
+        wtr.flush()?;
+        Ok(())
+    }
+
+    /// Moves the clock to the transaction's timestamp, if it has one, then applies it, or schedules it if it
+    /// takes effect after the clock.
+    fn process_at(
+        &mut self,
+        transaction: &Transaction,
+        effective: Option<u64>,
+    ) -> Result<(), TransactionError> {
+        // Kept from the transactions the clock releases
+        let metadata = std::mem::take(&mut self.metadata);
+
+        if let Some(timestamp) = transaction.timestamp() {
+            self.advance_clock(timestamp)?;
+        }
+
+        match effective {
+            Some(effective) if self.clock.is_none_or(|clock| effective > clock) => {
+                self.currencies_named |= transaction.currency().is_some();
+                self.scheduled
+                    .entry(effective)
+                    .or_default()
+                    .push(*transaction);
+                Ok(())
+            }
+            _ => {
+                self.metadata = metadata;
+                self.process(transaction)
+            }
+        }
+    }
+
+    /// The client's account and the receiver's for a transfer, as they are or new if they don't exist.
+    fn touched_accounts(&self, transaction: &Transaction) -> Vec<Account> {
+        let to = match *transaction {
+            Transaction::Transfer { to, .. } => Some(to),
+            _ => None,
+        };
+
+        std::iter::once(transaction.client())
+            .chain(to)
+            .map(|client| {
+                self.accounts
+                    .get(&client)
+                    .cloned()
+                    .unwrap_or_else(|| Account::new(client))
+            })
+            .collect()
+    }
+
+    fn is_locked(&self, client: ClientId) -> bool {
+        self.accounts
+            .get(&client)
+            .is_some_and(|account| account.locked)
+    }
+
+    fn emit(&self, event: &Event) {
+        for listener in &self.listeners {
+            listener(event);
+        }
+    }
+
+    /// Applies a row sent to a shard by `ClientActors`.
+    pub(crate) fn process_queued(
+        &mut self,
+        queued: &Queued,
+        metadata: &BTreeMap<String, String>,
+    ) -> Result<(), TransactionError> {
+        match queued {
+            Queued::Transaction(transaction, _) if self.already_applied(transaction) => {
+                Err(TransactionError::AlreadyApplied)
+            }
+            Queued::Transaction(transaction, effective) => {
+                self.metadata = metadata.clone();
+                self.process_at(transaction, *effective)?;
+                self.mark_applied(transaction);
+
+                Ok(())
+            }
+            Queued::Custom(transaction) => self.process_custom(transaction),
+        }
+    }
+
+    /// The types `register_handler` has been called with.
+    pub(crate) fn custom_types(&self) -> HashSet<String> {
+        self.handlers.keys().cloned().collect()
+    }
+
+    /// Applies a transaction with the handler registered for its type. The account is only changed, or
+    /// created, if the handler succeeds.
+    pub fn process_custom(
+        &mut self,
+        transaction: &CustomTransaction,
+    ) -> Result<(), TransactionError> {
+        let handler = self.handlers.get(&transaction.r#type).ok_or_else(|| {
+            TransactionError::Rejected(format!("unknown transaction type {}", transaction.r#type))
+        })?;
+
+        let mut account = self
+            .accounts
+            .get(&transaction.client)
+            .cloned()
+            .unwrap_or_else(|| Account::new(transaction.client));
+
+        let result = if account.locked {
+            Err(TransactionError::AccountLocked)
+        } else {
+            handler.apply(transaction, &mut account, &self.config)
+        }
+        .and_then(|()| {
+            // Handlers change balances directly, so what they did is booked against suspense
+            let before = self
+                .accounts
+                .get(&transaction.client)
+                .cloned()
+                .unwrap_or_else(|| Account::new(transaction.client));
+            self.ledger
+                .post(None, &ledger::adjustments(&before, &account))
+        });
+
+        match &result {
+            Ok(()) => {
+                self.accounts.insert(transaction.client, account);
+
+                tracing::debug!(
+                    r#type = transaction.r#type,
+                    client = %transaction.client,
+                    tx = %transaction.tx,
+                    "applied transaction"
+                )
+            }
+            Err(error) => tracing::debug!(
+                r#type = transaction.r#type,
+                client = %transaction.client,
+                tx = %transaction.tx,
+                %error,
+                "transaction failed"
+            ),
+        }
+
+        result
+    }
+
+    /// Applies a single transaction. The account is only created if the transaction succeeds.
+    pub fn process(&mut self, transaction: &Transaction) -> Result<(), TransactionError> {
+        let seq = match &mut self.wal {
+            Some(wal) => Some(wal.write(transaction).map_err(wal_error)?),
+            None => None,
+        };
+
+        self.process_logged(transaction, seq)
+    }
+
+    /// Applies transactions one after another, returning each one's result in the same order. A failed
+    /// transaction doesn't stop the rest. With a write-ahead log they're all written, and synced once, before
+    /// the first is applied.
+    pub fn process_batch(
+        &mut self,
+        transactions: &[Transaction],
+    ) -> Vec<Result<(), TransactionError>> {
+        let first = match &mut self.wal {
+            Some(wal) => match wal.write_batch(transactions) {
+                Ok(first) => Some(first),
+                Err(error) => {
+                    let error = wal_error(error);
+                    return transactions.iter().map(|_| Err(error.clone())).collect();
+                }
+            },
+            None => None,
+        };
+
+        transactions
+            .iter()
+            .zip(0..)
+            .map(|(transaction, index)| {
+                self.process_logged(transaction, first.map(|first| first + index))
+            })
+            .collect()
+    }
+
+    /// Applies a transaction that's already been written to the write-ahead log as `seq`.
+    fn process_logged(
+        &mut self,
+        transaction: &Transaction,
+        seq: Option<u64>,
+    ) -> Result<(), TransactionError> {
+        let metadata = std::mem::take(&mut self.metadata);
+        let was_locked = self.is_locked(transaction.client());
+        self.currencies_named |= transaction.currency().is_some();
+        let before = self
+            .check_invariants
+            .then(|| self.touched_accounts(transaction));
+        let result = match self.check_rules(transaction) {
+            Some(rule) => Err(TransactionError::RuleBroken(rule)),
+            None => self.apply(transaction),
+        };
+
+        if let (Ok(()), Some(before)) = (&result, &before) {
+            let after = self.touched_accounts(transaction);
+
+            if let Err(violation) = invariants::check(
+                transaction,
+                before,
+                &after,
+                &self.transactions,
+                &self.config,
+            ) {
+                panic!("{violation}");
+            }
+        }
+
+        if let (Ok(()), Some(seq), Some(wal)) = (&result, seq, &mut self.wal) {
+            wal.commit(seq).map_err(wal_error)?;
+        }
+
+        match &result {
+            Ok(()) => {
+                if let Some(metrics) = &self.metrics {
+                    metrics.applied(transaction.transaction_type());
+                }
+
+                if self.journal.is_some() || !self.listeners.is_empty() {
+                    let to = match *transaction {
+                        Transaction::Transfer { to, .. } => self.accounts.get(&to),
+                        _ => None,
+                    };
+
+                    let mut entry = JournalEntry::new(
+                        transaction,
+                        self.accounts
+                            .get(&transaction.client())
+                            .into_iter()
+                            .chain(to),
+                    );
+                    entry.metadata = metadata;
+
+                    if let Some(journal) = &self.journal {
+                        journal.record(&entry);
+                    }
+
+                    self.emit(&Event::Applied(entry));
+                }
+
+                let (client, tx) = (transaction.client(), transaction.tx());
+
+                if self.tracks_velocity() {
+                    let currency = transaction.currency().unwrap_or(self.config.base_currency);
+
+                    self.velocity
+                        .entry(client)
+                        .or_default()
+                        .record(transaction, currency);
+                }
+
+                if let Some(history) = &mut self.history {
+                    let timestamp = journal::timestamp();
+                    let to = match *transaction {
+                        Transaction::Transfer { to, .. } => Some(to),
+                        _ => None,
+                    };
+
+                    for client in std::iter::once(client).chain(to) {
+                        if let Some(account) = self.accounts.get(&client) {
+                            history
+                                .entry(client)
+                                .or_default()
+                                .push(AppliedTransaction::new(timestamp, transaction, account));
+                        }
+                    }
+                }
+
+                if let Transaction::Chargeback { .. } = transaction {
+                    if let Ok(Some(record)) = self.transactions.get(tx) {
+                        self.emit(&Event::Chargeback(Chargeback {
+                            client,
+                            tx,
+                            amount: record.amount,
+                            currency: record.currency,
+                        }));
+                    }
+                }
+
+                match (was_locked, self.is_locked(client)) {
+                    (false, true) => self.emit(&Event::Locked { client, tx }),
+                    (true, false) => self.emit(&Event::Unlocked { client, tx }),
+                    _ => {}
+                }
+
+                tracing::debug!(
+                    r#type = %transaction.transaction_type(),
+                    client = %transaction.client(),
+                    tx = %transaction.tx(),
+                    "applied transaction"
+                )
+            }
+            Err(error) => {
+                for listener in &self.rejected_listeners {
+                    listener(transaction, error);
+                }
+
+                tracing::debug!(
+                    r#type = %transaction.transaction_type(),
+                    client = %transaction.client(),
+                    tx = %transaction.tx(),
+                    %error,
+                    "transaction failed"
+                )
+            }
+        }
+
+        result
+    }
+
+    /// Checks the transaction against `Config::rules` and the risk scorer, sending every rule it breaks as a
+    /// `Suspicious` event. Returns the rule it's rejected for, if any.
+    fn check_rules(&self, transaction: &Transaction) -> Option<Rule> {
+        let broken = self.broken_rules(transaction);
+        let client = transaction.client();
+        let currency = transaction.currency().unwrap_or(self.config.base_currency);
+
+        for (rule, rejected) in &broken {
+            self.emit(&Event::Suspicious(SuspiciousActivity {
+                client,
+                tx: transaction.tx(),
+                r#type: transaction.transaction_type(),
+                amount: IntermediateTransaction::from(*transaction)
+                    .amount
+                    .unwrap_or_default(),
+                currency,
+                rule: *rule,
+                rejected: *rejected,
+            }));
+        }
+
+        rejected_by(broken)
+    }
+
+    /// Every rule the transaction breaks, and whether it's rejected for it.
+    fn broken_rules(&self, transaction: &Transaction) -> Vec<(Rule, bool)> {
+        if !self.tracks_velocity() {
+            return Vec::new();
+        }
+
+        let rules = &self.config.rules;
+        let client = transaction.client();
+        let currency = transaction.currency().unwrap_or(self.config.base_currency);
+        let velocity = self.velocity.get(&client);
+
+        let mut broken: Vec<_> = rules
+            .check(transaction, currency, velocity)
+            .into_iter()
+            .map(|rule| (rule, rules.action == RuleAction::Reject))
+            .collect();
+
+        if let Some(scorer) = &self.risk_scorer {
+            let context =
+                RiskContext::new(transaction, currency, self.accounts.get(&client), velocity);
+
+            match scorer.score(transaction, &context) {
+                RiskDecision::Allow => {}
+                RiskDecision::Flag => broken.push((Rule::RiskScore, false)),
+                RiskDecision::Reject => broken.push((Rule::RiskScore, true)),
+            }
+        }
+
+        broken
+    }
+
+    /// Whether what each client has done is kept, for the rules, risk scorer and daily limits.
+    fn tracks_velocity(&self) -> bool {
+        self.config.rules.enabled()
+            || self.risk_scorer.is_some()
+            || self.config.limits.has_daily_limits()
+    }
+
+    /// Whether `process` would apply the transaction, checked without changing anything: the rules, funds,
+    /// dispute state, client match, lock status and so on. Nothing is sent to listeners, and it doesn't see
+    /// rows rejected as `AlreadyApplied` as that's checked for rows rather than transactions.
+    pub fn validate(&self, transaction: &Transaction) -> Result<(), TransactionError> {
+        if let Some(rule) = rejected_by(self.broken_rules(transaction)) {
+            return Err(TransactionError::RuleBroken(rule));
+        }
+
+        self.check_precision(transaction)?;
+
+        let account = |client| {
+            self.accounts
+                .get(&client)
+                .cloned()
+                .unwrap_or_else(|| Account::new(client))
+        };
+
+        let mut postings = Vec::new();
+
+        match *transaction {
+            Transaction::Transfer {
+                client,
+                to,
+                amount,
+                currency,
+                ..
+            } => {
+                if client == to {
+                    return Err(TransactionError::SelfTransfer);
+                }
+
+                account(client).transfer(
+                    &mut account(to),
+                    &mut postings,
+                    currency.unwrap_or(self.config.base_currency),
+                    amount,
+                    self.config.fees.fee(TransactionType::Transfer, amount),
+                    self.config.credit_limits.limit(client),
+                )?;
+            }
+            _ => account(transaction.client()).process(
+                transaction,
+                &mut Overlay::new(&self.transactions),
+                &self.config,
+                self.withdrawn_today(transaction),
+                &mut postings,
+            )?,
+        }
+
+        // The system accounts' balances could still overflow
+        self.ledger
+            .posted(Some(transaction.transaction_type()), &postings)
+            .map(|_| ())
+    }
+
+    fn check_precision(&self, transaction: &Transaction) -> Result<(), TransactionError> {
+        let Some(amount) = IntermediateTransaction::from(*transaction).amount else {
+            return Ok(());
+        };
+
+        if self
+            .config
+            .precision
+            .is_some_and(|precision| amount.normalize().scale() > precision)
+        {
+            return Err(TransactionError::TooPrecise);
+        }
+
+        Money::check(amount)
+    }
+
+    /// What the client has already withdrawn today in the transaction's currency, for daily limits.
+    fn withdrawn_today(&self, transaction: &Transaction) -> Decimal {
+        self.velocity
+            .get(&transaction.client())
+            .map_or(Decimal::ZERO, |velocity| {
+                velocity.withdrawn_on(
+                    transaction,
+                    transaction.currency().unwrap_or(self.config.base_currency),
+                )
+            })
+    }
+
+    fn apply(&mut self, transaction: &Transaction) -> Result<(), TransactionError> {
+        self.check_precision(transaction)?;
+
+        if let Transaction::Transfer {
+            client,
+            to,
+            amount,
+            currency,
+            ..
+        } = *transaction
+        {
+            return self.transfer(
+                client,
+                to,
+                currency.unwrap_or(self.config.base_currency),
+                amount,
+            );
+        }
+
+        let withdrawn_today = self.withdrawn_today(transaction);
+        let mut postings = Vec::new();
+
+        // Processed on a copy, so a transaction that fails part way, e.g. as its record couldn't be stored,
+        // leaves the account as it was and books nothing
+        let mut account = self
+            .accounts
+            .get(&transaction.client())
+            .cloned()
+            .unwrap_or_else(|| Account::new(transaction.client()));
+        let result = account
+            .process(
+                transaction,
+                &mut self.transactions,
+                &self.config,
+                withdrawn_today,
+                &mut postings,
+            )
+            .and_then(|()| {
+                self.ledger
+                    .post(Some(transaction.transaction_type()), &postings)
+            });
+
+        if result.is_ok() {
+            self.accounts.insert(transaction.client(), account);
+        }
+
+        result
+    }
+
+    /// Both accounts are needed so transfers are applied here rather than in `Account::process`.
+    /// Either account is created if it doesn't exist and the transfer succeeds, a new sender needs a credit limit.
+    fn transfer(
+        &mut self,
+        from: ClientId,
+        to: ClientId,
+        currency: Currency,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        if from == to {
+            return Err(TransactionError::SelfTransfer);
+        }
+
+        // Copies, so both accounts can be borrowed at once and are left as they were if the transfer fails
+        let account = |client| {
+            self.accounts
+                .get(&client)
+                .cloned()
+                .unwrap_or_else(|| Account::new(client))
+        };
+        let mut sender = account(from);
+        let mut receiver = account(to);
+
+        let mut postings = Vec::new();
+        let result = sender
+            .transfer(
+                &mut receiver,
+                &mut postings,
+                currency,
+                amount,
+                self.config.fees.fee(TransactionType::Transfer, amount),
+                self.config.credit_limits.limit(from),
+            )
+            .and_then(|()| self.ledger.post(Some(TransactionType::Transfer), &postings));
+
+        if result.is_ok() {
+            self.accounts.insert(from, sender);
+            self.accounts.insert(to, receiver);
+        }
+
+        result
+    }
+
+    pub fn accounts(&self) -> impl Iterator<Item = &Account> {
+        self.accounts.values()
+    }
+
+    pub fn account(&self, client: ClientId) -> Option<&Account> {
+        self.accounts.get(&client)
+    }
+
+    /// The system accounts every transaction is posted against, the clients' accounts being `accounts`.
+    /// Balances loaded from a saved state are brought forward against `LedgerAccount::Opening`.
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
+
+    /// How far out the books are in each currency, see `Ledger::trial_balance`. Always empty, as every
+    /// transaction posts as much as it takes.
+    pub fn trial_balance(&self) -> BTreeMap<Currency, Money> {
+        self.ledger.trial_balance(self.accounts.values())
+    }
+
+    /// Checks clients' balances against what the ledger says went into them, currency by currency, see
+    /// `Ledger::reconcile`.
+    pub fn reconcile(&self) -> Vec<Reconciliation> {
+        self.ledger.reconcile(self.accounts.values())
+    }
+
+    /// Brings the accounts' balances forward against `LedgerAccount::Opening`, replacing the ledger, once
+    /// they've been loaded from somewhere that doesn't keep it.
+    pub(crate) fn open_ledger(&mut self) {
+        self.ledger = Ledger::opening(self.accounts.values());
+    }
+
+    /// The transactions applied to `client`'s account in the order they were applied, with the balances each
+    /// left, since `track_history` was called. Transfers are in the history of both accounts.
+    pub fn history(&self, client: ClientId) -> impl Iterator<Item = &AppliedTransaction> {
+        self.history
+            .as_ref()
+            .and_then(|history| history.get(&client))
+            .into_iter()
+            .flatten()
+    }
+
+    /// Writes the accounts to stdout as csv, a row for each of a client's currencies. The currency is in a last
+    /// column if a transaction named one or there's a balance in another currency than the base currency.
+    pub fn print_accounts(&self) -> Result<(), csv::Error> {
+        self.write_accounts(std::io::stdout(), None)
+    }
+
+    /// Writes the accounts as csv. Unless they're sorted the order of the rows changes from run to run.
+    pub fn write_accounts<W: Write>(
+        &self,
+        writer: W,
+        sort: Option<SortBy>,
+    ) -> Result<(), csv::Error> {
+        write_accounts(
+            self.accounts.values(),
+            &self.config,
+            self.currencies_named,
+            writer,
+            sort,
+        )
+    }
+
+    /// Whether a transaction has named its currency, see `write_accounts`.
+    pub(crate) fn currencies_named(&self) -> bool {
+        self.currencies_named
+    }
+}
+
+/// `TransactionProcessor::write_accounts` for accounts that aren't in a processor, e.g. `ClientActors`'.
+pub(crate) fn write_accounts<'a, I, W>(
+    accounts: I,
+    config: &Config,
+    currencies_named: bool,
+    writer: W,
+    sort: Option<SortBy>,
+) -> Result<(), csv::Error>
+where
+    I: IntoIterator<Item = &'a Account>,
+    W: Write,
+{
+    let mut rows: Vec<_> = accounts
+        .into_iter()
+        .flat_map(|account| {
+            account
+                .balances
+                .iter()
+                .map(move |(currency, balance)| (account, *currency, balance))
+        })
+        .collect();
+
+    if let Some(sort) = sort {
+        rows.sort_by(|(a, a_currency, a_balance), (b, b_currency, b_balance)| {
+            let ordering = match sort {
+                SortBy::Client => Ordering::Equal,
+                SortBy::Currency => a_currency.cmp(b_currency),
+                SortBy::Available => a_balance.available.cmp(&b_balance.available),
+                SortBy::Held => a_balance.held.cmp(&b_balance.held),
+                SortBy::Total => a_balance.total().cmp(&b_balance.total()),
+                SortBy::Locked => a.locked.cmp(&b.locked),
+            };
+
+            ordering
+                .then(a.client.cmp(&b.client))
+                .then(a_currency.cmp(b_currency))
+        });
+    }
+
+    let precision = config.output_precision();
+    let currency_column = currencies_named
+        || rows
+            .iter()
+            .any(|&(_, currency, _)| currency != config.base_currency);
+    let mut wtr = accounts_writer(writer, currency_column)?;
+
+    for (account, currency, balance) in rows {
+        write_account_row(
+            &mut wtr,
+            account,
+            currency_column.then_some(currency),
+            balance,
+            precision,
+        )?;
+    }
+
+    wtr.flush()?;
+    Ok(())
+}
+
+/// A csv writer for accounts that's written the header row.
+fn accounts_writer<W: Write>(
+    writer: W,
+    currency_column: bool,
+) -> Result<csv::Writer<W>, csv::Error> {
+    let mut wtr = csv::Writer::from_writer(writer);
+    wtr.write_record(account_headers(currency_column))?;
+
+    Ok(wtr)
+}
+
+fn account_headers(currency_column: bool) -> &'static [&'static str] {
+    let headers = ["client", "available", "held", "total", "locked", "currency"].as_slice();
+
+    match currency_column {
+        true => headers,
+        false => &headers[..5],
+    }
+}
+
+/// The currency is `None` if the accounts are written without a currency column.
+fn write_account_row<W: Write>(
+    wtr: &mut csv::Writer<W>,
+    account: &Account,
+    currency: Option<Currency>,
+    balance: &Balance,
+    precision: u32,
+) -> Result<(), csv::Error> {
+    let row = (
+        account.client,
+        format_amount(balance.available.clone(), precision),
+        format_amount(balance.held.clone(), precision),
+        format_amount(balance.total(), precision),
+        account.locked,
+    );
+
+    match currency {
+        Some(currency) => wtr.serialize((row.0, row.1, row.2, row.3, row.4, currency.as_str())),
+        None => wtr.serialize(row),
+    }
+}
+
+/// Accounts written as csv as their clients finish, see `TransactionProcessor::process_reader_streaming`.
+/// It's kept across readers, so a client that's finished in one file can't have rows in the next.
+pub struct AccountStream<W: Write> {
+    wtr: csv::Writer<W>,
+    /// Whether there's a currency column, once the header row has been written.
+    currency_column: Option<bool>,
+    /// The client of the last row, which isn't finished until a row for another client is read.
+    current: Option<ClientId>,
+    finished: HashSet<ClientId>,
+}
+
+impl<W: Write> AccountStream<W> {
+    /// The header row is written with the first account, with a currency column if a transaction named a
+    /// currency by then or the account has a balance in another currency than the base currency. An account
+    /// in another currency after that is an error.
+    pub fn new(writer: W) -> Result<Self, csv::Error> {
+        Ok(Self {
+            wtr: csv::Writer::from_writer(writer),
+            currency_column: None,
+            current: None,
+            finished: HashSet::new(),
+        })
+    }
+
+    fn write(
+        &mut self,
+        account: &Account,
+        config: &Config,
+        currencies_named: bool,
+    ) -> Result<(), csv::Error> {
+        let other_currency = account
+            .balances
+            .keys()
+            .find(|&&currency| currency != config.base_currency);
+        let currency_column = self.header(currencies_named || other_currency.is_some())?;
+
+        if let (false, Some(currency)) = (currency_column, other_currency) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "client {}'s {currency} balance can't be written, as the accounts were started without a currency column",
+                    account.client
+                ),
+            )
+            .into());
+        }
+
+        for (currency, balance) in &account.balances {
+            write_account_row(
+                &mut self.wtr,
+                account,
+                currency_column.then_some(*currency),
+                balance,
+                config.output_precision(),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the header row if it hasn't been, with a currency column if `currency_column`, and returns whether
+    /// there's a currency column.
+    fn header(&mut self, currency_column: bool) -> Result<bool, csv::Error> {
+        if let Some(currency_column) = self.currency_column {
+            return Ok(currency_column);
+        }
+
+        self.wtr.write_record(account_headers(currency_column))?;
+        self.currency_column = Some(currency_column);
+        Ok(currency_column)
+    }
+
+    /// Writes the header row if no accounts were written, and flushes.
+    fn finish(&mut self, currencies_named: bool) -> Result<(), csv::Error> {
+        self.header(currencies_named)?;
+        self.wtr.flush()?;
+        Ok(())
+    }
+}
+
+/// Column to sort the output by, ascending. Ties are sorted by client then currency.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortBy {
+    Client,
+    Currency,
+    Available,
+    Held,
+    Total,
+    Locked,
+}
+
+impl FromStr for SortBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "client" => Ok(SortBy::Client),
+            "currency" => Ok(SortBy::Currency),
+            "available" => Ok(SortBy::Available),
+            "held" => Ok(SortBy::Held),
+            "total" => Ok(SortBy::Total),
+            "locked" => Ok(SortBy::Locked),
+            _ => Err(format!("Unknown sort column {s}")),
+        }
+    }
+}
+
+fn wal_error(error: std::io::Error) -> TransactionError {
+    TransactionError::Store(StoreError::new(format!("write-ahead log: {error}")))
+}
+
+/// The first of the broken rules the transaction is rejected for, if any.
+fn rejected_by(broken: Vec<(Rule, bool)>) -> Option<Rule> {
+    broken
+        .into_iter()
+        .find_map(|(rule, rejected)| rejected.then_some(rule))
+}
+
+/// Rounded to `precision` decimal places. Overdrawn balances can round to -0, that's written as 0.
+pub(crate) fn format_amount(amount: impl Into<Money>, precision: u32) -> String {
+    let amount = amount.into().round_dp(precision);
+    let precision = precision as usize;
+
+    format!("{amount:.precision$}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::Balance;
+    use crate::config::{DisputePolicy, LockedPolicy};
+    use crate::error::StoreError;
+    use crate::fees::{Fee, FeeSchedule};
+    use crate::generate::Generator;
+    use crate::interest::Interest;
+    use crate::ledger::LedgerAccount;
+    use crate::limits::ClientLimit;
+    use crate::risk::BasicRiskScorer;
+    use crate::rules::Rules;
+    use crate::simulation::{Model, ReferenceModel, Simulation};
+    use crate::store::SpillStore;
+    use std::collections::BTreeMap;
+    use std::thread;
+    use TransactionError::*;
+
+    #[test]
+    fn deposit() {
+        let mut test = TransactionTest::default();
+
+        test.deposit(0, 0, 1.0, Ok(()));
+        test.deposit(1, 1, 2.0, Ok(()));
+        test.deposit(0, 2, 4.0, Ok(()));
+
+        test.expect(0, 5.0, 0.0, false);
+        test.expect(1, 2.0, 0.0, false);
+
+        test.run();
+    }
+
+    #[test]
+    fn negative_deposit() {
+        let mut test = TransactionTest::default();
+
+        test.deposit(0, 0, -1.0, Err(NegativeAmount));
+
+        test.run()
+    }
+
+    #[test]
+    fn withdrawal() {
+        let mut test = TransactionTest::default();
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.deposit(1, 1, 10.0, Ok(()));
+        test.withdrawal(0, 2, 1.0, Ok(()));
+        test.withdrawal(0, 3, 2.0, Ok(()));
+        test.withdrawal(1, 4, 7.0, Ok(()));
+
+        test.expect(0, 2.0, 0.0, false);
+        test.expect(1, 3.0, 0.0, false);
+
+        test.run();
+    }
+
+    #[test]
+    fn withdraw_negative() {
+        let mut test = TransactionTest::default();
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.withdrawal(0, 2, -1.0, Err(NegativeAmount));
+
+        test.expect(0, 5.0, 0.0, false);
+
+        test.run();
+    }
+
+    #[test]
+    fn withdrawal_amount_greater_than_available() {
+        let mut test = TransactionTest::default();
+
+        test.deposit(0, 0, 1.0, Ok(()));
+        test.withdrawal(0, 2, 2.0, Err(InsufficientFunds));
+
+        test.expect(0, 1.0, 0.0, false);
+
+        test.run()
+    }
+
+    #[test]
+    fn dispute() {
+        let mut test = TransactionTest::default();
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.deposit(1, 1, 10.0, Ok(()));
+        test.deposit(2, 2, 15.0, Ok(()));
+        test.dispute(0, 0, Ok(()));
+        test.dispute(1, 1, Ok(()));
+
+        test.expect(0, 0.0, 5.0, false);
+        test.expect(1, 0.0, 10.0, false);
+        test.expect(2, 15.0, 0.0, false);
+
+        test.run();
+    }
+
+    #[test]
+    fn dispute_the_same_transaction_multiple_times() {
+        let mut test = TransactionTest::default();
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.dispute(0, 0, Ok(()));
+        test.dispute(0, 0, Err(AlreadyDisputed));
+
+        test.expect(0, 0.0, 5.0, false);
+
+        test.run();
+    }
+
+    #[test]
+    fn dispute_missing_transaction() {
+        let mut test = TransactionTest::default();
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.dispute(0, 1, Err(UnknownTransaction));
+
+        test.expect(0, 5.0, 0.0, false);
+
+        test.run();
+    }
+
+    #[test]
+    fn dispute_and_disputed_have_different_clients() {
+        let mut test = TransactionTest::default();
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.dispute(1, 0, Err(ClientMismatch));
+
+        test.expect(0, 5.0, 0.0, false);
+
+        test.run();
+    }
+
+    #[test]
+    fn resolve() {
+        let mut test = TransactionTest::default();
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.deposit(1, 1, 10.0, Ok(()));
+        test.deposit(2, 2, 15.0, Ok(()));
+        test.dispute(0, 0, Ok(()));
+        test.dispute(1, 1, Ok(()));
+        test.resolve(0, 0, Ok(()));
+        test.resolve(1, 1, Ok(()));
+
+        test.expect(0, 5.0, 0.0, false);
+        test.expect(1, 10.0, 0.0, false);
+        test.expect(2, 15.0, 0.0, false);
+
+        test.run();
+    }
+
+    #[test]
+    fn resolve_with_different_client() {
+        let mut test = TransactionTest::default();
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.dispute(0, 0, Ok(()));
+        test.resolve(1, 0, Err(ClientMismatch));
+
+        test.expect(0, 0.0, 5.0, false);
+
+        test.run();
+    }
+
+    #[test]
+    fn resolve_undisputed_transaction() {
+        let mut test = TransactionTest::default();
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.resolve(0, 0, Err(NotDisputed));
+
+        test.expect(0, 5.0, 0.0, false);
+
+        test.run();
+    }
+
+    #[test]
+    fn resolve_missing_transaction() {
+        let mut test = TransactionTest::default();
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.resolve(0, 1, Err(UnknownTransaction));
+
+        test.expect(0, 5.0, 0.0, false);
+
+        test.run();
+    }
+
+    #[test]
+    fn resolve_transaction_that_has_been_chargebacked() {
+        let mut test = TransactionTest::default();
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.dispute(0, 0, Ok(()));
+        test.chargeback(0, 0, Ok(()));
+        test.resolve(0, 0, Err(NotDisputed));
+
+        test.expect(0, 0.0, 0.0, true);
+
+        test.run();
+    }
+
+    #[test]
+    fn chargeback() {
+        let mut test = TransactionTest::default();
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.deposit(1, 1, 10.0, Ok(()));
+        test.deposit(2, 2, 15.0, Ok(()));
+        test.dispute(0, 0, Ok(()));
+        test.dispute(1, 1, Ok(()));
+        test.chargeback(0, 0, Ok(()));
+        test.chargeback(1, 1, Ok(()));
+
+        test.expect(0, 0.0, 0.0, true);
+        test.expect(1, 0.0, 0.0, true);
+        test.expect(2, 15.0, 0.0, false);
+
+        test.run();
+    }
+
+    #[test]
+    fn chargeback_with_different_client() {
+        let mut test = TransactionTest::default();
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.dispute(0, 0, Ok(()));
+        test.chargeback(1, 0, Err(ClientMismatch));
+
+        test.expect(0, 0.0, 5.0, false);
+
+        test.run();
+    }
+
+    #[test]
+    fn chargeback_undisputed_transaction() {
+        let mut test = TransactionTest::default();
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.chargeback(0, 0, Err(NotDisputed));
+
+        test.expect(0, 5.0, 0.0, false);
+
+        test.run();
+    }
+
+    #[test]
+    fn chargeback_missing_transaction() {
+        let mut test = TransactionTest::default();
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.chargeback(0, 1, Err(UnknownTransaction));
+
+        test.expect(0, 5.0, 0.0, false);
+
+        test.run();
+    }
+
+    #[test]
+    fn chargeback_transaction_that_has_been_resolved() {
+        let mut test = TransactionTest::default();
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.dispute(0, 0, Ok(()));
+        test.resolve(0, 0, Ok(()));
+        test.chargeback(0, 0, Err(NotDisputed));
+
+        test.expect(0, 5.0, 0.0, false);
+
+        test.run();
+    }
+
+    #[test]
+    fn transfer() {
+        let mut test = TransactionTest::default();
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.deposit(1, 1, 1.0, Ok(()));
+        test.transfer(0, 1, 2, 2.0, Ok(()));
+        test.transfer(0, 2, 3, 1.0, Ok(()));
+
+        test.expect(0, 2.0, 0.0, false);
+        test.expect(1, 3.0, 0.0, false);
+        test.expect(2, 1.0, 0.0, false);
+
+        test.run();
+    }
+
+    #[test]
+    fn transfer_amount_greater_than_available() {
+        let mut test = TransactionTest::default();
+
+        test.deposit(0, 0, 1.0, Ok(()));
+        test.transfer(0, 1, 1, 2.0, Err(InsufficientFunds));
+        test.transfer(2, 0, 2, 1.0, Err(InsufficientFunds));
+        test.transfer(0, 1, 3, -1.0, Err(NegativeAmount));
+
+        test.expect(0, 1.0, 0.0, false);
+
+        test.run();
+    }
+
+    #[test]
+    fn transfer_locked_account() {
+        let mut test = TransactionTest::default();
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.deposit(1, 1, 5.0, Ok(()));
+        test.dispute(1, 1, Ok(()));
+        test.chargeback(1, 1, Ok(()));
+        test.transfer(0, 1, 2, 1.0, Err(AccountLocked));
+        test.transfer(1, 0, 3, 0.0, Err(AccountLocked));
+
+        test.expect(0, 5.0, 0.0, false);
+        test.expect(1, 0.0, 0.0, true);
+
+        test.run();
+    }
+
+    #[test]
+    fn transfer_to_same_client() {
+        let mut test = TransactionTest::default();
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.transfer(0, 0, 1, 1.0, Err(SelfTransfer));
+
+        test.expect(0, 5.0, 0.0, false);
+
+        test.run();
+    }
+
+    #[test]
+    fn transfer_csv() {
+        let input = "type,client,tx,amount,to
+            deposit,0,0,5.0,
+            transfer,0,1,2.0,1";
+
+        let mut transaction_processor = TransactionProcessor::new();
+        transaction_processor
+            .process_transactions(input.as_bytes())
+            .unwrap();
+
+        assert_eq!(
+            transaction_processor
+                .account(ClientId::from(1))
+                .map(|account| account.balance(Currency::default()).available),
+            Some(Decimal::from(2).into())
+        );
+    }
+
+    #[test]
+    fn unlock() {
+        let mut test = TransactionTest::default();
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.deposit(0, 1, 1.0, Ok(()));
+        test.dispute(0, 1, Ok(()));
+        test.chargeback(0, 1, Ok(()));
+        test.unlock(0, 2, Ok(()));
+        test.unlock(0, 3, Err(NotLocked));
+        test.unlock(1, 4, Err(NotLocked));
+
+        test.expect(0, 5.0, 0.0, false);
+
+        test.run();
+    }
+
+    #[test]
+    fn redispute_resolved() {
+        let mut test = TransactionTest::default();
+        test.config.redispute_resolved = true;
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.deposit(0, 1, 1.0, Ok(()));
+        test.dispute(0, 0, Ok(()));
+        test.dispute(0, 0, Err(AlreadyDisputed));
+        test.resolve(0, 0, Ok(()));
+        test.dispute(0, 0, Ok(()));
+        test.chargeback(0, 0, Ok(()));
+        test.dispute(0, 0, Err(AlreadyDisputed));
+
+        test.expect(0, 1.0, 0.0, true);
+
+        test.run();
+    }
+
+    #[test]
+    fn chargeback_reversal() {
+        let mut test = TransactionTest::default();
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.deposit(0, 1, 1.0, Ok(()));
+        test.chargeback_reversal(0, 1, Err(NotChargedBack));
+        test.dispute(0, 1, Ok(()));
+        test.chargeback(0, 1, Ok(()));
+        test.chargeback_reversal(1, 1, Err(ClientMismatch));
+        test.chargeback_reversal(0, 2, Err(UnknownTransaction));
+        test.chargeback_reversal(0, 1, Ok(()));
+        test.chargeback_reversal(0, 1, Err(NotChargedBack));
+        test.dispute(0, 1, Err(AlreadyDisputed));
+
+        // Still locked until it's unlocked
+        test.expect(0, 6.0, 0.0, true);
+
+        test.run();
+    }
+
+    #[test]
+    fn chargeback_reversal_unlocks() {
+        let mut test = TransactionTest::default();
+        test.config.locked_policy = LockedPolicy::Reject;
+        test.config.unlock_on_chargeback_reversal = true;
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.dispute(0, 0, Ok(()));
+        test.chargeback(0, 0, Ok(()));
+        test.withdrawal(0, 1, 1.0, Err(AccountLocked));
+        test.chargeback_reversal(0, 0, Ok(()));
+        test.withdrawal(0, 2, 1.0, Ok(()));
+
+        test.expect(0, 4.0, 0.0, false);
+
+        test.run();
+    }
+
+    #[test]
+    fn fee() {
+        let mut test = TransactionTest::default();
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.fee(0, 1, 1.5, Ok(()));
+        test.fee(0, 2, -1.0, Err(NegativeAmount));
+        test.fee(1, 3, 2.0, Ok(()));
+        test.expect(0, 3.5, 0.0, false);
+        test.expect(1, -2.0, 0.0, false);
+        test.run();
+    }
+
+    #[test]
+    fn fee_schedule() {
+        let mut test = TransactionTest::default();
+        test.config.fees.set(
+            TransactionType::Withdrawal,
+            Fee {
+                rate: Decimal::new(25, 2),
+                flat: Decimal::ZERO,
+            },
+        );
+        test.config.fees.set(
+            TransactionType::Chargeback,
+            Fee {
+                rate: Decimal::ZERO,
+                flat: Decimal::from(10),
+            },
+        );
+
+        test.deposit(0, 0, 10.0, Ok(()));
+        test.withdrawal(0, 1, 4.0, Ok(()));
+        test.withdrawal(0, 2, 4.5, Err(InsufficientFunds));
+        test.deposit(1, 3, 5.0, Ok(()));
+        test.dispute(1, 3, Ok(()));
+        test.chargeback(1, 3, Ok(()));
+        test.expect(0, 5.0, 0.0, false);
+        test.expect(1, -10.0, 0.0, true);
+        test.run();
+    }
+
+    #[test]
+    fn fee_schedule_csv() {
+        let fees = "type,rate,flat\nwithdrawal,0.01,\nchargeback,,15\n";
+        let fees = FeeSchedule::from_reader(fees.as_bytes()).unwrap();
+
+        assert_eq!(
+            fees.fee(TransactionType::Withdrawal, Decimal::from(200)),
+            Decimal::from(2)
+        );
+        assert_eq!(
+            fees.fee(TransactionType::Chargeback, Decimal::from(200)),
+            Decimal::from(15)
+        );
+        assert_eq!(
+            fees.fee(TransactionType::Deposit, Decimal::from(200)),
+            Decimal::ZERO
+        );
+    }
+
+    #[test]
+    fn reversal() {
+        let mut test = TransactionTest::default();
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.withdrawal(0, 1, 2.0, Ok(()));
+        test.reversal(0, 0, Err(InsufficientFunds));
+        test.reversal(0, 1, Ok(()));
+        test.reversal(0, 1, Err(NotReversible));
+        test.reversal(0, 0, Ok(()));
+        test.dispute(0, 0, Err(AlreadyDisputed));
+        test.expect(0, 0.0, 0.0, false);
+        test.run();
+    }
+
+    #[test]
+    fn reversal_of_disputed_transaction() {
+        let mut test = TransactionTest::default();
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.deposit(0, 1, 2.0, Ok(()));
+        test.dispute(0, 0, Ok(()));
+        test.reversal(0, 0, Err(NotReversible));
+        test.dispute(0, 1, Ok(()));
+        test.resolve(0, 1, Ok(()));
+        test.reversal(0, 1, Ok(()));
+        test.expect(0, 0.0, 5.0, false);
+        test.run();
+    }
+
+    #[test]
+    fn reversal_with_different_client() {
+        let mut test = TransactionTest::default();
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.reversal(1, 0, Err(ClientMismatch));
+        test.reversal(0, 1, Err(UnknownTransaction));
+        test.expect(0, 5.0, 0.0, false);
+        test.run();
+    }
+
+    #[test]
+    fn dispute_withdrawal() {
+        let mut test = TransactionTest::default();
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.withdrawal(0, 1, 2.0, Ok(()));
+        test.dispute(0, 1, Err(UnknownTransaction));
+        test.expect(0, 3.0, 0.0, false);
+        test.run();
+    }
+
+    #[test]
+    fn currencies() {
+        let input = "type,client,tx,amount,currency
+            deposit,0,0,5.0,
+            deposit,0,1,3.0,EUR
+            withdrawal,0,2,4.0,eur
+            dispute,0,1,,
+            withdrawal,0,3,1.0,USD
+            deposit,0,4,1.0,EURO";
+
+        let mut transaction_processor = TransactionProcessor::new();
+        let mut rejected = Vec::new();
+        transaction_processor
+            .process_reader(input.as_bytes(), InputFormat::Csv, |rejection| {
+                rejected.push(rejection.line);
+                Ok(())
+            })
+            .unwrap();
+
+        let account = transaction_processor.account(ClientId::from(0)).unwrap();
+        assert_eq!(rejected, vec![4, 7]);
+        assert_eq!(account.balances.len(), 2);
+        assert_eq!(
+            account.balance("USD".parse().unwrap()),
+            Balance {
+                available: Decimal::from(4).into(),
+                held: Decimal::ZERO.into(),
+                shortfall: Decimal::ZERO.into(),
+            }
+        );
+        assert_eq!(
+            account.balance("EUR".parse().unwrap()),
+            Balance {
+                available: Decimal::ZERO.into(),
+                held: Decimal::from(3).into(),
+                shortfall: Decimal::ZERO.into(),
+            }
+        );
+    }
+
+    #[test]
+    fn base_currency() {
+        let mut test = TransactionTest::default();
+        test.config.base_currency = "GBP".parse().unwrap();
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.expect(0, 5.0, 0.0, false);
+        test.run();
+    }
+
+    #[test]
+    fn convert() {
+        let usd = Currency::default();
+        let eur: Currency = "EUR".parse().unwrap();
+        let mut config = Config::default();
+        config.rates.set(eur, usd, Decimal::new(108, 2));
+
+        let mut transaction_processor = TransactionProcessor::new();
+        transaction_processor.set_config(config);
+
+        let convert = |tx: u64, amount, to_currency| Transaction::Convert {
+            client: ClientId::from(0),
+            tx: TxId::from(tx),
+            amount: Decimal::from(amount),
+            currency: None,
+            to_currency,
+            timestamp: None,
+        };
+
+        transaction_processor
+            .process(&Transaction::Deposit {
+                client: ClientId::from(0),
+                tx: TxId::from(0),
+                amount: Decimal::from(10),
+                currency: None,
+                timestamp: None,
+            })
+            .unwrap();
+        assert_eq!(transaction_processor.process(&convert(1, 5, eur)), Ok(()));
+        assert_eq!(
+            transaction_processor.process(&convert(2, 10, eur)),
+            Err(InsufficientFunds)
+        );
+        assert_eq!(
+            transaction_processor.process(&convert(3, 1, "GBP".parse().unwrap())),
+            Err(UnknownRate)
+        );
+
+        let account = transaction_processor.account(ClientId::from(0)).unwrap();
+        assert_eq!(account.balance(usd).available, Decimal::from(5));
+        assert_eq!(account.balance(eur).available, Decimal::new(46296, 4));
+    }
+
+    #[test]
+    fn ledger() {
+        let usd = Currency::default();
+        let eur: Currency = "EUR".parse().unwrap();
+        let mut config = Config::default();
+        config.rates.set(usd, eur, Decimal::new(93, 2));
+        config.dispute_policy = DisputePolicy::Clamp;
+        config.fees =
+            FeeSchedule::from_reader("type,rate,flat\nwithdrawal,,0.5\n".as_bytes()).unwrap();
+
+        let mut transaction_processor = TransactionProcessor::new();
+        transaction_processor.set_config(config);
+
+        let input = "type,client,tx,amount,to,to_currency
+            deposit,1,1,10.0,,
+            deposit,2,2,5.0,,
+            withdrawal,1,3,4.0,,
+            reversal,1,3,,,
+            transfer,2,4,2.0,1,
+            convert,2,5,1.5,,EUR
+            fee,2,6,0.25,,
+            interest,2,7,0.1,,
+            withdrawal,1,8,5.0,,
+            dispute,1,1,,,
+            chargeback,1,1,,,
+            chargeback_reversal,1,1,,,";
+        transaction_processor
+            .process_transactions(input.as_bytes())
+            .unwrap();
+
+        let ledger = transaction_processor.ledger();
+        assert_eq!(
+            ledger.balance(LedgerAccount::Suspense, usd),
+            Decimal::from(-10)
+        );
+        assert_eq!(
+            ledger.balance(LedgerAccount::Fees, usd),
+            Decimal::new(125, 2)
+        );
+        assert_eq!(
+            ledger.balance(LedgerAccount::Interest, usd),
+            Decimal::new(-1, 1)
+        );
+        assert_eq!(
+            ledger.balance(LedgerAccount::Conversion, usd),
+            Decimal::new(15, 1)
+        );
+        assert_eq!(
+            ledger.balance(LedgerAccount::Conversion, eur),
+            Decimal::new(-1395, 3)
+        );
+        assert_eq!(
+            ledger.balance(LedgerAccount::ChargebackLoss, usd),
+            Decimal::ZERO
+        );
+        assert!(transaction_processor.trial_balance().is_empty());
+
+        // A handler's changes are booked against suspense, and loaded balances against opening
+        transaction_processor.register_handler(
+            "bonus",
+            |transaction: &CustomTransaction, account: &mut Account, config: &Config| {
+                account
+                    .balances
+                    .entry(config.base_currency)
+                    .or_default()
+                    .available += transaction.amount.unwrap_or_default();
+                Ok(())
+            },
+        );
+        transaction_processor
+            .process_transactions("type,client,tx,amount\nbonus,3,8,1.0\n".as_bytes())
+            .unwrap();
+        assert!(transaction_processor.trial_balance().is_empty());
+
+        let path = std::env::temp_dir().join("payments_ledger.bin");
+        transaction_processor.save(&path).unwrap();
+        let loaded = TransactionProcessor::load(&path).unwrap();
+        assert_eq!(
+            loaded.ledger().balance(LedgerAccount::Opening, eur),
+            Decimal::new(-1395, 3)
+        );
+        assert!(loaded.trial_balance().is_empty());
+    }
+
+    #[test]
+    fn reconcile() {
+        let usd = Currency::default();
+        let mut transaction_processor = TransactionProcessor::new();
+        transaction_processor
+            .process_transactions(
+                "type,client,tx,amount
+                deposit,1,1,10.0
+                deposit,1,2,2.0
+                withdrawal,1,3,3.0
+                dispute,1,2,
+                chargeback,1,2,"
+                    .as_bytes(),
+            )
+            .unwrap();
+
+        let reconciliations = transaction_processor.reconcile();
+        assert_eq!(reconciliations.len(), 1);
+        assert_eq!(
+            reconciliations[0].flows,
+            vec![
+                (
+                    LedgerAccount::Suspense,
+                    Some(TransactionType::Deposit),
+                    Money::from(Decimal::from(12))
+                ),
+                (
+                    LedgerAccount::Suspense,
+                    Some(TransactionType::Withdrawal),
+                    Money::from(Decimal::from(-3))
+                ),
+                (
+                    LedgerAccount::Suspense,
+                    Some(TransactionType::Chargeback),
+                    Money::from(Decimal::from(-2))
+                ),
+            ]
+        );
+        assert!(reconciliations[0].balances());
+
+        // Changed behind the ledger's back
+        transaction_processor
+            .accounts
+            .get_mut(&ClientId::from(1))
+            .unwrap()
+            .balances
+            .get_mut(&usd)
+            .unwrap()
+            .available += Decimal::ONE;
+
+        let reconciliation = &transaction_processor.reconcile()[0];
+        assert_eq!(reconciliation.difference(), Decimal::ONE);
+        assert_eq!(
+            transaction_processor.trial_balance(),
+            BTreeMap::from([(usd, Decimal::ONE.into())])
+        );
+        let breakdown = reconciliation.to_string();
+        let lines: Vec<Vec<_>> = breakdown
+            .lines()
+            .map(|line| line.split_whitespace().collect())
+            .collect();
+        assert!(lines.contains(&vec!["suspense", "from", "chargeback", "-2.0"]));
+        assert!(lines.contains(&vec!["out", "by", "1.0"]));
+    }
+
+    #[test]
+    fn credit_limit() {
+        let mut test = TransactionTest::default();
+        test.config.credit_limits.default = Decimal::from(5);
+        test.config
+            .credit_limits
+            .set(ClientId::from(1), Decimal::ZERO);
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.withdrawal(0, 1, 8.0, Ok(()));
+        test.withdrawal(0, 2, 3.0, Err(InsufficientFunds));
+        test.withdrawal(1, 3, 1.0, Err(InsufficientFunds));
+        test.transfer(2, 0, 4, 1.0, Ok(()));
+        test.expect(0, -2.0, 0.0, false);
+        test.expect(2, -1.0, 0.0, false);
+        test.run();
+    }
+
+    #[test]
+    fn format_negative_zero() {
+        assert_eq!(format_amount(Decimal::new(-1, 5), 4), "0.0000");
+        assert_eq!(format_amount(Decimal::new(-15, 1), 4), "-1.5000");
+        assert_eq!(format_amount(Decimal::new(-1, 3), 2), "0.00");
+    }
+
+    #[test]
+    fn precision() {
+        let mut test = TransactionTest::default();
+        test.config.precision = Some(2);
+
+        test.deposit(0, 0, 1.5, Ok(()));
+        test.deposit(0, 1, 0.125, Err(TooPrecise));
+        test.withdrawal(0, 2, 0.25, Ok(()));
+        test.expect(0, 1.25, 0.0, false);
+        test.run();
+    }
+
+    #[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+    #[test]
+    fn minor_units() {
+        let mut test = TransactionTest::default();
+
+        test.deposit(0, 0, 1.5, Ok(()));
+        test.deposit(0, 1, 0.12345, Err(TooPrecise));
+        test.deposit(0, 2, 1e15, Err(AmountTooLarge));
+        test.withdrawal(0, 3, 0.25, Ok(()));
+        test.expect(0, 1.25, 0.0, false);
+        test.run();
+    }
+
+    #[cfg(not(feature = "bigdecimal"))]
+    #[test]
+    fn balance_overflow() {
+        // Fits in a balance once but not twice
+        let amount = match cfg!(feature = "minor-units") {
+            true => Decimal::from(900_000_000_000_000_u64),
+            false => Decimal::MAX,
+        };
+        let deposit = |client, tx| Transaction::Deposit {
+            client: ClientId::from(client),
+            tx: TxId::from(tx),
+            amount,
+            currency: None,
+            timestamp: None,
+        };
+        let mut transaction_processor = TransactionProcessor::new();
+
+        assert_eq!(transaction_processor.process(&deposit(1, 1)), Ok(()));
+        assert_eq!(
+            transaction_processor.process(&deposit(1, 2)),
+            Err(AmountTooLarge)
+        );
+        // Another client's balance would fit, but not what all deposits have taken from suspense
+        assert_eq!(
+            transaction_processor.validate(&deposit(2, 3)),
+            Err(AmountTooLarge)
+        );
+        assert_eq!(
+            transaction_processor.process(&deposit(2, 3)),
+            Err(AmountTooLarge)
+        );
+
+        assert_eq!(
+            transaction_processor
+                .account(ClientId::from(1))
+                .unwrap()
+                .balance(Currency::default())
+                .available,
+            amount
+        );
+        assert_eq!(transaction_processor.account(ClientId::from(2)), None);
+        assert!(transaction_processor.trial_balance().is_empty());
+    }
+
+    #[test]
+    fn dispute_policy_reject() {
+        let mut test = TransactionTest::default();
+        test.config.dispute_policy = DisputePolicy::Reject;
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.withdrawal(0, 1, 3.0, Ok(()));
+        test.dispute(0, 0, Err(InsufficientFunds));
+        test.deposit(0, 2, 1.0, Ok(()));
+        test.dispute(0, 2, Ok(()));
+        test.expect(0, 2.0, 1.0, false);
+        test.run();
+    }
+
+    #[test]
+    fn locked_policy_allow() {
+        let mut test = TransactionTest::default();
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.deposit(0, 1, 1.0, Ok(()));
+        test.dispute(0, 0, Ok(()));
+        test.chargeback(0, 0, Ok(()));
+        test.deposit(0, 2, 2.0, Ok(()));
+        test.withdrawal(0, 3, 1.0, Ok(()));
+        test.expect(0, 2.0, 0.0, true);
+        test.run();
+    }
+
+    #[test]
+    fn locked_policy_reject_debits() {
+        let mut test = TransactionTest::default();
+        test.config.locked_policy = LockedPolicy::RejectDebits;
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.deposit(0, 1, 1.0, Ok(()));
+        test.dispute(0, 0, Ok(()));
+        test.chargeback(0, 0, Ok(()));
+        test.deposit(0, 2, 2.0, Ok(()));
+        test.withdrawal(0, 3, 1.0, Err(AccountLocked));
+        test.fee(0, 4, 1.0, Err(AccountLocked));
+        test.reversal(0, 1, Err(AccountLocked));
+        test.dispute(0, 2, Ok(()));
+        test.expect(0, 1.0, 2.0, true);
+        test.run();
+    }
+
+    #[test]
+    fn locked_policy_reject() {
+        let mut test = TransactionTest::default();
+        test.config.locked_policy = LockedPolicy::Reject;
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.deposit(0, 1, 1.0, Ok(()));
+        test.dispute(0, 0, Ok(()));
+        test.chargeback(0, 0, Ok(()));
+        test.deposit(0, 2, 2.0, Err(AccountLocked));
+        test.dispute(0, 1, Err(AccountLocked));
+        test.unlock(0, 3, Ok(()));
+        test.deposit(0, 4, 2.0, Ok(()));
+        test.expect(0, 3.0, 0.0, false);
+        test.run();
+    }
+
+    #[test]
+    fn dispute_window() {
+        let mut transaction_processor = TransactionProcessor::new();
+        transaction_processor.set_config(Config {
+            dispute_window: Some(std::time::Duration::from_secs(90 * 24 * 60 * 60)),
+            ..Config::default()
+        });
+
+        // 1665360000 is 2022-10-10, 1673136000 is 90 days later and 1673222400 91 days later
+        let input = "type,client,tx,amount,timestamp
+            deposit,1,1,1.0,1665360000
+            deposit,1,2,2.0,1665360000
+            deposit,1,3,4.0,
+            dispute,1,1,,1673136000
+            dispute,1,2,,1673222400
+            dispute,1,3,,1673222400";
+
+        let mut rejects = Vec::new();
+        transaction_processor
+            .process_transactions_with_rejects(input.as_bytes(), |rejection| {
+                rejects.push((rejection.line, rejection.reason));
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(rejects, [(6, DisputeWindowExpired.into())]);
+        assert_eq!(
+            transaction_processor
+                .account(ClientId::from(1))
+                .map(|account| account.balance(Currency::default()).held),
+            Some(Decimal::from(5).into())
+        );
+    }
+
+    #[test]
+    fn interest() {
+        let mut transaction_processor = TransactionProcessor::new();
+        transaction_processor.set_config(Config {
+            interest: Some(Interest {
+                rate: "0.0365".parse().unwrap(),
+                ..Interest::default()
+            }),
+            ..Config::default()
+        });
+
+        // 1665360000 is 2022-10-10 and 1666224000 10 days later
+        let input = "type,client,tx,amount,timestamp
+            deposit,1,1,1000.0,1665360000
+            deposit,2,2,0.5,1665360000
+            deposit,1,3,1.0,1666224000";
+
+        transaction_processor
+            .process_transactions(input.as_bytes())
+            .unwrap();
+
+        let available = |transaction_processor: &TransactionProcessor, client: u64| {
+            transaction_processor
+                .account(ClientId::from(client))
+                .map(|account| account.balance(Currency::default()).available)
+        };
+
+        assert_eq!(available(&transaction_processor, 1), "1002".parse().ok());
+        assert_eq!(available(&transaction_processor, 2), "0.5005".parse().ok());
+
+        transaction_processor.post_interest(365, None).unwrap();
+
+        assert_eq!(
+            available(&transaction_processor, 1),
+            "1038.5730".parse().ok()
+        );
+    }
+
+    #[test]
+    fn scheduled() {
+        let mut transaction_processor = TransactionProcessor::new();
+
+        let input = "type,client,tx,amount,timestamp,effective
+            deposit,1,1,10.0,1000,
+            deposit,1,2,5.0,1000,3000
+            withdrawal,1,3,2.0,2000,
+            deposit,1,4,1.5,4000,5000";
+
+        transaction_processor
+            .process_transactions(input.as_bytes())
+            .unwrap();
+
+        let available = |transaction_processor: &TransactionProcessor| {
+            transaction_processor
+                .account(ClientId::from(1))
+                .map(|account| account.balance(Currency::default()).available)
+        };
+
+        assert_eq!(
+            available(&transaction_processor),
+            Some(Decimal::from(13).into())
+        );
+        assert_eq!(
+            transaction_processor
+                .pending()
+                .map(|(effective, transaction)| (effective, transaction.tx()))
+                .collect::<Vec<_>>(),
+            [(5000, TxId::from(4))]
+        );
+
+        let mut pending = Vec::new();
+        transaction_processor.write_pending(&mut pending).unwrap();
+        assert_eq!(
+            String::from_utf8(pending).unwrap(),
+            "effective,type,client,tx,amount,currency\n5000,deposit,1,4,1.5000,USD\n"
+        );
+
+        transaction_processor.advance_clock(5000).unwrap();
+
+        assert_eq!(available(&transaction_processor), "14.5".parse().ok());
+        assert_eq!(transaction_processor.pending().count(), 0);
+    }
+
+    #[test]
+    fn held_funds() {
+        let mut transaction_processor = TransactionProcessor::new();
+
+        let input = "type,client,tx,amount,timestamp
+            deposit,1,1,5.0,1000
+            deposit,1,2,3.0,1000
+            deposit,2,3,2.0,1000
+            deposit,2,4,1.0,
+            dispute,2,4,,
+            dispute,1,2,,90000
+            dispute,1,1,,1000
+            dispute,2,3,,2000
+            resolve,2,3,,3000
+            deposit,3,5,1.0,260200";
+
+        transaction_processor
+            .process_transactions(input.as_bytes())
+            .unwrap();
+
+        assert_eq!(
+            transaction_processor
+                .held_funds()
+                .into_iter()
+                .map(|(tx, record, held_for)| (tx, record.client, held_for))
+                .collect::<Vec<_>>(),
+            [
+                (TxId::from(1), ClientId::from(1), Some(259200)),
+                (TxId::from(2), ClientId::from(1), Some(170200)),
+                (TxId::from(4), ClientId::from(2), None),
+            ]
+        );
+
+        let mut held_funds = Vec::new();
+        transaction_processor
+            .write_held_funds(&mut held_funds)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(held_funds).unwrap(),
+            "client,tx,amount,currency,disputed_at,held_seconds,held_days\n\
+            1,1,5.0000,USD,1000,259200,3\n\
+            1,2,3.0000,USD,90000,170200,1\n\
+            2,4,1.0000,USD,,,\n"
+        );
+    }
+
+    #[test]
+    fn rules() {
+        // 86400 is the second day after the epoch and 172800 the third
+        let input = "type,client,tx,amount,timestamp
+            deposit,1,1,1.0,86400
+            deposit,1,2,1.0,86400
+            deposit,1,3,1.0,86400
+            deposit,1,4,10.0,172800
+            withdrawal,1,5,6.0,172800";
+
+        for (action, available, rejected) in [
+            (RuleAction::Flag, 7, vec![]),
+            (
+                RuleAction::Reject,
+                12,
+                vec![
+                    (4, RuleBroken(Rule::DepositsPerDay).into()),
+                    (6, RuleBroken(Rule::MaxWithdrawal).into()),
+                ],
+            ),
+        ] {
+            let suspicious = Arc::new(std::sync::Mutex::new(Vec::new()));
+            let mut transaction_processor = TransactionProcessor::new();
+            transaction_processor.set_config(Config {
+                rules: Rules {
+                    max_deposits_per_day: Some(2),
+                    max_withdrawal: Some(Decimal::from(5)),
+                    action,
+                    ..Rules::default()
+                },
+                ..Config::default()
+            });
+
+            let recorded = suspicious.clone();
+            transaction_processor.subscribe(move |event| {
+                if let Event::Suspicious(activity) = event {
+                    recorded.lock().unwrap().push((activity.tx, activity.rule));
+                }
+            });
+
+            let mut rejects = Vec::new();
+            transaction_processor
+                .process_transactions_with_rejects(input.as_bytes(), |rejection| {
+                    rejects.push((rejection.line, rejection.reason));
+                    Ok(())
+                })
+                .unwrap();
+
+            assert_eq!(
+                *suspicious.lock().unwrap(),
+                [
+                    (TxId::from(3), Rule::DepositsPerDay),
+                    (TxId::from(5), Rule::MaxWithdrawal)
+                ]
+            );
+            assert_eq!(rejects, rejected);
+            assert_eq!(
+                transaction_processor
+                    .account(ClientId::from(1))
+                    .map(|account| account.balance(Currency::default()).available),
+                Some(Decimal::from(available).into())
+            );
+        }
+    }
+
+    #[test]
+    fn risk_scorer() {
+        let suspicious = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut transaction_processor = TransactionProcessor::new();
+        transaction_processor.set_risk_scorer(BasicRiskScorer {
+            flag_at: 30,
+            reject_at: 60,
+        });
+
+        let recorded = suspicious.clone();
+        transaction_processor.subscribe(move |event| {
+            if let Event::Suspicious(activity) = event {
+                recorded
+                    .lock()
+                    .unwrap()
+                    .push((activity.tx, activity.rejected));
+            }
+        });
+
+        // Client 1's withdrawal scores 10 for the dispute and 20 for taking out over half, client 2's
+        // deposit 40 for the chargeback, 10 for the dispute and 30 for the locked account
+        let input = "type,client,tx,amount
+            deposit,1,1,10.0
+            dispute,1,1,
+            resolve,1,1,
+            withdrawal,1,2,6.0
+            deposit,2,3,5.0
+            dispute,2,3,
+            chargeback,2,3,
+            deposit,2,4,1.0";
+
+        let mut rejects = Vec::new();
+        transaction_processor
+            .process_transactions_with_rejects(input.as_bytes(), |rejection| {
+                rejects.push((rejection.line, rejection.reason));
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(
+            *suspicious.lock().unwrap(),
+            [(TxId::from(2), false), (TxId::from(4), true)]
+        );
+        assert_eq!(rejects, [(9, RuleBroken(Rule::RiskScore).into())]);
+        assert_eq!(
+            transaction_processor
+                .account(ClientId::from(1))
+                .map(|account| account.balance(Currency::default()).available),
+            Some(Decimal::from(4).into())
+        );
+    }
+
+    #[test]
+    fn client_limits() {
+        let mut test = TransactionTest::default();
+        test.config.limits.set(
+            ClientId::from(0),
+            ClientLimit {
+                max_withdrawal: Some(Decimal::from(3)),
+                max_balance: Some(Decimal::from(10)),
+                daily_out_limit: Some(Decimal::from(4)),
+            },
+        );
+
+        test.deposit(0, 0, 8.0, Ok(()));
+        test.deposit(0, 1, 3.0, Err(BalanceLimitExceeded));
+        test.withdrawal(0, 2, 3.5, Err(WithdrawalLimitExceeded));
+        test.withdrawal(0, 3, 3.0, Ok(()));
+        test.withdrawal(0, 4, 1.5, Err(DailyLimitExceeded));
+        test.withdrawal(0, 5, 1.0, Ok(()));
+        test.deposit(1, 6, 20.0, Ok(()));
+        test.expect(0, 4.0, 0.0, false);
+        test.expect(1, 20.0, 0.0, false);
+        test.run();
+    }
+
+    #[test]
+    fn dispute_policy_clamp() {
+        let mut test = TransactionTest::default();
+        test.config.dispute_policy = DisputePolicy::Clamp;
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.withdrawal(0, 1, 3.0, Ok(()));
+        test.dispute(0, 0, Ok(()));
+        test.deposit(0, 2, 1.0, Ok(()));
+        test.resolve(0, 0, Ok(()));
+        test.deposit(1, 3, 5.0, Ok(()));
+        test.withdrawal(1, 4, 4.0, Ok(()));
+        test.dispute(1, 3, Ok(()));
+        test.chargeback(1, 3, Ok(()));
+        test.expect(0, 3.0, 0.0, false);
+        test.expect(1, 0.0, 0.0, true);
+        test.expect_shortfall(1, 4.0);
+        test.run();
+    }
+
+    #[test]
+    fn duplicate_transaction() {
+        let mut test = TransactionTest::default();
+
+        test.deposit(0, 0, 5.0, Ok(()));
+        test.deposit(0, 0, 2.0, Err(DuplicateTransaction));
+        test.withdrawal(0, 0, 1.0, Err(DuplicateTransaction));
+        test.withdrawal(0, 1, 1.0, Ok(()));
+        test.deposit(1, 1, 1.0, Err(DuplicateTransaction));
+        test.deposit(0, 2, -1.0, Err(NegativeAmount));
+        test.dispute(0, 2, Err(UnknownTransaction));
+        test.dispute(0, 0, Ok(()));
+        test.expect(0, -1.0, 5.0, false);
+        test.run();
+    }
+
+    #[test]
+    fn track_applied() {
+        let input = "type,client,tx,amount
+            deposit,0,0,5.0
+            dispute,0,0,";
+
+        let mut transaction_processor = TransactionProcessor::new();
+        transaction_processor.track_applied();
+        let mut rejected = Vec::new();
+
+        for _ in 0..2 {
+            transaction_processor
+                .process_reader(input.as_bytes(), InputFormat::Csv, |rejection| {
+                    rejected.push((rejection.line, rejection.reason));
+                    Ok(())
+                })
+                .unwrap();
+        }
+
+        assert_eq!(
+            rejected,
+            vec![
+                (2, RejectReason::Transaction(AlreadyApplied)),
+                (3, RejectReason::Transaction(AlreadyApplied))
+            ]
+        );
+        assert_eq!(
+            transaction_processor
+                .account(ClientId::from(0))
+                .map(|account| account.balance(Currency::default()).held),
+            Some(Decimal::from(5).into())
+        );
+    }
+
+    #[test]
+    fn track_applied_rejected() {
+        let input = "type,client,tx,amount
+            withdrawal,1,1,5.0
+            deposit,1,2,10.0
+            withdrawal,1,1,5.0";
+
+        // A rejected row isn't taken as applied, so tracking doesn't change what a single run does
+        let available = |track: bool, shards: Option<usize>| {
+            let mut transaction_processor = TransactionProcessor::new();
+
+            if track {
+                transaction_processor.track_applied();
+            }
+
+            match shards {
+                Some(shards) => transaction_processor
+                    .process_reader_sharded(input.as_bytes(), InputFormat::Csv, shards, |_| Ok(()))
+                    .unwrap(),
+                None => transaction_processor
+                    .process_reader(input.as_bytes(), InputFormat::Csv, |_| Ok(()))
+                    .unwrap(),
+            }
+
+            transaction_processor
+                .account(ClientId::from(1))
+                .map(|account| account.balance(Currency::default()).available)
+        };
+
+        for shards in [None, Some(2)] {
+            assert_eq!(available(false, shards), Some(Decimal::from(5).into()));
+            assert_eq!(available(true, shards), available(false, shards));
+        }
+    }
+
+    #[test]
+    fn track_applied_redispute_resolved() {
+        let mut transaction_processor = TransactionProcessor::new();
+        transaction_processor.set_config(Config {
+            redispute_resolved: true,
+            ..Config::default()
+        });
+        transaction_processor.track_applied();
+        let mut rejected = Vec::new();
+
+        for input in [
+            "type,client,tx,amount
+            deposit,0,0,5.0
+            dispute,0,0,
+            resolve,0,0,",
+            "type,client,tx,amount
+            dispute,0,0,",
+            "type,client,tx,amount
+            dispute,0,0,",
+        ] {
+            transaction_processor
+                .process_reader(input.as_bytes(), InputFormat::Csv, |rejection| {
+                    rejected.push((rejection.line, rejection.reason));
+                    Ok(())
+                })
+                .unwrap();
+        }
+
+        // The second dispute starts a second cycle rather than being taken for the first, and only its
+        // repeat is rejected
+        assert_eq!(
+            rejected,
+            vec![(2, RejectReason::Transaction(AlreadyApplied))]
+        );
+        assert_eq!(
+            transaction_processor
+                .account(ClientId::from(0))
+                .map(|account| account.balance(Currency::default()).held),
+            Some(Decimal::from(5).into())
+        );
+    }
+
+    #[test]
+    fn wal() {
+        let path = std::env::temp_dir().join("payments_wal_test.log");
+        let _ = std::fs::remove_file(&path);
+        let input = "type,client,tx,amount
+            deposit,0,0,5.0
+            withdrawal,0,1,9.0
+            dispute,0,0,";
+
+        let (wal, entries) = Wal::open(&path).unwrap();
+        assert!(entries.is_empty());
+
+        let mut transaction_processor = TransactionProcessor::new();
+        transaction_processor.set_wal(wal);
+        transaction_processor
+            .process_transactions(input.as_bytes())
+            .unwrap();
+        drop(transaction_processor);
+
+        let (_, entries) = Wal::open(&path).unwrap();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|entry| (entry.transaction.tx(), entry.committed))
+                .collect::<Vec<_>>(),
+            vec![
+                (TxId::from(0), true),
+                (TxId::from(1), false),
+                (TxId::from(0), true)
+            ]
+        );
+
+        let mut transaction_processor = TransactionProcessor::new();
+        transaction_processor.track_applied();
+        transaction_processor.replay_wal(&entries).unwrap();
+        let mut rejected = Vec::new();
+        transaction_processor
+            .process_reader(input.as_bytes(), InputFormat::Csv, |rejection| {
+                rejected.push(rejection.line);
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(rejected, vec![2, 3, 4]);
+        assert_eq!(
+            transaction_processor
+                .account(ClientId::from(0))
+                .map(|account| account.balance(Currency::default()).held),
+            Some(Decimal::from(5).into())
+        );
+    }
+
+    #[test]
+    fn process_batch() {
+        let path = std::env::temp_dir().join("payments_batch_wal_test.log");
+        let _ = std::fs::remove_file(&path);
+        let (wal, _) = Wal::open(&path).unwrap();
+
+        let mut transaction_processor = TransactionProcessor::new();
+        transaction_processor.set_wal(wal);
+        let results = transaction_processor.process_batch(&[
+            Transaction::Deposit {
+                client: ClientId::from(0),
+                tx: TxId::from(0),
+                amount: Decimal::from(5),
+                currency: None,
+                timestamp: None,
+            },
+            Transaction::Withdrawal {
+                client: ClientId::from(0),
+                tx: TxId::from(1),
+                amount: Decimal::from(6),
+                currency: None,
+                timestamp: None,
+            },
+            Transaction::Dispute {
+                client: ClientId::from(0),
+                tx: TxId::from(0),
+                timestamp: None,
+            },
+        ]);
+
+        assert_eq!(results, vec![Ok(()), Err(InsufficientFunds), Ok(())]);
+        assert_eq!(
+            transaction_processor
+                .account(ClientId::from(0))
+                .map(|account| account.balance(Currency::default()).held),
+            Some(Decimal::from(5).into())
+        );
+        drop(transaction_processor);
+
+        let (_, entries) = Wal::open(&path).unwrap();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|entry| (entry.transaction.tx(), entry.committed))
+                .collect::<Vec<_>>(),
+            vec![
+                (TxId::from(0), true),
+                (TxId::from(1), false),
+                (TxId::from(0), true)
+            ]
+        );
+    }
+
+    #[test]
+    fn process_iter() {
+        let input = "type,client,tx,amount,effective
+            deposit,0,0,5.0,
+            withdrawal,0,1,9.0,
+            deposit,0,2,1.0,4102444800
+            bogus";
+
+        let mut transaction_processor = TransactionProcessor::new();
+        let results: Vec<_> = transaction_processor
+            .process_iter(input.as_bytes(), InputFormat::Csv)
+            .map(|result| match result {
+                Ok(applied) => Ok((
+                    applied.line,
+                    applied.transaction.map(|transaction| transaction.tx()),
+                    applied.scheduled,
+                )),
+                Err(ProcessError::Rejected(rejection)) => Err(rejection.line),
+                Err(ProcessError::Io(error)) => panic!("{error}"),
+            })
+            .collect();
+
+        assert_eq!(
+            results,
+            vec![
+                Ok((2, Some(TxId::from(0)), None)),
+                Err(3),
+                Ok((4, Some(TxId::from(2)), Some(4102444800))),
+                Err(5)
+            ]
+        );
+        assert_eq!(
+            transaction_processor
+                .account(ClientId::from(0))
+                .map(|account| account.balance(Currency::default()).available),
+            Some(Decimal::from(5).into())
+        );
+
+        // Only a rejection for the first row is read
+        let mut iter = transaction_processor.process_iter(
+            "type,client,tx,amount\nwithdrawal,0,3,9.0\ndeposit,0,4,1.0".as_bytes(),
+            InputFormat::Csv,
+        );
+        assert!(matches!(iter.next(), Some(Err(ProcessError::Rejected(_)))));
+        drop(iter);
+        assert_eq!(
+            transaction_processor
+                .account(ClientId::from(0))
+                .map(|account| account.balance(Currency::default()).available),
+            Some(Decimal::from(5).into())
+        );
+
+        let mut iter = transaction_processor.process_iter("a,b\n1,2".as_bytes(), InputFormat::Csv);
+        assert!(matches!(iter.next(), Some(Err(ProcessError::Io(_)))));
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn currency_column() {
+        let accounts = |input: &str| {
+            let mut transaction_processor = TransactionProcessor::new();
+            transaction_processor
+                .process_transactions(input.as_bytes())
+                .unwrap();
+
+            let mut output = Vec::new();
+            transaction_processor
+                .write_accounts(&mut output, None)
+                .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+
+        assert_eq!(
+            accounts("type,client,tx,amount\ndeposit,1,1,1.0\n"),
+            "client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n"
+        );
+        // Named, even if it's the base currency
+        assert_eq!(
+            accounts("type,client,tx,amount,currency\ndeposit,1,1,1.0,USD\n"),
+            "client,available,held,total,locked,currency\n1,1.0000,0.0000,1.0000,false,USD\n"
+        );
+
+        // A stream has to decide with its first account
+        let input = "type,client,tx,amount,currency
+            deposit,1,1,1.0,
+            deposit,2,2,1.0,EUR
+            deposit,3,3,1.0,";
+        let mut transaction_processor = TransactionProcessor::new();
+        let mut written = Vec::new();
+        let mut stream = AccountStream::new(&mut written).unwrap();
+        transaction_processor
+            .process_reader_streaming(input.as_bytes(), InputFormat::Csv, &mut stream, |_| Ok(()))
+            .unwrap_err();
+    }
+
+    #[test]
+    fn write_accounts_sorted() {
+        let input = "type,client,tx,amount,currency
+            deposit,2,0,1.0,
+            deposit,1,1,3.0,EUR
+            deposit,1,2,2.0,";
+
+        let mut transaction_processor = TransactionProcessor::new();
+        transaction_processor
+            .process_transactions(input.as_bytes())
+            .unwrap();
+
+        let mut output = Vec::new();
+        transaction_processor
+            .write_accounts(&mut output, Some(SortBy::Client))
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked,currency
+1,3.0000,0.0000,3.0000,false,EUR
+1,2.0000,0.0000,2.0000,false,USD
+2,1.0000,0.0000,1.0000,false,USD
+"
+        );
+
+        let mut output = Vec::new();
+        transaction_processor
+            .write_accounts(&mut output, Some(SortBy::Total))
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked,currency
+2,1.0000,0.0000,1.0000,false,USD
+1,2.0000,0.0000,2.0000,false,USD
+1,3.0000,0.0000,3.0000,false,EUR
+"
+        );
+    }
+
+    #[test]
+    fn register_handler() {
+        let mut transaction_processor = TransactionProcessor::new();
+        transaction_processor.register_handler(
+            "loyalty",
+            |transaction: &CustomTransaction,
+             account: &mut Account,
+             config: &Config|
+             -> Result<(), TransactionError> {
+                let amount = transaction
+                    .amount
+                    .ok_or_else(|| Rejected("missing amount".to_string()))?;
+                let currency = transaction.currency.unwrap_or(config.base_currency);
+                account.balances.entry(currency).or_default().available += amount;
+                Ok(())
+            },
+        );
+
+        let input = "type,client,tx,amount
+            loyalty,1,1,2.0
+            loyalty,2,2,
+            deposit,1,3,1.0
+            dispute,1,3,
+            chargeback,1,3,
+            loyalty,1,4,1.0
+            bonus,1,5,1.0";
+
+        let mut rejects = Vec::new();
+        transaction_processor
+            .process_transactions_with_rejects(input.as_bytes(), |rejection| {
+                rejects.push((rejection.line, rejection.reason));
+                Ok(())
+            })
+            .unwrap();
+
+        let account = transaction_processor.account(ClientId::from(1)).unwrap();
+        assert_eq!(
+            account.balance(Currency::default()).available,
+            Decimal::from(2)
+        );
+        assert!(transaction_processor.account(ClientId::from(2)).is_none());
+
+        assert_eq!(rejects.len(), 3);
+        assert_eq!(
+            rejects[0],
+            (3, Rejected("missing amount".to_string()).into())
+        );
+        assert_eq!(rejects[1], (7, AccountLocked.into()));
+        assert!(matches!(rejects[2], (8, RejectReason::Malformed(_))));
+    }
+
+    #[test]
+    fn malformed_field() {
+        let mut transaction_processor = TransactionProcessor::new();
+
+        let input = "type,client,tx,amount
+            deposit,1,1,1.0
+            deposit,1,2,x
+            deposit,1,3";
+
+        let mut rejects = Vec::new();
+        transaction_processor
+            .process_transactions_with_rejects(input.as_bytes(), |rejection| {
+                rejects.push((rejection.line, rejection.row, rejection.reason.to_string()));
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(
+            rejects,
+            [
+                (
+                    3,
+                    "deposit,1,2,x".to_string(),
+                    "malformed row: field amount \"x\": Invalid decimal: unknown character"
+                        .to_string()
+                ),
+                (
+                    4,
+                    "deposit,1,3".to_string(),
+                    "malformed row: expected 4 fields but found 3".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn dead_letters() {
+        let path = std::env::temp_dir().join("payments_dead_letters.jsonl");
+        let _ = std::fs::remove_file(&path);
+        let dead_letters = Arc::new(crate::DeadLetters::open(&path).unwrap());
+        let mut transaction_processor = TransactionProcessor::new();
+        dead_letters.record(&mut transaction_processor);
+
+        let input = "type,client,tx,amount
+            deposit,1,1,1.0
+            junk
+            withdrawal,1,2,5.0";
+
+        transaction_processor
+            .process_transactions_with_rejects(input.as_bytes(), |rejection| {
+                dead_letters.rejected_row(&rejection);
+                Ok(())
+            })
+            .unwrap();
+
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["row"], "junk");
+        assert_eq!(
+            lines[0]["reason"],
+            "malformed row: expected 4 fields but found 1"
+        );
+        assert_eq!(lines[1]["type"], "withdrawal");
+        assert_eq!(lines[1]["tx"], serde_json::json!(TxId::from(2)));
+        assert_eq!(lines[1]["amount"], "5.0000");
+        assert_eq!(lines[1]["reason"], "insufficient available funds");
+    }
+
+    #[test]
+    fn subscribe() {
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut transaction_processor = TransactionProcessor::new();
+        let recorded = events.clone();
+        transaction_processor.subscribe(move |event| recorded.lock().unwrap().push(event.clone()));
+
+        let input = "type,client,tx,amount
+            deposit,1,1,2.0
+            deposit,2,2,1.5
+            dispute,1,1,
+            chargeback,1,1,";
+
+        transaction_processor
+            .process_transactions(input.as_bytes())
+            .unwrap();
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 6);
+        assert!(matches!(
+            &events[3],
+            Event::Applied(entry) if entry.r#type == TransactionType::Chargeback && entry.balances[0].locked
+        ));
+        assert_eq!(
+            events[5],
+            Event::Locked {
+                client: ClientId::from(1),
+                tx: TxId::from(1)
+            }
+        );
+        assert!(events[5].involves(ClientId::from(1)));
+        assert!(!events[5].involves(ClientId::from(2)));
+        assert_eq!(
+            serde_json::to_value(&events[5]).unwrap(),
+            serde_json::json!({ "event": "locked", "client": ClientId::from(1), "tx": TxId::from(1) })
+        );
+    }
+
+    #[test]
+    fn history() {
+        let mut test = TransactionTest::default();
+        test.deposit(1, 1, 5.0, Ok(()));
+        test.deposit(2, 2, 1.0, Ok(()));
+        test.withdrawal(1, 3, 9.0, Err(InsufficientFunds));
+        test.transfer(1, 2, 4, 2.0, Ok(()));
+        test.dispute(1, 1, Ok(()));
+
+        let mut transaction_processor = TransactionProcessor::new();
+        assert_eq!(transaction_processor.history(ClientId::from(1)).count(), 0);
+        transaction_processor.track_history();
+
+        for transaction in &test.transactions {
+            let _ = transaction_processor.process(transaction);
+        }
+
+        let history: Vec<_> = transaction_processor
+            .history(ClientId::from(1))
+            .map(|applied| {
+                let balance = &applied.balances[&Currency::default()];
+                (
+                    applied.transaction.tx(),
+                    balance.available.clone(),
+                    balance.held.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            history,
+            [
+                (TxId::from(1), Decimal::from(5), Decimal::ZERO),
+                (TxId::from(4), Decimal::from(3), Decimal::ZERO),
+                (TxId::from(1), Decimal::from(-2), Decimal::from(5)),
+            ]
+            .map(|(tx, available, held)| (
+                tx,
+                Money::from(available),
+                Money::from(held)
+            ))
+        );
+
+        let history: Vec<_> = transaction_processor
+            .history(ClientId::from(2))
+            .map(|applied| applied.transaction.tx())
+            .collect();
+        assert_eq!(history, [TxId::from(2), TxId::from(4)]);
+    }
+
+    #[test]
+    fn write_statement() {
+        // Ids as the journal writes them, which depends on their type
+        let one = serde_json::json!(ClientId::from(1));
+        let two = serde_json::json!(ClientId::from(2));
+        let (tx1, tx2) = (
+            serde_json::json!(TxId::from(1)),
+            serde_json::json!(TxId::from(2)),
+        );
+        let journal = format!(
+            r#"{{"timestamp":951782400000,"type":"deposit","client":{one},"tx":{tx1},"amount":"5.0","currency":null,"to":null,"to_currency":null,"balances":[{{"client":{one},"currency":"USD","available":"5.0","held":"0","total":"5.0","locked":false}}]}}
+{{"timestamp":1665360000000,"type":"deposit","client":{two},"tx":{tx2},"amount":"1.0","currency":null,"to":null,"to_currency":null,"balances":[{{"client":{two},"currency":"USD","available":"1.0","held":"0","total":"1.0","locked":false}}]}}
+{{"timestamp":1665363723000,"type":"dispute","client":{one},"tx":{tx1},"amount":null,"currency":null,"to":null,"to_currency":null,"balances":[{{"client":{one},"currency":"USD","available":"0","held":"5.0","total":"5.0","locked":false}}]}}
+"#
+        );
+
+        let mut output = Vec::new();
+        crate::write_statement(journal.as_bytes(), ClientId::from(1), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "date,type,tx,amount,currency,available,held,total,locked
+2000-02-29 00:00:00,deposit,1,5.0000,USD,5.0000,0.0000,5.0000,false
+2022-10-10 01:02:03,dispute,1,,USD,0.0000,5.0000,5.0000,false
+"
+        );
+    }
+
+    #[test]
+    fn hooks() {
+        let hooked = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut transaction_processor = TransactionProcessor::new();
+
+        let locked = hooked.clone();
+        transaction_processor.on_account_locked(move |client, tx| {
+            locked.lock().unwrap().push(format!("locked {client} {tx}"))
+        });
+        let chargebacks = hooked.clone();
+        transaction_processor.on_chargeback(move |chargeback| {
+            chargebacks.lock().unwrap().push(format!(
+                "chargeback {} {} {} {}",
+                chargeback.client, chargeback.tx, chargeback.amount, chargeback.currency
+            ))
+        });
+        let rejected = hooked.clone();
+        transaction_processor.on_rejected(move |transaction, error| {
+            rejected
+                .lock()
+                .unwrap()
+                .push(format!("rejected {} {error}", transaction.tx()))
+        });
+
+        let input = "type,client,tx,amount
+            deposit,1,1,2.5
+            withdrawal,1,2,5.0
+            dispute,1,1,
+            chargeback,1,1,";
+
+        transaction_processor
+            .process_transactions(input.as_bytes())
+            .unwrap();
+
+        assert_eq!(
+            *hooked.lock().unwrap(),
+            [
+                "rejected 2 insufficient available funds",
+                "chargeback 1 1 2.5 USD",
+                "locked 1 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn interrupt() {
+        let interrupt = Arc::new(AtomicBool::new(false));
+        let mut transaction_processor = TransactionProcessor::new();
+        transaction_processor.set_interrupt(interrupt.clone());
+
+        let set = interrupt.clone();
+        transaction_processor.subscribe(move |_| set.store(true, AtomicOrdering::SeqCst));
+
+        let input = "type,client,tx,amount
+            deposit,1,1,2.0
+            deposit,1,2,3.0";
+
+        let error = transaction_processor
+            .process_transactions(input.as_bytes())
+            .unwrap_err();
+
+        assert_eq!(error.kind(), std::io::ErrorKind::Interrupted);
+        assert_eq!(
+            transaction_processor
+                .account(ClientId::from(1))
+                .map(|account| account.balance(Currency::default()).available),
+            "2.0".parse().ok()
+        );
+    }
+
+    #[test]
+    fn process_following() {
+        let path = std::env::temp_dir().join("payments_process_following.csv");
+        std::fs::write(
+            &path,
+            "type,client,tx,amount\ndeposit,1,1,2.0\ndeposit,1,2,",
+        )
+        .unwrap();
+
+        let interrupt = Arc::new(AtomicBool::new(false));
+        let mut transaction_processor = TransactionProcessor::new();
+        transaction_processor.set_interrupt(interrupt.clone());
+
+        let mut caught_up = Vec::new();
+        let error = transaction_processor
+            .process_following(
+                File::open(&path).unwrap(),
+                Duration::from_millis(1),
+                |_| Ok(()),
+                |transaction_processor| {
+                    let available = transaction_processor
+                        .account(ClientId::from(1))
+                        .map(|account| account.balance(Currency::default()).available);
+
+                    if caught_up.last() != Some(&available) {
+                        caught_up.push(available);
+
+                        // The half written row is finished and another appended, then it's stopped
+                        match caught_up.len() {
+                            1 => std::fs::OpenOptions::new()
+                                .append(true)
+                                .open(&path)?
+                                .write_all(b"3.0\nwithdrawal,1,3,1.0\n")?,
+                            _ => interrupt.store(true, AtomicOrdering::SeqCst),
+                        }
+                    }
+
+                    Ok(())
+                },
+            )
+            .unwrap_err();
+
+        assert_eq!(error.kind(), std::io::ErrorKind::Interrupted);
+        assert_eq!(caught_up, ["2.0".parse().ok(), "4.0".parse().ok()]);
+    }
+
+    #[test]
+    fn webhook_urls() {
+        use crate::webhook::WebhookUrl;
+
+        let url = |s: &str| s.parse::<WebhookUrl>().map(|url| url.to_string());
+
+        assert_eq!(
+            url("http://alerts.example.com/hooks"),
+            Ok("http://alerts.example.com:80/hooks".to_string())
+        );
+        assert_eq!(
+            url("http://127.0.0.1:8080"),
+            Ok("http://127.0.0.1:8080/".to_string())
+        );
+        assert_eq!(
+            url("http://[::1]:8080/alerts"),
+            Ok("http://[::1]:8080/alerts".to_string())
+        );
+        assert_eq!(
+            url("http://[fe80::1]/alerts"),
+            Ok("http://[fe80::1]:80/alerts".to_string())
+        );
+        assert!(url("http://[::1/alerts").is_err());
+        assert!(url("http://[::1]8080/alerts").is_err());
+        assert!(url("http://::1/alerts").is_err());
+        assert!(url("https://example.com").is_err());
+    }
+
+    #[test]
+    fn webhooks() {
+        use std::io::{BufRead, Read};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/alerts", listener.local_addr().unwrap());
+
+        let server = thread::spawn(move || {
+            let mut posted = Vec::new();
+
+            for stream in listener.incoming().take(2) {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                let mut length = 0;
+
+                reader.read_line(&mut line).unwrap();
+                let request_line = line.trim_end().to_string();
+
+                loop {
+                    line.clear();
+                    reader.read_line(&mut line).unwrap();
+
+                    match line.trim_end().split_once(": ") {
+                        Some(("Content-Length", value)) => length = value.parse().unwrap(),
+                        Some(_) => {}
+                        None => break,
+                    }
+                }
+
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                stream
+                    .write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n")
+                    .unwrap();
+
+                posted.push((request_line, String::from_utf8(body).unwrap()));
+            }
+
+            posted
+        });
+
+        let mut transaction_processor = TransactionProcessor::new();
+        let mut webhooks = crate::webhook::Webhooks::new(
+            vec![url.parse().unwrap()],
+            vec![
+                crate::webhook::WebhookEvent::Chargeback,
+                crate::webhook::WebhookEvent::LargeWithdrawal,
+            ],
+        );
+        webhooks.set_large_withdrawal(Decimal::from(5));
+        webhooks.record(&mut transaction_processor);
+
+        let input = "type,client,tx,amount
+            deposit,1,1,10.0
+            withdrawal,1,2,1.0
+            withdrawal,1,3,6.0
+            dispute,1,1,
+            chargeback,1,1,";
+
+        transaction_processor
+            .process_transactions(input.as_bytes())
+            .unwrap();
+
+        // Compared as json, as ids are strings with string client ids or uuid tx ids
+        let posted: Vec<_> = server
+            .join()
+            .unwrap()
+            .into_iter()
+            .map(|(request_line, body)| {
+                (
+                    request_line,
+                    serde_json::from_str::<serde_json::Value>(&body).unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            posted,
+            [
+                (
+                    "POST /alerts HTTP/1.1".to_string(),
+                    serde_json::json!({
+                        "event": "large_withdrawal",
+                        "client": ClientId::from(1),
+                        "tx": TxId::from(3),
+                        "amount": "6.0000",
+                        "currency": "USD"
+                    })
+                ),
+                (
+                    "POST /alerts HTTP/1.1".to_string(),
+                    serde_json::json!({
+                        "event": "chargeback",
+                        "client": ClientId::from(1),
+                        "tx": TxId::from(1),
+                        "amount": "10.0000",
+                        "currency": "USD"
+                    })
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn metrics() {
+        let metrics = Arc::new(Metrics::new());
+        let mut transaction_processor = TransactionProcessor::new();
+        transaction_processor.set_metrics(metrics.clone());
+
+        let input = "type,client,tx,amount
+            deposit,1,1,2.0
+            deposit,2,2,1.5
+            dispute,1,1,
+            withdrawal,2,3,5.0";
+
+        transaction_processor
+            .process_transactions_with_rejects(input.as_bytes(), |_| {
+                metrics.rejected();
+                Ok(())
+            })
+            .unwrap();
+        metrics.update(transaction_processor.accounts());
+
+        let output = metrics.render();
+        assert!(output.contains("payments_transactions_total{type=\"deposit\"} 2\n"));
+        assert!(output.contains("payments_transactions_total{type=\"dispute\"} 1\n"));
+        assert!(output.contains("payments_transactions_total{type=\"withdrawal\"} 0\n"));
+        assert!(output.contains("payments_rejected_total 1\n"));
+        assert!(output.contains("payments_accounts 2\n"));
+        assert!(output.contains("payments_locked_accounts 0\n"));
+        assert!(output.contains("payments_held{currency=\"USD\"} 2\n"));
+    }
+
+    #[cfg(feature = "tui")]
+    #[test]
+    fn dashboard() {
+        let dashboard = Arc::new(crate::Dashboard::new());
+        let mut transaction_processor = TransactionProcessor::new();
+        dashboard.record(&mut transaction_processor);
+
+        let input = "type,client,tx,amount
+            deposit,1,1,2.0
+            deposit,2,2,7.5
+            dispute,2,2,
+            deposit,3,3,1.0
+            dispute,3,3,
+            chargeback,3,3,
+            withdrawal,1,4,5.0
+            junk";
+
+        transaction_processor
+            .process_transactions_with_rejects(input.as_bytes(), |rejection| {
+                dashboard.rejected_row(&rejection);
+                Ok(())
+            })
+            .unwrap();
+        dashboard.update(transaction_processor.accounts());
+
+        let mut terminal =
+            ratatui::Terminal::new(ratatui::backend::TestBackend::new(100, 24)).unwrap();
+        terminal.draw(|frame| dashboard.draw(frame)).unwrap();
+        let screen: Vec<String> = terminal
+            .backend()
+            .buffer()
+            .content()
+            .chunks(100)
+            .map(|line| line.iter().map(|cell| cell.symbol()).collect())
+            .collect();
+
+        assert!(screen[1].contains("6 applied    2 rejected"));
+        assert!(screen[5].starts_with("│2            USD      7.5"));
+        assert!(screen[5].contains("│3            USD      1"));
+        assert!(screen
+            .iter()
+            .any(|line| line.contains("1          insufficient available funds")));
+        assert!(screen
+            .iter()
+            .any(|line| line.contains("1          malformed row")));
+    }
+
+    #[test]
+    fn store_error() {
+        struct OfflineStore;
+
+        impl TransactionStore for OfflineStore {
+            fn get(&self, _: TxId) -> Result<Option<TransactionRecord>, StoreError> {
+                Err(StoreError::new("offline"))
+            }
+
+            fn insert(&mut self, _: TxId, _: TransactionRecord) -> Result<(), StoreError> {
+                Err(StoreError::new("offline"))
+            }
+        }
+
+        let mut transaction_processor = TransactionProcessor::with_store(OfflineStore);
+        let deposit = Transaction::Deposit {
+            client: ClientId::from(0),
+            tx: TxId::from(0),
+            amount: Decimal::ONE,
+            currency: None,
+            timestamp: None,
+        };
+
+        assert_eq!(
+            transaction_processor.process(&deposit),
+            Err(Store(StoreError::new("offline")))
+        );
+        assert_eq!(transaction_processor.account(ClientId::from(0)), None);
+    }
+
+    #[test]
+    fn store_insert_error() {
+        /// Keeps one record, anything else doesn't fit.
+        struct FullStore(Option<(TxId, TransactionRecord)>);
+
+        impl TransactionStore for FullStore {
+            fn get(&self, tx: TxId) -> Result<Option<TransactionRecord>, StoreError> {
+                Ok(self
+                    .0
+                    .filter(|&(stored, _)| stored == tx)
+                    .map(|(_, record)| record))
+            }
+
+            fn insert(&mut self, tx: TxId, record: TransactionRecord) -> Result<(), StoreError> {
+                match self.0 {
+                    Some((stored, _)) if stored != tx => Err(StoreError::new("disk full")),
+                    _ => {
+                        self.0 = Some((tx, record));
+                        Ok(())
+                    }
+                }
+            }
+        }
+
+        let mut transaction_processor = TransactionProcessor::with_store(FullStore(None));
+        let client = ClientId::from(0);
+        let usd = Currency::default();
+        transaction_processor
+            .process(&Transaction::Deposit {
+                client,
+                tx: TxId::from(0),
+                amount: Decimal::ONE,
+                currency: None,
+                timestamp: None,
+            })
+            .unwrap();
+
+        for transaction in [
+            Transaction::Deposit {
+                client,
+                tx: TxId::from(1),
+                amount: Decimal::TEN,
+                currency: None,
+                timestamp: None,
+            },
+            Transaction::Withdrawal {
+                client,
+                tx: TxId::from(2),
+                amount: Decimal::ONE,
+                currency: None,
+                timestamp: None,
+            },
+        ] {
+            assert_eq!(
+                transaction_processor.process(&transaction),
+                Err(Store(StoreError::new("disk full")))
+            );
+        }
+
+        // Neither was applied, to the account or the books
+        assert_eq!(
+            transaction_processor
+                .account(client)
+                .unwrap()
+                .balance(usd)
+                .available,
+            Decimal::ONE
+        );
+        assert_eq!(
+            transaction_processor
+                .ledger()
+                .balance(LedgerAccount::Suspense, usd),
+            -Decimal::ONE
+        );
+        assert!(transaction_processor.trial_balance().is_empty());
+    }
+
+    #[test]
+    fn retry_store() {
+        use crate::retry::Retry;
+        use crate::store::RetryStore;
+        use std::cell::Cell;
+
+        /// Fails transiently `timeouts` times, then permanently once the deposit's been inserted.
+        struct FlakyStore {
+            timeouts: Cell<u32>,
+            records: HashMap<TxId, TransactionRecord>,
+        }
+
+        impl FlakyStore {
+            fn fail(&self) -> Result<(), StoreError> {
+                if self.timeouts.get() > 0 {
+                    self.timeouts.set(self.timeouts.get() - 1);
+                    Err(StoreError::transient("timed out"))
+                } else if !self.records.is_empty() {
+                    Err(StoreError::new("disk full"))
+                } else {
+                    Ok(())
+                }
+            }
+        }
+
+        impl TransactionStore for FlakyStore {
+            fn get(&self, tx: TxId) -> Result<Option<TransactionRecord>, StoreError> {
+                self.fail()?;
+                Ok(self.records.get(&tx).copied())
+            }
+
+            fn insert(&mut self, tx: TxId, record: TransactionRecord) -> Result<(), StoreError> {
+                self.fail()?;
+                self.records.insert(tx, record);
+                Ok(())
+            }
+        }
+
+        let store = FlakyStore {
+            timeouts: Cell::new(3),
+            records: HashMap::new(),
+        };
+        let mut transaction_processor =
+            TransactionProcessor::with_store(RetryStore::new(store, Retry::new(3, Duration::ZERO)));
+        let deposit = |tx| Transaction::Deposit {
+            client: ClientId::from(0),
+            tx: TxId::from(tx),
+            amount: Decimal::ONE,
+            currency: None,
+            timestamp: None,
+        };
+
+        assert_eq!(transaction_processor.process(&deposit(0)), Ok(()));
+        assert_eq!(
+            transaction_processor.process(&deposit(1)),
+            Err(Store(StoreError::new("disk full")))
+        );
+
+        let mut transaction_processor = TransactionProcessor::with_store(RetryStore::new(
+            FlakyStore {
+                timeouts: Cell::new(4),
+                records: HashMap::new(),
+            },
+            Retry::new(3, Duration::ZERO),
+        ));
+
+        assert_eq!(
+            transaction_processor.process(&deposit(0)),
+            Err(Store(StoreError::transient("timed out")))
+        );
+    }
+
+    #[test]
+    fn throttle() {
+        let throttle = Throttle::new(10);
+
+        for _ in 0..10 {
+            assert_eq!(throttle.reserve(), Duration::ZERO);
+        }
+
+        // Each caller waits a token's worth longer than the last
+        let wait = throttle.reserve();
+        assert!(wait > Duration::from_millis(90) && wait <= Duration::from_millis(100));
+        let wait = throttle.reserve();
+        assert!(wait > Duration::from_millis(190) && wait <= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn spill_store() {
+        let input = "type,client,tx,amount
+            deposit,0,0,5.0
+            deposit,0,1,10.0
+            deposit,1,2,15.0
+            dispute,0,0,
+            dispute,0,1,
+            resolve,0,0,
+            dispute,0,0,
+            chargeback,0,1,
+            dispute,1,3,";
+
+        let mut expected = TransactionProcessor::new();
+        let mut expected_rejected = Vec::new();
+        expected
+            .process_reader(input.as_bytes(), InputFormat::Csv, |rejection| {
+                expected_rejected.push(rejection.line);
+                Ok(())
+            })
+            .unwrap();
+
+        // Only room for one record, so every other one is read back from the file
+        let mut spilled = TransactionProcessor::with_store(SpillStore::new(1).unwrap());
+        let mut spilled_rejected = Vec::new();
+        spilled
+            .process_reader(input.as_bytes(), InputFormat::Csv, |rejection| {
+                spilled_rejected.push(rejection.line);
+                Ok(())
+            })
+            .unwrap();
+
+        let mut spilled_accounts: Vec<_> = spilled.accounts().collect();
+        let mut expected_accounts: Vec<_> = expected.accounts().collect();
+        spilled_accounts.sort_by_key(|account| account.client);
+        expected_accounts.sort_by_key(|account| account.client);
+        assert_eq!(spilled_accounts, expected_accounts);
+        assert_eq!(spilled_rejected, expected_rejected);
+        assert_eq!(spilled_rejected, vec![8, 10]);
+    }
+
+    #[test]
+    fn simulation() {
+        let mut csv = Vec::new();
+        Generator {
+            rows: 5000,
+            clients: 30,
+            dispute_rate: 0.05,
+            seed: 7,
+        }
+        .write(&mut csv)
+        .unwrap();
 
-        test.deposit(0, 0, 5.0, Ok(()));
-        test.deposit(1, 1, 10.0, Ok(()));
-        test.deposit(2, 2, 15.0, Ok(()));
-        test.dispute(0, 0, Ok(()));
-        test.dispute(1, 1, Ok(()));
-        test.resolve(0, 0, Ok(()));
-        test.resolve(1, 1, Ok(()));
+        let mut transactions = Vec::new();
+        input::read(
+            csv.as_slice(),
+            InputFormat::Csv,
+            &CsvOptions::default(),
+            |row| {
+                transactions.push(row.transaction.unwrap());
+                Ok(())
+            },
+        )
+        .unwrap();
 
-        test.expect(0, 5.0, 0.0, false);
-        test.expect(1, 10.0, 0.0, false);
-        test.expect(2, 15.0, 0.0, false);
+        // Some that should be rejected as well
+        transactions.extend([
+            Transaction::Dispute {
+                client: ClientId::from(1),
+                tx: TxId::from(999_999),
+                timestamp: None,
+            },
+            Transaction::Withdrawal {
+                client: ClientId::from(1),
+                tx: TxId::from(1),
+                amount: Decimal::ONE,
+                currency: None,
+                timestamp: None,
+            },
+            Transaction::Deposit {
+                client: ClientId::from(1),
+                tx: TxId::from(999_999),
+                amount: -Decimal::ONE,
+                currency: None,
+                timestamp: None,
+            },
+        ]);
 
-        test.run();
+        let mut simulation = Simulation::new(ReferenceModel::default());
+        simulation.run(transactions).unwrap();
+
+        // A model that forgets chargebacks lock the account
+        struct NeverLocks(ReferenceModel);
+
+        impl Model for NeverLocks {
+            fn apply(&mut self, transaction: &Transaction) -> bool {
+                self.0.apply(transaction)
+            }
+
+            fn account(&self, client: ClientId) -> Option<Account> {
+                let mut account = self.0.account(client)?;
+                account.locked = false;
+                Some(account)
+            }
+        }
+
+        let deposit = Transaction::Deposit {
+            client: ClientId::from(1),
+            tx: TxId::from(1),
+            amount: Decimal::TEN,
+            currency: None,
+            timestamp: None,
+        };
+        let dispute = Transaction::Dispute {
+            client: ClientId::from(1),
+            tx: TxId::from(1),
+            timestamp: None,
+        };
+        let chargeback = Transaction::Chargeback {
+            client: ClientId::from(1),
+            tx: TxId::from(1),
+            timestamp: None,
+        };
+
+        let mut simulation = Simulation::new(NeverLocks(ReferenceModel::default()));
+        let divergence = simulation.run([deposit, dispute, chargeback]).unwrap_err();
+        assert_eq!(divergence.step, 2);
+        assert!(divergence.message.starts_with("client 1's account is"));
     }
 
     #[test]
-    fn resolve_with_different_client() {
-        let mut test = TransactionTest::default();
+    fn sharded() {
+        let input = "type,client,tx,amount
+            deposit,0,0,5.0
+            deposit,1,1,10.0
+            deposit,2,2,15.0
+            withdrawal,2,3,20.0
+            dispute,1,1,
+            dispute,0,1,
+            chargeback,1,1,
+            deposit,3,4,1.0";
 
-        test.deposit(0, 0, 5.0, Ok(()));
-        test.dispute(0, 0, Ok(()));
-        test.resolve(1, 0, Err(()));
+        let mut sequential = TransactionProcessor::new();
+        let mut sequential_rejected = Vec::new();
+        sequential
+            .process_reader(input.as_bytes(), InputFormat::Csv, |rejection| {
+                sequential_rejected.push(rejection.line);
+                Ok(())
+            })
+            .unwrap();
 
-        test.expect(0, 0.0, 5.0, false);
+        let mut sharded = TransactionProcessor::new();
+        let mut sharded_rejected = Vec::new();
+        sharded
+            .process_reader_sharded(input.as_bytes(), InputFormat::Csv, 3, |rejection| {
+                sharded_rejected.push(rejection.line);
+                Ok(())
+            })
+            .unwrap();
+        sharded_rejected.sort();
 
-        test.run();
+        assert_eq!(sharded_rejected, sequential_rejected);
+        assert_eq!(sharded.accounts, sequential.accounts);
     }
 
     #[test]
-    fn resolve_undisputed_transaction() {
-        let mut test = TransactionTest::default();
+    fn sharded_reused_tx() {
+        // Clients 1 and 2, and 3 and 4, are on different shards. Tx 2's withdrawal fails, so on one thread the
+        // deposit reusing it is applied
+        let input = "type,client,tx,amount
+            deposit,1,1,1.0
+            deposit,2,1,1.0
+            withdrawal,3,2,5.0
+            deposit,4,2,1.0
+            withdrawal,3,2,1.0
+            withdrawal,1,3,0.5
+            deposit,1,3,1.0";
 
-        test.deposit(0, 0, 5.0, Ok(()));
-        test.resolve(0, 0, Err(()));
+        for shards in [2, 3] {
+            let mut sequential = TransactionProcessor::new();
+            let mut sequential_rejected = Vec::new();
+            sequential
+                .process_reader(input.as_bytes(), InputFormat::Csv, |rejection| {
+                    sequential_rejected.push((rejection.line, rejection.reason));
+                    Ok(())
+                })
+                .unwrap();
 
-        test.expect(0, 5.0, 0.0, false);
+            let mut sharded = TransactionProcessor::new();
+            let mut sharded_rejected = Vec::new();
+            sharded
+                .process_reader_sharded(input.as_bytes(), InputFormat::Csv, shards, |rejection| {
+                    sharded_rejected.push((rejection.line, rejection.reason));
+                    Ok(())
+                })
+                .unwrap();
+            sharded_rejected.sort_by_key(|(line, _)| *line);
 
-        test.run();
+            assert_eq!(sharded_rejected, sequential_rejected);
+            assert_eq!(sharded.accounts, sequential.accounts);
+        }
     }
 
     #[test]
-    fn resolve_missing_transaction() {
-        let mut test = TransactionTest::default();
+    fn save_and_load() {
+        let path = std::env::temp_dir().join("payments_save_and_load.bin");
 
-        test.deposit(0, 0, 5.0, Ok(()));
-        test.resolve(0, 1, Err(()));
+        let mut saved = TransactionProcessor::new();
+        saved
+            .process_transactions(
+                "type,client,tx,amount\ndeposit,1,1,2.0\ndeposit,2,2,3.0\n".as_bytes(),
+            )
+            .unwrap();
+        saved.save(&path).unwrap();
 
-        test.expect(0, 5.0, 0.0, false);
+        let mut loaded = TransactionProcessor::load(&path).unwrap();
+        assert_eq!(loaded.accounts, saved.accounts);
 
-        test.run();
+        // The deposit can still be disputed after loading
+        loaded
+            .process_transactions("type,client,tx,amount\ndispute,1,1,\n".as_bytes())
+            .unwrap();
+        assert_eq!(
+            loaded.accounts[&ClientId::from(1)].balances[&Currency::default()].held,
+            Decimal::TWO
+        );
     }
 
     #[test]
-    fn resolve_transaction_that_has_been_chargebacked() {
-        let mut test = TransactionTest::default();
+    fn save_and_load_scheduled() {
+        let path = std::env::temp_dir().join("payments_save_and_load_scheduled.bin");
 
-        test.deposit(0, 0, 5.0, Ok(()));
-        test.dispute(0, 0, Ok(()));
-        test.chargeback(0, 0, Ok(()));
-        test.resolve(0, 0, Err(()));
+        let mut saved = TransactionProcessor::new();
+        saved
+            .process_transactions(
+                "type,client,tx,amount,timestamp,effective
+                deposit,1,1,2.0,1000,
+                deposit,1,2,3.0,1000,5000"
+                    .as_bytes(),
+            )
+            .unwrap();
+        saved.save(&path).unwrap();
 
-        test.expect(0, 0.0, 0.0, true);
+        // The clock and the deposit waiting for it are loaded too, so the deposit's applied when it's reached
+        let mut loaded = TransactionProcessor::load(&path).unwrap();
+        assert_eq!(loaded.clock, Some(1000));
+        assert_eq!(loaded.pending().count(), 1);
 
-        test.run();
+        loaded.advance_clock(5000).unwrap();
+        assert_eq!(
+            loaded.accounts[&ClientId::from(1)].balances[&Currency::default()].available,
+            Decimal::from(5)
+        );
+
+        // States saved before the clock was can still be loaded
+        checkpoint::save_atomically(&path, &(&saved.accounts, &saved.transactions)).unwrap();
+        let loaded = TransactionProcessor::load(&path).unwrap();
+        assert_eq!(loaded.accounts, saved.accounts);
+        assert_eq!(loaded.pending().count(), 0);
     }
 
     #[test]
-    fn chargeback() {
-        let mut test = TransactionTest::default();
+    fn validate() {
+        let mut transaction_processor = TransactionProcessor::new();
+        transaction_processor
+            .process_transactions("type,client,tx,amount\ndeposit,1,1,2.0\n".as_bytes())
+            .unwrap();
 
-        test.deposit(0, 0, 5.0, Ok(()));
-        test.deposit(1, 1, 10.0, Ok(()));
-        test.deposit(2, 2, 15.0, Ok(()));
-        test.dispute(0, 0, Ok(()));
-        test.dispute(1, 1, Ok(()));
-        test.chargeback(0, 0, Ok(()));
-        test.chargeback(1, 1, Ok(()));
+        let withdrawal = |amount| Transaction::Withdrawal {
+            client: ClientId::from(1),
+            tx: TxId::from(2),
+            amount,
+            currency: None,
+            timestamp: None,
+        };
+        let dispute = |client| Transaction::Dispute {
+            client,
+            tx: TxId::from(1),
+            timestamp: None,
+        };
 
-        test.expect(0, 0.0, 0.0, true);
-        test.expect(1, 0.0, 0.0, true);
-        test.expect(2, 15.0, 0.0, false);
+        assert_eq!(
+            transaction_processor.validate(&withdrawal(Decimal::ONE)),
+            Ok(())
+        );
+        assert_eq!(
+            transaction_processor.validate(&withdrawal(Decimal::TEN)),
+            Err(TransactionError::InsufficientFunds)
+        );
+        assert_eq!(
+            transaction_processor.validate(&dispute(ClientId::from(1))),
+            Ok(())
+        );
+        assert_eq!(
+            transaction_processor.validate(&dispute(ClientId::from(2))),
+            Err(TransactionError::ClientMismatch)
+        );
+        assert_eq!(
+            transaction_processor.validate(&Transaction::Transfer {
+                client: ClientId::from(1),
+                tx: TxId::from(3),
+                amount: Decimal::ONE,
+                to: ClientId::from(1),
+                currency: None,
+                timestamp: None,
+            }),
+            Err(TransactionError::SelfTransfer)
+        );
 
-        test.run();
+        // Nothing was changed, so the dispute is still open to be applied and the new client wasn't created
+        assert_eq!(
+            transaction_processor.accounts[&ClientId::from(1)].balances[&Currency::default()].held,
+            Decimal::ZERO
+        );
+        assert!(!transaction_processor
+            .accounts
+            .contains_key(&ClientId::from(2)));
+        transaction_processor
+            .process(&dispute(ClientId::from(1)))
+            .unwrap();
+        assert_eq!(
+            transaction_processor.validate(&dispute(ClientId::from(1))),
+            Err(TransactionError::AlreadyDisputed)
+        );
     }
 
     #[test]
-    fn chargeback_with_different_client() {
-        let mut test = TransactionTest::default();
+    fn merge() {
+        let mut first = TransactionProcessor::new();
+        first
+            .process_transactions("type,client,tx,amount\ndeposit,1,1,2.0\n".as_bytes())
+            .unwrap();
+        let mut second = TransactionProcessor::new();
+        second
+            .process_transactions("type,client,tx,amount\ndeposit,2,2,3.0\n".as_bytes())
+            .unwrap();
+        let mut overlapping = TransactionProcessor::new();
+        overlapping
+            .process_transactions("type,client,tx,amount\ndeposit,2,3,1.0\n".as_bytes())
+            .unwrap();
 
-        test.deposit(0, 0, 5.0, Ok(()));
-        test.dispute(0, 0, Ok(()));
-        test.chargeback(1, 0, Err(()));
+        first.merge(second).unwrap();
+        assert_eq!(first.accounts.len(), 2);
+        assert_eq!(
+            first.merge(overlapping),
+            Err(MergeError {
+                client: ClientId::from(2)
+            })
+        );
+        assert_eq!(
+            first.accounts[&ClientId::from(2)].balances[&Currency::default()].total(),
+            Decimal::from(3)
+        );
 
-        test.expect(0, 0.0, 5.0, false);
+        // Records came along, so the second part's deposit can be disputed
+        first
+            .process_transactions("type,client,tx,amount\ndispute,2,2,\n".as_bytes())
+            .unwrap();
+        assert_eq!(
+            first.accounts[&ClientId::from(2)].balances[&Currency::default()].held,
+            Decimal::from(3)
+        );
+    }
 
-        test.run();
+    #[test]
+    fn check_invariants() {
+        let mut csv = Vec::new();
+        Generator {
+            rows: 3000,
+            clients: 20,
+            dispute_rate: 0.05,
+            seed: 11,
+        }
+        .write(&mut csv)
+        .unwrap();
+        csv.extend_from_slice(b"unlock,1,900000,\nfee,2,900001,1.5\nreversal,3,1,\n");
+
+        for locked_policy in [
+            LockedPolicy::Allow,
+            LockedPolicy::RejectDebits,
+            LockedPolicy::Reject,
+        ] {
+            for dispute_policy in [DisputePolicy::AllowNegative, DisputePolicy::Clamp] {
+                let config = Config {
+                    locked_policy,
+                    dispute_policy,
+                    ..Config::default()
+                };
+
+                let mut transaction_processor = TransactionProcessor::new();
+                transaction_processor.set_config(config);
+                transaction_processor.check_invariants();
+                transaction_processor
+                    .process_transactions(csv.as_slice())
+                    .unwrap();
+            }
+        }
     }
 
     #[test]
-    fn chargeback_undisputed_transaction() {
-        let mut test = TransactionTest::default();
+    #[should_panic(expected = "client 1's held USD is negative, -1")]
+    fn invariant_broken() {
+        let mut transaction_processor = TransactionProcessor::new();
+        transaction_processor.check_invariants();
+        transaction_processor
+            .process_transactions("type,client,tx,amount\ndeposit,1,1,2.0\n".as_bytes())
+            .unwrap();
 
-        test.deposit(0, 0, 5.0, Ok(()));
-        test.chargeback(0, 0, Err(()));
+        // Changed behind the processor's back, so the next transaction finds it
+        transaction_processor
+            .accounts
+            .get_mut(&ClientId::from(1))
+            .unwrap()
+            .balances
+            .get_mut(&Currency::default())
+            .unwrap()
+            .held = (-Decimal::ONE).into();
+        let _ = transaction_processor
+            .process_transactions("type,client,tx,amount\ndeposit,1,2,1.0\n".as_bytes());
+    }
 
-        test.expect(0, 5.0, 0.0, false);
+    #[test]
+    fn process_bytes() {
+        let input = b"type,client,tx,amount
+deposit,1,1,2.0
+withdrawal,1,2,5.0
+deposit,,3,1.0
+dispute,1,1,
+transfer,1,4,1.0";
 
-        test.run();
+        let report = TransactionProcessor::new().process_bytes(input);
+        assert_eq!(report.applied, 2);
+        assert_eq!(
+            report
+                .rejected
+                .iter()
+                .map(|rejection| rejection.line)
+                .collect::<Vec<_>>(),
+            [3, 4, 6]
+        );
+        assert!(report.error.is_none());
+        assert_eq!(
+            report.accounts,
+            "client,available,held,total,locked\n1,0.0000,2.0000,2.0000,false\n"
+        );
+
+        let report = TransactionProcessor::new().process_bytes(b"\xff\xfe,\x00\n\"junk");
+        assert_eq!(report.applied, 0);
+        assert!(report.error.is_some());
+
+        // Cut off anywhere, the input is still handled without panicking
+        for end in 0..input.len() {
+            let mut transaction_processor = TransactionProcessor::new();
+            transaction_processor.check_invariants();
+            transaction_processor.process_bytes(&input[..end]);
+        }
     }
 
     #[test]
-    fn chargeback_missing_transaction() {
-        let mut test = TransactionTest::default();
+    fn parse_threads() {
+        let mut csv = Vec::new();
+        Generator {
+            rows: 5000,
+            clients: 30,
+            dispute_rate: 0.05,
+            seed: 3,
+        }
+        .write(&mut csv)
+        .unwrap();
+        csv.extend_from_slice(b"deposit,one,1,1.0\n\nwithdrawal,1,1,1000000.0\ndispute,2,999999");
 
-        test.deposit(0, 0, 5.0, Ok(()));
-        test.chargeback(0, 1, Err(()));
+        let process = |threads| {
+            let mut transaction_processor = TransactionProcessor::new();
+            transaction_processor.set_parse_threads(threads);
+            let mut rejected = Vec::new();
+            transaction_processor
+                .process_reader(csv.as_slice(), InputFormat::Csv, |rejection| {
+                    rejected.push((rejection.line, rejection.row));
+                    Ok(())
+                })
+                .unwrap();
 
-        test.expect(0, 5.0, 0.0, false);
+            (transaction_processor.accounts, rejected)
+        };
 
-        test.run();
+        let (accounts, rejected) = process(1);
+        assert_eq!(rejected.len(), 3);
+        assert_eq!(rejected[0].0, 5002);
+        assert_eq!(rejected[2].0, 5005);
+
+        for threads in [2, 7] {
+            assert_eq!(process(threads), (accounts.clone(), rejected.clone()));
+        }
     }
 
     #[test]
-    fn chargeback_transaction_that_has_been_resolved() {
-        let mut test = TransactionTest::default();
+    fn pipelined() {
+        let mut csv = Vec::new();
+        Generator {
+            rows: 5000,
+            clients: 30,
+            dispute_rate: 0.05,
+            seed: 4,
+        }
+        .write(&mut csv)
+        .unwrap();
+        csv.extend_from_slice(b"deposit,one,1,1.0\n\nwithdrawal,1,1,1000000.0\ndispute,2,999999");
 
-        test.deposit(0, 0, 5.0, Ok(()));
-        test.dispute(0, 0, Ok(()));
-        test.resolve(0, 0, Ok(()));
-        test.chargeback(0, 0, Err(()));
+        let process = |pipelined, threads| {
+            let mut transaction_processor = TransactionProcessor::new();
+            transaction_processor.set_parse_threads(threads);
+            let mut rejected = Vec::new();
+            let on_reject = |rejection: Rejection| {
+                rejected.push((rejection.line, rejection.row));
+                Ok(())
+            };
 
-        test.expect(0, 5.0, 0.0, false);
+            match pipelined {
+                true => transaction_processor.process_reader_pipelined(
+                    csv.as_slice(),
+                    InputFormat::Csv,
+                    on_reject,
+                ),
+                false => transaction_processor.process_reader(
+                    csv.as_slice(),
+                    InputFormat::Csv,
+                    on_reject,
+                ),
+            }
+            .unwrap();
 
-        test.run();
+            (transaction_processor.accounts, rejected)
+        };
+
+        let (accounts, rejected) = process(false, 1);
+        assert_eq!(rejected.len(), 3);
+        assert_eq!(rejected[2].0, 5005);
+
+        for threads in [1, 4] {
+            assert_eq!(process(true, threads), (accounts.clone(), rejected.clone()));
+        }
+
+        let mut transaction_processor = TransactionProcessor::new();
+        let failed = transaction_processor.process_reader_pipelined(
+            csv.as_slice(),
+            InputFormat::Csv,
+            |_| Err(std::io::Error::other("stop")),
+        );
+        assert_eq!(failed.unwrap_err().to_string(), "stop");
+    }
+
+    #[test]
+    fn fast_csv() {
+        let mut csv = Vec::new();
+        Generator {
+            rows: 2000,
+            clients: 20,
+            dispute_rate: 0.05,
+            seed: 5,
+        }
+        .write(&mut csv)
+        .unwrap();
+        csv.extend_from_slice(
+            b"deposit,,9001,1.0\ndeposit,1,9002,1e1\ndeposit,1,9003,-\nwithdrawal,1,9004\n\
+            refund,2,9005,1.0\ndeposit,2,9006,2.50,extra\ndeposit,3,9007,0.1234567\n",
+        );
+
+        let process = |fast| {
+            let mut transaction_processor = TransactionProcessor::new();
+            transaction_processor.set_csv_options(CsvOptions {
+                fast,
+                ..CsvOptions::default()
+            });
+            let mut rejected = Vec::new();
+            transaction_processor
+                .process_reader(csv.as_slice(), InputFormat::Csv, |rejection| {
+                    rejected.push((rejection.line, rejection.row, rejection.reason));
+                    Ok(())
+                })
+                .unwrap();
+
+            (transaction_processor.accounts, rejected)
+        };
+
+        let (accounts, rejected) = process(false);
+        // Balances in minor units don't take the last deposit's 7 decimal places either
+        let too_precise = cfg!(all(feature = "minor-units", not(feature = "bigdecimal")));
+        assert_eq!(rejected.len(), 5 + usize::from(too_precise));
+        assert_eq!(process(true), (accounts, rejected));
+    }
+
+    #[test]
+    fn process_reader_streaming() {
+        let input = "type,client,to,tx,amount
+            deposit,1,,1,5.0
+            transfer,1,3,2,1.0
+            deposit,2,,3,2.0
+            deposit,1,,4,1.0
+            transfer,2,1,5,1.0
+            deposit,3,,6,1.0";
+
+        let mut transaction_processor = TransactionProcessor::new();
+        let mut written = Vec::new();
+        let mut stream = AccountStream::new(&mut written).unwrap();
+        let mut rejected = Vec::new();
+        transaction_processor
+            .process_reader_streaming(
+                input.as_bytes(),
+                InputFormat::Csv,
+                &mut stream,
+                |rejection| {
+                    rejected.push((rejection.line, rejection.reason));
+                    Ok(())
+                },
+            )
+            .unwrap();
+
+        assert_eq!(
+            rejected,
+            [
+                (5, TransactionError::ClientFinished.into()),
+                (6, TransactionError::ClientFinished.into())
+            ]
+        );
+        // Only the last client's account is left
+        assert_eq!(
+            transaction_processor.accounts.keys().collect::<Vec<_>>(),
+            [&ClientId::from(3)]
+        );
+
+        transaction_processor.finish_stream(stream).unwrap();
+        assert!(transaction_processor.accounts.is_empty());
+        assert_eq!(
+            String::from_utf8(written).unwrap(),
+            "client,available,held,total,locked
+1,4.0000,0.0000,4.0000,false
+2,2.0000,0.0000,2.0000,false
+3,2.0000,0.0000,2.0000,false
+"
+        );
+    }
+
+    #[test]
+    fn keep_metadata() {
+        let input = "type,client,merchant,tx,amount,reference
+            deposit,1,m1,1,2.0,ref-1
+            deposit,2,m2,2,1.0,
+            withdrawal,1,m1,3,5.0,ref-3";
+
+        let process = |keep| {
+            let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+            let mut transaction_processor = TransactionProcessor::new();
+            let recorded = events.clone();
+            transaction_processor.subscribe(move |event| {
+                if let Event::Applied(entry) = event {
+                    recorded.lock().unwrap().push(entry.metadata.clone());
+                }
+            });
+
+            if keep {
+                transaction_processor.keep_metadata();
+            }
+
+            let mut rejected = Vec::new();
+            transaction_processor
+                .process_reader(input.as_bytes(), InputFormat::Csv, |rejection| {
+                    rejected.push(rejection.metadata);
+                    Ok(())
+                })
+                .unwrap();
+
+            let applied = events.lock().unwrap().clone();
+            (applied, rejected)
+        };
+
+        let metadata = |columns: &[(&str, &str)]| -> BTreeMap<String, String> {
+            columns
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect()
+        };
+
+        assert_eq!(
+            process(true),
+            (
+                vec![
+                    metadata(&[("merchant", "m1"), ("reference", "ref-1")]),
+                    metadata(&[("merchant", "m2")]),
+                ],
+                vec![metadata(&[("merchant", "m1"), ("reference", "ref-3")])]
+            )
+        );
+        assert_eq!(
+            process(false),
+            (
+                vec![BTreeMap::new(), BTreeMap::new()],
+                vec![BTreeMap::new()]
+            )
+        );
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn process_stream() {
+        let mut transaction_processor = TransactionProcessor::new();
+        let mut rejected = Vec::new();
+
+        let transactions = tokio_stream::iter(vec![
+            Transaction::Deposit {
+                client: ClientId::from(0),
+                tx: TxId::from(0),
+                amount: Decimal::from(5),
+                currency: None,
+                timestamp: None,
+            },
+            Transaction::Withdrawal {
+                client: ClientId::from(0),
+                tx: TxId::from(1),
+                amount: Decimal::from(6),
+                currency: None,
+                timestamp: None,
+            },
+        ]);
+
+        transaction_processor
+            .process_stream_with_rejects(transactions, |transaction, error| {
+                rejected.push((transaction.tx(), error))
+            })
+            .await;
+
+        assert_eq!(rejected, vec![(TxId::from(1), InsufficientFunds)]);
+        assert_eq!(
+            transaction_processor
+                .account(ClientId::from(0))
+                .map(|account| account.balance(Currency::default()).total()),
+            Some(Decimal::from(5).into())
+        );
+    }
+
+    #[test]
+    fn ingest_tcp() {
+        use std::io::Write;
+        use std::net::{TcpListener, TcpStream};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let transaction_processor = Arc::new(Mutex::new(TransactionProcessor::new()));
+        let (rejected_sender, rejected) = mpsc::channel();
+
+        {
+            let transaction_processor = transaction_processor.clone();
+            let rejected_sender = Mutex::new(rejected_sender);
+
+            thread::spawn(move || {
+                crate::ingest_tcp(
+                    listener,
+                    transaction_processor,
+                    InputFormat::Csv,
+                    move |_, rejection| {
+                        let _ = rejected_sender.lock().unwrap().send(rejection.line);
+                        Ok(())
+                    },
+                )
+            });
+        }
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"type,client,tx,amount\ndeposit,1,1,2.0\nwithdrawal,1,2,3.0\n")
+            .unwrap();
+        drop(stream);
+
+        assert_eq!(
+            rejected
+                .recv_timeout(std::time::Duration::from_secs(5))
+                .unwrap(),
+            3
+        );
+        assert_eq!(
+            lock(&transaction_processor)
+                .account(ClientId::from(1))
+                .map(|account| account.balance(Currency::default()).available),
+            Some(Decimal::from(2).into())
+        );
+    }
+
+    #[test]
+    fn client_actors() {
+        use crate::actor::ClientActors;
+        use std::io::Write;
+        use std::net::{TcpListener, TcpStream};
+
+        let mut transaction_processor = TransactionProcessor::new();
+        transaction_processor
+            .process_transactions("type,client,tx,amount\ndeposit,1,1,5.0".as_bytes())
+            .unwrap();
+        let actors = Arc::new(ClientActors::spawn(&mut transaction_processor, 2));
+        assert_eq!(transaction_processor.accounts().count(), 0);
+
+        let deposit = |client: u64, tx: u64| Transaction::Deposit {
+            client: ClientId::from(client),
+            tx: TxId::from(tx),
+            amount: Decimal::ONE,
+            currency: None,
+            timestamp: None,
+        };
+        let transfer = |to: u64| Transaction::Transfer {
+            client: ClientId::from(1),
+            to: ClientId::from(to),
+            tx: TxId::from(10 + to),
+            amount: Decimal::ONE,
+            currency: None,
+            timestamp: None,
+        };
+
+        assert_eq!(actors.process(deposit(2, 2)), Ok(()));
+        assert_eq!(actors.process(deposit(2, 2)), Err(DuplicateTransaction));
+        // Clients 1 and 3 are on the same shard, 2 isn't
+        assert_eq!(actors.process(transfer(3)), Ok(()));
+        assert_eq!(actors.process(transfer(2)), Err(CrossShardTransfer));
+        assert_eq!(actors.accounts().len(), 3);
+
+        let Ok(actors) = Arc::try_unwrap(actors) else {
+            panic!("nothing else has the actors");
+        };
+        actors.join(&mut transaction_processor);
+        let available = |account: Option<&Account>| {
+            account.map(|account| account.balance(Currency::default()).available)
+        };
+        assert_eq!(
+            available(transaction_processor.account(ClientId::from(1))),
+            Some(Decimal::from(4).into())
+        );
+        assert_eq!(
+            available(transaction_processor.account(ClientId::from(3))),
+            Some(Decimal::ONE.into())
+        );
+
+        let actors = Arc::new(ClientActors::spawn(&mut transaction_processor, 2));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (rejected_sender, rejected) = mpsc::channel();
+
+        {
+            let actors = actors.clone();
+            let rejected_sender = Mutex::new(rejected_sender);
+
+            thread::spawn(move || {
+                crate::ingest_tcp_sharded(
+                    listener,
+                    actors,
+                    InputFormat::Csv,
+                    move |_, rejection| {
+                        let _ = rejected_sender.lock().unwrap().send(rejection.line);
+                        Ok(())
+                    },
+                )
+            });
+        }
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"type,client,tx,amount\ndeposit,2,3,2.0\nwithdrawal,1,4,9.0\n")
+            .unwrap();
+        drop(stream);
+
+        assert_eq!(rejected.recv_timeout(Duration::from_secs(5)).unwrap(), 3);
+        assert_eq!(
+            actors
+                .account(ClientId::from(2))
+                .map(|account| account.balance(Currency::default()).available),
+            Some(Decimal::from(3).into())
+        );
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn http_router() {
+        use axum::{body::Body, http::Request};
+        use tower::ServiceExt;
+
+        let transaction_processor = Arc::new(std::sync::Mutex::new(TransactionProcessor::new()));
+        let router = crate::http_router(transaction_processor);
+
+        for (body, status) in [
+            (
+                r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 5.0}"#,
+                200,
+            ),
+            (
+                r#"{"type": "withdrawal", "client": 1, "tx": 2, "amount": "6.0"}"#,
+                422,
+            ),
+        ] {
+            let response = router
+                .clone()
+                .oneshot(
+                    Request::post("/transactions")
+                        .header("content-type", "application/json")
+                        .body(Body::from(body))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status);
+        }
+
+        let response = router
+            .clone()
+            .oneshot(Request::get("/accounts/1").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let account: Account = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            account.balance(Currency::default()).available,
+            Decimal::from(5)
+        );
+
+        let response = router
+            .oneshot(Request::get("/accounts/2").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 404);
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn http_router_sharded() {
+        use axum::{body::Body, http::Request};
+        use tower::ServiceExt;
+
+        let mut transaction_processor = TransactionProcessor::new();
+        let (router, actors) = crate::http_router_sharded(&mut transaction_processor, 2);
+
+        for (body, status) in [
+            (
+                r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 5.0}"#,
+                200,
+            ),
+            (
+                r#"{"type": "deposit", "client": 2, "tx": 2, "amount": 1.0}"#,
+                200,
+            ),
+            // Clients 1 and 2 are on different shards
+            (
+                r#"{"type": "transfer", "client": 1, "to": 2, "tx": 3, "amount": 1.0}"#,
+                422,
+            ),
+        ] {
+            let response = router
+                .clone()
+                .oneshot(
+                    Request::post("/transactions")
+                        .header("content-type", "application/json")
+                        .body(Body::from(body))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status);
+        }
+
+        let response = router
+            .oneshot(Request::get("/accounts").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let accounts: Vec<Account> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            accounts
+                .iter()
+                .map(|account| account.client)
+                .collect::<Vec<_>>(),
+            [ClientId::from(1), ClientId::from(2)]
+        );
+        assert_eq!(actors.shards(), 2);
+    }
+
+    #[cfg(feature = "grpc")]
+    #[tokio::test]
+    async fn grpc() {
+        use crate::proto::{payments_server::Payments, GetAccountRequest};
+        use tonic::{Code, Request};
+
+        let transaction_processor = Arc::new(std::sync::Mutex::new(TransactionProcessor::new()));
+        let service = crate::PaymentsService::new(transaction_processor);
+        let deposit = crate::proto::Transaction {
+            r#type: "deposit".to_string(),
+            client: "1".to_string(),
+            tx: "1".to_string(),
+            amount: Some("5.0".to_string()),
+            ..Default::default()
+        };
+
+        service
+            .submit_transaction(Request::new(deposit.clone()))
+            .await
+            .unwrap();
+        let status = service
+            .submit_transaction(Request::new(deposit))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::FailedPrecondition);
+
+        let account = service
+            .get_account(Request::new(GetAccountRequest {
+                client: "1".to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(account.balances[0].available, "5.0");
+
+        let status = service
+            .get_account(Request::new(GetAccountRequest {
+                client: "2".to_string(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
     }
 
     #[derive(Debug, Default)]
     struct TransactionTest {
         transactions: Vec<Transaction>,
-        transaction_results: Vec<Result<(), ()>>,
-        expected: HashMap<u16, Account>,
+        transaction_results: Vec<Result<(), TransactionError>>,
+        expected: HashMap<ClientId, Account>,
+        config: Config,
     }
 
     impl TransactionTest {
         fn deposit(
             &mut self,
-            client: u16,
-            tx: u32,
+            client: u64,
+            tx: u64,
             amount: f32,
-            transaction_result: Result<(), ()>,
+            transaction_result: Result<(), TransactionError>,
         ) {
             self.transactions.push(Transaction::Deposit {
-                client,
-                tx,
+                client: client.into(),
+                tx: tx.into(),
                 amount: Decimal::from_f32_retain(amount).unwrap(),
+                currency: None,
+                timestamp: None,
             });
             self.transaction_results.push(transaction_result);
         }
 
         fn withdrawal(
             &mut self,
-            client: u16,
-            tx: u32,
+            client: u64,
+            tx: u64,
             amount: f32,
-            transaction_result: Result<(), ()>,
+            transaction_result: Result<(), TransactionError>,
         ) {
             self.transactions.push(Transaction::Withdrawal {
-                client,
-                tx,
+                client: client.into(),
+                tx: tx.into(),
                 amount: Decimal::from_f32_retain(amount).unwrap(),
+                currency: None,
+                timestamp: None,
+            });
+            self.transaction_results.push(transaction_result);
+        }
+
+        fn dispute(
+            &mut self,
+            client: u64,
+            tx: u64,
+            transaction_result: Result<(), TransactionError>,
+        ) {
+            self.transactions.push(Transaction::Dispute {
+                client: client.into(),
+                tx: tx.into(),
+                timestamp: None,
             });
             self.transaction_results.push(transaction_result);
         }
 
-        fn dispute(&mut self, client: u16, tx: u32, transaction_result: Result<(), ()>) {
-            self.transactions.push(Transaction::Dispute { client, tx });
+        fn resolve(
+            &mut self,
+            client: u64,
+            tx: u64,
+            transaction_result: Result<(), TransactionError>,
+        ) {
+            self.transactions.push(Transaction::Resolve {
+                client: client.into(),
+                tx: tx.into(),
+                timestamp: None,
+            });
             self.transaction_results.push(transaction_result);
         }
 
-        fn resolve(&mut self, client: u16, tx: u32, transaction_result: Result<(), ()>) {
-            self.transactions.push(Transaction::Resolve { client, tx });
+        fn chargeback(
+            &mut self,
+            client: u64,
+            tx: u64,
+            transaction_result: Result<(), TransactionError>,
+        ) {
+            self.transactions.push(Transaction::Chargeback {
+                client: client.into(),
+                tx: tx.into(),
+                timestamp: None,
+            });
             self.transaction_results.push(transaction_result);
         }
 
-        fn chargeback(&mut self, client: u16, tx: u32, transaction_result: Result<(), ()>) {
-            self.transactions
-                .push(Transaction::Chargeback { client, tx });
+        fn unlock(
+            &mut self,
+            client: u64,
+            tx: u64,
+            transaction_result: Result<(), TransactionError>,
+        ) {
+            self.transactions.push(Transaction::Unlock {
+                client: client.into(),
+                tx: tx.into(),
+                timestamp: None,
+            });
+            self.transaction_results.push(transaction_result);
+        }
+
+        fn chargeback_reversal(
+            &mut self,
+            client: u64,
+            tx: u64,
+            transaction_result: Result<(), TransactionError>,
+        ) {
+            self.transactions.push(Transaction::ChargebackReversal {
+                client: client.into(),
+                tx: tx.into(),
+                timestamp: None,
+            });
+            self.transaction_results.push(transaction_result);
+        }
+
+        fn transfer(
+            &mut self,
+            from: u64,
+            to: u64,
+            tx: u64,
+            amount: f32,
+            transaction_result: Result<(), TransactionError>,
+        ) {
+            self.transactions.push(Transaction::Transfer {
+                client: from.into(),
+                to: to.into(),
+                tx: tx.into(),
+                amount: Decimal::from_f32_retain(amount).unwrap(),
+                currency: None,
+                timestamp: None,
+            });
             self.transaction_results.push(transaction_result);
         }
 
-        fn expect(&mut self, client: u16, available: f32, held: f32, locked: bool) {
+        fn reversal(
+            &mut self,
+            client: u64,
+            tx: u64,
+            transaction_result: Result<(), TransactionError>,
+        ) {
+            self.transactions.push(Transaction::Reversal {
+                client: client.into(),
+                tx: tx.into(),
+                timestamp: None,
+            });
+            self.transaction_results.push(transaction_result);
+        }
+
+        fn fee(
+            &mut self,
+            client: u64,
+            tx: u64,
+            amount: f32,
+            transaction_result: Result<(), TransactionError>,
+        ) {
+            self.transactions.push(Transaction::Fee {
+                client: client.into(),
+                tx: tx.into(),
+                amount: Decimal::from_f32_retain(amount).unwrap(),
+                currency: None,
+                timestamp: None,
+            });
+            self.transaction_results.push(transaction_result);
+        }
+
+        fn expect(&mut self, client: u64, available: f32, held: f32, locked: bool) {
+            let client = ClientId::from(client);
             self.expected.insert(
                 client,
                 Account {
                     client,
-                    available: Decimal::from_f32_retain(available).unwrap(),
-                    held: Decimal::from_f32_retain(held).unwrap(),
+                    balances: BTreeMap::from([(
+                        self.config.base_currency,
+                        Balance {
+                            available: Decimal::from_f32_retain(available).unwrap().into(),
+                            held: Decimal::from_f32_retain(held).unwrap().into(),
+                            shortfall: Decimal::ZERO.into(),
+                        },
+                    )]),
                     locked,
                 },
             );
         }
 
+        fn expect_shortfall(&mut self, client: u64, shortfall: f32) {
+            let balance = self
+                .expected
+                .get_mut(&ClientId::from(client))
+                .and_then(|account| account.balances.get_mut(&self.config.base_currency))
+                .unwrap();
+            balance.shortfall = Decimal::from_f32_retain(shortfall).unwrap().into();
+        }
+
         fn run(&self) {
             let mut transaction_processor = TransactionProcessor::new();
+            transaction_processor.set_config(self.config.clone());
 
             self.transactions
                 .iter()
@@ -0,0 +1,151 @@
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+/// A transaction's id, a number up to `u64::MAX`. With the `uuid-tx-ids` feature it's 128 bits instead, so
+/// it can be a UUID as well as a number.
+#[cfg(not(feature = "uuid-tx-ids"))]
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default,
+)]
+#[serde(transparent)]
+pub struct TxId(u64);
+
+/// A transaction's id, a UUID like `67e55044-10b1-426f-9247-bb680e5fe0c8` or a number up to `u64::MAX`.
+/// Numbers are written as numbers and anything bigger as a UUID. In json it has to be a string.
+#[cfg(feature = "uuid-tx-ids")]
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default,
+)]
+#[serde(try_from = "String", into = "String")]
+pub struct TxId(u128);
+
+impl TxId {
+    /// Big endian, so keys sort like the ids.
+    #[cfg(feature = "rocksdb")]
+    pub(crate) fn to_bytes(self) -> impl AsRef<[u8]> {
+        self.0.to_be_bytes()
+    }
+}
+
+impl From<u64> for TxId {
+    #[cfg(not(feature = "uuid-tx-ids"))]
+    fn from(id: u64) -> Self {
+        TxId(id)
+    }
+
+    #[cfg(feature = "uuid-tx-ids")]
+    fn from(id: u64) -> Self {
+        TxId(u128::from(id))
+    }
+}
+
+#[cfg(not(feature = "uuid-tx-ids"))]
+impl FromStr for TxId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(TxId).map_err(|_| format!("Invalid tx {s}"))
+    }
+}
+
+/// A number, or a UUID with or without its hyphens in either case.
+#[cfg(feature = "uuid-tx-ids")]
+impl FromStr for TxId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(id) = s.parse::<u64>() {
+            return Ok(TxId::from(id));
+        }
+
+        let hex: String = match s.len() {
+            32 => s.to_owned(),
+            36 if [8, 13, 18, 23]
+                .iter()
+                .all(|&index| s.as_bytes()[index] == b'-') =>
+            {
+                s.split('-').collect()
+            }
+            _ => return Err(format!("Invalid tx {s}")),
+        };
+
+        // from_str_radix takes a leading +
+        if !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(format!("Invalid tx {s}"));
+        }
+
+        u128::from_str_radix(&hex, 16)
+            .map(TxId)
+            .map_err(|_| format!("Invalid tx {s}"))
+    }
+}
+
+#[cfg(feature = "uuid-tx-ids")]
+impl TryFrom<String> for TxId {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+#[cfg(feature = "uuid-tx-ids")]
+impl From<TxId> for String {
+    fn from(tx: TxId) -> Self {
+        tx.to_string()
+    }
+}
+
+impl fmt::Display for TxId {
+    #[cfg(not(feature = "uuid-tx-ids"))]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+
+    #[cfg(feature = "uuid-tx-ids")]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Ok(id) = u64::try_from(self.0) {
+            return id.fmt(f);
+        }
+
+        let hex = format!("{:032x}", self.0);
+        write!(
+            f,
+            "{}-{}-{}-{}-{}",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        )
+    }
+}
+
+/// Numbers are stored as integers, UUIDs as text.
+#[cfg(feature = "sqlite")]
+impl rusqlite::types::ToSql for TxId {
+    #[cfg(not(feature = "uuid-tx-ids"))]
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+        rusqlite::types::ToSql::to_sql(&self.0)
+    }
+
+    #[cfg(feature = "uuid-tx-ids")]
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+        Ok(rusqlite::types::ToSqlOutput::from(self.to_string()))
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl rusqlite::types::FromSql for TxId {
+    #[cfg(not(feature = "uuid-tx-ids"))]
+    fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
+        <u64 as rusqlite::types::FromSql>::column_result(value).map(TxId)
+    }
+
+    #[cfg(feature = "uuid-tx-ids")]
+    fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
+        <String as rusqlite::types::FromSql>::column_result(value)?
+            .parse()
+            .map_err(|error: String| rusqlite::types::FromSqlError::Other(error.into()))
+    }
+}
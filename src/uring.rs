@@ -0,0 +1,228 @@
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+};
+
+/// Blocks `UringReader` keeps queued ahead of what's been read.
+#[cfg(target_os = "linux")]
+const QUEUE_DEPTH: usize = 4;
+
+/// A file read with io_uring, with a few blocks queued ahead of what's been read so the next ones are on
+/// their way while this one's parsed, for fast NVMe drives where the read calls add up. Anywhere but Linux,
+/// or if io_uring isn't available, e.g. an old kernel or a sandbox that blocks it, it's read through a buffer
+/// as usual. The file is read up to where it ended when it got there, so it can't be followed.
+pub struct UringReader(Inner);
+
+enum Inner {
+    #[cfg(target_os = "linux")]
+    Ring(ring::Ring),
+    Buffered(BufReader<File>),
+}
+
+impl UringReader {
+    /// Reads `block_size` bytes at a time.
+    pub fn open<P: AsRef<Path>>(path: P, block_size: usize) -> Result<Self, std::io::Error> {
+        let file = File::open(path)?;
+
+        #[cfg(target_os = "linux")]
+        match ring::Ring::new(file.try_clone()?, block_size.max(1)) {
+            Ok(ring) => return Ok(Self(Inner::Ring(ring))),
+            Err(error) => {
+                tracing::warn!(%error, "io_uring isn't available, reading through a buffer")
+            }
+        }
+
+        Ok(Self(Inner::Buffered(BufReader::with_capacity(
+            block_size.max(1),
+            file,
+        ))))
+    }
+}
+
+impl Read for UringReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match &mut self.0 {
+            #[cfg(target_os = "linux")]
+            Inner::Ring(ring) => ring.read(buf),
+            Inner::Buffered(reader) => reader.read(buf),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod ring {
+    use super::QUEUE_DEPTH;
+    use io_uring::{opcode, types, IoUring};
+    use std::{fs::File, io::ErrorKind, os::fd::AsRawFd};
+
+    /// Each block of the file goes to the next slot round, and a slot's read again further on once it's been
+    /// read from.
+    pub(super) struct Ring {
+        ring: IoUring,
+        file: File,
+        slots: Vec<Slot>,
+        /// The slot being read from.
+        current: usize,
+        /// Where the next block to be queued starts.
+        next: u64,
+    }
+
+    struct Slot {
+        /// Boxed so it doesn't move while the kernel's reading into it.
+        buffer: Box<[u8]>,
+        offset: u64,
+        /// Bytes read into `buffer` so far.
+        filled: usize,
+        /// Bytes of `buffer` that have been read from.
+        read: usize,
+        queued: bool,
+        /// The file ended before `buffer` was filled.
+        end: bool,
+        error: Option<std::io::Error>,
+    }
+
+    impl Ring {
+        pub(super) fn new(file: File, block_size: usize) -> Result<Self, std::io::Error> {
+            let mut ring = Self {
+                ring: IoUring::new(QUEUE_DEPTH as u32)?,
+                file,
+                slots: (0..QUEUE_DEPTH)
+                    .map(|index| Slot {
+                        buffer: vec![0; block_size].into_boxed_slice(),
+                        offset: (index * block_size) as u64,
+                        filled: 0,
+                        read: 0,
+                        queued: false,
+                        end: false,
+                        error: None,
+                    })
+                    .collect(),
+                current: 0,
+                next: (QUEUE_DEPTH * block_size) as u64,
+            };
+
+            for index in 0..QUEUE_DEPTH {
+                ring.queue(index)?;
+            }
+
+            Ok(ring)
+        }
+
+        pub(super) fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            loop {
+                self.wait(self.current)?;
+                let slot = &mut self.slots[self.current];
+
+                if let Some(error) = &slot.error {
+                    return Err(std::io::Error::new(error.kind(), error.to_string()));
+                }
+
+                if slot.read < slot.filled {
+                    let len = buf.len().min(slot.filled - slot.read);
+                    buf[..len].copy_from_slice(&slot.buffer[slot.read..slot.read + len]);
+                    slot.read += len;
+                    return Ok(len);
+                }
+
+                if slot.end {
+                    return Ok(0);
+                }
+
+                // Read it all, so it's queued for the block after the last one queued
+                slot.offset = self.next;
+                slot.filled = 0;
+                slot.read = 0;
+                self.next += slot.buffer.len() as u64;
+                self.queue(self.current)?;
+                self.current = (self.current + 1) % self.slots.len();
+            }
+        }
+
+        /// Queues a read of the rest of the slot's block.
+        fn queue(&mut self, index: usize) -> Result<(), std::io::Error> {
+            let slot = &mut self.slots[index];
+            let rest = &mut slot.buffer[slot.filled..];
+            let read = opcode::Read::new(
+                types::Fd(self.file.as_raw_fd()),
+                rest.as_mut_ptr(),
+                rest.len() as u32,
+            )
+            .offset(slot.offset + slot.filled as u64)
+            .build()
+            .user_data(index as u64);
+
+            // SAFETY: the slot's buffer is boxed so it stays put, and it isn't touched or dropped until the
+            // read's completed, see `wait` and `drop`
+            unsafe { self.ring.submission().push(&read) }
+                .map_err(|_| std::io::Error::other("io_uring submission queue is full"))?;
+            slot.queued = true;
+            self.ring.submit()?;
+
+            Ok(())
+        }
+
+        /// Waits until the slot's block has been read, or the file ended or failed before it was.
+        fn wait(&mut self, index: usize) -> Result<(), std::io::Error> {
+            while self.slots[index].queued {
+                match self.ring.submit_and_wait(1) {
+                    Ok(_) => {}
+                    Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+                    Err(error) => return Err(error),
+                }
+
+                let completed: Vec<_> = self
+                    .ring
+                    .completion()
+                    .map(|completion| (completion.user_data() as usize, completion.result()))
+                    .collect();
+
+                for (completed, result) in completed {
+                    let slot = &mut self.slots[completed];
+                    slot.queued = false;
+
+                    match result {
+                        result if result < 0 => {
+                            slot.error = Some(std::io::Error::from_raw_os_error(-result));
+                        }
+                        0 => slot.end = true,
+                        read => {
+                            slot.filled += read as usize;
+
+                            // Short reads don't mean the file's ended, so the rest is asked for again
+                            if slot.filled < slot.buffer.len() {
+                                self.queue(completed)?;
+                            }
+                        }
+                    }
+                }
+            }
+
+            Ok(())
+        }
+    }
+
+    impl Drop for Ring {
+        fn drop(&mut self) {
+            // The kernel could still write to the buffers of reads that haven't completed
+            while self.slots.iter().any(|slot| slot.queued) {
+                match self.ring.submit_and_wait(1) {
+                    Ok(_) => {}
+                    Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+                    // Leaked rather than freed while they might still be written to
+                    Err(_) => {
+                        for slot in &mut self.slots {
+                            std::mem::forget(std::mem::take(&mut slot.buffer));
+                        }
+
+                        return;
+                    }
+                }
+
+                for completion in self.ring.completion() {
+                    self.slots[completion.user_data() as usize].queued = false;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use crate::transaction::Transaction;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::Path,
+};
+
+/// Transactions are written here, and synced to disk, before they're applied. Every entry since the
+/// state was last saved is replayed on startup, so transactions applied by a run that crashed aren't lost.
+/// Truncated once the state has been saved.
+pub struct Wal {
+    file: File,
+    seq: u64,
+}
+
+/// A line of json in the log.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+enum WalLine {
+    /// Written and synced before the transaction is applied.
+    Entry { seq: u64, transaction: Transaction },
+    /// Written once the transaction has been applied, entries without one were in flight or failed.
+    Commit { commit: u64 },
+}
+
+/// A transaction that was in the log when it was opened.
+#[derive(Debug, Clone, Copy)]
+pub struct WalEntry {
+    pub transaction: Transaction,
+    /// Whether the transaction was applied, rather than failing or being interrupted.
+    pub committed: bool,
+}
+
+impl Wal {
+    /// Creates the log if it doesn't exist, and returns the entries in it so they can be replayed.
+    /// A partly written last line, from a crash while it was being written, is ignored.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<(Self, Vec<WalEntry>), std::io::Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+
+        let mut entries: Vec<(u64, WalEntry)> = Vec::new();
+        let mut reader = BufReader::new(&file);
+        let mut line = String::new();
+        let mut valid = 0;
+
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+
+            if read == 0 {
+                break;
+            }
+
+            let parsed = match line.strip_suffix('\n') {
+                Some(line) => serde_json::from_str(line).ok(),
+                None => None,
+            };
+
+            match parsed {
+                Some(WalLine::Entry { seq, transaction }) => entries.push((
+                    seq,
+                    WalEntry {
+                        transaction,
+                        committed: false,
+                    },
+                )),
+                Some(WalLine::Commit { commit }) => {
+                    if let Some((_, entry)) =
+                        entries.iter_mut().rev().find(|(seq, _)| *seq == commit)
+                    {
+                        entry.committed = true;
+                    }
+                }
+                None => {
+                    if reader.read_line(&mut line)? != 0 {
+                        return Err(std::io::Error::other("corrupt write-ahead log"));
+                    }
+
+                    break;
+                }
+            }
+
+            valid += read as u64;
+        }
+
+        file.set_len(valid)?;
+
+        let wal = Self {
+            file,
+            seq: entries.last().map_or(0, |(seq, _)| *seq),
+        };
+
+        Ok((wal, entries.into_iter().map(|(_, entry)| entry).collect()))
+    }
+
+    /// Returns once the transaction is on disk.
+    pub(crate) fn write(&mut self, transaction: &Transaction) -> Result<u64, std::io::Error> {
+        self.seq += 1;
+        self.append(&WalLine::Entry {
+            seq: self.seq,
+            transaction: *transaction,
+        })?;
+        self.file.sync_data()?;
+
+        Ok(self.seq)
+    }
+
+    /// Like `write` for every transaction, but only synced once. Returns the first one's seq, the rest follow
+    /// on from it.
+    pub(crate) fn write_batch(
+        &mut self,
+        transactions: &[Transaction],
+    ) -> Result<u64, std::io::Error> {
+        let first = self.seq + 1;
+
+        for transaction in transactions {
+            self.seq += 1;
+            self.append(&WalLine::Entry {
+                seq: self.seq,
+                transaction: *transaction,
+            })?;
+        }
+
+        self.file.sync_data()?;
+
+        Ok(first)
+    }
+
+    /// Not synced, losing a commit only means the transaction is replayed.
+    pub(crate) fn commit(&mut self, seq: u64) -> Result<(), std::io::Error> {
+        self.append(&WalLine::Commit { commit: seq })
+    }
+
+    /// Empties the log, once everything in it has been saved.
+    pub fn truncate(&mut self) -> Result<(), std::io::Error> {
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        self.seq = 0;
+
+        Ok(())
+    }
+
+    fn append(&mut self, line: &WalLine) -> Result<(), std::io::Error> {
+        let mut line = serde_json::to_vec(line)?;
+        line.push(b'\n');
+        self.file.write_all(&line)
+    }
+}
@@ -0,0 +1,320 @@
+use crate::client::ClientId;
+use crate::currency::Currency;
+use crate::events::Event;
+use crate::retry::{Retry, Transient};
+use crate::store::TransactionStore;
+use crate::transaction::{format_amount, TransactionProcessor, TransactionType};
+use crate::tx::TxId;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::{
+    fmt,
+    io::{BufRead, BufReader, Write},
+    net::{TcpStream, ToSocketAddrs},
+    str::FromStr,
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+/// How long a webhook has to accept the connection and answer.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many events can wait to be posted. Once that many are waiting, e.g. as a webhook is down and posts are
+/// being retried, new events are dropped rather than held in memory.
+const QUEUE: usize = 1024;
+
+/// What's posted to webhooks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WebhookEvent {
+    Chargeback,
+    Locked,
+    /// A withdrawal of at least `Webhooks::large_withdrawal`.
+    LargeWithdrawal,
+}
+
+impl FromStr for WebhookEvent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "chargeback" => Ok(WebhookEvent::Chargeback),
+            "locked" => Ok(WebhookEvent::Locked),
+            "large-withdrawal" => Ok(WebhookEvent::LargeWithdrawal),
+            _ => Err(format!("Unknown webhook event {s}")),
+        }
+    }
+}
+
+/// An http:// URL events are posted to. There's no TLS, https endpoints need a proxy in front of them. IPv6
+/// hosts are in brackets, e.g. `http://[::1]:8080/alerts`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebhookUrl {
+    /// Without the brackets around an IPv6 address.
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl FromStr for WebhookUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("Webhook {s} isn't an http:// URL"))?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, "/"),
+        };
+        // An IPv6 address has colons of its own, so it's in brackets
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(bracketed) => {
+                let (host, port) = bracketed
+                    .split_once(']')
+                    .ok_or_else(|| format!("Webhook {s} has an unclosed ["))?;
+
+                match port {
+                    "" => (host, None),
+                    port => (
+                        host,
+                        Some(
+                            port.strip_prefix(':')
+                                .ok_or_else(|| format!("Webhook {s} has an invalid port"))?,
+                        ),
+                    ),
+                }
+            }
+            None => match authority.rsplit_once(':') {
+                Some((host, _)) if host.contains(':') => {
+                    return Err(format!("Webhook {s}'s IPv6 host has to be in brackets"))
+                }
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port
+                .parse()
+                .map_err(|_| format!("Webhook {s} has an invalid port"))?,
+            None => 80,
+        };
+
+        if host.is_empty() {
+            return Err(format!("Webhook {s} has no host"));
+        }
+
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl WebhookUrl {
+    /// The host as it's written in a URL or `Host` header, in brackets if it's an IPv6 address.
+    fn authority_host(&self) -> String {
+        match self.host.contains(':') {
+            true => format!("[{}]", self.host),
+            false => self.host.clone(),
+        }
+    }
+}
+
+impl fmt::Display for WebhookUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "http://{}:{}{}",
+            self.authority_host(),
+            self.port,
+            self.path
+        )
+    }
+}
+
+/// Posted for a chargeback, or a withdrawal of at least `Webhooks::large_withdrawal`. The amount is rounded like
+/// the accounts, so it's written the same however many decimal places it had in the input.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum AmountEvent {
+    Chargeback {
+        client: ClientId,
+        tx: TxId,
+        amount: String,
+        currency: Currency,
+    },
+    LargeWithdrawal {
+        client: ClientId,
+        tx: TxId,
+        amount: String,
+        currency: Currency,
+    },
+}
+
+/// Posts chargebacks, locks and large withdrawals as json to webhooks, for alerting. Posting happens on a
+/// background thread so it doesn't hold up processing, events that arrive while `QUEUE` are waiting are dropped
+/// and logged. Posts that time out, can't connect or get a 5xx or 429
+/// response are retried, other failures and posts that run out of retries are logged.
+pub struct Webhooks {
+    urls: Vec<WebhookUrl>,
+    events: Vec<WebhookEvent>,
+    /// Withdrawals of this much or more, in any currency, are `WebhookEvent::LargeWithdrawal`.
+    large_withdrawal: Option<Decimal>,
+    retry: Retry,
+}
+
+impl Webhooks {
+    /// Posts every one of `events` to each of `urls`. Large withdrawals are only posted once
+    /// `set_large_withdrawal` has been called.
+    pub fn new(urls: Vec<WebhookUrl>, events: Vec<WebhookEvent>) -> Self {
+        Self {
+            urls,
+            events,
+            large_withdrawal: None,
+            retry: Retry::default(),
+        }
+    }
+
+    pub fn set_large_withdrawal(&mut self, amount: Decimal) {
+        self.large_withdrawal = Some(amount);
+    }
+
+    /// `Retry::default()` if it isn't set.
+    pub fn set_retry(&mut self, retry: Retry) {
+        self.retry = retry;
+    }
+
+    /// Posts the events `transaction_processor` sends from now on, until it's dropped.
+    pub fn record<S: TransactionStore>(self, transaction_processor: &mut TransactionProcessor<S>) {
+        let (sender, receiver) = mpsc::sync_channel::<Vec<u8>>(QUEUE);
+        let urls = self.urls;
+        let retry = self.retry;
+
+        thread::spawn(move || {
+            for payload in receiver {
+                for url in &urls {
+                    if let Err(error) = retry.run(|| post(url, &payload)) {
+                        tracing::warn!(%url, %error, "Webhook failed");
+                    }
+                }
+            }
+        });
+
+        let events = self.events;
+        let large_withdrawal = self.large_withdrawal;
+        let precision = transaction_processor.config().output_precision();
+
+        transaction_processor.subscribe(move |event| {
+            let payload = match event {
+                Event::Chargeback(chargeback) if events.contains(&WebhookEvent::Chargeback) => {
+                    serde_json::to_vec(&AmountEvent::Chargeback {
+                        client: chargeback.client,
+                        tx: chargeback.tx,
+                        amount: format_amount(chargeback.amount, precision),
+                        currency: chargeback.currency,
+                    })
+                }
+                Event::Locked { .. } if events.contains(&WebhookEvent::Locked) => {
+                    serde_json::to_vec(event)
+                }
+                Event::Applied(entry)
+                    if entry.r#type == TransactionType::Withdrawal
+                        && events.contains(&WebhookEvent::LargeWithdrawal) =>
+                {
+                    let (Some(amount), Some(threshold), Some(balance)) =
+                        (entry.amount, large_withdrawal, entry.balances.first())
+                    else {
+                        return;
+                    };
+
+                    if amount < threshold {
+                        return;
+                    }
+
+                    serde_json::to_vec(&AmountEvent::LargeWithdrawal {
+                        client: entry.client,
+                        tx: entry.tx,
+                        amount: format_amount(amount, precision),
+                        currency: balance.currency,
+                    })
+                }
+                _ => return,
+            };
+
+            match payload.map(|payload| sender.try_send(payload)) {
+                Ok(Ok(())) => {}
+                Ok(Err(mpsc::TrySendError::Full(_))) => {
+                    tracing::warn!(?event, "Webhook queue is full, dropping event")
+                }
+                // The thread only stops once this listener has been dropped
+                Ok(Err(mpsc::TrySendError::Disconnected(_))) => {}
+                Err(error) => tracing::warn!(%error, "Couldn't serialize webhook event"),
+            }
+        });
+    }
+}
+
+/// Why a post failed.
+#[derive(Debug)]
+enum PostError {
+    Io(std::io::Error),
+    /// The webhook answered with a status other than 2xx.
+    Status(u16),
+}
+
+impl fmt::Display for PostError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PostError::Io(error) => error.fmt(f),
+            PostError::Status(status) => write!(f, "status {status}"),
+        }
+    }
+}
+
+impl From<std::io::Error> for PostError {
+    fn from(error: std::io::Error) -> Self {
+        PostError::Io(error)
+    }
+}
+
+impl Transient for PostError {
+    fn is_transient(&self) -> bool {
+        match self {
+            PostError::Io(error) => error.is_transient(),
+            PostError::Status(status) => *status >= 500 || *status == 429,
+        }
+    }
+}
+
+/// Posts `payload` and checks the webhook answered with a 2xx status.
+fn post(url: &WebhookUrl, payload: &[u8]) -> Result<(), PostError> {
+    let addr = (url.host.as_str(), url.port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| std::io::Error::other("host not found"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        url.path,
+        url.authority_host(),
+        payload.len()
+    )?;
+    stream.write_all(payload)?;
+
+    let mut status_line = String::new();
+    BufReader::new(&stream).read_line(&mut status_line)?;
+
+    match status_line.split_whitespace().nth(1).map(str::parse::<u16>) {
+        Some(Ok(200..=299)) => Ok(()),
+        Some(Ok(status)) => Err(PostError::Status(status)),
+        Some(Err(_)) => Err(std::io::Error::from(std::io::ErrorKind::InvalidData).into()),
+        // The connection was closed before it answered
+        None => Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+    }
+}
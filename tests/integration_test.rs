@@ -3,11 +3,21 @@ use std::process::Output;
 
 #[test]
 fn missing_file_arg() {
-    let mut cmd = Command::cargo_bin("payments").unwrap();
+    let mut cmd = process();
     let output = cmd.output().unwrap();
     let stderr = String::from_utf8_lossy(&output.stderr);
     assert!(!output.status.success());
-    assert!(stderr.contains("Missing filepath argument"));
+    assert!(stderr.contains("<FILES>"));
+}
+
+#[test]
+fn missing_subcommand() {
+    let mut cmd = Command::cargo_bin("payments").unwrap();
+    let output = cmd
+        .arg("./tests/deposit_and_withdraw.csv")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
 }
 
 #[test]
@@ -30,9 +40,11 @@ fn empty_file() {
 #[test]
 fn junk_file() {
     let output = run("./tests/junk.csv");
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    assert!(output.status.success());
-    assert_eq!(stdout, expect(&[]));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains(
+        "not a transactions file, the header row is missing the type, client, tx columns"
+    ));
 }
 
 #[test]
@@ -43,12 +55,53 @@ fn some_junk() {
     assert_eq!(stderr, expect(&["1,1.5000,0.0000,1.5000,false"]));
 }
 
+#[test]
+fn strict() {
+    let output = process()
+        .arg("./tests/some_junk.csv")
+        .arg("--strict")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(stderr.contains(
+        "./tests/some_junk.csv:2: malformed row: expected 4 fields but found 1: fdsafdfa"
+    ));
+}
+
+#[test]
+fn fail_on() {
+    // 4 of the 5 rows are rejected
+    for (policy, code) in [("io", 0), ("any-rejected", 3), ("80%", 0), ("75%", 3)] {
+        let output = process()
+            .arg("./tests/rejects.csv")
+            .arg("--fail-on")
+            .arg(policy)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(code), "{policy}");
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            expect(&["1,1.0000,0.0000,1.0000,false"])
+        );
+    }
+
+    let output = process()
+        .arg("./tests/rejects.csv")
+        .arg("--fail-on")
+        .arg("150%")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+}
+
 #[test]
 fn headers() {
     let output = run("./tests/headers.csv");
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    assert!(output.status.success());
-    assert_eq!(stdout, expect(&[]));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("the header row is missing the type, tx columns"));
 }
 
 #[test]
@@ -59,6 +112,14 @@ fn deposit_and_withdraw() {
     assert_eq!(stdout, expect(&["1,1.5000,0.0000,1.5000,false"]));
 }
 
+#[test]
+fn gzip() {
+    let output = run("./tests/deposit_and_withdraw.csv.gz");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert_eq!(stdout, expect(&["1,1.5000,0.0000,1.5000,false"]));
+}
+
 #[test]
 fn whitespace() {
     let output = run("./tests/whitespace.csv");
@@ -67,6 +128,8 @@ fn whitespace() {
     assert_eq!(stdout, expect(&["1,1.5000,0.0000,1.5000,false"]));
 }
 
+// Minor units reject amounts with more than 4 decimal places, see `minor_units`
+#[cfg(any(not(feature = "minor-units"), feature = "bigdecimal"))]
 #[test]
 fn precision() {
     let output = run("./tests/precision.csv");
@@ -75,6 +138,30 @@ fn precision() {
     assert_eq!(stdout, expect(&["1,2.2099,0.0000,2.2099,false"]));
 }
 
+#[cfg(any(not(feature = "minor-units"), feature = "bigdecimal"))]
+#[test]
+fn precision_option() {
+    let output = process()
+        .arg("./tests/precision.csv")
+        .arg("--precision")
+        .arg("5")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert_eq!(stdout, expect(&["1,2.20988,0.00000,2.20988,false"]));
+
+    let output = process()
+        .arg("./tests/precision.csv")
+        .arg("--precision")
+        .arg("2")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert_eq!(stdout, expect(&[]));
+}
+
 #[test]
 fn chargeback() {
     let output = run("./tests/chargeback.csv");
@@ -83,9 +170,1183 @@ fn chargeback() {
     assert_eq!(stdout, expect(&["0,5.0000,0.0000,5.0000,true"]));
 }
 
+#[test]
+fn multiple_files() {
+    let mut cmd = process();
+    let output = cmd
+        .arg("./tests/daily/2022-09-01.csv")
+        .arg("./tests/daily/2022-09-02.csv")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert_eq!(stdout, expect(&["1,0.5000,2.0000,2.5000,false"]));
+}
+
+#[test]
+fn glob() {
+    let output = run("./tests/daily/*.csv");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert_eq!(stdout, expect(&["1,0.5000,2.0000,2.5000,false"]));
+}
+
+#[test]
+fn glob_without_matches() {
+    let output = run("./tests/daily/*.json");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("No files match ./tests/daily/*.json"));
+}
+
+// The file's ids are json numbers, which string client ids and uuid tx ids aren't read from
+#[cfg(not(any(feature = "string-client-ids", feature = "uuid-tx-ids")))]
+#[test]
+fn jsonl() {
+    let mut cmd = process();
+    let output = cmd
+        .arg("--format")
+        .arg("jsonl")
+        .arg("./tests/deposit_and_withdraw.jsonl")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert_eq!(stdout, expect(&["1,1.5000,0.0000,1.5000,false"]));
+}
+
+#[test]
+fn fees() {
+    let mut cmd = process();
+    let output = cmd
+        .arg("--fees")
+        .arg("./tests/fees.csv")
+        .arg("./tests/deposit_and_withdraw.csv")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert_eq!(stdout, expect(&["1,1.3500,0.0000,1.3500,false"]));
+}
+
+#[test]
+fn currencies() {
+    let mut cmd = process();
+    let output = cmd
+        .arg("--base-currency")
+        .arg("GBP")
+        .arg("./tests/currencies.csv")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert_eq!(
+        stdout,
+        expect_currencies(&[
+            "1,1.0000,3.0000,4.0000,false,EUR",
+            "1,1.5000,0.0000,1.5000,false,GBP"
+        ])
+    );
+}
+
+#[test]
+fn verbose() {
+    let mut cmd = process();
+    let output = cmd
+        .arg("-vv")
+        .arg("./tests/rejects.csv")
+        .env_remove("RUST_LOG")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success());
+    assert!(stderr.contains("applied transaction"));
+    assert!(stderr.contains("rejected row"));
+    assert!(stderr.contains("insufficient available funds"));
+}
+
+#[test]
+fn journal() {
+    let journal = std::env::temp_dir().join("payments_journal.log");
+    let _ = std::fs::remove_file(&journal);
+
+    for _ in 0..2 {
+        let mut cmd = process();
+        let output = cmd
+            .arg("./tests/rejects.csv")
+            .arg("--journal")
+            .arg(&journal)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+    }
+
+    let journal = std::fs::read_to_string(&journal).unwrap();
+    let lines: Vec<_> = journal.lines().collect();
+    assert_eq!(lines.len(), 2);
+    // As the journal writes the ids, which are strings with string client ids or uuid tx ids
+    let client = serde_json::json!(payments::ClientId::from(1));
+    let tx = serde_json::json!(payments::TxId::from(1));
+    assert!(lines[0].contains(&format!(r#""type":"deposit","client":{client},"tx":{tx},"#)));
+    assert!(lines[0].contains(&format!(
+        r#""balances":[{{"client":{client},"currency":"USD","available":"1.0000","held":"0.0000","total":"1.0000","locked":false}}]"#
+    )));
+}
+
+#[cfg(any(not(feature = "minor-units"), feature = "bigdecimal"))]
+#[test]
+fn fast_csv() {
+    let run = |fast: bool| {
+        let journal = std::env::temp_dir().join(format!("payments_fast_csv_{fast}.log"));
+        let _ = std::fs::remove_file(&journal);
+        let mut cmd = process();
+        cmd.args(["--sort", "client", "./tests/exact_amounts.csv", "--journal"])
+            .arg(&journal);
+        if fast {
+            cmd.arg("--fast-csv");
+        }
+        let output = cmd.output().unwrap();
+        assert!(output.status.success());
+
+        // Without when each line was written, which is all that differs between runs
+        let journal: Vec<_> = std::fs::read_to_string(&journal)
+            .unwrap()
+            .lines()
+            .map(|line| line.split_once(',').unwrap().1.to_string())
+            .collect();
+
+        (String::from_utf8(output.stdout).unwrap(), journal)
+    };
+
+    let (stdout, journal) = run(false);
+    assert_eq!(
+        stdout,
+        expect(&[
+            "1,1.0000,2.5000,3.5000,false",
+            "2,123456789012345678.0000,0.0000,123456789012345678.0000,false",
+            "3,10.0000,0.0000,10.0000,false",
+        ])
+    );
+    assert!(journal[1].contains(r#""amount":"2.50""#));
+    assert_eq!(run(true), (stdout, journal));
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn zstd() {
+    let journal = std::env::temp_dir().join("payments_zstd.log.zst");
+    let accounts = std::env::temp_dir().join("payments_zstd.csv.zst");
+    let _ = std::fs::remove_file(&journal);
+
+    let output = process()
+        .arg("./tests/deposit_and_withdraw.csv")
+        .args(["--compress", "zstd", "--journal"])
+        .arg(&journal)
+        .arg("--output")
+        .arg(&accounts)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(std::fs::read(&accounts)
+        .unwrap()
+        .starts_with(&[0x28, 0xb5, 0x2f, 0xfd]));
+
+    // The journal is decompressed when it's replayed
+    let output = Command::cargo_bin("payments")
+        .unwrap()
+        .arg("replay")
+        .arg(&journal)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert_eq!(stdout, expect(&["1,1.5000,0.0000,1.5000,false"]));
+}
+
+#[cfg(feature = "bigdecimal")]
+#[test]
+fn bigdecimal() {
+    // Json strings, so the amounts are read exactly
+    let mut cmd = process();
+    let output = cmd
+        .arg("--format")
+        .arg("jsonl")
+        .arg("./tests/big_balances.jsonl")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert_eq!(
+        stdout,
+        expect(&[
+            "1,158456325028528675187087900669.5000,0.0000,158456325028528675187087900669.5000,false"
+        ])
+    );
+}
+
+#[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+#[test]
+fn minor_units() {
+    let rejects = std::env::temp_dir().join("payments_minor_units_rejects.csv");
+
+    let output = process()
+        .arg("./tests/precision.csv")
+        .arg("./tests/deposit_and_withdraw.csv")
+        .arg("--rejects")
+        .arg(&rejects)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert_eq!(stdout, expect(&["1,1.5000,0.0000,1.5000,false"]));
+    assert_eq!(
+        std::fs::read_to_string(&rejects)
+            .unwrap()
+            .matches("amount has more decimal places than allowed")
+            .count(),
+        3
+    );
+}
+
+#[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+#[test]
+fn minor_units_overflow() {
+    let rejects = std::env::temp_dir().join("payments_minor_units_overflow_rejects.csv");
+
+    let output = process()
+        .arg("./tests/overflow.csv")
+        .arg("--rejects")
+        .arg(&rejects)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert_eq!(
+        stdout,
+        expect(&["1,899999999999999.0000,0.0000,899999999999999.0000,false"])
+    );
+    assert_eq!(
+        std::fs::read_to_string(&rejects).unwrap(),
+        "file,line,row,reason\n\
+        ./tests/overflow.csv,3,\"deposit,1,2,900000000000000\",amount is too large\n"
+    );
+}
+
+#[cfg(feature = "s3")]
+#[test]
+fn s3_url() {
+    let output = run("s3://bucket-without-a-key");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("s3://bucket-without-a-key isn't an s3://bucket/key url"));
+}
+
+#[test]
+fn replay() {
+    let journal = std::env::temp_dir().join("payments_replay.log");
+    let _ = std::fs::remove_file(&journal);
+
+    let mut cmd = process();
+    let output = cmd
+        .arg("./tests/deposit_and_withdraw.csv")
+        .arg("--journal")
+        .arg(&journal)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    for (until, expected) in [
+        (None, "1,1.5000,0.0000,1.5000,false"),
+        (Some("3"), "1,3.0000,0.0000,3.0000,false"),
+    ] {
+        let mut cmd = Command::cargo_bin("payments").unwrap();
+        cmd.arg("replay").arg(&journal);
+
+        if let Some(until) = until {
+            cmd.arg("--until").arg(until);
+        }
+
+        let output = cmd.output().unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success());
+        assert_eq!(stdout, expect(&[expected]));
+    }
+
+    let mut cmd = Command::cargo_bin("payments").unwrap();
+    let output = cmd
+        .arg("replay")
+        .arg(&journal)
+        .arg("--fees")
+        .arg("./tests/fees.csv")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+}
+
+#[test]
+fn statement() {
+    let journal = std::env::temp_dir().join("payments_statement.log");
+    let _ = std::fs::remove_file(&journal);
+
+    let mut cmd = process();
+    let output = cmd
+        .arg("./tests/deposit_and_withdraw.csv")
+        .arg("--journal")
+        .arg(&journal)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let mut cmd = Command::cargo_bin("payments").unwrap();
+    let output = cmd
+        .arg("statement")
+        .arg(&journal)
+        .arg("--client")
+        .arg("1")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    // The date is when the test ran
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let rows: Vec<_> = stdout
+        .lines()
+        .map(|line| line.split_once(',').unwrap().1)
+        .collect();
+    assert_eq!(
+        rows,
+        [
+            "type,tx,amount,currency,available,held,total,locked",
+            "deposit,1,1.0000,USD,1.0000,0.0000,1.0000,false",
+            "deposit,3,2.0000,USD,3.0000,0.0000,3.0000,false",
+            "withdrawal,4,1.5000,USD,1.5000,0.0000,1.5000,false",
+        ]
+    );
+}
+
+#[test]
+fn summary() {
+    let summary = std::env::temp_dir().join("payments_summary.json");
+    let _ = std::fs::remove_file(&summary);
+
+    let mut cmd = process();
+    let output = cmd
+        .arg("./tests/chargeback.csv")
+        .arg("./tests/some_junk.csv")
+        .arg("--summary")
+        .arg(&summary)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let summary: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&summary).unwrap()).unwrap();
+    let amount = |value: &serde_json::Value| value.as_str().unwrap().parse::<f64>().unwrap();
+
+    // some_junk.csv's deposit of tx 1 fails as chargeback.csv has already used it
+    assert_eq!(summary["rows"], 10);
+    assert_eq!(summary["malformed"], 3);
+    assert_eq!(summary["rejected_rows"], 4);
+    assert_eq!(summary["applied"]["deposit"], 3);
+    assert_eq!(summary["applied"]["chargeback"], 1);
+    assert_eq!(summary["rejected"]["deposit"], 1);
+    assert_eq!(amount(&summary["deposits"]["USD"]), 8.0);
+    assert_eq!(amount(&summary["withdrawals"]["USD"]), 1.5);
+    assert_eq!(summary["locked_accounts"], 1);
+
+    let mut cmd = process();
+    let output = cmd
+        .arg("./tests/chargeback.csv")
+        .arg("--summary")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("\"locked_accounts\": 1"));
+}
+
+#[cfg(not(feature = "string-client-ids"))]
+#[test]
+fn wide_client_ids() {
+    let output = process()
+        .args(["--sort", "client", "./tests/wide_clients.csv"])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert_eq!(
+        stdout,
+        expect(&[
+            "70000,1.0000,0.0000,1.0000,false",
+            "18446744073709551615,2.0000,0.0000,2.0000,false"
+        ])
+    );
+}
+
+#[cfg(feature = "string-client-ids")]
+#[test]
+fn string_client_ids() {
+    let output = process()
+        .args(["--sort", "client", "./tests/string_clients.csv"])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert_eq!(
+        stdout,
+        expect(&[
+            "007,1.0000,0.0000,1.0000,false",
+            "acme-0042,1.5000,0.0000,1.5000,false"
+        ])
+    );
+}
+
+// The file's txs are json numbers, which uuid tx ids aren't read from
+#[cfg(all(feature = "string-client-ids", not(feature = "uuid-tx-ids")))]
+#[test]
+fn string_client_ids_jsonl() {
+    let output = process()
+        .args(["--sort", "client", "--format", "jsonl"])
+        .arg("./tests/string_clients.jsonl")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert_eq!(
+        stdout,
+        expect(&[
+            "007,1.0000,0.0000,1.0000,false",
+            "acme-0042,1.5000,0.0000,1.5000,false"
+        ])
+    );
+}
+
+#[test]
+fn wide_tx_ids() {
+    let output = process().arg("./tests/wide_txs.csv").output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert_eq!(stdout, expect(&["1,2.0000,3.0000,5.0000,false"]));
+}
+
+#[cfg(feature = "uuid-tx-ids")]
+#[test]
+fn uuid_tx_ids() {
+    let output = process().arg("./tests/uuid_txs.csv").output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert_eq!(stdout, expect(&["1,2.0000,3.0000,5.0000,false"]));
+}
+
+#[test]
+fn delimiter() {
+    let rejects = std::env::temp_dir().join("payments_delimiter_rejects.csv");
+
+    for parse_threads in ["1", "2"] {
+        let output = process()
+            .args(["--delimiter", ";", "--parse-threads", parse_threads])
+            .arg("./tests/semicolons.csv")
+            .arg("--rejects")
+            .arg(&rejects)
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success());
+        assert_eq!(stdout, expect(&["1,1.5000,0.0000,1.5000,false"]));
+        assert_eq!(
+            std::fs::read_to_string(&rejects).unwrap(),
+            "file,line,row,reason\n\
+            ./tests/semicolons.csv,4,withdrawal;1;3;5.0,insufficient available funds\n"
+        );
+    }
+
+    let output = process()
+        .args(["--delimiter", ";;", "./tests/semicolons.csv"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+}
+
+#[test]
+fn columns() {
+    let output = process()
+        .args([
+            "--columns",
+            "type=txn_type,client=customer_id,tx=txn_id",
+            "--columns",
+            "amount=value",
+            "./tests/renamed_columns.csv",
+        ])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert_eq!(stdout, expect(&["1,1.5000,0.0000,1.5000,false"]));
+
+    let output = process()
+        .args(["--columns", "kind=txn_type", "./tests/renamed_columns.csv"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("can't map kind"));
+}
+
+#[test]
+fn decimal_separator() {
+    let output = process()
+        .args(["--delimiter", ";", "--decimal-separator", ","])
+        .args(["--sort", "client", "./tests/decimal_commas.csv"])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert_eq!(
+        stdout,
+        expect(&[
+            "1,1234.0600,0.0000,1234.0600,false",
+            "2,7.2500,0.0000,7.2500,false"
+        ])
+    );
+}
+
+#[test]
+fn negative_balances() {
+    let negative = std::env::temp_dir().join("payments_negative_balances.csv");
+    let output = process()
+        .arg("./tests/negative.csv")
+        .arg("--negative-balances")
+        .arg(&negative)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(negative).unwrap(),
+        "client,currency,tx,type,available,total\n\
+        1,USD,1,dispute,-3.0000,2.0000\n\
+        1,USD,1,chargeback,-3.0000,-3.0000\n"
+    );
+}
+
+#[test]
+fn watch() {
+    let dir = std::env::temp_dir().join("payments_watch");
+    let output = std::env::temp_dir().join("payments_watch_accounts.csv");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::copy("./tests/deposit_and_withdraw.csv", dir.join("1.csv")).unwrap();
+    std::fs::copy("./tests/junk.csv", dir.join("2.csv")).unwrap();
+
+    let mut watch = std::process::Command::new(assert_cmd::cargo::cargo_bin("payments"))
+        .arg("watch")
+        .arg(&dir)
+        .arg("--interval")
+        .arg("1")
+        .arg("--output")
+        .arg(&output)
+        .spawn()
+        .unwrap();
+
+    // Files are only read once they're the same size on the next check
+    let processed = dir.join("processed").join("1.csv");
+    let failed = dir.join("failed").join("2.csv");
+
+    for _ in 0..100 {
+        if processed.exists() && failed.exists() {
+            break;
+        }
+
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+
+    watch.kill().unwrap();
+    watch.wait().unwrap();
+
+    assert!(processed.exists());
+    assert!(failed.exists());
+    assert_eq!(
+        std::fs::read_to_string(output).unwrap(),
+        expect(&["1,1.5000,0.0000,1.5000,false"])
+    );
+}
+
+#[test]
+fn sort() {
+    let mut cmd = process();
+    let output = cmd
+        .arg("--sort")
+        .arg("client")
+        .arg("./tests/clients.csv")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert_eq!(
+        stdout,
+        expect(&[
+            "1,3.0000,0.0000,3.0000,false",
+            "2,2.0000,0.0000,2.0000,false",
+            "3,1.0000,0.0000,1.0000,false",
+            "4,2.5000,0.0000,2.5000,false",
+            "5,0.5000,0.0000,0.5000,false"
+        ])
+    );
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn mmap() {
+    let output = process()
+        .arg("./tests/deposit_and_withdraw.csv")
+        .arg("--mmap")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        output.stdout,
+        run("./tests/deposit_and_withdraw.csv").stdout
+    );
+}
+
+#[cfg(feature = "io-uring")]
+#[test]
+fn io_uring() {
+    for read_buffer in ["7", "1M"] {
+        let output = process()
+            .arg("./tests/deposit_and_withdraw.csv")
+            .args(["--io-uring", "--read-buffer", read_buffer])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            output.stdout,
+            run("./tests/deposit_and_withdraw.csv").stdout
+        );
+    }
+}
+
+#[test]
+fn buffers() {
+    let output = process()
+        .arg("./tests/deposit_and_withdraw.csv")
+        .args(["--read-buffer", "1", "--write-buffer", "2K"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        output.stdout,
+        run("./tests/deposit_and_withdraw.csv").stdout
+    );
+
+    let output = process()
+        .arg("./tests/deposit_and_withdraw.csv")
+        .args(["--read-buffer", "8X"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn state() {
+    let state = std::env::temp_dir().join("payments_state.db");
+    let _ = std::fs::remove_file(&state);
+
+    for (file, expected) in [
+        (
+            "./tests/daily/2022-09-01.csv",
+            "1,3.0000,0.0000,3.0000,false",
+        ),
+        (
+            "./tests/daily/2022-09-02.csv",
+            "1,0.5000,2.0000,2.5000,false",
+        ),
+    ] {
+        let mut cmd = process();
+        let output = cmd.arg(file).arg("--state").arg(&state).output().unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success());
+        assert_eq!(stdout, expect(&[expected]));
+    }
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn state_rerun() {
+    let state = std::env::temp_dir().join("payments_state_rerun.db");
+    let _ = std::fs::remove_file(&state);
+
+    for _ in 0..2 {
+        let mut cmd = process();
+        let output = cmd
+            .arg("./tests/daily/2022-09-01.csv")
+            .arg("--state")
+            .arg(&state)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+    }
+
+    // Overlaps with the first file so only the withdrawal and dispute are applied
+    let mut cmd = process();
+    let output = cmd
+        .arg("./tests/daily/2022-09-01.csv")
+        .arg("./tests/overlapping.csv")
+        .arg("--state")
+        .arg(&state)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success());
+    assert!(stderr.contains("Skipping ./tests/daily/2022-09-01.csv"));
+    assert_eq!(stdout, expect(&["1,0.5000,2.0000,2.5000,false"]));
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn state_scheduled() {
+    let state = std::env::temp_dir().join("payments_state_scheduled.db");
+    let _ = std::fs::remove_file(&state);
+    let first = std::env::temp_dir().join("payments_state_scheduled_1.csv");
+    let second = std::env::temp_dir().join("payments_state_scheduled_2.csv");
+    std::fs::write(
+        &first,
+        "type,client,tx,amount,timestamp,effective\ndeposit,1,1,2.0,1000,\ndeposit,1,2,3.0,1000,5000\n",
+    )
+    .unwrap();
+    std::fs::write(
+        &second,
+        "type,client,tx,amount,timestamp,effective\ndeposit,1,3,1.0,6000,\n",
+    )
+    .unwrap();
+
+    // The deposit scheduled by the first run is kept in the state and applied once the second run's clock
+    // reaches it
+    for (file, expected) in [
+        (&first, "1,2.0000,0.0000,2.0000,false"),
+        (&second, "1,6.0000,0.0000,6.0000,false"),
+    ] {
+        let output = process()
+            .arg(file)
+            .arg("--state")
+            .arg(&state)
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success());
+        assert_eq!(stdout, expect(&[expected]));
+    }
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn state_redispute_resolved() {
+    let state = std::env::temp_dir().join("payments_state_redispute.db");
+    let _ = std::fs::remove_file(&state);
+
+    // Each file starts another dispute cycle after resolving the last, rather than being taken for the first
+    for (file, expected) in [
+        (
+            "./tests/redispute/2022-09-01.csv",
+            "1,0.0000,5.0000,5.0000,false",
+        ),
+        (
+            "./tests/redispute/2022-09-02.csv",
+            "1,0.0000,5.0000,5.0000,false",
+        ),
+    ] {
+        let output = process()
+            .arg(file)
+            .args(["--redispute-resolved", "--state"])
+            .arg(&state)
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success());
+        assert!(output.stderr.is_empty());
+        assert_eq!(stdout, expect(&[expected]));
+    }
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn query() {
+    let state = std::env::temp_dir().join("payments_query.db");
+    let _ = std::fs::remove_file(&state);
+
+    let output = process()
+        .arg("./tests/daily/2022-09-01.csv")
+        .arg("./tests/daily/2022-09-02.csv")
+        .arg("--state")
+        .arg(&state)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let output = Command::cargo_bin("payments")
+        .unwrap()
+        .arg("query")
+        .arg("--state")
+        .arg(&state)
+        .args(["--client", "1"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let query: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let amount = |value: &serde_json::Value| value.as_str().unwrap().parse::<f64>().unwrap();
+
+    assert_eq!(query["locked"], false);
+    assert_eq!(query["balances"][0]["currency"], "USD");
+    assert_eq!(amount(&query["balances"][0]["available"]), 0.5);
+    assert_eq!(amount(&query["balances"][0]["held"]), 2.0);
+    assert_eq!(query["open_disputes"].as_array().unwrap().len(), 1);
+    assert_eq!(
+        query["open_disputes"][0]["tx"],
+        serde_json::json!(payments::TxId::from(1))
+    );
+    assert_eq!(amount(&query["open_disputes"][0]["amount"]), 2.0);
+
+    let output = Command::cargo_bin("payments")
+        .unwrap()
+        .arg("query")
+        .arg("--state")
+        .arg(&state)
+        .args(["--client", "2"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("No client 2"));
+}
+
+#[test]
+fn save_state() {
+    let state = std::env::temp_dir().join("payments_save_state.bin");
+    let _ = std::fs::remove_file(&state);
+
+    let output = process()
+        .arg("./tests/daily/2022-09-01.csv")
+        .arg("--save-state")
+        .arg(&state)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    // The second day disputes a deposit from the first
+    let output = process()
+        .arg("./tests/daily/2022-09-02.csv")
+        .arg("--load-state")
+        .arg(&state)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert_eq!(stdout, expect(&["1,0.5000,2.0000,2.5000,false"]));
+}
+
+#[test]
+fn merge() {
+    let first = std::env::temp_dir().join("payments_merge_first.bin");
+    let second = std::env::temp_dir().join("payments_merge_second.bin");
+
+    for (file, state) in [
+        ("./tests/chargeback.csv", &first),
+        ("./tests/deposit_and_withdraw.csv", &second),
+    ] {
+        let output = process()
+            .arg(file)
+            .arg("--save-state")
+            .arg(state)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+    }
+
+    let output = Command::cargo_bin("payments")
+        .unwrap()
+        .arg("merge")
+        .arg(&first)
+        .arg(&second)
+        .arg("--sort")
+        .arg("client")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert_eq!(
+        stdout,
+        expect(&[
+            "0,5.0000,0.0000,5.0000,true",
+            "1,1.5000,0.0000,1.5000,false"
+        ])
+    );
+
+    let output = Command::cargo_bin("payments")
+        .unwrap()
+        .arg("merge")
+        .arg(&second)
+        .arg(&second)
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("client 1 is in more than one of the merged states"));
+}
+
+#[test]
+fn diff() {
+    let output = Command::cargo_bin("payments")
+        .unwrap()
+        .arg("diff")
+        .arg("./tests/accounts/2022-09-01.csv")
+        .arg("./tests/accounts/2022-09-02.csv")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert_eq!(
+        stdout,
+        "client,available,held,total,lock,currency\n\
+         2,-3.0000,0.0000,-3.0000,locked,USD\n\
+         3,1.0000,0.0000,1.0000,,EUR\n\
+         4,5.0000,0.0000,5.0000,,USD\n"
+    );
+}
+
+#[test]
+fn resume() {
+    let checkpoint = std::env::temp_dir().join("payments_checkpoint.bin");
+
+    let mut cmd = process();
+    let output = cmd
+        .arg("./tests/daily/2022-09-01.csv")
+        .arg("--checkpoint")
+        .arg(&checkpoint)
+        .arg("--checkpoint-every")
+        .arg("1")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    // The first file is finished in the checkpoint so its deposits aren't applied again
+    let mut cmd = process();
+    let output = cmd
+        .arg("./tests/daily/2022-09-01.csv")
+        .arg("./tests/daily/2022-09-02.csv")
+        .arg("--resume")
+        .arg(&checkpoint)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert_eq!(stdout, expect(&["1,0.5000,2.0000,2.5000,false"]));
+}
+
+#[test]
+fn stdin() {
+    let mut cmd = process();
+    let output = cmd
+        .arg("-")
+        .write_stdin(std::fs::read("./tests/deposit_and_withdraw.csv").unwrap())
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert_eq!(stdout, expect(&["1,1.5000,0.0000,1.5000,false"]));
+}
+
+#[cfg(unix)]
+#[test]
+fn interrupted_stdout() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let mut child = std::process::Command::new(assert_cmd::cargo::cargo_bin("payments"))
+        .args(["process", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    stdin
+        .write_all(b"type,client,tx,amount\ndeposit,1,1,1.0\n")
+        .unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(500));
+
+    let killed = std::process::Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(killed.success());
+    std::thread::sleep(std::time::Duration::from_millis(200));
+
+    // Reading from stdin stops once the next row arrives
+    stdin.write_all(b"deposit,1,2,1.0\n").unwrap();
+    drop(stdin);
+
+    let output = child.wait_with_output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(130));
+    assert_eq!(stdout, expect(&["1,1.0000,0.0000,1.0000,false"]));
+    assert!(stderr.contains("the accounts written to stdout are partial"));
+}
+
+#[test]
+fn output() {
+    let dir = std::env::temp_dir().join("payments_output");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir(&dir).unwrap();
+    let accounts = dir.join("accounts.csv");
+
+    let output = process()
+        .arg("./tests/deposit_and_withdraw.csv")
+        .arg("--output")
+        .arg(&accounts)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    assert_eq!(
+        std::fs::read_to_string(&accounts).unwrap(),
+        expect(&["1,1.5000,0.0000,1.5000,false"])
+    );
+    // Only the accounts are left, not the temporary file
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+}
+
+#[test]
+fn rejects() {
+    let rejects = std::env::temp_dir().join("payments_rejects.csv");
+    let mut cmd = process();
+    let output = cmd
+        .arg("./tests/rejects.csv")
+        .arg("--rejects")
+        .arg(&rejects)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert_eq!(stdout, expect(&["1,1.0000,0.0000,1.0000,false"]));
+    assert_eq!(
+        std::fs::read_to_string(rejects).unwrap(),
+        "file,line,row,reason\n\
+        ./tests/rejects.csv,3,junk,malformed row: expected 4 fields but found 1\n\
+        ./tests/rejects.csv,4,\"withdrawal,1,2,5.0\",insufficient available funds\n\
+        ./tests/rejects.csv,5,\"dispute,1,9,\",referenced transaction does not exist\n\
+        ./tests/rejects.csv,6,\"deposit,1,1,2.0\",transaction id has already been used\n"
+    );
+}
+
+#[test]
+fn keep_columns() {
+    let rejects = std::env::temp_dir().join("payments_keep_columns_rejects.csv");
+    let journal = std::env::temp_dir().join("payments_keep_columns.log");
+    let _ = std::fs::remove_file(&journal);
+
+    let output = process()
+        .arg("./tests/extra_columns.csv")
+        .args(["--sort", "client", "--keep-columns"])
+        .arg("--rejects")
+        .arg(&rejects)
+        .arg("--journal")
+        .arg(&journal)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert_eq!(
+        stdout,
+        expect(&[
+            "1,1.0000,0.0000,1.0000,false",
+            "2,2.0000,0.0000,2.0000,false"
+        ])
+    );
+    assert_eq!(
+        std::fs::read_to_string(rejects).unwrap(),
+        "file,line,row,reason,metadata\n\
+        ./tests/extra_columns.csv,3,\"withdrawal,1,2,5.0,m-17,inv-002\",insufficient available funds,\
+        \"{\"\"merchant\"\":\"\"m-17\"\",\"\"reference\"\":\"\"inv-002\"\"}\"\n"
+    );
+
+    let journal = std::fs::read_to_string(&journal).unwrap();
+    let lines: Vec<_> = journal.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].contains(r#""metadata":{"merchant":"m-17","reference":"inv-001"}"#));
+    assert!(lines[1].contains(r#""metadata":{"merchant":"m-4"}"#));
+}
+
+#[test]
+fn dry_run() {
+    let rejects = std::env::temp_dir().join("payments_dry_run_rejects.csv");
+    let state = std::env::temp_dir().join("payments_dry_run_state.bin");
+    let _ = std::fs::remove_file(&state);
+
+    let output = process()
+        .arg("./tests/rejects.csv")
+        .arg("--dry-run")
+        .arg("--rejects")
+        .arg(&rejects)
+        .arg("--save-state")
+        .arg(&state)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(!state.exists());
+    assert_eq!(std::fs::read_to_string(rejects).unwrap().lines().count(), 5);
+}
+
+#[test]
+fn reconcile() {
+    for threads in ["1", "4"] {
+        let output = process()
+            .arg("./tests/chargeback.csv")
+            .arg("./tests/currencies.csv")
+            .args(["--reconcile", "--threads", threads])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+    }
+}
+
+#[test]
+fn generate() {
+    let generate = || {
+        Command::cargo_bin("payments")
+            .unwrap()
+            .args(["generate", "--rows", "2000", "--clients", "20"])
+            .args(["--dispute-rate", "0.05", "--seed", "42"])
+            .output()
+            .unwrap()
+    };
+
+    let output = generate();
+    let csv = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert_eq!(csv.lines().count(), 2001);
+    assert!(csv.starts_with("type,client,tx,amount\n"));
+    assert!(csv.contains("\ndispute,"));
+    assert_eq!(output.stdout, generate().stdout);
+
+    let output = process()
+        .arg("-")
+        .arg("--strict")
+        .write_stdin(output.stdout)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+}
+
 fn run(file: &str) -> Output {
+    process().arg(file).output().unwrap()
+}
+
+fn process() -> Command {
     let mut cmd = Command::cargo_bin("payments").unwrap();
-    cmd.arg(file).output().unwrap()
+    cmd.arg("process");
+    cmd
 }
 
 fn expect(expected_accounts: &[&str]) -> String {
@@ -97,3 +1358,8 @@ fn expect(expected_accounts: &[&str]) -> String {
 
     expect
 }
+
+/// `expect` for inputs with currencies, which are written in a last column.
+fn expect_currencies(expected_accounts: &[&str]) -> String {
+    expect(expected_accounts).replacen('\n', ",currency\n", 1)
+}